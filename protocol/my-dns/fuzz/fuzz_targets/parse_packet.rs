@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use my_dns::packets::{BytePacketBuffer, DnsPacket, VectorPacketBuffer};
+
+// Any input may be rejected, but none may panic
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = VectorPacketBuffer::from_bytes(data);
+    let _ = DnsPacket::from_buffer(&mut buffer);
+
+    if data.len() <= 512 {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buffer[..data.len()].copy_from_slice(data);
+        let _ = DnsPacket::from_buffer(&mut buffer);
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use my_dns::packets::{DnsPacket, PacketBuffer, VectorPacketBuffer};
+
+// Whatever we manage to parse and write back out has to parse again
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = VectorPacketBuffer::from_bytes(data);
+    let mut packet = match DnsPacket::from_buffer(&mut buffer) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+
+    let mut written = VectorPacketBuffer::new();
+    if packet.write(&mut written).is_err() {
+        return;
+    }
+
+    let mut reread = VectorPacketBuffer::from_bytes(&written.buffer[..written.pos()]);
+    DnsPacket::from_buffer(&mut reread).expect("a message we wrote must parse");
+});
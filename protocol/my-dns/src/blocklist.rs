@@ -0,0 +1,346 @@
+//! Domain blocklists, for running the server as an ad and tracker blocker.
+//! Lists are read from hosts files or plain domain lists, and queries for
+//! listed names are answered according to the list's policy.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::name::DnsName;
+use crate::packets::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// TTL of the sinkhole addresses we make up
+const SINKHOLE_TTL: u32 = 60;
+
+/// Names hosts files map to themselves, which must keep resolving
+const IGNORED_HOSTS: [&str; 11] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+/// How queries for a blocked name are answered
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlockPolicy {
+    /// Pretend the name does not exist
+    #[default]
+    NxDomain,
+    /// Answer address queries with an address that goes nowhere
+    Sinkhole { v4: Ipv4Addr, v6: Ipv6Addr },
+    /// Refuse to answer
+    Refused,
+}
+
+impl FromStr for BlockPolicy {
+    type Err = String;
+
+    /// `nxdomain`, `refused`, `sinkhole` for the unspecified addresses, or
+    /// `sinkhole=addr` to hand out another IPv4 or IPv6 address
+    fn from_str(s: &str) -> std::result::Result<BlockPolicy, String> {
+        let unspecified = BlockPolicy::Sinkhole {
+            v4: Ipv4Addr::UNSPECIFIED,
+            v6: Ipv6Addr::UNSPECIFIED,
+        };
+        match s.to_ascii_lowercase().as_str() {
+            "nxdomain" => return Ok(BlockPolicy::NxDomain),
+            "refused" => return Ok(BlockPolicy::Refused),
+            "sinkhole" => return Ok(unspecified),
+            _ => {}
+        }
+
+        let addr = s
+            .strip_prefix("sinkhole=")
+            .and_then(|addr| addr.parse::<IpAddr>().ok())
+            .ok_or_else(|| format!("Invalid block policy: {}", s))?;
+        Ok(match addr {
+            IpAddr::V4(v4) => BlockPolicy::Sinkhole {
+                v4,
+                v6: Ipv6Addr::UNSPECIFIED,
+            },
+            IpAddr::V6(v6) => BlockPolicy::Sinkhole {
+                v4: Ipv4Addr::UNSPECIFIED,
+                v6,
+            },
+        })
+    }
+}
+
+impl BlockPolicy {
+    /// Fill in `response` to a query for a blocked name
+    pub fn apply(&self, response: &mut DnsPacket, qname: &DnsName, qtype: QueryType) {
+        response.header.authed_data = false;
+        response.answers.clear();
+        response.authorities.clear();
+        response.resources.clear();
+
+        match *self {
+            BlockPolicy::NxDomain => response.header.rescode = ResultCode::NXDOMAIN,
+            BlockPolicy::Refused => response.header.rescode = ResultCode::REFUSED,
+            BlockPolicy::Sinkhole { v4, v6 } => {
+                response.header.rescode = ResultCode::NOERROR;
+                // other types get an empty answer
+                match qtype {
+                    QueryType::A => response.answers.push(DnsRecord::A {
+                        domain: qname.clone(),
+                        addr: v4,
+                        ttl: SINKHOLE_TTL,
+                    }),
+                    QueryType::AAAA => response.answers.push(DnsRecord::AAAA {
+                        domain: qname.clone(),
+                        addr: v6,
+                        ttl: SINKHOLE_TTL,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// The names of a list: blocked exactly, or with all their subdomains
+/// when written as `*.domain`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entries {
+    pub exact: HashSet<DnsName>,
+    pub wildcards: HashSet<DnsName>,
+}
+
+impl Entries {
+    /// Parse a hosts file (`0.0.0.0 name...`) or a list with one domain
+    /// per line; both may be mixed. Lines that are neither are skipped,
+    /// lists from the internet are rarely clean.
+    pub fn parse(text: &str) -> Entries {
+        let mut entries = Entries::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace().peekable();
+
+            let hosts_format = words
+                .peek()
+                .is_some_and(|word| word.parse::<IpAddr>().is_ok());
+            if hosts_format {
+                words.next();
+            } else if line.split_whitespace().count() > 1 {
+                continue;
+            }
+            for word in words {
+                if hosts_format && IGNORED_HOSTS.contains(&word.to_ascii_lowercase().as_str()) {
+                    continue;
+                }
+                entries.add(word);
+            }
+        }
+        entries
+    }
+
+    fn add(&mut self, word: &str) {
+        let (set, domain) = match word.strip_prefix("*.") {
+            Some(domain) => (&mut self.wildcards, domain),
+            None => (&mut self.exact, word),
+        };
+        match domain.parse::<DnsName>() {
+            Ok(name) if !name.is_root() => {
+                set.insert(name);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn matches(&self, name: &DnsName) -> bool {
+        if self.exact.contains(name) {
+            return true;
+        }
+        (1..name.label_count()).any(|n| self.wildcards.contains(&name.suffix(n)))
+    }
+}
+
+/// A blocklist file, reloaded when it changes on disk
+#[derive(Debug)]
+pub struct Blocklist {
+    pub path: PathBuf,
+    pub policy: BlockPolicy,
+    entries: RwLock<Entries>,
+    /// Modification time and size of the file we loaded
+    stamp: Mutex<Option<(SystemTime, u64)>>,
+    blocked: AtomicU64,
+}
+
+impl Blocklist {
+    pub fn load<P: AsRef<Path>>(path: P, policy: BlockPolicy) -> Result<Blocklist> {
+        let list = Blocklist::new(path.as_ref().to_path_buf(), policy, Entries::default());
+        list.reload()?;
+        Ok(list)
+    }
+
+    pub fn new(path: PathBuf, policy: BlockPolicy, entries: Entries) -> Blocklist {
+        Blocklist {
+            path,
+            policy,
+            entries: RwLock::new(entries),
+            stamp: Mutex::new(None),
+            blocked: AtomicU64::new(0),
+        }
+    }
+
+    /// Read the file again if it changed since it was last loaded. Returns
+    /// whether it did; on errors the entries loaded before stay in use.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let stamp = file_stamp(&self.path)?;
+        if *self.stamp.lock().unwrap() == Some(stamp) {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    fn reload(&self) -> Result<()> {
+        let stamp = file_stamp(&self.path)?;
+        let text = fs::read_to_string(&self.path)?;
+        *self.entries.write().unwrap() = Entries::parse(&text);
+        *self.stamp.lock().unwrap() = Some(stamp);
+        Ok(())
+    }
+
+    /// Whether `name` is blocked, counting it if so
+    pub fn check(&self, name: &DnsName) -> bool {
+        let blocked = self.entries.read().unwrap().matches(name);
+        if blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        blocked
+    }
+
+    /// How many names the list holds
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many queries the list has blocked
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
+
+fn file_stamp(path: &Path) -> Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    const LIST: &str = "\
+# a hosts file
+127.0.0.1 localhost
+::1 ip6-localhost ip6-loopback
+0.0.0.0 ads.example.com tracker.example.net # trailing comment
+0.0.0.0\tMetrics.Example.org
+
+# plain domains and wildcards
+doubleclick.test
+*.telemetry.test
+prose is not a list of domains
+bad..name
+";
+
+    #[test]
+    fn test_parse_formats() {
+        let entries = Entries::parse(LIST);
+        assert!(entries.matches(&name("ads.example.com")));
+        assert!(entries.matches(&name("tracker.example.net")));
+        assert!(entries.matches(&name("metrics.example.org")));
+        assert!(entries.matches(&name("doubleclick.test")));
+        assert!(!entries.matches(&name("localhost")));
+        assert!(!entries.matches(&name("ip6-loopback")));
+        assert!(!entries.matches(&name("www.ads.example.com")));
+        assert!(!entries.matches(&name("example.com")));
+        assert!(!entries.matches(&name("prose")));
+
+        // wildcards block everything below, but not the name itself
+        assert!(entries.matches(&name("eu.telemetry.test")));
+        assert!(entries.matches(&name("a.b.telemetry.test")));
+        assert!(!entries.matches(&name("telemetry.test")));
+    }
+
+    #[test]
+    fn test_block_policy() {
+        assert_eq!("NXDOMAIN".parse(), Ok(BlockPolicy::NxDomain));
+        assert_eq!("refused".parse(), Ok(BlockPolicy::Refused));
+        assert_eq!(
+            "sinkhole=192.0.2.1".parse(),
+            Ok(BlockPolicy::Sinkhole {
+                v4: Ipv4Addr::new(192, 0, 2, 1),
+                v6: Ipv6Addr::UNSPECIFIED,
+            })
+        );
+        assert!("sinkhole=nowhere".parse::<BlockPolicy>().is_err());
+
+        let mut response = DnsPacket::new();
+        let policy: BlockPolicy = "sinkhole".parse().unwrap();
+        policy.apply(&mut response, &name("ads.example.com"), QueryType::AAAA);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            response.answers,
+            [DnsRecord::AAAA {
+                domain: name("ads.example.com"),
+                addr: Ipv6Addr::UNSPECIFIED,
+                ttl: SINKHOLE_TTL,
+            }]
+        );
+        policy.apply(&mut response, &name("ads.example.com"), QueryType::MX);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn test_reload_when_changed() {
+        let path = std::env::temp_dir().join(format!("my-dns-blocklist-{}", std::process::id()));
+        fs::write(&path, "ads.example.com\n").unwrap();
+
+        let list = Blocklist::load(&path, BlockPolicy::NxDomain).unwrap();
+        assert!(list.check(&name("ads.example.com")));
+        assert!(!list.check(&name("new.example.com")));
+        assert_eq!(list.blocked(), 1);
+        assert!(!list.reload_if_changed().unwrap());
+
+        fs::write(&path, "ads.example.com\nnew.example.com\n").unwrap();
+        assert!(list.reload_if_changed().unwrap());
+        assert!(list.check(&name("new.example.com")));
+        assert_eq!(list.len(), 2);
+
+        // a list that went missing keeps its last entries
+        fs::remove_file(&path).unwrap();
+        assert!(list.reload_if_changed().is_err());
+        assert!(list.check(&name("ads.example.com")));
+        assert_eq!(list.blocked(), 3);
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::name::DnsName;
+use crate::packets::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: DnsName,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &DnsName, qtype: QueryType, class: u16) -> CacheKey {
+        CacheKey {
+            name: name.clone(),
+            qtype,
+            class,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+}
+
+struct CacheEntry {
+    rescode: ResultCode,
+    /// Whether the response was validated with DNSSEC
+    authed_data: bool,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    resources: Vec<DnsRecord>,
+    inserted: Instant,
+    expires: Instant,
+    /// Position in the LRU order
+    tick: u64,
+}
+
+/// Answers from upstream, kept for as long as their TTLs allow. Negative
+/// answers are kept too, for the time the zone's SOA says (RFC 2308).
+pub struct DnsCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys by the tick they were last used at, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> DnsCache {
+        DnsCache {
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    pub fn lookup(&mut self, key: &CacheKey) -> Option<DnsPacket> {
+        self.lookup_at(key, Instant::now())
+    }
+
+    /// Look up a cached response, with TTLs lowered by the time it has
+    /// spent in the cache
+    pub fn lookup_at(&mut self, key: &CacheKey, now: Instant) -> Option<DnsPacket> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => now >= entry.expires,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            self.remove(key);
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.tick);
+        self.lru.insert(tick, key.clone());
+        entry.tick = tick;
+        self.stats.hits += 1;
+
+        let elapsed = (now - entry.inserted).as_secs() as u32;
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = entry.rescode;
+        packet.header.authed_data = entry.authed_data;
+        packet.answers = decrement_ttls(&entry.answers, elapsed);
+        packet.authorities = decrement_ttls(&entry.authorities, elapsed);
+        packet.resources = decrement_ttls(&entry.resources, elapsed);
+        if packet.answers.is_empty() {
+            // the SOA of a negative answer tells downstream caches how long
+            // to keep it, which is no longer than we do (RFC 2308 section 5)
+            let ttl = (entry.expires - entry.inserted).as_secs() as u32;
+            let remaining = ttl.saturating_sub(elapsed);
+            for rec in &mut packet.authorities {
+                if rec.query_type() == QueryType::SOA && rec.ttl() > remaining {
+                    rec.set_ttl(remaining);
+                }
+            }
+        }
+        Some(packet)
+    }
+
+    pub fn insert(&mut self, key: CacheKey, response: &DnsPacket) {
+        self.insert_at(key, response, Instant::now())
+    }
+
+    /// Cache `response` if it can be: positive answers live as long as
+    /// their shortest TTL, NXDOMAIN and NODATA as long as the SOA in the
+    /// authority section allows. Anything else is ignored.
+    pub fn insert_at(&mut self, key: CacheKey, response: &DnsPacket, now: Instant) {
+        if self.capacity == 0 || response.header.truncated_message {
+            return;
+        }
+
+        let ttl = match response.header.rescode {
+            ResultCode::NOERROR if !response.answers.is_empty() => {
+                response.answers.iter().map(|rec| rec.ttl()).min()
+            }
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => negative_ttl(response),
+            _ => None,
+        };
+        let ttl = match ttl {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            if let Some(old_key) = self.lru.remove(&oldest) {
+                self.entries.remove(&old_key);
+                self.stats.evictions += 1;
+            }
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                rescode: response.header.rescode,
+                authed_data: response.header.authed_data,
+                answers: response.answers.clone(),
+                authorities: response.authorities.clone(),
+                resources: response.resources.clone(),
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                tick: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+        }
+    }
+}
+
+/// RFC 2308: a negative answer may be cached for the smaller of the SOA's
+/// own TTL and its minimum field
+fn negative_ttl(response: &DnsPacket) -> Option<u32> {
+    response.authorities.iter().find_map(|rec| match *rec {
+        DnsRecord::SOA { minimum, ttl, .. } => Some(minimum.min(ttl)),
+        _ => None,
+    })
+}
+
+/// Copy the records that are still alive after `elapsed` seconds, with
+/// their TTLs lowered accordingly
+fn decrement_ttls(records: &[DnsRecord], elapsed: u32) -> Vec<DnsRecord> {
+    records
+        .iter()
+        .filter(|rec| rec.ttl() > elapsed)
+        .map(|rec| {
+            let mut rec = rec.clone();
+            rec.set_ttl(rec.ttl() - elapsed);
+            rec
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::CLASS_IN;
+    use crate::testing::name;
+    use std::net::Ipv4Addr;
+
+    fn a(domain: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(domain),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl,
+        }
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: name("example.com"),
+            mname: name("ns1.example.com"),
+            rname: name("admin.example.com"),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+            ttl,
+        }
+    }
+
+    fn key(qname: &str) -> CacheKey {
+        CacheKey::new(&name(qname), QueryType::A, CLASS_IN)
+    }
+
+    fn answer(records: Vec<DnsRecord>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers = records;
+        packet
+    }
+
+    #[test]
+    fn test_hit_decrements_ttls() {
+        let mut cache = DnsCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(
+            key("example.com"),
+            &answer(vec![a("example.com", 300), a("example.com", 60)]),
+            now,
+        );
+
+        let packet = cache
+            .lookup_at(&key("EXAMPLE.com"), now + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(
+            packet.answers,
+            vec![a("example.com", 280), a("example.com", 40)]
+        );
+
+        // the whole answer expires with its shortest lived record
+        assert!(cache
+            .lookup_at(&key("example.com"), now + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+                entries: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_keyed_by_type_and_class() {
+        let mut cache = DnsCache::new(10);
+        cache.insert(key("example.com"), &answer(vec![a("example.com", 300)]));
+
+        let aaaa = CacheKey::new(&name("example.com"), QueryType::AAAA, CLASS_IN);
+        assert!(cache.lookup(&aaaa).is_none());
+        let chaos = CacheKey::new(&name("example.com"), QueryType::A, 3);
+        assert!(cache.lookup(&chaos).is_none());
+        assert!(cache.lookup(&key("example.com")).is_some());
+    }
+
+    #[test]
+    fn test_negative_caching_uses_soa_minimum() {
+        let mut cache = DnsCache::new(10);
+        let now = Instant::now();
+
+        let mut nxdomain = DnsPacket::new();
+        nxdomain.header.rescode = ResultCode::NXDOMAIN;
+        nxdomain.authorities.push(soa(3600, 30));
+        cache.insert_at(key("nope.example.com"), &nxdomain, now);
+
+        let packet = cache
+            .lookup_at(&key("nope.example.com"), now + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].ttl(), 20);
+        assert!(cache
+            .lookup_at(&key("nope.example.com"), now + Duration::from_secs(30))
+            .is_none());
+
+        // NODATA is cached the same way
+        let mut nodata = DnsPacket::new();
+        nodata.authorities.push(soa(20, 300));
+        cache.insert_at(key("www.example.com"), &nodata, now);
+        let packet = cache
+            .lookup_at(&key("www.example.com"), now + Duration::from_secs(19))
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(cache
+            .lookup_at(&key("www.example.com"), now + Duration::from_secs(20))
+            .is_none());
+    }
+
+    #[test]
+    fn test_uncacheable_responses() {
+        let mut cache = DnsCache::new(10);
+
+        // negative answer without an SOA to take the TTL from
+        let mut nxdomain = DnsPacket::new();
+        nxdomain.header.rescode = ResultCode::NXDOMAIN;
+        cache.insert(key("a.example.com"), &nxdomain);
+
+        let mut servfail = DnsPacket::new();
+        servfail.header.rescode = ResultCode::SERVFAIL;
+        cache.insert(key("b.example.com"), &servfail);
+
+        cache.insert(key("c.example.com"), &answer(vec![a("c.example.com", 0)]));
+
+        let mut truncated = answer(vec![a("d.example.com", 60)]);
+        truncated.header.truncated_message = true;
+        cache.insert(key("d.example.com"), &truncated);
+
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = DnsCache::new(2);
+        cache.insert(key("a.com"), &answer(vec![a("a.com", 300)]));
+        cache.insert(key("b.com"), &answer(vec![a("b.com", 300)]));
+
+        // touch a.com, so b.com is the one to go
+        assert!(cache.lookup(&key("a.com")).is_some());
+        cache.insert(key("c.com"), &answer(vec![a("c.com", 300)]));
+
+        assert!(cache.lookup(&key("b.com")).is_none());
+        assert!(cache.lookup(&key("a.com")).is_some());
+        assert!(cache.lookup(&key("c.com")).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+    }
+}
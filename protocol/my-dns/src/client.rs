@@ -0,0 +1,417 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::name::DnsName;
+use crate::packets::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, Edns, PacketBuffer, QueryType, ResultCode,
+    VectorPacketBuffer, DEFAULT_EDNS_PAYLOAD_SIZE, MAX_UDP_SIZE,
+};
+use crate::tcp::{read_message, write_message, write_raw_message};
+use crate::tsig::{self, TsigKey};
+use crate::update::UpdateMessage;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Sends single queries and waits for the matching response. Queries go
+/// over UDP, and are repeated over TCP when the response was truncated.
+#[derive(Copy, Clone, Debug)]
+pub struct DnsClient {
+    /// How long to wait for a response before sending the query again
+    pub timeout: Duration,
+    /// How many times to resend the query after the first attempt times out
+    pub retries: usize,
+    pub recursion_desired: bool,
+    /// Skip UDP and always query over TCP
+    pub use_tcp: bool,
+    /// UDP payload size to advertise over EDNS, or `None` to send plain
+    /// queries limited to 512 byte responses
+    pub edns_payload_size: Option<u16>,
+    /// Ask for RRSIG and NSEC records with the DO bit. Only sent along
+    /// with EDNS.
+    pub dnssec_ok: bool,
+    /// Ask the server to skip DNSSEC validation and hand out bogus data
+    pub checking_disabled: bool,
+}
+
+impl Default for DnsClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsClient {
+    pub fn new() -> DnsClient {
+        DnsClient {
+            timeout: Duration::from_secs(2),
+            retries: 2,
+            recursion_desired: true,
+            use_tcp: false,
+            edns_payload_size: Some(DEFAULT_EDNS_PAYLOAD_SIZE),
+            dnssec_ok: false,
+            checking_disabled: false,
+        }
+    }
+
+    pub(crate) fn build_query(&self, qname: &DnsName, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = random_id();
+        packet.header.recursion_desired = self.recursion_desired;
+        packet.header.checking_disabled = self.checking_disabled;
+        packet
+            .questions
+            .push(DnsQuestion::new(qname.clone(), qtype));
+        packet.edns = self.edns_payload_size.map(|size| Edns {
+            dnssec_ok: self.dnssec_ok,
+            ..Edns::new(size)
+        });
+        packet
+    }
+
+    pub fn send_query(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        server: SocketAddr,
+    ) -> Result<DnsPacket> {
+        if self.use_tcp {
+            return self.send_query_tcp(qname, qtype, server);
+        }
+
+        let mut response = self.send_query_udp(qname, qtype, server)?;
+        if self.edns_payload_size.is_some()
+            && response.edns.is_none()
+            && response.header.rescode == ResultCode::FORMERR
+        {
+            // servers that predate EDNS may reject the OPT record outright
+            let plain = DnsClient {
+                edns_payload_size: None,
+                ..*self
+            };
+            response = plain.send_query_udp(qname, qtype, server)?;
+        }
+        if response.header.truncated_message {
+            // the full answer did not fit in a datagram, TCP has no such limit
+            return self.send_query_tcp(qname, qtype, server);
+        }
+
+        Ok(response)
+    }
+
+    pub fn send_query_udp(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        server: SocketAddr,
+    ) -> Result<DnsPacket> {
+        let bind_addr: SocketAddr = if server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+
+        let mut packet = self.build_query(qname, qtype);
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+
+        for _ in 0..=self.retries {
+            socket.send_to(&req_buffer.buffer[0..req_buffer.pos()], server)?;
+
+            if let Some(response) = self.recv_response(&socket, server, packet.header.id)? {
+                return Ok(response);
+            }
+        }
+
+        Err(Box::new(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no response from {} for {} {}", server, qname, qtype),
+        )))
+    }
+
+    pub fn send_query_tcp(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        server: SocketAddr,
+    ) -> Result<DnsPacket> {
+        let mut stream = TcpStream::connect_timeout(&server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut packet = self.build_query(qname, qtype);
+        write_message(&mut stream, &mut packet)?;
+
+        let mut res_buffer = read_message(&mut stream)?
+            .ok_or_else(|| format!("{} closed the connection without answering", server))?;
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if response.header.id != packet.header.id {
+            return Err(format!("response from {} does not match the query id", server).into());
+        }
+
+        Ok(response)
+    }
+
+    /// Send a dynamic update over TCP and return the server's response,
+    /// whose code tells whether it was applied. With a `key` the update is
+    /// signed, and so has the response to be, unless it reports a TSIG
+    /// error.
+    pub fn send_update(
+        &self,
+        update: &mut UpdateMessage,
+        key: Option<&TsigKey>,
+        server: SocketAddr,
+    ) -> Result<DnsPacket> {
+        let mut stream = TcpStream::connect_timeout(&server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        update.write(&mut req_buffer)?;
+        let mut message = req_buffer.buffer[..req_buffer.pos()].to_vec();
+        let mut request_mac = Vec::new();
+        if let Some(key) = key {
+            let signature = tsig::sign_message(&message, key, &[], false, tsig::now())?;
+            tsig::append(&mut message, &signature)?;
+            request_mac = signature.mac;
+        }
+        write_raw_message(&mut stream, &message)?;
+
+        let mut res_buffer = read_message(&mut stream)?
+            .ok_or_else(|| format!("{} closed the connection without answering", server))?;
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if response.header.id != update.header.id {
+            return Err(format!("response from {} does not match the update id", server).into());
+        }
+        let tsig_error = response.tsig.as_ref().is_some_and(|tsig| tsig.error != 0);
+        if let (Some(key), false) = (key, tsig_error) {
+            let keys = std::slice::from_ref(key);
+            if tsig::verify(&mut res_buffer, keys, &request_mac, false, tsig::now())?.is_none() {
+                return Err(format!("{} sent an unsigned response", server).into());
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Wait for a response from `server` carrying `id`, ignoring anything
+    /// else that arrives on the socket. Returns `None` once the timeout
+    /// has passed.
+    pub(crate) fn recv_response(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        id: u16,
+    ) -> Result<Option<DnsPacket>> {
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            socket.set_read_timeout(Some(deadline - now))?;
+
+            let mut received = vec![0; self.max_response_size()];
+            let (len, src) = match socket.recv_from(&mut received) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if src != server {
+                continue;
+            }
+            let mut res_buffer = VectorPacketBuffer::from_bytes(&received[..len]);
+
+            // a mangled or stale datagram should not end the wait for the
+            // real response
+            match DnsPacket::from_buffer(&mut res_buffer) {
+                Ok(response) if response.header.response && response.header.id == id => {
+                    return Ok(Some(response))
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Largest UDP response the server may send us
+    fn max_response_size(&self) -> usize {
+        match self.edns_payload_size {
+            Some(size) => (size as usize).max(MAX_UDP_SIZE),
+            None => MAX_UDP_SIZE,
+        }
+    }
+}
+
+/// Pick an unpredictable query id, so responses can not be spoofed by
+/// guessing it. The id comes from the operating system's CSPRNG.
+pub fn random_id() -> u16 {
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .expect("system random number generator failed");
+    u16::from_be_bytes(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::DnsRecord;
+    use crate::testing::name;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    /// Answer every query with an A record, after running `before` on the
+    /// socket and the incoming query
+    fn spawn_responder<F>(queries: usize, before: F) -> SocketAddr
+    where
+        F: Fn(&UdpSocket, &DnsPacket, SocketAddr, usize) -> bool + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for i in 0..queries {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = socket.recv_from(&mut req_buffer.buffer).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                if !before(&socket, &request, src, i) {
+                    continue;
+                }
+
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                response.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    addr: Ipv4Addr::new(127, 0, 0, 42),
+                    ttl: 60,
+                });
+
+                let mut res_buffer = BytePacketBuffer::new();
+                response.write(&mut res_buffer).unwrap();
+                socket
+                    .send_to(&res_buffer.buffer[..res_buffer.pos()], src)
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn client() -> DnsClient {
+        DnsClient {
+            timeout: Duration::from_millis(200),
+            ..DnsClient::new()
+        }
+    }
+
+    #[test]
+    fn test_send_query() {
+        let server = spawn_responder(1, |_, request, _, _| {
+            assert!(request.header.recursion_desired);
+            assert_eq!(request.questions[0].qtype, QueryType::A);
+            true
+        });
+
+        let response = client()
+            .send_query(&name("example.com"), QueryType::A, server)
+            .unwrap();
+        assert_eq!(
+            response.answers,
+            vec![DnsRecord::A {
+                domain: name("example.com"),
+                addr: Ipv4Addr::new(127, 0, 0, 42),
+                ttl: 60,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ignores_mismatched_id() {
+        let server = spawn_responder(1, |socket, request, src, _| {
+            let mut bogus = DnsPacket::new();
+            bogus.header.id = request.header.id.wrapping_add(1);
+            bogus.header.response = true;
+            let mut buffer = BytePacketBuffer::new();
+            bogus.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buffer[..buffer.pos()], src).unwrap();
+            true
+        });
+
+        let response = client()
+            .send_query(&name("example.com"), QueryType::A, server)
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_retries_after_timeout() {
+        // drop the first query on the floor
+        let server = spawn_responder(2, |_, _, _, i| i > 0);
+
+        let response = client()
+            .send_query(&name("example.com"), QueryType::A, server)
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_sends_edns() {
+        let server = spawn_responder(1, |_, request, _, _| {
+            assert_eq!(request.edns, Some(Edns::new(DEFAULT_EDNS_PAYLOAD_SIZE)));
+            true
+        });
+
+        let response = client()
+            .send_query(&name("example.com"), QueryType::A, server)
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_retries_without_edns_on_formerr() {
+        let server = spawn_responder(2, |socket, request, src, _| {
+            if request.edns.is_none() {
+                return true;
+            }
+            let mut formerr = DnsPacket::new();
+            formerr.header.id = request.header.id;
+            formerr.header.response = true;
+            formerr.header.rescode = ResultCode::FORMERR;
+            let mut buffer = BytePacketBuffer::new();
+            formerr.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buffer[..buffer.pos()], src).unwrap();
+            false
+        });
+
+        let response = client()
+            .send_query(&name("example.com"), QueryType::A, server)
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_gives_up_after_retries() {
+        let server = spawn_responder(3, |_, _, _, _| false);
+
+        let client = DnsClient {
+            retries: 2,
+            ..client()
+        };
+        let err = client
+            .send_query(&name("example.com"), QueryType::A, server)
+            .unwrap_err();
+        assert!(err.to_string().contains("no response"));
+    }
+}
@@ -0,0 +1,1560 @@
+//! DNSSEC: checking the signatures on record sets, and a validator that
+//! follows the chain of trust from a configured trust anchor down to the
+//! zone that signed an answer (RFC 4033, 4034, 4035, and RFC 5155 for
+//! hashed denial of existence)
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+use crate::encoding::{from_base32hex, from_hex};
+use crate::name::DnsName;
+use crate::packets::{
+    DnsPacket, DnsRecord, PacketBuffer, QueryType, ResultCode, VectorPacketBuffer,
+};
+use crate::zone;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Signature algorithms we can verify
+pub const RSASHA256: u8 = 8;
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ED25519: u8 = 15;
+
+/// DS digest types
+pub const DIGEST_SHA1: u8 = 1;
+pub const DIGEST_SHA256: u8 = 2;
+pub const DIGEST_SHA384: u8 = 4;
+
+/// DNSKEY flag of keys that sign zone data
+pub const ZONE_KEY_FLAG: u16 = 0x0100;
+/// DNSKEY flag of the keys a DS usually points to, the key signing keys
+pub const SEP_FLAG: u16 = 0x0001;
+
+/// NSEC3 flag: unsigned delegations may be left out of the chain
+pub const OPT_OUT_FLAG: u8 = 0x01;
+
+/// NSEC3 chains hashed more often than this are treated as unsigned, as
+/// RFC 9276 allows, rather than spending the CPU time on them
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// Digests of the root zone's key signing keys, KSK-2017 and KSK-2024, as
+/// published by IANA
+const ROOT_ANCHORS: [(u16, &str); 2] = [
+    (
+        20326,
+        "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ),
+    (
+        38696,
+        "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+    ),
+];
+
+/// The outcome of validating a response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Security {
+    /// Signed all the way up to a trust anchor
+    Secure,
+    /// Provably unsigned, or outside of every trust anchor
+    Insecure,
+    /// Signed, but the signatures or proofs do not check out
+    Bogus(String),
+}
+
+impl Security {
+    /// The outcome for a response made of both parts: bogus if either is,
+    /// otherwise secure only if both are
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Secure, Security::Secure) => Security::Secure,
+            _ => Security::Insecure,
+        }
+    }
+}
+
+/// Keys trusted without further proof, given as DS or DNSKEY records
+#[derive(Clone, Debug)]
+pub struct TrustAnchor {
+    pub records: Vec<DnsRecord>,
+}
+
+impl Default for TrustAnchor {
+    fn default() -> Self {
+        Self::root()
+    }
+}
+
+impl TrustAnchor {
+    /// The DS records of the root zone's key signing keys
+    pub fn root() -> TrustAnchor {
+        let records = ROOT_ANCHORS
+            .iter()
+            .map(|&(key_tag, digest)| DnsRecord::DS {
+                domain: DnsName::root(),
+                key_tag,
+                algorithm: RSASHA256,
+                digest_type: DIGEST_SHA256,
+                digest: from_hex(digest).unwrap(),
+                ttl: 0,
+            })
+            .collect();
+
+        TrustAnchor { records }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TrustAnchor> {
+        let text = fs::read_to_string(path)?;
+        TrustAnchor::parse(&text)
+    }
+
+    /// Read DS and DNSKEY records in master file format, the way trust
+    /// anchor files for other resolvers are written. Relative names are
+    /// taken to be relative to the root.
+    pub fn parse(text: &str) -> Result<TrustAnchor> {
+        let records: Vec<DnsRecord> = zone::parse_records(text, ".")?
+            .into_iter()
+            .filter(|rec| matches!(rec.query_type(), QueryType::DS | QueryType::DNSKEY))
+            .collect();
+        if records.is_empty() {
+            return Err("trust anchor has no DS or DNSKEY records".into());
+        }
+
+        Ok(TrustAnchor { records })
+    }
+
+    /// Whether `name` is at or below one of the anchored zones
+    fn covers(&self, name: &DnsName) -> bool {
+        self.records
+            .iter()
+            .any(|rec| name.is_subdomain_of(rec.domain()))
+    }
+
+    /// Whether `dnskey` is one of the anchored keys, or matches an
+    /// anchored DS
+    fn trusts(&self, dnskey: &DnsRecord) -> bool {
+        self.records
+            .iter()
+            .filter(|rec| rec.domain() == dnskey.domain())
+            .any(|rec| match rec {
+                DnsRecord::DS { .. } => ds_matches(rec, dnskey),
+                _ => rec.rdata().ok() == dnskey.rdata().ok(),
+            })
+    }
+
+    fn anchors(&self, zone: &DnsName) -> bool {
+        self.records.iter().any(|rec| rec.domain() == zone)
+    }
+}
+
+/// Seconds since the epoch, truncated to the 32 bits RRSIG timestamps have
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Whether `now` lies between inception and expiration, comparing in the
+/// serial number arithmetic of RFC 1982 so the timestamps may wrap
+fn in_validity_period(inception: u32, expiration: u32, now: u32) -> bool {
+    now.wrapping_sub(inception) as i32 >= 0 && expiration.wrapping_sub(now) as i32 >= 0
+}
+
+/// A name in lower case and without compression, as digests and
+/// signatures are computed over it
+fn canonical_name(name: &DnsName) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(name.wire_len());
+    for label in name.labels() {
+        wire.push(label.len() as u8);
+        wire.extend(label.iter().map(u8::to_ascii_lowercase));
+    }
+    wire.push(0);
+    wire
+}
+
+/// The canonical form of a record (RFC 4034 section 6.2): the owner and the
+/// names inside the types listed there in lower case, with the TTL the
+/// signature was made with
+fn canonical_record(record: &DnsRecord, original_ttl: u32) -> DnsRecord {
+    let mut rec = record.clone();
+    match rec {
+        DnsRecord::NS { ref mut host, .. }
+        | DnsRecord::CNAME { ref mut host, .. }
+        | DnsRecord::PTR { ref mut host, .. }
+        | DnsRecord::MX { ref mut host, .. }
+        | DnsRecord::SRV { ref mut host, .. } => *host = host.to_lowercase(),
+        DnsRecord::SOA {
+            ref mut mname,
+            ref mut rname,
+            ..
+        } => {
+            *mname = mname.to_lowercase();
+            *rname = rname.to_lowercase();
+        }
+        DnsRecord::RRSIG {
+            ref mut signer_name,
+            ..
+        } => *signer_name = signer_name.to_lowercase(),
+        _ => {}
+    }
+    rec.set_domain(record.domain().to_lowercase());
+    rec.set_ttl(original_ttl);
+    rec
+}
+
+/// The data an RRSIG signs (RFC 4034 section 3.1.8.1): its own rdata up to
+/// the signature, followed by the records of the set in canonical form
+/// and canonical order
+pub fn signed_data(rrsig: &DnsRecord, rrset: &[DnsRecord]) -> Result<Vec<u8>> {
+    let (labels, original_ttl) = match *rrsig {
+        DnsRecord::RRSIG {
+            labels,
+            original_ttl,
+            ..
+        } => (labels as usize, original_ttl),
+        _ => return Err("not an RRSIG record".into()),
+    };
+
+    let mut unsigned = canonical_record(rrsig, original_ttl);
+    if let DnsRecord::RRSIG {
+        ref mut signature, ..
+    } = unsigned
+    {
+        signature.clear();
+    }
+    let mut data = unsigned.rdata()?;
+
+    let mut records = Vec::new();
+    for rec in rrset {
+        let mut rec = canonical_record(rec, original_ttl);
+        // an answer expanded from a wildcard was signed as the wildcard
+        if labels < rec.domain().label_count() {
+            let wildcard = rec.domain().suffix(labels).child(b"*")?;
+            rec.set_domain(wildcard);
+        }
+
+        let mut buffer = VectorPacketBuffer::uncompressed();
+        rec.write(&mut buffer)?;
+        let wire = buffer.buffer[..buffer.pos()].to_vec();
+        let rdata_start = rec.domain().wire_len() + 10;
+        records.push((wire[rdata_start..].to_vec(), wire));
+    }
+    // ordered by rdata, with duplicates removed (RFC 4034 section 6.3)
+    records.sort();
+    records.dedup_by(|a, b| a.0 == b.0);
+    for (_, wire) in records {
+        data.extend(wire);
+    }
+
+    Ok(data)
+}
+
+/// The key tag of a DNSKEY (RFC 4034 appendix B), a checksum DS and RRSIG
+/// records use to point at the key they mean
+pub fn key_tag(dnskey: &DnsRecord) -> Option<u16> {
+    if dnskey.query_type() != QueryType::DNSKEY {
+        return None;
+    }
+
+    let rdata = dnskey.rdata().ok()?;
+    let mut acc: u32 = 0;
+    for (i, &b) in rdata.iter().enumerate() {
+        acc += if i % 2 == 0 {
+            (b as u32) << 8
+        } else {
+            b as u32
+        };
+    }
+    acc += (acc >> 16) & 0xFFFF;
+
+    Some(acc as u16)
+}
+
+/// The digest a DS record of type `digest_type` holds for `dnskey`, or
+/// `None` for digest types we do not implement
+pub fn ds_digest(dnskey: &DnsRecord, digest_type: u8) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &digest::SHA256,
+        DIGEST_SHA384 => &digest::SHA384,
+        _ => return None,
+    };
+
+    let mut ctx = digest::Context::new(algorithm);
+    ctx.update(&canonical_name(dnskey.domain()));
+    ctx.update(&dnskey.rdata().ok()?);
+    Some(ctx.finish().as_ref().to_vec())
+}
+
+/// Whether `ds` refers to `dnskey`
+pub fn ds_matches(ds: &DnsRecord, dnskey: &DnsRecord) -> bool {
+    let (key_algorithm, flags) = match *dnskey {
+        DnsRecord::DNSKEY {
+            algorithm, flags, ..
+        } => (algorithm, flags),
+        _ => return false,
+    };
+
+    match *ds {
+        DnsRecord::DS {
+            ref domain,
+            key_tag: tag,
+            algorithm,
+            digest_type,
+            ref digest,
+            ..
+        } => {
+            domain == dnskey.domain()
+                && algorithm == key_algorithm
+                && flags & ZONE_KEY_FLAG != 0
+                && key_tag(dnskey) == Some(tag)
+                && ds_digest(dnskey, digest_type).as_ref() == Some(digest)
+        }
+        _ => false,
+    }
+}
+
+/// Whether we can check signatures made with `algorithm`
+pub fn is_supported(algorithm: u8) -> bool {
+    matches!(algorithm, RSASHA256 | ECDSAP256SHA256 | ED25519)
+}
+
+/// Verify a signature with a key in its DNSKEY format
+pub fn verify_signature(algorithm: u8, public_key: &[u8], message: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        RSASHA256 => {
+            let (e, n) = match rsa_components(public_key) {
+                Some(components) => components,
+                None => return false,
+            };
+            // zones still sign with 1024 bit keys, which ring only
+            // accepts through the legacy parameters
+            RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    message,
+                    sig,
+                )
+                .is_ok()
+        }
+        ECDSAP256SHA256 => {
+            // the DNSKEY holds the bare point, without the SEC1 prefix
+            if public_key.len() != 64 {
+                return false;
+            }
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, sig)
+                .is_ok()
+        }
+        ED25519 => UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Split an RSA key in the RFC 3110 format into exponent and modulus
+fn rsa_components(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (exp_len, rest) = match *key.first()? {
+        0 => {
+            let len = u16::from_be_bytes([*key.get(1)?, *key.get(2)?]) as usize;
+            (len, &key[3..])
+        }
+        len => (len as usize, &key[1..]),
+    };
+    if exp_len == 0 || rest.len() <= exp_len {
+        return None;
+    }
+
+    let (e, n) = rest.split_at(exp_len);
+    let leading_zeros = n.iter().take_while(|&&b| b == 0).count();
+    Some((e, &n[leading_zeros..]))
+}
+
+/// Check that `rrsig` is a valid signature over `rrset` by `dnskey` at
+/// time `now`, or say why it is not
+pub fn verify_rrsig(
+    rrsig: &DnsRecord,
+    dnskey: &DnsRecord,
+    rrset: &[DnsRecord],
+    now: u32,
+) -> std::result::Result<(), &'static str> {
+    let (owner, type_covered, algorithm, labels, expiration, inception, tag, signer, sig) =
+        match *rrsig {
+            DnsRecord::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } => (
+                domain,
+                type_covered,
+                algorithm,
+                labels,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+            ),
+            _ => return Err("not an RRSIG record"),
+        };
+    let (flags, protocol, key_algorithm, public_key) = match *dnskey {
+        DnsRecord::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            ref public_key,
+            ..
+        } => (flags, protocol, algorithm, public_key),
+        _ => return Err("not a DNSKEY record"),
+    };
+
+    if rrset.is_empty()
+        || rrset
+            .iter()
+            .any(|rec| rec.domain() != owner || rec.query_type() != type_covered)
+    {
+        return Err("signature covers another record set");
+    }
+    if !owner.is_subdomain_of(signer) || dnskey.domain() != signer {
+        return Err("signer is not the zone of the records");
+    }
+    if flags & ZONE_KEY_FLAG == 0 || protocol != 3 {
+        return Err("key is not a zone key");
+    }
+    if algorithm != key_algorithm || key_tag(dnskey) != Some(tag) {
+        return Err("signature was made with another key");
+    }
+    if labels as usize > owner.label_count() {
+        return Err("label count exceeds the owner name");
+    }
+    if !in_validity_period(inception, expiration, now) {
+        return Err("signature has expired or is not valid yet");
+    }
+
+    let data = signed_data(rrsig, rrset).map_err(|_| "records can not be written")?;
+    if !verify_signature(algorithm, public_key, &data, sig) {
+        return Err("signature does not verify");
+    }
+
+    Ok(())
+}
+
+/// The hashed owner name an NSEC3 chain is ordered by (RFC 5155 section 5)
+pub fn nsec3_hash(name: &DnsName, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut hash = canonical_name(name);
+    for _ in 0..=iterations {
+        let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        ctx.update(&hash);
+        ctx.update(salt);
+        hash = ctx.finish().as_ref().to_vec();
+    }
+    hash
+}
+
+/// Split records into sets by owner and type, each with the signatures
+/// covering it
+fn rrsets(records: &[DnsRecord]) -> Vec<(Vec<DnsRecord>, Vec<DnsRecord>)> {
+    let mut sets: Vec<(Vec<DnsRecord>, Vec<DnsRecord>)> = Vec::new();
+    for rec in records {
+        if rec.query_type() == QueryType::RRSIG {
+            continue;
+        }
+        match sets.iter_mut().find(|(set, _)| {
+            set[0].domain() == rec.domain() && set[0].query_type() == rec.query_type()
+        }) {
+            Some((set, _)) => set.push(rec.clone()),
+            None => sets.push((vec![rec.clone()], Vec::new())),
+        }
+    }
+
+    for (set, sigs) in &mut sets {
+        sigs.extend(
+            records
+                .iter()
+                .filter(|rec| match rec {
+                    DnsRecord::RRSIG {
+                        domain,
+                        type_covered,
+                        ..
+                    } => domain == set[0].domain() && *type_covered == set[0].query_type(),
+                    _ => false,
+                })
+                .cloned(),
+        );
+    }
+
+    sets
+}
+
+/// Where a CNAME chain starting at `qname` ends
+fn cname_target(qname: &DnsName, answers: &[DnsRecord]) -> DnsName {
+    let mut target = qname.clone();
+    for _ in 0..answers.len() {
+        match answers.iter().find_map(|rec| match rec {
+            DnsRecord::CNAME { domain, host, .. } if *domain == target => Some(host),
+            _ => None,
+        }) {
+            Some(host) => target = host.clone(),
+            None => break,
+        }
+    }
+    target
+}
+
+/// The zone a negative response comes from: the signer of its records, or
+/// else the owner of its SOA
+fn denying_zone(name: &DnsName, authority: &[DnsRecord]) -> Option<DnsName> {
+    let signer = authority.iter().find_map(|rec| match rec {
+        DnsRecord::RRSIG { signer_name, .. } if name.is_subdomain_of(signer_name) => {
+            Some(signer_name.clone())
+        }
+        _ => None,
+    });
+
+    signer.or_else(|| {
+        authority.iter().find_map(|rec| match rec {
+            DnsRecord::SOA { domain, .. } if name.is_subdomain_of(domain) => Some(domain.clone()),
+            _ => None,
+        })
+    })
+}
+
+/// What the keys of a zone are known to be
+#[derive(Clone, Debug)]
+enum ZoneKeys {
+    /// The validated DNSKEY set of a signed zone
+    Secure(Vec<DnsRecord>),
+    Insecure,
+    Bogus(String),
+}
+
+/// Validates responses by following the chain of trust from the trust
+/// anchor down to the zone that signed them. `fetch` looks up the DNSKEY,
+/// DS and SOA records needed along the way, usually through the resolver
+/// itself; whatever it returns is checked like everything else.
+pub struct Validator<'a, F> {
+    anchor: &'a TrustAnchor,
+    fetch: F,
+    /// The time signatures have to be valid at
+    pub now: u32,
+    zones: HashMap<DnsName, ZoneKeys>,
+}
+
+impl<'a, F> Validator<'a, F>
+where
+    F: FnMut(&DnsName, QueryType) -> Result<DnsPacket>,
+{
+    pub fn new(anchor: &'a TrustAnchor, fetch: F) -> Validator<'a, F> {
+        Validator {
+            anchor,
+            fetch,
+            now: unix_time(),
+            zones: HashMap::new(),
+        }
+    }
+
+    /// Validate `response` to the query `qname` `qtype`: every record set
+    /// in the answer, and the proof that the rest does not exist
+    pub fn validate(
+        &mut self,
+        qname: &DnsName,
+        qtype: QueryType,
+        response: &DnsPacket,
+    ) -> Security {
+        if !matches!(
+            response.header.rescode,
+            ResultCode::NOERROR | ResultCode::NXDOMAIN
+        ) {
+            return Security::Insecure;
+        }
+
+        let mut security = Security::Secure;
+        for (rrset, rrsigs) in rrsets(&response.answers) {
+            security = security.and(self.validate_rrset(&rrset, &rrsigs));
+
+            // an answer made up from a wildcard needs proof that there was
+            // nothing closer to use instead
+            let owner = rrset[0].domain();
+            let wildcard_labels = rrsigs.iter().find_map(|rec| match *rec {
+                DnsRecord::RRSIG { labels, .. } if (labels as usize) < owner.label_count() => {
+                    Some(labels as usize)
+                }
+                _ => None,
+            });
+            if let (Some(labels), Security::Secure) = (wildcard_labels, &security) {
+                security = match self.validated_denial_records(&response.authorities) {
+                    Ok(records) => proof_security(
+                        wildcard_expansion(owner, labels, &records),
+                        owner,
+                        rrset[0].query_type(),
+                    ),
+                    Err(security) => security,
+                };
+            }
+        }
+
+        let target = cname_target(qname, &response.answers);
+        let answered = response
+            .answers
+            .iter()
+            .any(|rec| rec.domain() == &target && rec.query_type() != QueryType::RRSIG);
+        if response.header.rescode == ResultCode::NXDOMAIN || !answered {
+            security = security.and(self.validate_denial(&target, qtype, response));
+        }
+
+        security
+    }
+
+    /// Check the signatures of a record set against the keys of its zone.
+    /// Unsigned sets are only acceptable in unsigned zones.
+    fn validate_rrset(&mut self, rrset: &[DnsRecord], rrsigs: &[DnsRecord]) -> Security {
+        let owner = rrset[0].domain();
+        let qtype = rrset[0].query_type();
+
+        if rrsigs.is_empty() {
+            let zone = match self.zone_of(owner) {
+                Ok(zone) => zone,
+                Err(reason) => return Security::Bogus(reason),
+            };
+            return match self.keys(&zone) {
+                ZoneKeys::Secure(_) => {
+                    Security::Bogus(format!("{} {} is not signed", owner, qtype))
+                }
+                ZoneKeys::Insecure => Security::Insecure,
+                ZoneKeys::Bogus(reason) => Security::Bogus(reason),
+            };
+        }
+
+        let mut reason = format!("no valid signature over {} {}", owner, qtype);
+        for rrsig in rrsigs {
+            let signer = match rrsig {
+                DnsRecord::RRSIG { signer_name, .. } if owner.is_subdomain_of(signer_name) => {
+                    signer_name
+                }
+                _ => continue,
+            };
+            match self.keys(signer) {
+                ZoneKeys::Secure(keys) => {
+                    for key in &keys {
+                        match verify_rrsig(rrsig, key, rrset, self.now) {
+                            Ok(()) => return Security::Secure,
+                            Err(why) => {
+                                if key_tag(key) == rrsig_key_tag(rrsig) {
+                                    reason = format!("{} {}: {}", owner, qtype, why);
+                                }
+                            }
+                        }
+                    }
+                }
+                ZoneKeys::Insecure => return Security::Insecure,
+                ZoneKeys::Bogus(why) => reason = why,
+            }
+        }
+
+        Security::Bogus(reason)
+    }
+
+    /// Check that the authority section of `response` proves there is no
+    /// `qtype` at `name`, or no `name` at all for NXDOMAIN
+    fn validate_denial(
+        &mut self,
+        name: &DnsName,
+        qtype: QueryType,
+        response: &DnsPacket,
+    ) -> Security {
+        let zone = match denying_zone(name, &response.authorities) {
+            Some(zone) => zone,
+            None => match self.zone_of(name) {
+                Ok(zone) => zone,
+                Err(reason) => return Security::Bogus(reason),
+            },
+        };
+        match self.keys(&zone) {
+            ZoneKeys::Secure(_) => {}
+            ZoneKeys::Insecure => return Security::Insecure,
+            ZoneKeys::Bogus(reason) => return Security::Bogus(reason),
+        }
+
+        let records = match self.validated_denial_records(&response.authorities) {
+            Ok(records) => records,
+            Err(security) => return security,
+        };
+        let proof = if response.header.rescode == ResultCode::NXDOMAIN {
+            nxdomain(name, &records)
+        } else {
+            nodata(name, qtype, &records)
+        };
+
+        proof_security(proof, name, qtype)
+    }
+
+    /// The NSEC and NSEC3 records of an authority section, once every SOA,
+    /// NSEC and NSEC3 set in there has been validated
+    fn validated_denial_records(
+        &mut self,
+        authority: &[DnsRecord],
+    ) -> std::result::Result<Vec<DnsRecord>, Security> {
+        let mut records = Vec::new();
+        for (rrset, rrsigs) in rrsets(authority) {
+            let qtype = rrset[0].query_type();
+            if !matches!(qtype, QueryType::SOA | QueryType::NSEC | QueryType::NSEC3) {
+                continue;
+            }
+            match self.validate_rrset(&rrset, &rrsigs) {
+                Security::Secure => {}
+                other => return Err(other),
+            }
+            if qtype != QueryType::SOA {
+                records.extend(rrset);
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// The apex of the zone holding `name`, found by asking for its SOA
+    fn zone_of(&mut self, name: &DnsName) -> std::result::Result<DnsName, String> {
+        let response = (self.fetch)(name, QueryType::SOA)
+            .map_err(|e| format!("could not find the zone of {}: {}", name, e))?;
+
+        response
+            .answers
+            .iter()
+            .chain(&response.authorities)
+            .find_map(|rec| match rec {
+                DnsRecord::SOA { domain, .. } if name.is_subdomain_of(domain) => {
+                    Some(domain.clone())
+                }
+                _ => None,
+            })
+            .ok_or_else(|| format!("could not find the zone of {}", name))
+    }
+
+    /// The keys of `zone`, validated from the trust anchor down
+    fn keys(&mut self, zone: &DnsName) -> ZoneKeys {
+        if let Some(keys) = self.zones.get(zone) {
+            return keys.clone();
+        }
+
+        let keys = self.find_keys(zone);
+        self.zones.insert(zone.clone(), keys.clone());
+        keys
+    }
+
+    fn find_keys(&mut self, zone: &DnsName) -> ZoneKeys {
+        if self.anchor.anchors(zone) {
+            let anchor = self.anchor;
+            return self.trusted_keys(zone, |key| anchor.trusts(key));
+        }
+        if !self.anchor.covers(zone) {
+            return ZoneKeys::Insecure;
+        }
+
+        let response = match (self.fetch)(zone, QueryType::DS) {
+            Ok(response) => response,
+            Err(e) => return ZoneKeys::Bogus(format!("could not look up DS {}: {}", zone, e)),
+        };
+        if response.header.rescode == ResultCode::NXDOMAIN {
+            return ZoneKeys::Bogus(format!("the parent of {} says it does not exist", zone));
+        }
+
+        // the DS set lives on the parent side of the cut, the parent has
+        // to be secure for it to mean anything
+        let parent = match denying_zone(zone, &response.answers)
+            .or_else(|| denying_zone(zone, &response.authorities))
+        {
+            Some(parent) if parent.label_count() < zone.label_count() => parent,
+            _ => return ZoneKeys::Bogus(format!("could not find the parent zone of {}", zone)),
+        };
+        match self.keys(&parent) {
+            ZoneKeys::Secure(_) => {}
+            other => return other,
+        }
+
+        let ds_set: Vec<DnsRecord> = response
+            .answers
+            .iter()
+            .filter(|rec| rec.query_type() == QueryType::DS && rec.domain() == zone)
+            .cloned()
+            .collect();
+        if ds_set.is_empty() {
+            // a secure parent has to prove the delegation is unsigned
+            return match self.validate_denial(zone, QueryType::DS, &response) {
+                Security::Secure | Security::Insecure => ZoneKeys::Insecure,
+                Security::Bogus(reason) => ZoneKeys::Bogus(reason),
+            };
+        }
+
+        let ds_sigs: Vec<DnsRecord> = rrsets(&response.answers)
+            .into_iter()
+            .find(|(set, _)| set[0].query_type() == QueryType::DS)
+            .map(|(_, sigs)| sigs)
+            .unwrap_or_default();
+        match self.validate_rrset(&ds_set, &ds_sigs) {
+            Security::Secure => {}
+            Security::Insecure => return ZoneKeys::Insecure,
+            Security::Bogus(reason) => return ZoneKeys::Bogus(reason),
+        }
+
+        // a zone signed only with algorithms we do not know is treated as
+        // unsigned (RFC 4035 section 5.2)
+        let usable = ds_set.iter().any(|ds| match *ds {
+            DnsRecord::DS {
+                algorithm,
+                digest_type,
+                ..
+            } => is_supported(algorithm) && matches!(digest_type, 1 | 2 | 4),
+            _ => false,
+        });
+        if !usable {
+            return ZoneKeys::Insecure;
+        }
+
+        self.trusted_keys(zone, |key| ds_set.iter().any(|ds| ds_matches(ds, key)))
+    }
+
+    /// Fetch the DNSKEY set of `zone` and accept it if one of the keys
+    /// picked out by `trusted` signed it
+    fn trusted_keys(&mut self, zone: &DnsName, trusted: impl Fn(&DnsRecord) -> bool) -> ZoneKeys {
+        let response = match (self.fetch)(zone, QueryType::DNSKEY) {
+            Ok(response) => response,
+            Err(e) => return ZoneKeys::Bogus(format!("could not look up DNSKEY {}: {}", zone, e)),
+        };
+
+        for (keys, rrsigs) in rrsets(&response.answers) {
+            if keys[0].query_type() != QueryType::DNSKEY || keys[0].domain() != zone {
+                continue;
+            }
+            let signed = keys.iter().filter(|key| trusted(key)).any(|key| {
+                rrsigs
+                    .iter()
+                    .any(|rrsig| verify_rrsig(rrsig, key, &keys, self.now).is_ok())
+            });
+            if signed {
+                return ZoneKeys::Secure(keys);
+            }
+        }
+
+        ZoneKeys::Bogus(format!("no trusted key signed the DNSKEY set of {}", zone))
+    }
+}
+
+fn rrsig_key_tag(rrsig: &DnsRecord) -> Option<u16> {
+    match *rrsig {
+        DnsRecord::RRSIG { key_tag, .. } => Some(key_tag),
+        _ => None,
+    }
+}
+
+/// How well the NSEC or NSEC3 records of a response show that something
+/// does not exist
+#[derive(Debug, PartialEq, Eq)]
+enum Denial {
+    Proven,
+    /// Covered by an opt-out span, or by a chain too costly to check
+    Insecure,
+    Missing,
+}
+
+fn proof_security(proof: Denial, name: &DnsName, qtype: QueryType) -> Security {
+    match proof {
+        Denial::Proven => Security::Secure,
+        Denial::Insecure => Security::Insecure,
+        Denial::Missing => {
+            Security::Bogus(format!("no proof that {} {} does not exist", name, qtype))
+        }
+    }
+}
+
+/// The longest name both `a` and `b` are in
+fn common_ancestor(a: &DnsName, b: &DnsName) -> DnsName {
+    let shared = a
+        .labels()
+        .iter()
+        .rev()
+        .zip(b.labels().iter().rev())
+        .take_while(|(x, y)| x.eq_ignore_ascii_case(y))
+        .count();
+    a.suffix(shared)
+}
+
+/// Whether an NSEC record says there are no names between its owner and
+/// `name`
+fn nsec_covers(nsec: &DnsRecord, name: &DnsName) -> bool {
+    match nsec {
+        DnsRecord::NSEC {
+            domain,
+            next_domain,
+            types,
+            ..
+        } => {
+            // the parent side of a delegation knows nothing about the
+            // names below it
+            if name.is_subdomain_of(domain)
+                && types.contains(&QueryType::NS)
+                && !types.contains(&QueryType::SOA)
+            {
+                return false;
+            }
+            // the last NSEC of the zone points back at its apex
+            domain < name && (name < next_domain || next_domain <= domain)
+        }
+        _ => false,
+    }
+}
+
+fn nsec_types<'r>(records: &'r [DnsRecord], name: &DnsName) -> Option<&'r [QueryType]> {
+    records.iter().find_map(|rec| match rec {
+        DnsRecord::NSEC { domain, types, .. } if domain == name => Some(types.as_slice()),
+        _ => None,
+    })
+}
+
+/// Whether a type bitmap shows `qtype` does not exist at the name
+fn lacks(types: &[QueryType], qtype: QueryType) -> bool {
+    !types.contains(&qtype) && !types.contains(&QueryType::CNAME)
+}
+
+fn nxdomain(name: &DnsName, records: &[DnsRecord]) -> Denial {
+    if records
+        .iter()
+        .any(|rec| rec.query_type() == QueryType::NSEC)
+    {
+        // a covering NSEC, and one showing there is no wildcard at the
+        // closest encloser, the longest ancestor that does exist
+        let covering = records.iter().find_map(|rec| match rec {
+            DnsRecord::NSEC {
+                domain,
+                next_domain,
+                ..
+            } if nsec_covers(rec, name) => Some((domain, next_domain)),
+            _ => None,
+        });
+        let (owner, next) = match covering {
+            Some(covering) => covering,
+            None => return Denial::Missing,
+        };
+        let encloser = [common_ancestor(name, owner), common_ancestor(name, next)]
+            .into_iter()
+            .max_by_key(|ancestor| ancestor.label_count())
+            .unwrap_or_default();
+        let wildcard = match encloser.child(b"*") {
+            Ok(wildcard) => wildcard,
+            Err(_) => return Denial::Missing,
+        };
+        if records.iter().any(|rec| nsec_covers(rec, &wildcard)) {
+            return Denial::Proven;
+        }
+        return Denial::Missing;
+    }
+
+    match Nsec3Chain::new(records) {
+        Some(chain) => chain.nxdomain(name),
+        None => Denial::Missing,
+    }
+}
+
+fn nodata(name: &DnsName, qtype: QueryType, records: &[DnsRecord]) -> Denial {
+    if records
+        .iter()
+        .any(|rec| rec.query_type() == QueryType::NSEC)
+    {
+        if let Some(types) = nsec_types(records, name) {
+            return if lacks(types, qtype) {
+                Denial::Proven
+            } else {
+                Denial::Missing
+            };
+        }
+        // an empty non-terminal: nothing here, but names below it
+        let empty_non_terminal = records.iter().any(|rec| match rec {
+            DnsRecord::NSEC { next_domain, .. } => {
+                nsec_covers(rec, name) && next_domain.is_subdomain_of(name)
+            }
+            _ => false,
+        });
+        if empty_non_terminal {
+            return Denial::Proven;
+        }
+        // a wildcard that matched, but has no data of this type
+        let wildcard = records.iter().find_map(|rec| match rec {
+            DnsRecord::NSEC { domain, types, .. } if domain.is_wildcard() => {
+                domain.parent().map(|encloser| (encloser, types))
+            }
+            _ => None,
+        });
+        if let Some((encloser, types)) = wildcard {
+            let covered = records.iter().any(|rec| nsec_covers(rec, name));
+            if covered && name.is_subdomain_of(&encloser) && lacks(types, qtype) {
+                return Denial::Proven;
+            }
+        }
+        return Denial::Missing;
+    }
+
+    match Nsec3Chain::new(records) {
+        Some(chain) => chain.nodata(name, qtype),
+        None => Denial::Missing,
+    }
+}
+
+/// Proof that the name an answer was expanded to does not exist itself
+fn wildcard_expansion(owner: &DnsName, labels: usize, records: &[DnsRecord]) -> Denial {
+    if records.iter().any(|rec| nsec_covers(rec, owner)) {
+        return Denial::Proven;
+    }
+
+    // with NSEC3, the wildcard's parent is the closest encloser and the
+    // name one label below it towards the owner has to be covered
+    match Nsec3Chain::new(records) {
+        Some(chain) if chain.iterations > MAX_NSEC3_ITERATIONS => Denial::Insecure,
+        Some(chain) if chain.covering(&owner.suffix(labels + 1)).is_some() => Denial::Proven,
+        _ => Denial::Missing,
+    }
+}
+
+/// The NSEC3 records of a response, all of which have to use the same
+/// parameters
+struct Nsec3Chain<'r> {
+    /// Each record with the hash its owner name stands for
+    records: Vec<(Vec<u8>, &'r DnsRecord)>,
+    salt: Vec<u8>,
+    iterations: u16,
+}
+
+impl<'r> Nsec3Chain<'r> {
+    fn new(records: &'r [DnsRecord]) -> Option<Nsec3Chain<'r>> {
+        let mut chain: Option<Nsec3Chain> = None;
+        for rec in records {
+            let (salt, iterations) = match rec {
+                DnsRecord::NSEC3 {
+                    hash_algorithm: 1,
+                    salt,
+                    iterations,
+                    ..
+                } => (salt, *iterations),
+                _ => continue,
+            };
+            let label = rec.domain().labels().first()?;
+            let hash = from_base32hex(std::str::from_utf8(label).ok()?)?;
+
+            let chain = chain.get_or_insert_with(|| Nsec3Chain {
+                records: Vec::new(),
+                salt: salt.clone(),
+                iterations,
+            });
+            if chain.salt != *salt || chain.iterations != iterations {
+                return None;
+            }
+            chain.records.push((hash, rec));
+        }
+        chain
+    }
+
+    fn hash(&self, name: &DnsName) -> Vec<u8> {
+        nsec3_hash(name, &self.salt, self.iterations)
+    }
+
+    fn matching(&self, name: &DnsName) -> Option<&'r DnsRecord> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|(owner, rec)| {
+                *owner == hash
+                    && rec
+                        .domain()
+                        .parent()
+                        .is_some_and(|zone| name.is_subdomain_of(&zone))
+            })
+            .map(|(_, rec)| *rec)
+    }
+
+    fn covering(&self, name: &DnsName) -> Option<&'r DnsRecord> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|(owner, rec)| match rec {
+                DnsRecord::NSEC3 { next_hashed, .. } if owner < next_hashed => {
+                    *owner < hash && hash < *next_hashed
+                }
+                // the last record of the chain wraps around to the first
+                DnsRecord::NSEC3 { next_hashed, .. } => *owner < hash || hash < *next_hashed,
+                _ => false,
+            })
+            .map(|(_, rec)| *rec)
+    }
+
+    /// The closest encloser proof (RFC 5155 section 7.2.1): the longest
+    /// ancestor of `name` that exists, and the record covering the name
+    /// one label below it
+    fn closest_encloser(&self, name: &DnsName) -> Option<(DnsName, &'r DnsRecord)> {
+        for count in (0..name.label_count()).rev() {
+            let encloser = name.suffix(count);
+            if self.matching(&encloser).is_some() {
+                let next_closer = name.suffix(count + 1);
+                return self.covering(&next_closer).map(|rec| (encloser, rec));
+            }
+        }
+        None
+    }
+
+    fn nxdomain(&self, name: &DnsName) -> Denial {
+        if self.iterations > MAX_NSEC3_ITERATIONS {
+            return Denial::Insecure;
+        }
+
+        let encloser = match self.closest_encloser(name) {
+            Some((encloser, _)) => encloser,
+            None => return Denial::Missing,
+        };
+        match encloser.child(b"*") {
+            Ok(wildcard) if self.covering(&wildcard).is_some() => Denial::Proven,
+            _ => Denial::Missing,
+        }
+    }
+
+    fn nodata(&self, name: &DnsName, qtype: QueryType) -> Denial {
+        if self.iterations > MAX_NSEC3_ITERATIONS {
+            return Denial::Insecure;
+        }
+
+        if let Some(DnsRecord::NSEC3 { types, .. }) = self.matching(name) {
+            return if lacks(types, qtype) {
+                Denial::Proven
+            } else {
+                Denial::Missing
+            };
+        }
+
+        let (encloser, covering) = match self.closest_encloser(name) {
+            Some(proof) => proof,
+            None => return Denial::Missing,
+        };
+        // an unsigned delegation left out of an opt-out chain
+        if let DnsRecord::NSEC3 { flags, .. } = covering {
+            if qtype == QueryType::DS && flags & OPT_OUT_FLAG != 0 {
+                return Denial::Insecure;
+            }
+        }
+        // a wildcard that matched, but has no data of this type
+        let wildcard = match encloser.child(b"*") {
+            Ok(wildcard) => wildcard,
+            Err(_) => return Denial::Missing,
+        };
+        match self.matching(&wildcard) {
+            Some(DnsRecord::NSEC3 { types, .. }) if lacks(types, qtype) => Denial::Proven,
+            _ => Denial::Missing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::from_base64;
+    use crate::testing::{name, SigningKey};
+    use std::net::Ipv4Addr;
+
+    fn a(domain: &str, last: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(domain),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 300,
+        }
+    }
+
+    fn soa(zone: &str) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: name(zone),
+            mname: name("ns.test"),
+            rname: name("hostmaster.test"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        }
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[QueryType]) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: name(owner),
+            next_domain: name(next),
+            types: types.to_vec(),
+            ttl: 300,
+        }
+    }
+
+    /// The records followed by their signature
+    fn signed(key: &SigningKey, rrset: Vec<DnsRecord>) -> Vec<DnsRecord> {
+        let rrsig = key.sign(&rrset);
+        let mut records = rrset;
+        records.push(rrsig);
+        records
+    }
+
+    /// A signed root with a P-256 signed `com` below it, and below that a
+    /// signed `example.com` and an unsigned `insecure.com`
+    struct Hierarchy {
+        anchor: TrustAnchor,
+        root: SigningKey,
+        com: SigningKey,
+        example: SigningKey,
+    }
+
+    impl Hierarchy {
+        fn new() -> Hierarchy {
+            let root = SigningKey::ed25519(".");
+            Hierarchy {
+                anchor: TrustAnchor {
+                    records: vec![root.dnskey.clone()],
+                },
+                root,
+                com: SigningKey::p256("com"),
+                example: SigningKey::ed25519("example.com"),
+            }
+        }
+
+        /// Answer the lookups the validator makes, the way a resolver would
+        fn fetch(&self, qname: &DnsName, qtype: QueryType) -> Result<DnsPacket> {
+            let mut response = DnsPacket::new();
+            match (qname.to_string().as_str(), qtype) {
+                ("", QueryType::DNSKEY) => {
+                    response.answers = signed(&self.root, vec![self.root.dnskey.clone()])
+                }
+                ("com", QueryType::DNSKEY) => {
+                    response.answers = signed(&self.com, vec![self.com.dnskey.clone()])
+                }
+                ("example.com", QueryType::DNSKEY) => {
+                    response.answers = signed(&self.example, vec![self.example.dnskey.clone()])
+                }
+                ("com", QueryType::DS) => {
+                    response.answers = signed(&self.root, vec![self.com.ds()])
+                }
+                ("example.com", QueryType::DS) => {
+                    response.answers = signed(&self.com, vec![self.example.ds()])
+                }
+                ("insecure.com", QueryType::DS) => {
+                    response.authorities = signed(&self.com, vec![soa("com")]);
+                    response.authorities.extend(signed(
+                        &self.com,
+                        vec![nsec(
+                            "insecure.com",
+                            "zzz.com",
+                            &[QueryType::NS, QueryType::RRSIG, QueryType::NSEC],
+                        )],
+                    ));
+                }
+                (_, QueryType::SOA) => {
+                    let zone = ["example.com", "insecure.com", "com", "."]
+                        .into_iter()
+                        .find(|zone| qname.is_subdomain_of(&name(zone)))
+                        .unwrap();
+                    response.authorities.push(soa(zone));
+                }
+                _ => return Err(format!("unexpected lookup {} {}", qname, qtype).into()),
+            }
+            Ok(response)
+        }
+
+        fn validate(&self, qname: &str, qtype: QueryType, response: &DnsPacket) -> Security {
+            let mut validator = Validator::new(&self.anchor, |n: &DnsName, t| self.fetch(n, t));
+            validator.validate(&name(qname), qtype, response)
+        }
+
+        /// NXDOMAIN for a name in example.com, with `authorities` as proof
+        fn nxdomain(&self, authorities: Vec<DnsRecord>) -> DnsPacket {
+            let mut response = DnsPacket::new();
+            response.header.rescode = ResultCode::NXDOMAIN;
+            response.authorities = signed(&self.example, vec![soa("example.com")]);
+            response.authorities.extend(authorities);
+            response
+        }
+    }
+
+    fn is_bogus(security: &Security) -> bool {
+        matches!(security, Security::Bogus(_))
+    }
+
+    #[test]
+    fn test_rsa_signature() {
+        let key = from_base64(
+            "AwEAAd9syKbS7dQRlxGFAnwXuqfTa3qwcqbcWjBtAn8GJ7iIAFBYxAUg+din+C91StJDXhE5QErjFfS1\
+             yzjr9PkdzqpOi5tCqitXDMRCbzZaA0px4ec5eUElZv1uWniwKO8FR3S4yMd7iMyS4XxBrF4u93z+9aFI\
+             KiPeqnUkT7XwoFY3",
+        )
+        .unwrap();
+        let sig = from_base64(
+            "rDKRRkZUoAiFnAxQgKssvhSNctBVMdSXwDO7CKBV47t7ck7Y365Ap3FmL0mUDvDBPheKA4dHy/g4b3mA\
+             hEFvZZWjSCUIaADssxkRWdWAZyHK+6GPdlw2Y5tT+PwhHD/uSUHoMcc54fD58F60P3bRZH3m+5ufHpaY\
+             yDCCeeGogcU=",
+        )
+        .unwrap();
+        let message = b"my-dns rsasha256 test vector";
+
+        assert!(verify_signature(RSASHA256, &key, message, &sig));
+        assert!(!verify_signature(RSASHA256, &key, b"another message", &sig));
+        assert!(!verify_signature(RSASHA256, &key[..10], message, &sig));
+    }
+
+    #[test]
+    fn test_root_key_matches_anchor() {
+        let dnskey = DnsRecord::DNSKEY {
+            domain: DnsName::root(),
+            flags: 257,
+            protocol: 3,
+            algorithm: RSASHA256,
+            public_key: from_base64(
+                "AwEAAaz/tAm8yTn4Mfeh5eyI96WSVexTBAvkMgJzkKTOiW1vkIbzxeF3+/4RgWOq7HrxRixHlFlExOLA\
+                 Jr5emLvN7SWXgnLh4+B5xQlNVz8Og8kvArMtNROxVQuCaSnIDdD5LKyWbRd2n9WGe2R8PzgCmr3EgVLr\
+                 jyBxWezF0jLHwVN8efS3rCj/EWgvIWgb9tarpVUDK/b58Da+sqqls3eNbuv7pr+eoZG+SrDK6nWeL3c6\
+                 H5Apxz7LjVc1uTIdsIXxuOLYA4/ilBmSVIzuDWfdRUfhHdY6+cn8HFRm+2hM8AnXGXws9555KrUB5qih\
+                 ylGa8subX2Nn6UwNR1AkUTV74bU=",
+            )
+            .unwrap(),
+            ttl: 0,
+        };
+
+        assert_eq!(key_tag(&dnskey), Some(20326));
+        assert!(TrustAnchor::root().trusts(&dnskey));
+    }
+
+    #[test]
+    fn test_nsec3_hash() {
+        // RFC 5155 appendix A
+        let hash = nsec3_hash(&name("example"), &[0xaa, 0xbb, 0xcc, 0xdd], 12);
+        assert_eq!(
+            crate::encoding::to_base32hex(&hash),
+            "0P9MHAVEQVM6T7VBL5LOP2U3T2RP3TOM"
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        // the validity period is compared with serial number arithmetic
+        assert!(in_validity_period(u32::MAX - 10, 10, 0));
+        assert!(!in_validity_period(100, 200, 201));
+    }
+
+    #[test]
+    fn test_signature_covers_canonical_form() {
+        let key = SigningKey::ed25519("example.com");
+        let rrset = vec![a("www.example.com", 1), a("www.example.com", 2)];
+        let rrsig = key.sign(&rrset);
+        let now = unix_time();
+        assert_eq!(verify_rrsig(&rrsig, &key.dnskey, &rrset, now), Ok(()));
+
+        // order, case and TTL do not matter to the signature
+        let mut reordered = vec![a("WWW.Example.com", 2), a("www.example.com", 1)];
+        reordered[0].set_ttl(5);
+        assert_eq!(verify_rrsig(&rrsig, &key.dnskey, &reordered, now), Ok(()));
+
+        assert!(verify_rrsig(&rrsig, &key.dnskey, &rrset[..1], now).is_err());
+        assert!(verify_rrsig(&rrsig, &key.dnskey, &rrset, now + 7200).is_err());
+    }
+
+    #[test]
+    fn test_secure_answer() {
+        let world = Hierarchy::new();
+        let mut response = DnsPacket::new();
+        response.answers = signed(&world.example, vec![a("www.example.com", 1)]);
+        assert_eq!(
+            world.validate("www.example.com", QueryType::A, &response),
+            Security::Secure
+        );
+
+        // tampered with after signing
+        response.answers[0] = a("www.example.com", 66);
+        assert!(is_bogus(&world.validate(
+            "www.example.com",
+            QueryType::A,
+            &response
+        )));
+
+        // signatures stripped on the way
+        response.answers.truncate(1);
+        assert!(is_bogus(&world.validate(
+            "www.example.com",
+            QueryType::A,
+            &response
+        )));
+    }
+
+    #[test]
+    fn test_expired_signature() {
+        let world = Hierarchy::new();
+        let rrset = vec![a("www.example.com", 1)];
+        let now = unix_time();
+        let rrsig = world.example.sign_between(&rrset, now - 7200, now - 3600);
+
+        let mut response = DnsPacket::new();
+        response.answers = vec![rrset[0].clone(), rrsig];
+        assert!(is_bogus(&world.validate(
+            "www.example.com",
+            QueryType::A,
+            &response
+        )));
+    }
+
+    #[test]
+    fn test_insecure_delegation() {
+        let world = Hierarchy::new();
+        let mut response = DnsPacket::new();
+        response.answers.push(a("host.insecure.com", 1));
+        assert_eq!(
+            world.validate("host.insecure.com", QueryType::A, &response),
+            Security::Insecure
+        );
+    }
+
+    #[test]
+    fn test_nxdomain_with_nsec() {
+        let world = Hierarchy::new();
+        let proof = signed(
+            &world.example,
+            vec![nsec(
+                "example.com",
+                "www.example.com",
+                &[QueryType::SOA, QueryType::RRSIG, QueryType::NSEC],
+            )],
+        );
+        let response = world.nxdomain(proof);
+        assert_eq!(
+            world.validate("nope.example.com", QueryType::A, &response),
+            Security::Secure
+        );
+
+        // the NSEC does not cover a name after www
+        assert!(is_bogus(&world.validate(
+            "zzz.example.com",
+            QueryType::A,
+            &response
+        )));
+
+        let response = world.nxdomain(Vec::new());
+        assert!(is_bogus(&world.validate(
+            "nope.example.com",
+            QueryType::A,
+            &response
+        )));
+    }
+
+    #[test]
+    fn test_nodata_with_nsec() {
+        let world = Hierarchy::new();
+        let mut response = DnsPacket::new();
+        response.authorities = signed(&world.example, vec![soa("example.com")]);
+        response.authorities.extend(signed(
+            &world.example,
+            vec![nsec(
+                "www.example.com",
+                "example.com",
+                &[QueryType::A, QueryType::RRSIG, QueryType::NSEC],
+            )],
+        ));
+
+        assert_eq!(
+            world.validate("www.example.com", QueryType::AAAA, &response),
+            Security::Secure
+        );
+        assert!(is_bogus(&world.validate(
+            "www.example.com",
+            QueryType::A,
+            &response
+        )));
+    }
+
+    /// An NSEC3 chain for a zone holding only `example` and `a.example`
+    fn nsec3_chain(flags: u8, iterations: u16) -> Vec<DnsRecord> {
+        let salt = vec![0xaa, 0xbb];
+        let mut hashes: Vec<(Vec<u8>, Vec<QueryType>)> = vec![
+            (
+                nsec3_hash(&name("example"), &salt, iterations),
+                vec![QueryType::NS, QueryType::SOA],
+            ),
+            (
+                nsec3_hash(&name("a.example"), &salt, iterations),
+                vec![QueryType::A],
+            ),
+        ];
+        hashes.sort();
+
+        (0..hashes.len())
+            .map(|i| {
+                let owner = crate::encoding::to_base32hex(&hashes[i].0);
+                DnsRecord::NSEC3 {
+                    domain: name(&format!("{}.example", owner)),
+                    hash_algorithm: 1,
+                    flags,
+                    iterations,
+                    salt: salt.clone(),
+                    next_hashed: hashes[(i + 1) % hashes.len()].0.clone(),
+                    types: hashes[i].1.clone(),
+                    ttl: 300,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nsec3_denial() {
+        let chain = nsec3_chain(0, 5);
+        assert_eq!(nxdomain(&name("nope.example"), &chain), Denial::Proven);
+        assert_eq!(nxdomain(&name("x.y.a.example"), &chain), Denial::Proven);
+        assert_eq!(
+            nodata(&name("a.example"), QueryType::AAAA, &chain),
+            Denial::Proven
+        );
+        assert_eq!(
+            nodata(&name("a.example"), QueryType::A, &chain),
+            Denial::Missing
+        );
+        // the closest encloser has to be in the chain
+        assert_eq!(
+            nxdomain(&name("nope.example"), &chain[..0]),
+            Denial::Missing
+        );
+
+        // an unsigned delegation inside an opt-out span
+        let chain = nsec3_chain(OPT_OUT_FLAG, 5);
+        assert_eq!(
+            nodata(&name("child.example"), QueryType::DS, &chain),
+            Denial::Insecure
+        );
+
+        let chain = nsec3_chain(0, MAX_NSEC3_ITERATIONS + 1);
+        assert_eq!(nxdomain(&name("nope.example"), &chain), Denial::Insecure);
+    }
+
+    #[test]
+    fn test_trust_anchor_file() {
+        let anchor = TrustAnchor::parse(
+            ". 0 IN DS 20326 8 2 (\n\
+             E06D44B80B8F1D39A95C0B0D7C65D084\n\
+             58E880409BBC683457104237C7F8EC8D )\n",
+        )
+        .unwrap();
+        assert_eq!(anchor.records, TrustAnchor::root().records[..1]);
+        assert!(anchor.covers(&name("example.com")));
+
+        assert!(TrustAnchor::parse(". 3600 IN A 192.0.2.1").is_err());
+    }
+}
@@ -0,0 +1,588 @@
+//! DNS over HTTPS (RFC 8484): messages carried over HTTP/1.1 and TLS,
+//! either as `application/dns-message` POST bodies or base64url encoded
+//! in the `dns` parameter of a GET request
+
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, StreamOwned};
+
+use crate::client::DnsClient;
+use crate::encoding::to_base64url;
+use crate::name::DnsName;
+use crate::packets::{DnsPacket, PacketBuffer, QueryType, VectorPacketBuffer};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Where DoH servers conventionally answer
+pub const DOH_PATH: &str = "/dns-query";
+
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Longest request or status line and header we accept
+const MAX_LINE_LENGTH: usize = 8192;
+const MAX_HEADERS: usize = 64;
+
+/// A DNS message never gets larger than this
+const MAX_BODY_SIZE: usize = 65535;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The path and query, as sent
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// The first value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// The value of a query parameter. Percent escapes are not decoded,
+    /// the base64url DoH carries does not need any.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_once('?')?;
+        query
+            .split('&')
+            .find_map(|pair| match pair.split_once('=') {
+                Some((key, value)) if key == name => Some(value),
+                _ => None,
+            })
+    }
+
+    /// Whether the client wants the connection closed after the response
+    pub fn wants_close(&self) -> bool {
+        self.header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// An error status with a short plain text explanation
+    pub fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: vec![("Content-Type".into(), "text/plain".into())],
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+
+    /// A DNS response, cacheable for as long as its records are valid
+    /// (RFC 8484 section 5.1)
+    pub fn dns_message(response: &mut DnsPacket) -> Result<HttpResponse> {
+        let mut buffer = VectorPacketBuffer::new();
+        response.write(&mut buffer)?;
+
+        let max_age = response
+            .answers
+            .iter()
+            .chain(&response.authorities)
+            .map(|rec| rec.ttl())
+            .min()
+            .unwrap_or(0);
+        Ok(HttpResponse {
+            status: 200,
+            headers: vec![
+                ("Content-Type".into(), DNS_MESSAGE.into()),
+                ("Cache-Control".into(), format!("max-age={}", max_age)),
+            ],
+            body: buffer.buffer[..buffer.pos()].to_vec(),
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+}
+
+fn header<'h>(headers: &'h [(String, String)], name: &str) -> Option<&'h str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+/// Read a line without its CRLF. Returns `None` at the end of the stream.
+fn read_line<R: BufRead>(stream: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    match stream
+        .take(MAX_LINE_LENGTH as u64 + 2)
+        .read_until(b'\n', &mut line)
+    {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        // TLS peers often hang up without a close_notify
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && line.is_empty() => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if line.last() != Some(&b'\n') {
+        return Err("HTTP line too long".into());
+    }
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(String::from_utf8(line)?))
+}
+
+fn read_headers<R: BufRead>(stream: &mut R) -> Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(stream)?.ok_or("connection closed inside the headers")?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err("too many HTTP headers".into());
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("malformed HTTP header: {}", line))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// Read a body delimited by Content-Length or chunked encoding
+fn read_body<R: BufRead>(stream: &mut R, headers: &[(String, String)]) -> Result<Vec<u8>> {
+    let chunked = header(headers, "Transfer-Encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    if !chunked {
+        let len: usize = match header(headers, "Content-Length") {
+            Some(len) => len.parse().map_err(|_| "invalid Content-Length")?,
+            None => 0,
+        };
+        if len > MAX_BODY_SIZE {
+            return Err("HTTP body too large".into());
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body)?;
+        return Ok(body);
+    }
+
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream)?.ok_or("connection closed inside a chunk")?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| "invalid chunk size")?;
+        let end = body
+            .len()
+            .checked_add(size)
+            .filter(|&end| end <= MAX_BODY_SIZE)
+            .ok_or("HTTP body too large")?;
+        if size == 0 {
+            // trailers end with an empty line like the headers do
+            read_headers(stream)?;
+            return Ok(body);
+        }
+
+        let start = body.len();
+        body.resize(end, 0);
+        stream.read_exact(&mut body[start..])?;
+        if read_line(stream)?.as_deref() != Some("") {
+            return Err("chunk not followed by CRLF".into());
+        }
+    }
+}
+
+/// Read one request. Returns `None` if the client closed the connection
+/// before starting a new one.
+pub fn read_request<R: BufRead>(stream: &mut R) -> Result<Option<HttpRequest>> {
+    let line = match read_line(stream)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(format!("malformed request line: {}", line).into()),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(format!("unsupported HTTP version: {}", version).into());
+    }
+
+    let headers = read_headers(stream)?;
+    let body = read_body(stream, &headers)?;
+    Ok(Some(HttpRequest {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body,
+    }))
+}
+
+pub fn write_request<W: Write>(stream: &mut W, host: &str, request: &HttpRequest) -> Result<()> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+        request.method,
+        request.target,
+        host,
+        request.body.len()
+    );
+    for (name, value) in &request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&request.body);
+    stream.write_all(&message)?;
+    stream.flush()?;
+    Ok(())
+}
+
+pub fn read_response<R: BufRead>(stream: &mut R) -> Result<HttpResponse> {
+    let line = read_line(stream)?.ok_or("connection closed before the response")?;
+    let status = match line.split(' ').collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status
+            .parse()
+            .map_err(|_| format!("malformed status line: {}", line))?,
+        _ => return Err(format!("malformed status line: {}", line).into()),
+    };
+
+    let headers = read_headers(stream)?;
+    let body = read_body(stream, &headers)?;
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+pub fn write_response<W: Write>(stream: &mut W, response: &HttpResponse) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&response.body);
+    stream.write_all(&message)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Build a TLS configuration for serving from a PEM certificate chain and
+/// private key
+pub fn load_server_config<P: AsRef<Path>>(cert_path: P, key_path: P) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_slice_iter(&fs::read(cert_path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err("no certificates in the certificate file".into());
+    }
+    let key = PrivateKeyDer::from_pem_slice(&fs::read(key_path)?)?;
+
+    server_config(certs, key)
+}
+
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>> {
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// A TLS client configuration trusting the given roots, or the Mozilla set
+/// bundled with webpki-roots if there are none
+pub fn client_config(roots: Vec<CertificateDer<'static>>) -> Result<Arc<ClientConfig>> {
+    let mut store = RootCertStore::empty();
+    if roots.is_empty() {
+        store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    for root in roots {
+        store.add(root)?;
+    }
+
+    let mut config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(store)
+            .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// An `https://host[:port]/path` URL naming a DoH endpoint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DohUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for DohUrl {
+    type Err = String;
+
+    fn from_str(url: &str) -> std::result::Result<DohUrl, String> {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| format!("not an https URL: {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, DOH_PATH),
+        };
+
+        // IPv6 literals are bracketed, their colons are not a port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port in {}", url))?;
+                (host, port)
+            }
+            _ => (authority, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("no host in {}", url));
+        }
+
+        Ok(DohUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for DohUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "https://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "https://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+/// Sends queries to a DoH server, one connection per query
+pub struct DohClient {
+    pub url: DohUrl,
+    /// Settings for the queries themselves; only the timeout,
+    /// recursion_desired, EDNS and DNSSEC fields apply
+    pub client: DnsClient,
+    /// Send GET requests, which HTTP caches can answer, instead of POST
+    pub use_get: bool,
+    tls: Arc<ClientConfig>,
+}
+
+impl DohClient {
+    /// A client for `url` trusting the usual public certificate authorities
+    pub fn new(url: DohUrl) -> Result<DohClient> {
+        Ok(DohClient::with_tls_config(url, client_config(Vec::new())?))
+    }
+
+    pub fn with_tls_config(url: DohUrl, tls: Arc<ClientConfig>) -> DohClient {
+        DohClient {
+            url,
+            client: DnsClient::new(),
+            use_get: false,
+            tls,
+        }
+    }
+
+    pub fn send_query(&self, qname: &DnsName, qtype: QueryType) -> Result<DnsPacket> {
+        let mut query = self.client.build_query(qname, qtype);
+        // a zero id lets HTTP caches share answers (RFC 8484 section 4.1)
+        query.header.id = 0;
+        let mut buffer = VectorPacketBuffer::new();
+        query.write(&mut buffer)?;
+        let message = &buffer.buffer[..buffer.pos()];
+
+        let request = if self.use_get {
+            HttpRequest {
+                method: "GET".into(),
+                target: format!("{}?dns={}", self.url.path, to_base64url(message)),
+                headers: vec![("Accept".into(), DNS_MESSAGE.into())],
+                body: Vec::new(),
+            }
+        } else {
+            HttpRequest {
+                method: "POST".into(),
+                target: self.url.path.clone(),
+                headers: vec![
+                    ("Accept".into(), DNS_MESSAGE.into()),
+                    ("Content-Type".into(), DNS_MESSAGE.into()),
+                ],
+                body: message.to_vec(),
+            }
+        };
+
+        let response = self.exchange(&request)?;
+        if response.status != 200 {
+            return Err(format!("{} answered with HTTP {}", self.url, response.status).into());
+        }
+        if !response
+            .header("Content-Type")
+            .is_some_and(|value| value.eq_ignore_ascii_case(DNS_MESSAGE))
+        {
+            return Err(format!("{} did not answer with a DNS message", self.url).into());
+        }
+
+        let response = DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&response.body))?;
+        if response.questions != query.questions {
+            return Err(format!("response from {} does not match the query", self.url).into());
+        }
+        Ok(response)
+    }
+
+    fn exchange(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let stream = self.connect()?;
+        stream.set_read_timeout(Some(self.client.timeout))?;
+        stream.set_write_timeout(Some(self.client.timeout))?;
+
+        let server_name = ServerName::try_from(self.url.host.clone())?;
+        let conn = ClientConnection::new(Arc::clone(&self.tls), server_name)?;
+        let mut tls = BufReader::new(StreamOwned::new(conn, stream));
+
+        let mut request = request.clone();
+        request.headers.push(("Connection".into(), "close".into()));
+        write_request(tls.get_mut(), &self.url.host, &request)?;
+        read_response(&mut tls)
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let mut last_err: Error = format!("could not resolve {}", self.url.host).into();
+        for addr in (self.url.host.as_str(), self.url.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.client.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = e.into(),
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_request() {
+        let text = "POST /dns-query HTTP/1.1\r\nHost: example.com\r\n\
+                    content-type: application/dns-message\r\nContent-Length: 3\r\n\r\nabc\
+                    GET /dns-query?ct&dns=AAAB HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut stream = Cursor::new(text.as_bytes());
+
+        let post = read_request(&mut stream).unwrap().unwrap();
+        assert_eq!(post.method, "POST");
+        assert_eq!(post.header("Content-Type"), Some(DNS_MESSAGE));
+        assert_eq!(post.body, b"abc");
+        assert!(!post.wants_close());
+
+        let get = read_request(&mut stream).unwrap().unwrap();
+        assert_eq!(get.path(), DOH_PATH);
+        assert_eq!(get.query_param("dns"), Some("AAAB"));
+        assert_eq!(get.query_param("ct"), None);
+        assert!(get.wants_close());
+
+        assert_eq!(read_request(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        let text = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n";
+        let response = read_response(&mut Cursor::new(text.as_bytes())).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"abcde");
+    }
+
+    #[test]
+    fn test_rejects_malformed_http() {
+        let oversized = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        for text in [
+            "GET /\r\n\r\n",
+            "GET / HTTP/2\r\n\r\n",
+            "GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             2\r\nab\r\nfffffffffffffffe\r\n",
+            &oversized,
+            &long_line,
+        ] {
+            assert!(
+                read_request(&mut Cursor::new(text.as_bytes())).is_err(),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            "https://dns.example/dns-query".parse(),
+            Ok(DohUrl {
+                host: "dns.example".into(),
+                port: 443,
+                path: "/dns-query".into(),
+            })
+        );
+        assert_eq!(
+            "https://[::1]:8443".parse(),
+            Ok(DohUrl {
+                host: "::1".into(),
+                port: 8443,
+                path: DOH_PATH.into(),
+            })
+        );
+        assert!("http://dns.example/".parse::<DohUrl>().is_err());
+        assert!("https://:443/".parse::<DohUrl>().is_err());
+    }
+}
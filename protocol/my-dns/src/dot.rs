@@ -0,0 +1,336 @@
+//! DNS over TLS (RFC 7858): the length-prefixed messages of DNS over TCP,
+//! inside a TLS session
+
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ServerConfig, StreamOwned};
+
+use crate::client::{random_id, DnsClient};
+use crate::doh;
+use crate::name::DnsName;
+use crate::packets::{DnsPacket, QueryType};
+use crate::tcp::{read_message, write_message};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const DOT_PORT: u16 = 853;
+
+/// The ALPN protocol id of DNS over TLS
+const DOT_ALPN: &[u8] = b"dot";
+
+/// Idle sessions kept open for later queries
+const MAX_IDLE_SESSIONS: usize = 4;
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Build a TLS configuration for serving DoT from a PEM certificate chain
+/// and private key
+pub fn load_server_config<P: AsRef<Path>>(cert_path: P, key_path: P) -> Result<Arc<ServerConfig>> {
+    let mut config = Arc::unwrap_or_clone(doh::load_server_config(cert_path, key_path)?);
+    config.alpn_protocols = vec![DOT_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>> {
+    let mut config = Arc::unwrap_or_clone(doh::server_config(certs, key)?);
+    config.alpn_protocols = vec![DOT_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// A DoT client configuration trusting the certificate authorities in a PEM
+/// file instead of the public ones
+pub fn load_client_config<P: AsRef<Path>>(ca_path: P) -> Result<Arc<ClientConfig>> {
+    let roots = CertificateDer::pem_slice_iter(&fs::read(ca_path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if roots.is_empty() {
+        return Err("no certificates in the CA file".into());
+    }
+    client_config(roots)
+}
+
+/// A DoT client configuration trusting the given roots, or the Mozilla set
+/// without any
+pub fn client_config(roots: Vec<CertificateDer<'static>>) -> Result<Arc<ClientConfig>> {
+    let mut config = Arc::unwrap_or_clone(doh::client_config(roots)?);
+    config.alpn_protocols = vec![DOT_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Sends queries to a DoT server, keeping sessions open between them.
+/// Concurrent exchanges each get a session of their own.
+pub struct DotClient {
+    pub server: SocketAddr,
+    /// The name the server's certificate has to be issued for
+    pub server_name: ServerName<'static>,
+    /// Settings for the queries themselves; the retries and use_tcp fields
+    /// do not apply
+    pub client: DnsClient,
+    tls: Arc<ClientConfig>,
+    /// Open sessions no exchange is using, reused by the next ones
+    idle: Mutex<Vec<TlsStream>>,
+}
+
+impl DotClient {
+    /// A client for `server`, whose certificate has to be issued for
+    /// `server_name` by one of the usual public certificate authorities
+    pub fn new(server: SocketAddr, server_name: &str) -> Result<DotClient> {
+        DotClient::with_tls_config(server, server_name, client_config(Vec::new())?)
+    }
+
+    pub fn with_tls_config(
+        server: SocketAddr,
+        server_name: &str,
+        tls: Arc<ClientConfig>,
+    ) -> Result<DotClient> {
+        Ok(DotClient {
+            server,
+            server_name: ServerName::try_from(server_name.to_string())?,
+            client: DnsClient::new(),
+            tls,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn send_query(&self, qname: &DnsName, qtype: QueryType) -> Result<DnsPacket> {
+        let mut responses = self.send_queries(&[(qname.clone(), qtype)])?;
+        Ok(responses.remove(0))
+    }
+
+    /// Send several queries at once, returning the responses in the same
+    /// order
+    pub fn send_queries(&self, questions: &[(DnsName, QueryType)]) -> Result<Vec<DnsPacket>> {
+        let queries = questions
+            .iter()
+            .map(|(qname, qtype)| self.client.build_query(qname, *qtype))
+            .collect();
+        self.exchange(queries)
+    }
+
+    /// Send already built queries, all of them before reading any response
+    /// (RFC 7766 section 6.2.1.1), over an idle session or a new one if
+    /// there is none. The server may answer them in any order; the
+    /// responses come back in query order.
+    pub fn exchange(&self, mut queries: Vec<DnsPacket>) -> Result<Vec<DnsPacket>> {
+        // the ids are all that tells the responses apart
+        for i in 0..queries.len() {
+            while queries[..i]
+                .iter()
+                .any(|query| query.header.id == queries[i].header.id)
+            {
+                queries[i].header.id = random_id();
+            }
+        }
+
+        // the lock is only held to take a session, not over the exchange
+        let idle = self.idle.lock().unwrap().pop();
+        let reused = idle.is_some();
+        let mut stream = match idle {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        let mut result = self.exchange_on(&mut stream, &mut queries);
+        if result.is_err() && reused {
+            // the server may have closed the idle session in the meantime
+            stream = self.connect()?;
+            result = self.exchange_on(&mut stream, &mut queries);
+        }
+
+        if result.is_ok() {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_SESSIONS {
+                idle.push(stream);
+            }
+        }
+        result
+    }
+
+    fn exchange_on(
+        &self,
+        stream: &mut TlsStream,
+        queries: &mut [DnsPacket],
+    ) -> Result<Vec<DnsPacket>> {
+        let mut framed = Vec::new();
+        for query in queries.iter_mut() {
+            write_message(&mut framed, query)?;
+        }
+        stream.write_all(&framed)?;
+        stream.flush()?;
+
+        let mut responses: Vec<Option<DnsPacket>> = vec![None; queries.len()];
+        for _ in 0..queries.len() {
+            let mut res_buffer = read_message(stream)?.ok_or_else(|| {
+                format!("{} closed the connection without answering", self.server)
+            })?;
+            let response = DnsPacket::from_buffer(&mut res_buffer)?;
+            let slot = queries
+                .iter()
+                .position(|query| query.header.id == response.header.id)
+                .filter(|&i| responses[i].is_none() && response.questions == queries[i].questions)
+                .ok_or_else(|| format!("response from {} does not match any query", self.server))?;
+            responses[slot] = Some(response);
+        }
+
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    fn connect(&self) -> Result<TlsStream> {
+        let stream = TcpStream::connect_timeout(&self.server, self.client.timeout)?;
+        stream.set_read_timeout(Some(self.client.timeout))?;
+        stream.set_write_timeout(Some(self.client.timeout))?;
+        stream.set_nodelay(true)?;
+
+        let conn = ClientConnection::new(Arc::clone(&self.tls), self.server_name.clone())?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::ResultCode;
+    use crate::testing::{ca_signed_cert, name};
+    use rustls::ServerConnection;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A DoT server that answers NXDOMAIN, in reverse order, to batches of
+    /// `batch` queries and hangs up after `per_connection` of them. Returns
+    /// its address and a count of the connections it accepted.
+    fn fake_server(
+        tls: Arc<ServerConfig>,
+        batch: usize,
+        per_connection: usize,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&connections);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let conn = ServerConnection::new(Arc::clone(&tls)).unwrap();
+                let mut stream = StreamOwned::new(conn, stream.unwrap());
+                let mut answered = 0;
+                while answered < per_connection {
+                    let mut requests = Vec::new();
+                    while requests.len() < batch.min(per_connection - answered) {
+                        let mut buffer = read_message(&mut stream).unwrap().unwrap();
+                        requests.push(DnsPacket::from_buffer(&mut buffer).unwrap());
+                    }
+                    for request in requests.iter().rev() {
+                        let mut response = request.clone();
+                        response.header.response = true;
+                        response.header.rescode = ResultCode::NXDOMAIN;
+                        write_message(&mut stream, &mut response).unwrap();
+                        answered += 1;
+                    }
+                    stream.flush().unwrap();
+                }
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+        });
+
+        (addr, connections)
+    }
+
+    #[test]
+    fn test_pipelining_and_reuse() {
+        let (ca, cert, key) = ca_signed_cert();
+        let tls = server_config(vec![cert], key).unwrap();
+        let (addr, connections) = fake_server(tls, 3, 4);
+        let client =
+            DotClient::with_tls_config(addr, "localhost", client_config(vec![ca]).unwrap())
+                .unwrap();
+
+        let questions: Vec<_> = ["a.example", "b.example", "c.example"]
+            .iter()
+            .map(|qname| (name(qname), QueryType::A))
+            .collect();
+        let responses = client.send_queries(&questions).unwrap();
+        for (response, (qname, _)) in responses.iter().zip(&questions) {
+            assert_eq!(response.questions[0].name, *qname);
+            assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        }
+
+        // the session stays open for the next query, until the server closes it
+        client.send_query(&name("d.example"), QueryType::A).unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(client.send_queries(&questions).unwrap().len(), 3);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_concurrent_exchanges() {
+        let (ca, cert, key) = ca_signed_cert();
+        let tls = server_config(vec![cert], key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // answers everything but slow.example, on a thread per connection
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let conn = ServerConnection::new(Arc::clone(&tls)).unwrap();
+                let mut stream = StreamOwned::new(conn, stream.unwrap());
+                thread::spawn(move || {
+                    while let Ok(Some(mut buffer)) = read_message(&mut stream) {
+                        let mut response = DnsPacket::from_buffer(&mut buffer).unwrap();
+                        if response.questions[0].name == "slow.example" {
+                            continue;
+                        }
+                        response.header.response = true;
+                        write_message(&mut stream, &mut response).unwrap();
+                        stream.flush().unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut client =
+            DotClient::with_tls_config(addr, "localhost", client_config(vec![ca]).unwrap())
+                .unwrap();
+        client.client.timeout = Duration::from_secs(2);
+        let client = Arc::new(client);
+        let slow_client = Arc::clone(&client);
+        let slow = thread::spawn(move || {
+            slow_client
+                .send_query(&name("slow.example"), QueryType::A)
+                .is_err()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        // not held up by the exchange waiting for its answer
+        let start = Instant::now();
+        client
+            .send_query(&name("fast.example"), QueryType::A)
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(slow.join().unwrap());
+    }
+
+    #[test]
+    fn test_rejects_unknown_ca() {
+        let (_, cert, key) = ca_signed_cert();
+        let (other_ca, _, _) = ca_signed_cert();
+        let tls = server_config(vec![cert], key).unwrap();
+        let (addr, _) = fake_server(tls, 1, 1);
+        let client =
+            DotClient::with_tls_config(addr, "localhost", client_config(vec![other_ca]).unwrap())
+                .unwrap();
+        assert!(client
+            .send_query(&name("example.com"), QueryType::A)
+            .is_err());
+    }
+}
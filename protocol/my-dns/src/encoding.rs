@@ -0,0 +1,166 @@
+//! Text encodings of binary data used in presentation formats
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const BASE32HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Decode hex digits of either case, and nothing else: no signs, spaces or
+/// `0x` prefix
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digit = |b: u8| (b as char).to_digit(16).unwrap_or(0) as u8;
+    Some(
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| digit(pair[0]) << 4 | digit(pair[1]))
+            .collect(),
+    )
+}
+
+/// Base64 with padding (RFC 4648 section 4), as DNSKEY and RRSIG use
+pub fn to_base64(bytes: &[u8]) -> String {
+    encode_base64(bytes, BASE64, true)
+}
+
+/// Decode base64, with or without padding. Whitespace is not allowed, join
+/// the pieces of a split value first.
+pub fn from_base64(text: &str) -> Option<Vec<u8>> {
+    decode_bits(text.trim_end_matches('='), 6, |c| {
+        BASE64.iter().position(|&b| b == c)
+    })
+}
+
+/// The URL and filename safe base64 alphabet without padding (RFC 4648
+/// section 5), as DoH GET requests carry their message
+pub fn to_base64url(bytes: &[u8]) -> String {
+    encode_base64(bytes, BASE64URL, false)
+}
+
+/// Decode unpadded base64url. RFC 8484 forbids the padding, so it is
+/// rejected here.
+pub fn from_base64url(text: &str) -> Option<Vec<u8>> {
+    decode_bits(text, 6, |c| BASE64URL.iter().position(|&b| b == c))
+}
+
+fn encode_base64(bytes: &[u8], alphabet: &[u8; 64], pad: bool) -> String {
+    let mut res = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(alphabet[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else if pad {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+/// Base32 with the extended hex alphabet and no padding, the way NSEC3
+/// hashes are written (RFC 5155 section 3.3)
+pub fn to_base32hex(bytes: &[u8]) -> String {
+    let mut res = String::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &b in bytes {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            res.push(BASE32HEX[(acc >> bits) as usize & 0x1F] as char);
+        }
+    }
+    if bits > 0 {
+        res.push(BASE32HEX[(acc << (5 - bits)) as usize & 0x1F] as char);
+    }
+    res
+}
+
+pub fn from_base32hex(text: &str) -> Option<Vec<u8>> {
+    decode_bits(text.trim_end_matches('='), 5, |c| {
+        BASE32HEX.iter().position(|&b| b == c.to_ascii_uppercase())
+    })
+}
+
+/// Collect `width` bits per character into bytes. Leftover bits have to
+/// be zero, so every value has exactly one encoding.
+fn decode_bits(text: &str, width: u32, value: impl Fn(u8) -> Option<usize>) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        acc = (acc << width) | value(c)? as u32;
+        bits += width;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if bits >= width || acc != 0 {
+        return None;
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc4648_vectors() {
+        let vectors = [
+            ("", "", ""),
+            ("f", "Zg==", "CO"),
+            ("fo", "Zm8=", "CPNG"),
+            ("foo", "Zm9v", "CPNMU"),
+            ("foob", "Zm9vYg==", "CPNMUOG"),
+            ("fooba", "Zm9vYmE=", "CPNMUOJ1"),
+            ("foobar", "Zm9vYmFy", "CPNMUOJ1E8"),
+        ];
+        for (plain, base64, base32hex) in vectors {
+            assert_eq!(to_base64(plain.as_bytes()), base64);
+            assert_eq!(from_base64(base64).unwrap(), plain.as_bytes());
+            assert_eq!(to_base32hex(plain.as_bytes()), base32hex);
+            assert_eq!(from_base32hex(base32hex).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid_input() {
+        assert_eq!(from_base64("Zm9v!"), None);
+        // the last character carries bits that do not fit in a byte
+        assert_eq!(from_base64("Zh=="), None);
+        assert_eq!(from_base64("Z"), None);
+        assert_eq!(from_base32hex("W"), None);
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+f"), None);
+        assert_eq!(from_hex("aéb"), None);
+        assert_eq!(from_base64url("Zm8="), None);
+        assert_eq!(from_base64url("+/"), None);
+    }
+
+    #[test]
+    fn test_base64url() {
+        assert_eq!(to_base64url(&[0xfb, 0xff, 0xbf]), "-_-_");
+        assert_eq!(to_base64url(b"fo"), "Zm8");
+        assert_eq!(from_base64url("-_-_").unwrap(), [0xfb, 0xff, 0xbf]);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0xff]), "00ABFF");
+        assert_eq!(from_hex("00abFF").unwrap(), [0x00, 0xab, 0xff]);
+    }
+}
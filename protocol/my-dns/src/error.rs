@@ -0,0 +1,98 @@
+//! Errors raised while reading or writing DNS messages
+
+use std::fmt;
+
+use crate::packets::QueryType;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    /// Read or write past the end of the message, at `offset`
+    BufferOverrun {
+        offset: usize,
+    },
+    /// A name at `offset` that follows too many compression pointers
+    PointerLoop {
+        offset: usize,
+    },
+    /// A compression pointer at `offset` that does not point backwards
+    ForwardPointer {
+        offset: usize,
+    },
+    /// A label longer than the 63 bytes its length byte allows
+    LabelTooLong {
+        len: usize,
+    },
+    /// A name longer than 255 bytes on the wire
+    NameTooLong {
+        len: usize,
+    },
+    /// A character string, such as a TXT string or CAA tag, over 255 bytes
+    StringTooLong {
+        len: usize,
+    },
+    /// RDATA that does not span the length given in the record header
+    BadRdataLength {
+        qtype: QueryType,
+        expected: usize,
+        actual: usize,
+    },
+    /// RDATA whose fields do not make sense for its type
+    BadRdata {
+        qtype: QueryType,
+        reason: &'static str,
+    },
+    UnknownClass(u16),
+    /// A name in presentation format that could not be parsed
+    BadName(&'static str),
+    /// An OPT record that breaks the rules of RFC 6891
+    BadOpt(&'static str),
+    /// A TSIG record that breaks the rules of RFC 8945
+    BadTsig(&'static str),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DnsError::BufferOverrun { offset } => {
+                write!(f, "buffer overrun at offset {}", offset)
+            }
+            DnsError::PointerLoop { offset } => {
+                write!(f, "compression pointer loop in name at offset {}", offset)
+            }
+            DnsError::ForwardPointer { offset } => {
+                write!(
+                    f,
+                    "compression pointer at offset {} does not point backwards",
+                    offset
+                )
+            }
+            DnsError::LabelTooLong { len } => {
+                write!(f, "label of {} bytes exceeds 63 bytes", len)
+            }
+            DnsError::NameTooLong { len } => write!(f, "name of {} bytes exceeds 255 bytes", len),
+            DnsError::StringTooLong { len } => {
+                write!(f, "character string of {} bytes exceeds 255 bytes", len)
+            }
+            DnsError::BadRdataLength {
+                qtype,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} record data is {} bytes, but its length says {}",
+                qtype, actual, expected
+            ),
+            DnsError::BadRdata { qtype, reason } => {
+                write!(f, "invalid {} record data: {}", qtype, reason)
+            }
+            DnsError::UnknownClass(class) => write!(f, "unknown class {}", class),
+            DnsError::BadName(reason) => write!(f, "invalid name: {}", reason),
+            DnsError::BadOpt(reason) => write!(f, "invalid OPT record: {}", reason),
+            DnsError::BadTsig(reason) => write!(f, "invalid TSIG record: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+pub type Result<T> = std::result::Result<T, DnsError>;
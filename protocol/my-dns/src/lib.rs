@@ -0,0 +1,25 @@
+pub mod blocklist;
+pub mod cache;
+pub mod client;
+pub mod dnssec;
+pub mod doh;
+pub mod dot;
+pub mod encoding;
+pub mod error;
+pub mod mdns;
+pub mod name;
+pub mod packets;
+pub mod querylog;
+pub mod ratelimit;
+pub mod resolver;
+pub mod server;
+pub mod tcp;
+pub mod time;
+pub mod tsig;
+pub mod update;
+pub mod view;
+pub mod xfr;
+pub mod zone;
+
+#[cfg(test)]
+mod testing;
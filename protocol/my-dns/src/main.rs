@@ -1,4 +1,902 @@
-mod packets;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use my_dns::blocklist::{BlockPolicy, Blocklist};
+use my_dns::cache::DnsCache;
+use my_dns::client::DnsClient;
+use my_dns::dnssec::TrustAnchor;
+use my_dns::doh::{self, DohClient, DohUrl};
+use my_dns::dot::{self, DotClient, DOT_PORT};
+use my_dns::mdns::{Browser, Host, MdnsSocket, Responder, Service, MDNS_GROUP, MDNS_PORT};
+use my_dns::name::DnsName;
+use my_dns::packets::QueryType;
+use my_dns::querylog::{LogFormat, QueryLog};
+use my_dns::ratelimit::{RateLimiter, RateLimits};
+use my_dns::resolver::RecursiveResolver;
+use my_dns::server::{DnsServer, Upstream, DEFAULT_CACHE_SIZE};
+use my_dns::tsig::TsigKey;
+use my_dns::view::{Subnet, View};
+use my_dns::xfr::Secondary;
+use my_dns::zone::Zone;
+
+const DEFAULT_SERVER: &str = "8.8.8.8:53";
+const DEFAULT_LISTEN: &str = "127.0.0.1:53";
+/// How often blocklist files are checked for changes
+const BLOCKLIST_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+struct QueryConfig {
+    server: SocketAddr,
+    /// Ask this DoH endpoint instead of `server`
+    doh: Option<DohUrl>,
+    /// Send DoH queries as GET requests
+    https_get: bool,
+    /// Ask this DoT server, `host` and port, instead of `server`
+    tls: Option<(String, u16)>,
+    qname: DnsName,
+    qtype: QueryType,
+    client: DnsClient,
+    iterate: bool,
+}
+
+impl QueryConfig {
+    /// Parse `dig`-like arguments:
+    /// `my-dns [@server[:port] | @https://host[:port]/path | @tls://host[:port]] name [type]
+    /// [+timeout=secs] [+retries=n] [+norec] [+tcp] [+bufsize=bytes] [+noedns] [+iterate] [+dnssec] [+cd]
+    /// [+https-get]`
+    ///
+    /// With `+iterate` the name is resolved from the root servers instead of
+    /// asking `server`. `+dnssec` asks for signatures with the DO bit, and
+    /// together with `+iterate` validates them against the root key.
+    fn build(args: impl Iterator<Item = String>) -> Result<QueryConfig, String> {
+        let mut server = DEFAULT_SERVER.parse().unwrap();
+        let mut doh = None;
+        let mut https_get = false;
+        let mut tls = None;
+        let mut qname = None;
+        let mut qtype = None;
+        let mut client = DnsClient::new();
+        let mut iterate = false;
+
+        for arg in args {
+            if let Some(url) = arg.strip_prefix("@https://") {
+                doh = Some(format!("https://{}", url).parse()?);
+            } else if let Some(host) = arg.strip_prefix("@tls://") {
+                tls = Some(parse_host(host, DOT_PORT)?);
+            } else if let Some(addr) = arg.strip_prefix('@') {
+                server = parse_server(addr)?;
+            } else if let Some(secs) = arg.strip_prefix("+timeout=") {
+                let secs: u64 = secs.parse().map_err(|_| "Invalid timeout")?;
+                client.timeout = Duration::from_secs(secs);
+            } else if let Some(retries) = arg.strip_prefix("+retries=") {
+                client.retries = retries.parse().map_err(|_| "Invalid retry count")?;
+            } else if arg == "+norec" {
+                client.recursion_desired = false;
+            } else if arg == "+tcp" {
+                client.use_tcp = true;
+            } else if let Some(size) = arg.strip_prefix("+bufsize=") {
+                let size = size.parse().map_err(|_| "Invalid buffer size")?;
+                client.edns_payload_size = Some(size);
+            } else if arg == "+noedns" {
+                client.edns_payload_size = None;
+            } else if arg == "+iterate" {
+                iterate = true;
+            } else if arg == "+dnssec" {
+                client.dnssec_ok = true;
+            } else if arg == "+cd" {
+                client.checking_disabled = true;
+            } else if arg == "+https-get" {
+                https_get = true;
+            } else if qname.is_none() {
+                let name = arg
+                    .parse::<DnsName>()
+                    .map_err(|e| format!("Invalid name {}: {}", arg, e))?;
+                qname = Some(name);
+            } else if qtype.is_none() {
+                qtype = Some(arg.parse::<QueryType>()?);
+            } else {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+        }
+
+        Ok(QueryConfig {
+            server,
+            doh,
+            https_get,
+            tls,
+            qname: qname.ok_or("Didn't get a name to look up")?,
+            qtype: qtype.unwrap_or(QueryType::A),
+            client,
+            iterate,
+        })
+    }
+}
+
+fn parse_client(addr: &str) -> Result<IpAddr, String> {
+    addr.parse()
+        .map_err(|_| format!("Invalid client address: {}", addr))
+}
+
+fn parse_key_name(name: &str) -> Result<DnsName, String> {
+    name.parse()
+        .map_err(|_| format!("Invalid key name: {}", name))
+}
+
+/// Accept a bare address, defaulting to port 53, or an `addr:port` pair
+fn parse_server(addr: &str) -> Result<SocketAddr, String> {
+    parse_server_port(addr, 53)
+}
+
+fn parse_server_port(addr: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    addr.parse()
+        .map_err(|_| format!("Invalid server address: {}", addr))
+}
+
+/// Split `host[:port]` where the host may be a name, keeping IPv6 literals
+/// in brackets whole
+fn parse_host(host: &str, default_port: u16) -> Result<(String, u16), String> {
+    let (host, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("Invalid port in {}", host))?;
+            (name, port)
+        }
+        _ => (host, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("Didn't get a host".into());
+    }
+    Ok((host.to_string(), port))
+}
+
+/// Parse `addr[:port][#name]`, an upstream DoT server and the name its
+/// certificate is issued for, which defaults to the address itself
+fn parse_tls_upstream(arg: &str) -> Result<(SocketAddr, String), String> {
+    let (addr, name) = match arg.split_once('#') {
+        Some((addr, name)) => (addr, Some(name.to_string())),
+        None => (arg, None),
+    };
+    let addr = parse_server_port(addr, DOT_PORT)?;
+    Ok((addr, name.unwrap_or_else(|| addr.ip().to_string())))
+}
+
+/// A split-horizon view, with its own zones and upstreams
+struct ViewConfig {
+    name: String,
+    subnets: Vec<Subnet>,
+    zones: Vec<Zone>,
+    /// Resolve names ourselves if empty
+    upstreams: Vec<SocketAddr>,
+}
+
+impl FromStr for ViewConfig {
+    type Err = String;
+
+    /// `name=subnet[,subnet]...`
+    fn from_str(s: &str) -> Result<ViewConfig, String> {
+        let (name, subnets) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid view: {}", s))?;
+        Ok(ViewConfig {
+            name: name.to_string(),
+            subnets: subnets
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            zones: Vec::new(),
+            upstreams: Vec::new(),
+        })
+    }
+}
+
+struct ServeConfig {
+    listen: SocketAddr,
+    upstreams: Vec<SocketAddr>,
+    /// Upstreams asked over DNS over TLS, and the names on their certificates
+    tls_upstreams: Vec<(SocketAddr, String)>,
+    /// Certificate authorities trusted for `tls_upstreams` instead of the
+    /// public ones
+    tls_ca: Option<PathBuf>,
+    recursive: bool,
+    cache_size: usize,
+    zones: Vec<Zone>,
+    /// Clients allowed to send dynamic updates for the zones
+    update_clients: Vec<IpAddr>,
+    /// Secondaries allowed to transfer the zones
+    transfer_clients: Vec<IpAddr>,
+    tsig_keys: Vec<TsigKey>,
+    /// Keys that allow updates and transfers from anywhere
+    update_keys: Vec<DnsName>,
+    transfer_keys: Vec<DnsName>,
+    notify_targets: Vec<SocketAddr>,
+    /// Zones copied from other servers
+    secondaries: Vec<Secondary>,
+    trust_anchor: Option<TrustAnchor>,
+    /// Where to serve DNS over HTTPS, and the TLS certificate and key
+    doh: Option<(SocketAddr, PathBuf, PathBuf)>,
+    /// Where to serve DNS over TLS, and the certificate and key
+    dot: Option<(SocketAddr, PathBuf, PathBuf)>,
+    blocklists: Vec<Blocklist>,
+    query_log: Option<QueryLog>,
+    rate_limits: Option<RateLimits>,
+    views: Vec<ViewConfig>,
+    /// Clients whose EDNS Client Subnet picks their view
+    client_subnet_sources: Vec<Subnet>,
+}
+
+impl ServeConfig {
+    /// Parse `serve [--listen addr:port] [--upstream addr[:port]]...
+    /// [--upstream-tls addr[:port][#name]]... [--tls-ca file] [--recursive]
+    /// [--cache-size n] [--zone file]... [--tsig-key [hmac-sha256:]name:secret]...
+    /// [--allow-update addr | key=name]... [--allow-transfer addr | key=name]...
+    /// [--notify addr[:port]]... [--secondary zone=addr[:port][,file][,key=name]]... [--dnssec]
+    /// [--trust-anchor file] [--doh addr:port] [--dot addr[:port]]
+    /// [--tls-cert file --tls-key file]
+    /// [[--blocklist-policy policy] --blocklist file]...
+    /// [--query-log file | unix:path] [--query-log-format json | dnstap]
+    /// [--rate-limit limit=n[,limit=n]...]
+    /// [--view name=subnet[,subnet]... [--zone file]... [--upstream addr[:port]]...]...
+    /// [--client-subnet-from subnet]...`
+    ///
+    /// Without any upstream the server resolves names itself, same as with
+    /// `--recursive`. Upstreams given with `--upstream-tls` are asked over DNS
+    /// over TLS, port 853 by default, checking their certificates against
+    /// the name after `#` or their address, and the CAs in `--tls-ca` if
+    /// given. Zones are loaded from master files and answered
+    /// authoritatively; the clients given with `--allow-update` may change
+    /// them with dynamic updates, which are saved back to the files. The
+    /// clients given with `--allow-transfer` may copy the zones with AXFR or
+    /// IXFR, and the servers given with `--notify` are told when they change.
+    /// A `--secondary` zone is copied from the primary server given, and kept
+    /// in the file if there is one. Updates and transfers signed with TSIG
+    /// are allowed from anywhere for the keys given as `key=name`, each one
+    /// defined by a `--tsig-key` with its secret in base64; a secondary
+    /// with a `key` signs its transfers with it. When
+    /// resolving, `--dnssec` validates responses against the root key,
+    /// `--trust-anchor` against the DS or DNSKEY records in a file. `--doh`
+    /// also serves DNS over HTTPS on `/dns-query` and `--dot` DNS over TLS,
+    /// port 853 by default, both with the PEM certificate chain and key
+    /// given.
+    /// Blocklists are hosts files or domain lists, reloaded when they change;
+    /// names on them get NXDOMAIN unless a `--blocklist-policy` of `refused`,
+    /// `sinkhole` or `sinkhole=addr` comes before the list. `--query-log`
+    /// appends every query and response to a file, as JSON lines unless
+    /// dnstap is asked for, or streams them to a dnstap collector's socket.
+    /// `--rate-limit` caps the `responses`, `nxdomains` and `errors` sent
+    /// over UDP and the `queries` taken from each client network per second,
+    /// sending every `slip`th limited response truncated; the networks are
+    /// grouped by `ipv4-prefix` and `ipv6-prefix`.
+    /// A `--view` answers the clients in its subnets from its own zones,
+    /// upstreams and cache: the `--zone` and `--upstream` options after it
+    /// are the view's, and it resolves names itself without upstreams.
+    /// Clients no view matches are answered as usual. The EDNS Client
+    /// Subnet of queries from `--client-subnet-from` picks their view.
+    fn build(mut args: impl Iterator<Item = String>) -> Result<ServeConfig, String> {
+        let mut listen = DEFAULT_LISTEN.parse().unwrap();
+        let mut upstreams = Vec::new();
+        let mut tls_upstreams = Vec::new();
+        let mut tls_ca = None;
+        let mut recursive = false;
+        let mut cache_size = DEFAULT_CACHE_SIZE;
+        let mut zones = Vec::new();
+        let mut update_clients = Vec::new();
+        let mut transfer_clients = Vec::new();
+        let mut tsig_keys: Vec<TsigKey> = Vec::new();
+        let mut update_keys = Vec::new();
+        let mut transfer_keys = Vec::new();
+        let mut notify_targets = Vec::new();
+        let mut secondaries = Vec::new();
+        let mut trust_anchor = None;
+        let mut doh_listen = None;
+        let mut dot_listen = None;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut blocklists = Vec::new();
+        let mut block_policy = BlockPolicy::default();
+        let mut query_log = None;
+        let mut log_format = LogFormat::Json;
+        let mut rate_limits = None;
+        let mut views: Vec<ViewConfig> = Vec::new();
+        let mut client_subnet_sources = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => {
+                    let addr = args.next().ok_or("Didn't get a listen address")?;
+                    listen = parse_server(&addr)?;
+                }
+                "--upstream" => {
+                    let addr = args.next().ok_or("Didn't get an upstream address")?;
+                    match views.last_mut() {
+                        Some(view) => view.upstreams.push(parse_server(&addr)?),
+                        None => upstreams.push(parse_server(&addr)?),
+                    }
+                }
+                "--upstream-tls" => {
+                    let arg = args.next().ok_or("Didn't get an upstream address")?;
+                    tls_upstreams.push(parse_tls_upstream(&arg)?);
+                }
+                "--tls-ca" => {
+                    tls_ca = Some(PathBuf::from(args.next().ok_or("Didn't get a CA file")?));
+                }
+                "--recursive" => recursive = true,
+                "--cache-size" => {
+                    let size = args.next().ok_or("Didn't get a cache size")?;
+                    cache_size = size.parse().map_err(|_| "Invalid cache size")?;
+                }
+                "--zone" => {
+                    let path = args.next().ok_or("Didn't get a zone file")?;
+                    let zone = Zone::load(&path)
+                        .map_err(|e| format!("Failed to load zone {}: {}", path, e))?;
+                    match views.last_mut() {
+                        Some(view) => view.zones.push(zone),
+                        None => zones.push(zone),
+                    }
+                }
+                "--allow-update" => {
+                    let client = args.next().ok_or("Didn't get a client address or key")?;
+                    match client.strip_prefix("key=") {
+                        Some(key) => update_keys.push(parse_key_name(key)?),
+                        None => update_clients.push(parse_client(&client)?),
+                    }
+                }
+                "--allow-transfer" => {
+                    let client = args.next().ok_or("Didn't get a client address or key")?;
+                    match client.strip_prefix("key=") {
+                        Some(key) => transfer_keys.push(parse_key_name(key)?),
+                        None => transfer_clients.push(parse_client(&client)?),
+                    }
+                }
+                "--tsig-key" => {
+                    let key: TsigKey = args.next().ok_or("Didn't get a TSIG key")?.parse()?;
+                    tsig_keys.push(key);
+                }
+                "--notify" => {
+                    let addr = args.next().ok_or("Didn't get a server to notify")?;
+                    notify_targets.push(parse_server(&addr)?);
+                }
+                "--secondary" => {
+                    let secondary: Secondary =
+                        args.next().ok_or("Didn't get a secondary zone")?.parse()?;
+                    // start from the copy kept last time, if there is one
+                    if let Some(ref path) = secondary.path {
+                        if path.exists() {
+                            let zone = Zone::load(path).map_err(|e| {
+                                format!("Failed to load zone {}: {}", path.display(), e)
+                            })?;
+                            zones.push(zone);
+                        }
+                    }
+                    secondaries.push(secondary);
+                }
+                "--dnssec" => trust_anchor = Some(TrustAnchor::root()),
+                "--trust-anchor" => {
+                    let path = args.next().ok_or("Didn't get a trust anchor file")?;
+                    let anchor = TrustAnchor::load(&path)
+                        .map_err(|e| format!("Failed to load trust anchor {}: {}", path, e))?;
+                    trust_anchor = Some(anchor);
+                }
+                "--doh" => {
+                    let addr = args.next().ok_or("Didn't get a DoH listen address")?;
+                    doh_listen = Some(addr.parse().map_err(|_| "Invalid DoH listen address")?);
+                }
+                "--dot" => {
+                    let addr = args.next().ok_or("Didn't get a DoT listen address")?;
+                    dot_listen = Some(parse_server_port(&addr, DOT_PORT)?);
+                }
+                "--tls-cert" => {
+                    tls_cert = Some(PathBuf::from(
+                        args.next().ok_or("Didn't get a certificate file")?,
+                    ));
+                }
+                "--tls-key" => {
+                    tls_key = Some(PathBuf::from(args.next().ok_or("Didn't get a key file")?));
+                }
+                "--blocklist-policy" => {
+                    let policy = args.next().ok_or("Didn't get a blocklist policy")?;
+                    block_policy = policy.parse()?;
+                }
+                "--blocklist" => {
+                    let path = args.next().ok_or("Didn't get a blocklist file")?;
+                    let list = Blocklist::load(&path, block_policy)
+                        .map_err(|e| format!("Failed to load blocklist {}: {}", path, e))?;
+                    blocklists.push(list);
+                }
+                "--query-log" => {
+                    query_log = Some(args.next().ok_or("Didn't get a query log target")?);
+                }
+                "--query-log-format" => {
+                    let format = args.next().ok_or("Didn't get a query log format")?;
+                    log_format = format.parse()?;
+                }
+                "--rate-limit" => {
+                    let limits = args.next().ok_or("Didn't get rate limits")?;
+                    rate_limits = Some(limits.parse()?);
+                }
+                "--view" => {
+                    views.push(args.next().ok_or("Didn't get a view")?.parse()?);
+                }
+                "--client-subnet-from" => {
+                    let subnet = args.next().ok_or("Didn't get a subnet")?;
+                    client_subnet_sources.push(subnet.parse()?);
+                }
+                _ => return Err(format!("Unexpected argument: {}", arg)),
+            }
+        }
+
+        let doh = match (doh_listen, &tls_cert, &tls_key) {
+            (Some(addr), Some(cert), Some(key)) => Some((addr, cert.clone(), key.clone())),
+            (Some(_), _, _) => return Err("--doh needs --tls-cert and --tls-key".into()),
+            (None, _, _) => None,
+        };
+        let dot = match (dot_listen, tls_cert, tls_key) {
+            (Some(addr), Some(cert), Some(key)) => Some((addr, cert, key)),
+            (Some(_), _, _) => return Err("--dot needs --tls-cert and --tls-key".into()),
+            (None, _, _) => None,
+        };
+        if !upstreams.is_empty() && !tls_upstreams.is_empty() {
+            return Err("--upstream and --upstream-tls can't be mixed".into());
+        }
+        let secondary_keys = secondaries
+            .iter()
+            .filter_map(|s: &Secondary| s.key.as_ref());
+        for name in update_keys
+            .iter()
+            .chain(&transfer_keys)
+            .chain(secondary_keys)
+        {
+            if !tsig_keys.iter().any(|key| key.name == *name) {
+                return Err(format!("No --tsig-key named {}", name));
+            }
+        }
+
+        let query_log = match query_log {
+            Some(target) => Some(
+                QueryLog::open(log_format, &target)
+                    .map_err(|e| format!("Failed to open query log {}: {}", target, e))?,
+            ),
+            None => None,
+        };
+
+        Ok(ServeConfig {
+            listen,
+            recursive: recursive || (upstreams.is_empty() && tls_upstreams.is_empty()),
+            upstreams,
+            tls_upstreams,
+            tls_ca,
+            cache_size,
+            zones,
+            update_clients,
+            transfer_clients,
+            tsig_keys,
+            update_keys,
+            transfer_keys,
+            notify_targets,
+            secondaries,
+            trust_anchor,
+            doh,
+            dot,
+            blocklists,
+            query_log,
+            rate_limits,
+            views,
+            client_subnet_sources,
+        })
+    }
+}
+
+struct MdnsConfig {
+    /// Address of the interface to join the group on
+    interface: Ipv4Addr,
+    port: u16,
+    responder: Responder,
+}
+
+impl MdnsConfig {
+    /// Parse `mdns [--interface addr] [--port n] --host name[=addr[,addr]...]...
+    /// [--service instance=_type._proto:port[,key=value]...]...`
+    ///
+    /// Host names get `.local` appended if they lack it; without addresses
+    /// a host goes by the address of the interface. Services are provided by
+    /// the first host.
+    fn build(mut args: impl Iterator<Item = String>) -> Result<MdnsConfig, String> {
+        let mut interface = Ipv4Addr::UNSPECIFIED;
+        let mut port = MDNS_PORT;
+        let mut hosts: Vec<(DnsName, Vec<IpAddr>)> = Vec::new();
+        let mut services: Vec<Service> = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--interface" => {
+                    let addr = args.next().ok_or("Didn't get an interface address")?;
+                    interface = addr
+                        .parse()
+                        .map_err(|_| format!("Invalid address: {}", addr))?;
+                }
+                "--port" => {
+                    let n = args.next().ok_or("Didn't get a port")?;
+                    port = n.parse().map_err(|_| format!("Invalid port: {}", n))?;
+                }
+                "--host" => {
+                    let host = args.next().ok_or("Didn't get a host name")?;
+                    let (name, addrs) = host.split_once('=').unwrap_or((&host, ""));
+                    let name = local_name(name)?;
+                    let addrs = addrs
+                        .split(',')
+                        .filter(|addr| !addr.is_empty())
+                        .map(|addr| {
+                            addr.parse()
+                                .map_err(|_| format!("Invalid address: {}", addr))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    hosts.push((name, addrs));
+                }
+                "--service" => {
+                    let service = args.next().ok_or("Didn't get a service")?;
+                    services.push(service.parse()?);
+                }
+                _ => return Err(format!("Unexpected argument: {}", arg)),
+            }
+        }
+
+        let first = hosts.first().ok_or("Didn't get a host name")?.0.clone();
+        for service in &mut services {
+            service.host = first.clone();
+        }
+        let mut responder = Responder {
+            services,
+            ..Responder::default()
+        };
+        for (name, mut addrs) in hosts {
+            if addrs.is_empty() {
+                addrs.push(interface_addr(interface)?);
+            }
+            responder.hosts.push(Host { name, addrs });
+        }
+
+        Ok(MdnsConfig {
+            interface,
+            port,
+            responder,
+        })
+    }
+}
+
+/// `name`, in the `.local` domain
+fn local_name(name: &str) -> Result<DnsName, String> {
+    let name: DnsName = name
+        .parse()
+        .map_err(|e| format!("Invalid name {}: {}", name, e))?;
+    let local: DnsName = "local".parse().unwrap();
+    if name.is_subdomain_of(&local) {
+        Ok(name)
+    } else {
+        name.concat(&local).map_err(|e| e.to_string())
+    }
+}
+
+/// The address of `interface`, or of the one multicast goes out of if
+/// unspecified
+fn interface_addr(interface: Ipv4Addr) -> Result<IpAddr, String> {
+    if !interface.is_unspecified() {
+        return Ok(interface.into());
+    }
+    // connecting a UDP socket sends nothing, but picks the source address
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((MDNS_GROUP, MDNS_PORT))?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .map_err(|e| format!("Could not find the interface address: {}", e))
+}
+
+struct BrowseConfig {
+    service_type: DnsName,
+    browser: Browser,
+}
+
+impl BrowseConfig {
+    /// Parse `browse _type._proto [+timeout=secs] [+port=n]`
+    fn build(args: impl Iterator<Item = String>) -> Result<BrowseConfig, String> {
+        let mut service_type = None;
+        let mut browser = Browser::default();
+
+        for arg in args {
+            if let Some(secs) = arg.strip_prefix("+timeout=") {
+                let secs: u64 = secs.parse().map_err(|_| "Invalid timeout")?;
+                browser.timeout = Duration::from_secs(secs);
+            } else if let Some(port) = arg.strip_prefix("+port=") {
+                browser.port = port.parse().map_err(|_| "Invalid port")?;
+            } else if service_type.is_none() {
+                service_type = Some(local_name(&arg)?);
+            } else {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+        }
+
+        Ok(BrowseConfig {
+            service_type: service_type.ok_or("Didn't get a service type")?,
+            browser,
+        })
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("serve") => {
+            args.next();
+            let config = ServeConfig::build(args).unwrap_or_else(|err| {
+                eprintln!("Problem parsing arguments: {err}");
+                process::exit(1);
+            });
+            serve(config);
+        }
+        Some("mdns") => {
+            args.next();
+            let config = MdnsConfig::build(args).unwrap_or_else(|err| {
+                eprintln!("Problem parsing arguments: {err}");
+                process::exit(1);
+            });
+            mdns(config);
+        }
+        Some("browse") => {
+            args.next();
+            let config = BrowseConfig::build(args).unwrap_or_else(|err| {
+                eprintln!("Problem parsing arguments: {err}");
+                process::exit(1);
+            });
+            browse(config);
+            return;
+        }
+        _ => {}
+    }
+
+    let config = QueryConfig::build(args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {err}");
+        process::exit(1);
+    });
+    query(config);
+}
+
+fn serve(config: ServeConfig) -> ! {
+    let mut views = Vec::new();
+    for view_config in config.views {
+        let upstream = if view_config.upstreams.is_empty() {
+            Upstream::Recursive(RecursiveResolver {
+                trust_anchor: config.trust_anchor.clone(),
+                ..RecursiveResolver::default()
+            })
+        } else {
+            Upstream::Forward(view_config.upstreams)
+        };
+        let mut view = View::new(&view_config.name, view_config.subnets, upstream);
+        view.zones = RwLock::new(view_config.zones);
+        view.cache = Mutex::new(DnsCache::new(config.cache_size));
+        views.push(view);
+    }
+
+    let upstream = if config.recursive {
+        Upstream::Recursive(RecursiveResolver {
+            trust_anchor: config.trust_anchor,
+            ..RecursiveResolver::default()
+        })
+    } else if !config.tls_upstreams.is_empty() {
+        let tls = match config.tls_ca {
+            Some(ref path) => dot::load_client_config(path),
+            None => dot::client_config(Vec::new()),
+        };
+        let clients = tls.and_then(|tls| {
+            config
+                .tls_upstreams
+                .iter()
+                .map(|(addr, name)| DotClient::with_tls_config(*addr, name, Arc::clone(&tls)))
+                .collect()
+        });
+        Upstream::ForwardTls(clients.unwrap_or_else(|e| {
+            eprintln!("Server error: {e}");
+            process::exit(1);
+        }))
+    } else {
+        Upstream::Forward(config.upstreams)
+    };
+    let mut server = DnsServer::new(upstream);
+    server.cache = Mutex::new(DnsCache::new(config.cache_size));
+    server.zones = RwLock::new(config.zones);
+    server.update_clients = config.update_clients;
+    server.transfer_clients = config.transfer_clients;
+    server.tsig_keys = config.tsig_keys;
+    server.update_keys = config.update_keys;
+    server.transfer_keys = config.transfer_keys;
+    server.notify_targets = config.notify_targets;
+    server.secondaries = config.secondaries;
+    server.blocklists = config.blocklists;
+    server.query_log = config.query_log;
+    server.rate_limiter = config.rate_limits.map(RateLimiter::new);
+    server.views = views;
+    server.client_subnet_sources = config.client_subnet_sources;
+    let server = Arc::new(server);
+
+    if !server.blocklists.is_empty() {
+        for list in &server.blocklists {
+            println!("blocking {} names from {}", list.len(), list.path.display());
+        }
+        let watcher = Arc::clone(&server);
+        thread::spawn(move || watcher.watch_blocklists(BLOCKLIST_RELOAD_INTERVAL));
+    }
+
+    if !server.secondaries.is_empty() {
+        let watcher = Arc::clone(&server);
+        thread::spawn(move || watcher.watch_secondaries());
+    }
+
+    if let Some((addr, cert, key)) = config.doh {
+        let result = doh::load_server_config(&cert, &key)
+            .and_then(|tls| Ok((tls, TcpListener::bind(addr)?)));
+        let (tls, listener) = result.unwrap_or_else(|e| {
+            eprintln!("Server error: {e}");
+            process::exit(1);
+        });
+        println!("serving DNS over HTTPS on https://{}/dns-query", addr);
+        let doh_server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = doh_server.serve_https(listener, tls) {
+                eprintln!("Server error: {e}");
+                process::exit(1);
+            }
+        });
+    }
+
+    if let Some((addr, cert, key)) = config.dot {
+        let result = dot::load_server_config(&cert, &key)
+            .and_then(|tls| Ok((tls, TcpListener::bind(addr)?)));
+        let (tls, listener) = result.unwrap_or_else(|e| {
+            eprintln!("Server error: {e}");
+            process::exit(1);
+        });
+        println!("serving DNS over TLS on {}", addr);
+        let dot_server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = dot_server.serve_tls(listener, tls) {
+                eprintln!("Server error: {e}");
+                process::exit(1);
+            }
+        });
+    }
+
+    let result = UdpSocket::bind(config.listen)
+        .and_then(|socket| Ok((socket, TcpListener::bind(config.listen)?)))
+        .map_err(|e| e.into())
+        .and_then(|(socket, listener)| {
+            println!("listening on {} (udp and tcp)", config.listen);
+            let tcp_server = Arc::clone(&server);
+            thread::spawn(move || {
+                if let Err(e) = tcp_server.serve_tcp(listener) {
+                    eprintln!("Server error: {e}");
+                    process::exit(1);
+                }
+            });
+            server.serve_udp(socket)
+        });
+    if let Err(e) = result {
+        eprintln!("Server error: {e}");
+    }
+    process::exit(1);
+}
+
+fn mdns(config: MdnsConfig) -> ! {
+    let socket = MdnsSocket::bind(config.interface, config.port).unwrap_or_else(|e| {
+        eprintln!("Server error: {e}");
+        process::exit(1);
+    });
+    for host in &config.responder.hosts {
+        for addr in &host.addrs {
+            println!("announcing {} at {}", host.name, addr);
+        }
+    }
+    for service in &config.responder.services {
+        println!(
+            "announcing {} ({}) on port {}",
+            service.instance, service.service_type, service.port
+        );
+    }
+
+    let socket = Arc::new(socket);
+    let responder = Arc::new(config.responder);
+    let (announce_socket, announcer) = (Arc::clone(&socket), Arc::clone(&responder));
+    thread::spawn(move || {
+        if let Err(e) = announcer.announce(&announce_socket) {
+            eprintln!("Failed to announce: {e}");
+        }
+    });
+    if let Err(e) = responder.serve(&socket) {
+        eprintln!("Server error: {e}");
+    }
+    process::exit(1);
+}
+
+fn browse(config: BrowseConfig) {
+    match config.browser.browse(&config.service_type) {
+        Ok(instances) => {
+            for instance in instances {
+                let addrs: Vec<_> = instance.addrs.iter().map(IpAddr::to_string).collect();
+                println!(
+                    "{}\t{}:{}\t{}\t{}",
+                    instance.name,
+                    instance.host,
+                    instance.port,
+                    addrs.join(","),
+                    instance.txt.join(" ")
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!(";; {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn query(config: QueryConfig) {
+    let result = if config.iterate {
+        let mut resolver = RecursiveResolver::default();
+        resolver.client.timeout = config.client.timeout;
+        resolver.client.retries = config.client.retries;
+        resolver.client.edns_payload_size = config.client.edns_payload_size;
+        if config.client.dnssec_ok {
+            resolver.trust_anchor = Some(TrustAnchor::root());
+        }
+        resolver.resolve_with_cd(&config.qname, config.qtype, config.client.checking_disabled)
+    } else if let Some((ref host, port)) = config.tls {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| e.into())
+            .and_then(|mut addrs| {
+                addrs
+                    .next()
+                    .ok_or_else(|| format!("could not resolve {}", host).into())
+            })
+            .and_then(|addr| DotClient::new(addr, host))
+            .and_then(|mut dot| {
+                dot.client = config.client;
+                dot.send_query(&config.qname, config.qtype)
+            })
+    } else if let Some(ref url) = config.doh {
+        DohClient::new(url.clone()).and_then(|mut doh| {
+            doh.client = config.client;
+            doh.use_get = config.https_get;
+            doh.send_query(&config.qname, config.qtype)
+        })
+    } else {
+        config
+            .client
+            .send_query(&config.qname, config.qtype, config.server)
+    };
+
+    match result {
+        Ok(response) => {
+            print!("{}", response);
+            match (&config.doh, &config.tls) {
+                (Some(url), _) => println!("\n;; SERVER: {}", url),
+                (None, Some((host, port))) => println!("\n;; SERVER: tls://{}:{}", host, port),
+                (None, None) if !config.iterate => println!("\n;; SERVER: {}", config.server),
+                (None, None) => {}
+            }
+        }
+        Err(e) => {
+            eprintln!(";; {e}");
+            process::exit(1);
+        }
+    }
 }
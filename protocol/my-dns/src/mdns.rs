@@ -0,0 +1,804 @@
+//! Multicast DNS (RFC 6762) and DNS-based service discovery (RFC 6763):
+//! a responder announcing `.local` host names and services, and a browser
+//! finding the services others announce.
+//!
+//! mDNS messages are regular DNS messages, except that the top bit of the
+//! class field is taken: in questions it asks for a unicast response, in
+//! records it tells caches to flush what they hold for the name and type.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::random_id;
+use crate::name::DnsName;
+use crate::packets::{
+    DnsPacket, DnsQuestion, DnsRecord, PacketBuffer, QueryType, VectorPacketBuffer, CLASS_IN,
+    TYPE_ANY,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+
+/// Largest message, as jumbo frames allow (RFC 6762 section 17)
+pub const MAX_MDNS_SIZE: usize = 9000;
+
+/// The top bit of the class field
+const CLASS_FLAG: u16 = 0x8000;
+
+/// TTLs of records naming hosts, and of everything else (RFC 6762 section 10)
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+/// Largest TTL in responses to legacy unicast queries (RFC 6762 section 6.7)
+const LEGACY_TTL: u32 = 10;
+
+/// Announcements are sent this many times, a second apart
+const ANNOUNCEMENTS: usize = 2;
+
+/// The name listing every service type on the network (RFC 6763 section 9)
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+
+/// A DNS message along with the class flags a plain parse would choke on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MdnsMessage {
+    pub packet: DnsPacket,
+    /// For each question, whether it asks for a unicast response (QU)
+    pub unicast: Vec<bool>,
+    /// For each record of the answer, authority and additional sections in
+    /// turn, whether it replaces what caches hold for its name and type
+    pub cache_flush: Vec<bool>,
+}
+
+impl MdnsMessage {
+    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<MdnsMessage> {
+        let (questions, records) = class_offsets(buffer)?;
+        let unicast = take_flags(buffer, &questions)?;
+        let cache_flush = take_flags(buffer, &records)?;
+        buffer.seek(0);
+
+        Ok(MdnsMessage {
+            packet: DnsPacket::from_buffer(buffer)?,
+            unicast,
+            cache_flush,
+        })
+    }
+}
+
+/// Write `packet`, setting the cache flush bit on the records that are
+/// unique to us: everything but the shared PTR records
+pub fn write_message(packet: &mut DnsPacket, cache_flush: bool) -> Result<VectorPacketBuffer> {
+    let mut buffer = VectorPacketBuffer::with_limit(MAX_MDNS_SIZE);
+    packet.write(&mut buffer)?;
+    if !cache_flush {
+        return Ok(buffer);
+    }
+
+    let end = buffer.pos();
+    let (_, offsets) = class_offsets(&mut buffer)?;
+    let records = packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .chain(&packet.resources);
+    for (rec, offset) in records.zip(offsets) {
+        if rec.query_type() != QueryType::PTR {
+            buffer.set_u16(offset, CLASS_IN | CLASS_FLAG)?;
+        }
+    }
+    buffer.seek(end);
+    Ok(buffer)
+}
+
+/// Offsets of the class fields of the questions, and of the records but the
+/// OPT one, in the message in `buffer`
+fn class_offsets<T: PacketBuffer>(buffer: &mut T) -> Result<(Vec<usize>, Vec<usize>)> {
+    buffer.seek(4);
+    let questions = buffer.read_u16()?;
+    let mut records = 0;
+    for _ in 0..3 {
+        records += buffer.read_u16()? as usize;
+    }
+
+    let mut question_offsets = Vec::new();
+    for _ in 0..questions {
+        buffer.read_query_name()?;
+        buffer.step(2);
+        question_offsets.push(buffer.pos());
+        buffer.step(2);
+    }
+
+    let mut record_offsets = Vec::new();
+    for _ in 0..records {
+        buffer.read_query_name()?;
+        let rtype = QueryType::from_num(buffer.read_u16()?);
+        if rtype != QueryType::OPT {
+            record_offsets.push(buffer.pos());
+        }
+        buffer.step(6);
+        let len = buffer.read_u16()? as usize;
+        buffer.step(len);
+    }
+
+    Ok((question_offsets, record_offsets))
+}
+
+/// Read and clear the top bit of the class fields at `offsets`
+fn take_flags<T: PacketBuffer>(buffer: &mut T, offsets: &[usize]) -> Result<Vec<bool>> {
+    let mut flags = Vec::new();
+    for &offset in offsets {
+        let class = match *buffer.get_range(offset, 2)? {
+            [high, low] => u16::from_be_bytes([high, low]),
+            _ => unreachable!(),
+        };
+        flags.push(class & CLASS_FLAG != 0);
+        buffer.set_u16(offset, class & !CLASS_FLAG)?;
+    }
+    Ok(flags)
+}
+
+/// A `.local` name of this host, and the addresses it goes by
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Host {
+    pub name: DnsName,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// A service announced with DNS-SD
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Service {
+    /// The human readable instance name, which may hold any character
+    pub instance: String,
+    /// Service type and domain, e.g. `_http._tcp.local`
+    pub service_type: DnsName,
+    /// The host providing the service
+    pub host: DnsName,
+    pub port: u16,
+    /// `key=value` attributes
+    pub txt: Vec<String>,
+}
+
+impl Service {
+    pub fn instance_name(&self) -> Result<DnsName> {
+        Ok(self.service_type.child(self.instance.as_bytes())?)
+    }
+}
+
+impl FromStr for Service {
+    type Err = String;
+
+    /// `instance=_type._proto:port[,key=value]...`, leaving the host empty
+    fn from_str(s: &str) -> std::result::Result<Service, String> {
+        let invalid = || format!("Invalid service: {}", s);
+        let (instance, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let mut attributes = rest.split(',');
+        let (service_type, port) = attributes
+            .next()
+            .and_then(|first| first.rsplit_once(':'))
+            .ok_or_else(invalid)?;
+        let mut service_type: DnsName = service_type.parse().map_err(|_| invalid())?;
+        if !service_type.is_subdomain_of(&local()) {
+            service_type = service_type.concat(&local()).map_err(|_| invalid())?;
+        }
+
+        let service = Service {
+            instance: instance.to_string(),
+            service_type,
+            host: DnsName::root(),
+            port: port.parse().map_err(|_| invalid())?,
+            txt: attributes.map(str::to_string).collect(),
+        };
+        // an instance name too long for a label could never be announced
+        service.instance_name().map_err(|_| invalid())?;
+        Ok(service)
+    }
+}
+
+fn local() -> DnsName {
+    "local".parse().unwrap()
+}
+
+/// A socket in the mDNS multicast group
+pub struct MdnsSocket {
+    pub socket: UdpSocket,
+    /// Where multicast messages go
+    pub group: SocketAddr,
+}
+
+impl MdnsSocket {
+    /// Join the group on the interface with address `interface`, or the
+    /// default one if unspecified. `port` is 5353 except in tests.
+    pub fn bind(interface: Ipv4Addr, port: u16) -> io::Result<MdnsSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.join_multicast_v4(&MDNS_GROUP, &interface)?;
+        // responders elsewhere on this host have to hear us too
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(255)?;
+        let port = socket.local_addr()?.port();
+
+        Ok(MdnsSocket {
+            socket,
+            group: SocketAddrV4::new(MDNS_GROUP, port).into(),
+        })
+    }
+
+    pub fn recv(&self) -> Result<(MdnsMessage, SocketAddr)> {
+        let mut received = vec![0; MAX_MDNS_SIZE];
+        let (len, src) = self.socket.recv_from(&mut received)?;
+        let mut buffer = VectorPacketBuffer::from_bytes(&received[..len]);
+        Ok((MdnsMessage::from_buffer(&mut buffer)?, src))
+    }
+
+    pub fn send(&self, packet: &mut DnsPacket, cache_flush: bool, dst: SocketAddr) -> Result<()> {
+        let buffer = write_message(packet, cache_flush)?;
+        self.socket.send_to(&buffer.buffer[..buffer.pos()], dst)?;
+        Ok(())
+    }
+}
+
+/// Answers for the hosts and services of this machine
+#[derive(Clone, Debug, Default)]
+pub struct Responder {
+    pub hosts: Vec<Host>,
+    pub services: Vec<Service>,
+}
+
+impl Responder {
+    /// Every record we answer for
+    pub fn records(&self) -> Result<Vec<DnsRecord>> {
+        let mut records = Vec::new();
+        for host in &self.hosts {
+            for addr in &host.addrs {
+                records.push(match *addr {
+                    IpAddr::V4(addr) => DnsRecord::A {
+                        domain: host.name.clone(),
+                        addr,
+                        ttl: HOST_TTL,
+                    },
+                    IpAddr::V6(addr) => DnsRecord::AAAA {
+                        domain: host.name.clone(),
+                        addr,
+                        ttl: HOST_TTL,
+                    },
+                });
+            }
+        }
+
+        let meta: DnsName = SERVICES_META.parse()?;
+        for service in &self.services {
+            let instance = service.instance_name()?;
+            let meta_ptr = DnsRecord::PTR {
+                domain: meta.clone(),
+                host: service.service_type.clone(),
+                ttl: OTHER_TTL,
+            };
+            if !records.contains(&meta_ptr) {
+                records.push(meta_ptr);
+            }
+            records.push(DnsRecord::PTR {
+                domain: service.service_type.clone(),
+                host: instance.clone(),
+                ttl: OTHER_TTL,
+            });
+            records.push(DnsRecord::SRV {
+                domain: instance.clone(),
+                priority: 0,
+                weight: 0,
+                port: service.port,
+                host: service.host.clone(),
+                ttl: HOST_TTL,
+            });
+            // an empty TXT record still holds one empty string
+            let mut data: Vec<Vec<u8>> =
+                service.txt.iter().map(|s| s.clone().into_bytes()).collect();
+            if data.is_empty() {
+                data.push(Vec::new());
+            }
+            records.push(DnsRecord::TXT {
+                domain: instance,
+                data,
+                ttl: OTHER_TTL,
+            });
+        }
+
+        Ok(records)
+    }
+
+    /// The unsolicited response announcing all our records
+    pub fn announcement(&self) -> Result<DnsPacket> {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.authoritative_answer = true;
+        packet.answers = self.records()?;
+        Ok(packet)
+    }
+
+    /// The response to `query`, if we have anything to say to it. Records
+    /// the querier listed as known with at least half their TTL left are
+    /// left out (RFC 6762 section 7.1).
+    pub fn respond(&self, query: &MdnsMessage) -> Result<Option<DnsPacket>> {
+        let request = &query.packet;
+        if request.header.response || request.header.opcode != 0 {
+            return Ok(None);
+        }
+
+        let records = self.records()?;
+        let known = |rec: &DnsRecord| {
+            request
+                .answers
+                .iter()
+                .any(|answer| rec.same_data(answer) && answer.ttl() >= rec.ttl() / 2)
+        };
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.header.authoritative_answer = true;
+        for question in &request.questions {
+            let matching = records.iter().filter(|rec| {
+                *rec.domain() == question.name
+                    && (question.qtype == rec.query_type() || question.qtype.to_num() == TYPE_ANY)
+            });
+            for rec in matching {
+                if !known(rec) && !response.answers.contains(rec) {
+                    response.answers.push(rec.clone());
+                }
+            }
+        }
+        if response.answers.is_empty() {
+            return Ok(None);
+        }
+
+        // save the querier a round trip for what it is going to ask next
+        // (RFC 6763 section 12)
+        let mut targets: Vec<DnsName> = Vec::new();
+        for rec in &response.answers {
+            match *rec {
+                DnsRecord::PTR { ref host, .. } => targets.push(host.clone()),
+                DnsRecord::SRV { ref host, .. } => targets.push(host.clone()),
+                _ => {}
+            }
+        }
+        for rec in &records {
+            if let DnsRecord::SRV {
+                ref domain,
+                ref host,
+                ..
+            } = *rec
+            {
+                if targets.contains(domain) {
+                    targets.push(host.clone());
+                }
+            }
+        }
+        for rec in &records {
+            let wanted = targets.contains(rec.domain()) && rec.query_type() != QueryType::PTR;
+            if wanted && !response.answers.contains(rec) && !response.resources.contains(rec) {
+                response.resources.push(rec.clone());
+            }
+        }
+
+        Ok(Some(response))
+    }
+
+    /// Announce our records to the group, a second apart, so that caches
+    /// pick up changes without asking
+    pub fn announce(&self, socket: &MdnsSocket) -> Result<()> {
+        for i in 0..ANNOUNCEMENTS {
+            if i > 0 {
+                thread::sleep(Duration::from_secs(1));
+            }
+            socket.send(&mut self.announcement()?, true, socket.group)?;
+        }
+        Ok(())
+    }
+
+    /// Answer queries on `socket` forever
+    pub fn serve(&self, socket: &MdnsSocket) -> Result<()> {
+        loop {
+            let (query, src) = match socket.recv() {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("failed to receive mDNS message: {}", e);
+                    continue;
+                }
+            };
+            let mut response = match self.respond(&query) {
+                Ok(Some(response)) => response,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("failed to answer mDNS query from {}: {}", src, e);
+                    continue;
+                }
+            };
+
+            let result = if src.port() != socket.group.port() {
+                // a plain resolver asking: answer it like a unicast DNS
+                // server would (RFC 6762 section 6.7)
+                response.header.id = query.packet.header.id;
+                response.questions = query.packet.questions.clone();
+                for rec in response.answers.iter_mut().chain(&mut response.resources) {
+                    rec.set_ttl(rec.ttl().min(LEGACY_TTL));
+                }
+                socket.send(&mut response, false, src)
+            } else if query.unicast.iter().all(|&unicast| unicast) {
+                socket.send(&mut response, true, src)
+            } else {
+                socket.send(&mut response, true, socket.group)
+            };
+            if let Err(e) = result {
+                eprintln!("failed to answer mDNS query from {}: {}", src, e);
+            }
+        }
+    }
+}
+
+/// A service instance found on the network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub name: DnsName,
+    pub host: DnsName,
+    pub port: u16,
+    pub txt: Vec<String>,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// Looks for services with one-shot queries (RFC 6762 section 5.1), which
+/// responders answer directly, so no other responder on this host has to
+/// give up port 5353
+pub struct Browser {
+    /// Port the responders listen on
+    pub port: u16,
+    /// How long to collect responses to each round of queries
+    pub timeout: Duration,
+}
+
+impl Default for Browser {
+    fn default() -> Self {
+        Browser {
+            port: MDNS_PORT,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Browser {
+    /// The instances of `service_type`, e.g. `_http._tcp.local`
+    pub fn browse(&self, service_type: &DnsName) -> Result<Vec<ServiceInstance>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_multicast_ttl_v4(255)?;
+
+        let mut records = Vec::new();
+        self.query(
+            &socket,
+            &[(service_type.clone(), QueryType::PTR)],
+            &mut records,
+        )?;
+
+        // ask for whatever did not come along as additional records
+        let mut missing = Vec::new();
+        for rec in &records {
+            if let DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ..
+            } = *rec
+            {
+                if domain != service_type {
+                    continue;
+                }
+                for qtype in [QueryType::SRV, QueryType::TXT] {
+                    if !records
+                        .iter()
+                        .any(|r| r.domain() == host && r.query_type() == qtype)
+                    {
+                        missing.push((host.clone(), qtype));
+                    }
+                }
+            }
+        }
+        for rec in &records {
+            if let DnsRecord::SRV { ref host, .. } = *rec {
+                let has_addrs = records.iter().any(|r| {
+                    r.domain() == host && matches!(r.query_type(), QueryType::A | QueryType::AAAA)
+                });
+                if !has_addrs {
+                    missing.push((host.clone(), QueryType::A));
+                    missing.push((host.clone(), QueryType::AAAA));
+                }
+            }
+        }
+        if !missing.is_empty() {
+            self.query(&socket, &missing, &mut records)?;
+        }
+
+        Ok(instances(service_type, &records))
+    }
+
+    /// Send the questions to the group and collect the records of all
+    /// responses that arrive in time
+    fn query(
+        &self,
+        socket: &UdpSocket,
+        questions: &[(DnsName, QueryType)],
+        records: &mut Vec<DnsRecord>,
+    ) -> Result<()> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = random_id();
+        for (qname, qtype) in questions {
+            packet
+                .questions
+                .push(DnsQuestion::new(qname.clone(), *qtype));
+        }
+        let buffer = write_message(&mut packet, false)?;
+        socket.send_to(&buffer.buffer[..buffer.pos()], (MDNS_GROUP, self.port))?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            let mut received = vec![0; MAX_MDNS_SIZE];
+            let len = match socket.recv_from(&mut received) {
+                Ok((len, _)) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let mut buffer = VectorPacketBuffer::from_bytes(&received[..len]);
+            let response = match MdnsMessage::from_buffer(&mut buffer) {
+                Ok(message) if message.packet.header.response => message.packet,
+                _ => continue,
+            };
+
+            let received = response
+                .answers
+                .into_iter()
+                .chain(response.authorities)
+                .chain(response.resources);
+            for rec in received {
+                records.retain(|known| !known.same_data(&rec));
+                // a TTL of zero says goodbye
+                if rec.ttl() > 0 {
+                    records.push(rec);
+                }
+            }
+        }
+    }
+}
+
+/// Put together the instances of `service_type` out of the records found
+fn instances(service_type: &DnsName, records: &[DnsRecord]) -> Vec<ServiceInstance> {
+    let mut instances: Vec<ServiceInstance> = Vec::new();
+    for rec in records {
+        let name = match *rec {
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ..
+            } if domain == service_type => host,
+            _ => continue,
+        };
+        let srv = records.iter().find_map(|rec| match *rec {
+            DnsRecord::SRV {
+                ref domain,
+                ref host,
+                port,
+                ..
+            } if domain == name => Some((host, port)),
+            _ => None,
+        });
+        let (host, port) = match srv {
+            Some(srv) => srv,
+            None => continue,
+        };
+        if instances.iter().any(|instance| instance.name == *name) {
+            continue;
+        }
+
+        let txt = records
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::TXT {
+                    ref domain,
+                    ref data,
+                    ..
+                } if domain == name => Some(data),
+                _ => None,
+            })
+            .flatten()
+            .filter(|s| !s.is_empty())
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect();
+        let addrs = records
+            .iter()
+            .filter(|rec| rec.domain() == host)
+            .filter_map(|rec| match *rec {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect();
+
+        instances.push(ServiceInstance {
+            name: name.clone(),
+            host: host.clone(),
+            port,
+            txt,
+            addrs,
+        });
+    }
+    instances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+    use std::sync::Arc;
+
+    fn responder() -> Responder {
+        let mut service: Service = "Office Printer=_ipp._tcp:631,rp=printer,color=T"
+            .parse()
+            .unwrap();
+        service.host = name("printer.local");
+        Responder {
+            hosts: vec![Host {
+                name: name("printer.local"),
+                addrs: vec!["192.0.2.9".parse().unwrap()],
+            }],
+            services: vec![service],
+        }
+    }
+
+    fn query(questions: &[(&str, QueryType)]) -> MdnsMessage {
+        let mut packet = DnsPacket::new();
+        for (qname, qtype) in questions {
+            packet.questions.push(DnsQuestion::new(name(qname), *qtype));
+        }
+        MdnsMessage {
+            unicast: vec![false; packet.questions.len()],
+            cache_flush: Vec::new(),
+            packet,
+        }
+    }
+
+    #[test]
+    fn test_class_flags() {
+        let mut packet = responder().announcement().unwrap();
+        packet
+            .questions
+            .push(DnsQuestion::new(name("printer.local"), QueryType::A));
+        let mut buffer = write_message(&mut packet, true).unwrap();
+        // ask for a unicast response
+        let (questions, _) = class_offsets(&mut buffer).unwrap();
+        buffer.set_u16(questions[0], CLASS_IN | CLASS_FLAG).unwrap();
+        buffer.seek(0);
+
+        // plain DNS rejects the classes
+        assert!(DnsPacket::from_buffer(&mut buffer).is_err());
+        buffer.seek(0);
+        let message = MdnsMessage::from_buffer(&mut buffer).unwrap();
+        assert_eq!(message.packet.answers, packet.answers);
+        assert_eq!(message.unicast, [true]);
+        let flushed: Vec<_> = packet
+            .answers
+            .iter()
+            .map(|rec| rec.query_type() != QueryType::PTR)
+            .collect();
+        assert_eq!(message.cache_flush, flushed);
+    }
+
+    #[test]
+    fn test_large_messages() {
+        let mut responder = responder();
+        for i in 0..20 {
+            let mut service: Service = format!("Printer {}=_ipp._tcp:631,rp=queue{}", i, i)
+                .parse()
+                .unwrap();
+            service.host = name("printer.local");
+            responder.services.push(service);
+        }
+        let mut packet = responder.announcement().unwrap();
+        let mut buffer = write_message(&mut packet, true).unwrap();
+        assert!(buffer.pos() > 512);
+
+        buffer.seek(0);
+        let message = MdnsMessage::from_buffer(&mut buffer).unwrap();
+        assert_eq!(message.packet.answers, packet.answers);
+    }
+
+    #[test]
+    fn test_instance_names() {
+        let service = &responder().services[0];
+        assert_eq!(service.service_type, name("_ipp._tcp.local"));
+        assert_eq!(service.txt, ["rp=printer", "color=T"]);
+        let instance = service.instance_name().unwrap();
+        assert_eq!(instance.label_count(), 4);
+        assert_eq!(instance.to_string(), "Office\\032Printer._ipp._tcp.local");
+        assert!("printer".parse::<Service>().is_err());
+        assert!("printer=_ipp._tcp".parse::<Service>().is_err());
+        let long = format!("{}=_ipp._tcp:631", "x".repeat(64));
+        assert!(long.parse::<Service>().is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let responder = responder();
+        assert_eq!(
+            responder
+                .respond(&query(&[("other.local", QueryType::A)]))
+                .unwrap(),
+            None
+        );
+
+        let response = responder
+            .respond(&query(&[("_ipp._tcp.local", QueryType::PTR)]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.header.id, 0);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 1);
+        let additional: Vec<_> = response
+            .resources
+            .iter()
+            .map(|rec| rec.query_type())
+            .collect();
+        assert_eq!(additional, [QueryType::A, QueryType::SRV, QueryType::TXT]);
+
+        // known answers are not repeated, unless about to expire
+        let mut known = query(&[("_ipp._tcp.local", QueryType::PTR)]);
+        known.packet.answers = response.answers.clone();
+        assert_eq!(responder.respond(&known).unwrap(), None);
+        known.packet.answers[0].set_ttl(60);
+        assert!(responder.respond(&known).unwrap().is_some());
+
+        let meta = query(&[(SERVICES_META, QueryType::PTR)]);
+        let response = responder.respond(&meta).unwrap().unwrap();
+        assert_eq!(response.answers.len(), 1);
+        let any = query(&[("printer.local", QueryType::UNKNOWN(TYPE_ANY))]);
+        assert_eq!(responder.respond(&any).unwrap().unwrap().answers.len(), 1);
+    }
+
+    #[test]
+    fn test_browse_over_multicast() {
+        let socket = MdnsSocket::bind(Ipv4Addr::UNSPECIFIED, 0).unwrap();
+        let port = socket.group.port();
+        let responder = Arc::new(responder());
+        let serving = Arc::clone(&responder);
+        thread::spawn(move || {
+            let _ = serving.serve(&socket);
+        });
+
+        let browser = Browser {
+            port,
+            timeout: Duration::from_millis(500),
+        };
+        let found = browser.browse(&name("_ipp._tcp.local")).unwrap();
+        assert_eq!(
+            found,
+            [ServiceInstance {
+                name: responder.services[0].instance_name().unwrap(),
+                host: name("printer.local"),
+                port: 631,
+                txt: vec!["rp=printer".into(), "color=T".into()],
+                addrs: vec!["192.0.2.9".parse().unwrap()],
+            }]
+        );
+        assert!(browser
+            .browse(&name("_http._tcp.local"))
+            .unwrap()
+            .is_empty());
+    }
+}
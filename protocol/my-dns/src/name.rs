@@ -0,0 +1,377 @@
+//! Domain names, kept as the raw labels seen on the wire
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::error::{DnsError, Result};
+
+/// Longest label the length byte allows
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Longest name on the wire, length bytes and the root label included
+pub const MAX_NAME_LEN: usize = 255;
+
+/// A domain name as a list of labels holding arbitrary bytes. The case of
+/// each label is kept as it was received, so 0x20 randomised queries are
+/// answered in kind, but it is ignored when names are compared or hashed.
+///
+/// Names render without the trailing dot, with dots and anything
+/// non-printable inside a label escaped (`\.`, `\DDD`). The root renders as
+/// an empty string.
+#[derive(Clone, Default)]
+pub struct DnsName {
+    labels: Vec<Vec<u8>>,
+}
+
+impl DnsName {
+    pub fn root() -> DnsName {
+        DnsName { labels: Vec::new() }
+    }
+
+    /// Build a name from its labels, leftmost first
+    pub fn from_labels(labels: Vec<Vec<u8>>) -> Result<DnsName> {
+        let mut len = 1;
+        for label in &labels {
+            if label.is_empty() {
+                return Err(DnsError::BadName("empty label"));
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(DnsError::LabelTooLong { len: label.len() });
+            }
+            len += label.len() + 1;
+        }
+        if len > MAX_NAME_LEN {
+            return Err(DnsError::NameTooLong { len });
+        }
+
+        Ok(DnsName { labels })
+    }
+
+    pub fn labels(&self) -> &[Vec<u8>] {
+        &self.labels
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Whether the leftmost label is `*`
+    pub fn is_wildcard(&self) -> bool {
+        self.labels.first().is_some_and(|label| label == b"*")
+    }
+
+    /// Length of the uncompressed name on the wire
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
+    /// The name one label up, or `None` for the root
+    pub fn parent(&self) -> Option<DnsName> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(self.suffix(self.labels.len() - 1))
+    }
+
+    /// The rightmost `count` labels of the name
+    pub fn suffix(&self, count: usize) -> DnsName {
+        let start = self.labels.len().saturating_sub(count);
+        DnsName {
+            labels: self.labels[start..].to_vec(),
+        }
+    }
+
+    /// This name followed by `origin`, as relative names in a zone file are
+    pub fn concat(&self, origin: &DnsName) -> Result<DnsName> {
+        let mut labels = self.labels.clone();
+        labels.extend(origin.labels.iter().cloned());
+        DnsName::from_labels(labels)
+    }
+
+    /// Prepend a single label, such as `*` for a wildcard
+    pub fn child(&self, label: &[u8]) -> Result<DnsName> {
+        let mut labels = vec![label.to_vec()];
+        labels.extend(self.labels.iter().cloned());
+        DnsName::from_labels(labels)
+    }
+
+    /// Whether this name is `zone` itself or somewhere below it
+    pub fn is_subdomain_of(&self, zone: &DnsName) -> bool {
+        self.labels.len() >= zone.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(zone.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    pub fn to_lowercase(&self) -> DnsName {
+        DnsName {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Equality that also takes case into account
+    pub fn eq_case_sensitive(&self, other: &DnsName) -> bool {
+        self.labels == other.labels
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &DnsName) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for DnsName {}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.labels.len());
+        for label in &self.labels {
+            state.write_usize(label.len());
+            for b in label {
+                state.write_u8(b.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+impl Ord for DnsName {
+    /// The canonical DNS order of RFC 4034 section 6.1: compare label by
+    /// label starting from the root, ignoring case, with parents sorting
+    /// before their children
+    fn cmp(&self, other: &DnsName) -> Ordering {
+        for (a, b) in self.labels.iter().rev().zip(other.labels.iter().rev()) {
+            let ord = a
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(b.iter().map(u8::to_ascii_lowercase));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+
+        self.labels.len().cmp(&other.labels.len())
+    }
+}
+
+impl PartialOrd for DnsName {
+    fn partial_cmp(&self, other: &DnsName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq<str> for DnsName {
+    fn eq(&self, other: &str) -> bool {
+        other.parse::<DnsName>().is_ok_and(|name| *self == name)
+    }
+}
+
+impl PartialEq<&str> for DnsName {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+impl FromStr for DnsName {
+    type Err = DnsError;
+
+    /// Parse a dotted name, with or without the trailing dot. A backslash
+    /// escapes the next character, or gives a byte as three decimal digits.
+    fn from_str(s: &str) -> Result<DnsName> {
+        if s.is_empty() || s == "." {
+            return Ok(DnsName::root());
+        }
+
+        let bytes = s.as_bytes();
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(DnsError::BadName("empty label"));
+                    }
+                    labels.push(std::mem::take(&mut label));
+                }
+                b'\\' => {
+                    let digits = bytes.get(i + 1..i + 4).unwrap_or_default();
+                    if digits.len() == 3 && digits.iter().all(u8::is_ascii_digit) {
+                        let value = digits
+                            .iter()
+                            .fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                        if value > 0xFF {
+                            return Err(DnsError::BadName("escaped byte over 255"));
+                        }
+                        label.push(value as u8);
+                        i += 3;
+                    } else {
+                        match bytes.get(i + 1) {
+                            Some(&b) => label.push(b),
+                            None => return Err(DnsError::BadName("dangling escape")),
+                        }
+                        i += 1;
+                    }
+                }
+                b => label.push(b),
+            }
+            i += 1;
+        }
+        if !label.is_empty() {
+            labels.push(label);
+        }
+
+        DnsName::from_labels(labels)
+    }
+}
+
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            for &b in label {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", b as char)?
+                    }
+                    0x21..=0x7e => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn test_parse_and_render() {
+        let parsed = name("WwW.Example.com.");
+        assert_eq!(parsed.labels().len(), 3);
+        assert_eq!(parsed.to_string(), "WwW.Example.com");
+        assert!(name(".").is_root());
+        assert_eq!(DnsName::root().to_string(), "");
+
+        let binary = DnsName::from_labels(vec![b"a.b".to_vec(), vec![0, 0xff, b' ']]).unwrap();
+        assert_eq!(binary.to_string(), "a\\.b.\\000\\255\\032");
+        assert!(name(&binary.to_string()).eq_case_sensitive(&binary));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "a..b".parse::<DnsName>(),
+            Err(DnsError::BadName("empty label"))
+        );
+        assert_eq!(
+            "a\\256".parse::<DnsName>(),
+            Err(DnsError::BadName("escaped byte over 255"))
+        );
+        assert_eq!(
+            format!("{}.com", "a".repeat(64)).parse::<DnsName>(),
+            Err(DnsError::LabelTooLong { len: 64 })
+        );
+        assert_eq!(
+            vec!["a".repeat(63); 4].join(".").parse::<DnsName>(),
+            Err(DnsError::NameTooLong { len: 257 })
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_comparison() {
+        assert_eq!(name("EXAMPLE.com"), name("example.COM"));
+        assert!(!name("EXAMPLE.com").eq_case_sensitive(&name("example.com")));
+        assert_eq!(name("Example.com"), "example.com");
+        assert_ne!(name("example.com"), name("example.org"));
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(name("Example.COM"));
+        assert!(set.contains(&name("example.com")));
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // the example from RFC 4034 section 6.1
+        let mut names: Vec<DnsName> = [
+            "z.example",
+            "\\001.z.example",
+            "*.z.example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "\\200.z.example",
+            "example",
+        ]
+        .iter()
+        .map(|s| name(s))
+        .collect();
+        names.sort();
+
+        let sorted: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        assert_eq!(
+            sorted,
+            [
+                "example",
+                "a.example",
+                "yljkjljk.a.example",
+                "Z.a.example",
+                "zABC.a.EXAMPLE",
+                "z.example",
+                "\\001.z.example",
+                "*.z.example",
+                "\\200.z.example",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subdomains() {
+        let zone = name("example.com");
+        assert!(name("www.EXAMPLE.com").is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&zone));
+        assert!(!name("badexample.com").is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&DnsName::root()));
+
+        assert_eq!(name("a.b.c").parent(), Some(name("b.c")));
+        assert_eq!(name("a.b.c").suffix(1), name("c"));
+        assert_eq!(name("www").concat(&zone).unwrap(), name("www.example.com"));
+        assert!(zone.child(b"*").unwrap().is_wildcard());
+    }
+}
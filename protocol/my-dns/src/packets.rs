@@ -1,54 +1,39 @@
-use std::simd::u16x2;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
-pub struct BytePacketBuffer {
-    pub buffer: [u8; 512],
-    pub pos: usize,
-}
-
-impl BytePacketBuffer {
-    pub fn new() -> BytePacketBuffer {
-        BytePacketBuffer { buffer: [0; 512], pos: 0 }
-    }
-
-    fn pos(&self) -> usize {
-        self.pos
-    } 
-
-    fn step(&mut self, n: usize) {
-        self.pos += n;
-    }
-
-    fn seek(&mut self, pos: usize) {
-        self.pos = pos;
-    }
-    //read a single byte
-    fn read(&mut self) -> Result<u8> {
-        if self.pos > 512 {
-            return Err("exceede buffer size".into())
-        }
-
-        let res = self.buffer[self.pos];
-        self.pos += 1;
-        Ok(res)
-    }
+use crate::encoding::{to_base32hex, to_base64, to_hex};
+use crate::error::{DnsError, Result};
+use crate::name::{DnsName, MAX_LABEL_LEN, MAX_NAME_LEN};
+use crate::time::format_timestamp;
 
-    fn get(&self, pos: usize) -> Result<u8> {
-        if pos > 512 {
-            return Err("exceede buffer size".into())
-        }
+/// Largest message that fits the 16 bit length prefix used over TCP
+pub const MAX_MESSAGE_SIZE: usize = 0xFFFF;
 
-        Ok(self.buffer[pos])
-    }
+/// Largest UDP message that can be sent without EDNS
+pub const MAX_UDP_SIZE: usize = 512;
 
-    fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
-        if start > 512 || start + len > 512 {
-            return Err("exceede buffer size".into())
-        }
+/// UDP payload size advertised over EDNS. Large enough for most answers,
+/// small enough to avoid IP fragmentation (DNS flag day 2020).
+pub const DEFAULT_EDNS_PAYLOAD_SIZE: u16 = 1232;
 
-        Ok(&self.buffer[start..start + len])
-    }
+/// Byte level access to a DNS message being read or written. Implementors
+/// only provide the primitives, everything DNS specific is built on top.
+pub trait PacketBuffer {
+    fn read(&mut self) -> Result<u8>;
+    fn get(&self, pos: usize) -> Result<u8>;
+    fn get_range(&self, start: usize, len: usize) -> Result<&[u8]>;
+    fn write(&mut self, val: u8) -> Result<()>;
+    /// Overwrite a byte that was already written, without moving
+    fn set(&mut self, pos: usize, val: u8) -> Result<()>;
+    fn pos(&self) -> usize;
+    fn seek(&mut self, pos: usize);
+    fn step(&mut self, n: usize);
+    /// Offset of a name suffix written earlier, used for compression. The
+    /// labels have to match byte for byte, so pointers never change case.
+    fn find_label(&self, labels: &[Vec<u8>]) -> Option<usize>;
+    fn save_label(&mut self, labels: &[Vec<u8>], pos: usize);
 
     /// Read two bytes, stepping two steps forward
     fn read_u16(&mut self) -> Result<u16> {
@@ -64,34 +49,47 @@ impl BytePacketBuffer {
         let res = ((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
-            | ((self.read()? as u32) << 0);
+            | (self.read()? as u32);
 
         Ok(res)
     }
 
-    fn read_query_name(&mut self) -> Result<String> {
-        let mut res = String::new();
-        let mut pos = self.pos();
+    /// Read a possibly compressed name. Pointers may only point backwards,
+    /// and the name may not exceed the RFC 1035 limits of 63 bytes per
+    /// label and 255 bytes in total.
+    fn read_query_name(&mut self) -> Result<DnsName> {
+        let mut labels = Vec::new();
+        let start = self.pos();
+        let mut pos = start;
 
         let mut jumped = false;
         let max_jumps = 5;
         let mut jumps_performed = 0;
+        // length on the wire, counting the terminating root label
+        let mut name_len = 1;
 
         loop {
             if jumps_performed > max_jumps {
-                return Err("too many jumps in query name".into())
+                return Err(DnsError::PointerLoop { offset: start });
             }
             //Now we are at the beginning of the label, with the length byte starting at pos
             let len = self.get(pos)?;
 
             if (len & 0xC0) == 0xC0 {
-                if !jumped {
-                    self.seek(pos + 2);
-                }
                 // Read another byte, calculate offset and perform the jump by
                 // updating our local position variable
                 let b2 = self.get(pos + 1)? as u16;
                 let offset = (((len as u16) ^ 0xC0) << 8) | b2;
+
+                // a pointer to anywhere but earlier in the message could
+                // only be there to make us loop
+                if offset as usize >= pos {
+                    return Err(DnsError::ForwardPointer { offset: pos });
+                }
+
+                if !jumped {
+                    self.seek(pos + 2);
+                }
                 pos = offset as usize;
 
                 // Indicate that a jump was performed.
@@ -100,49 +98,429 @@ impl BytePacketBuffer {
 
                 continue;
             } else {
+                // 0x40 and 0x80 are extended label types nobody uses, as
+                // lengths they would be over 63
+                if len as usize > MAX_LABEL_LEN {
+                    return Err(DnsError::LabelTooLong { len: len as usize });
+                }
                 pos += 1;
-                
+
                 if len == 0 {
                     break;
                 }
-                //TODO: check if this is correct
-                res.push_str("");
-                let str_buffer = self.get_range(pos, len as usize)?;
-                res.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                name_len += len as usize + 1;
+                if name_len > MAX_NAME_LEN {
+                    return Err(DnsError::NameTooLong { len: name_len });
+                }
+
+                labels.push(self.get_range(pos, len as usize)?.to_vec());
+                pos += len as usize;
             }
         }
 
         if !jumped {
             self.seek(pos);
         }
+        DnsName::from_labels(labels)
+    }
+
+    fn write_u8(&mut self, val: u8) -> Result<()> {
+        self.write(val)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for b in bytes {
+            self.write(*b)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write two bytes in network order, stepping two steps forward
+    fn write_u16(&mut self, val: u16) -> Result<()> {
+        self.write((val >> 8) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    /// Write four bytes in network order, stepping four steps forward
+    fn write_u32(&mut self, val: u32) -> Result<()> {
+        self.write(((val >> 24) & 0xFF) as u8)?;
+        self.write(((val >> 16) & 0xFF) as u8)?;
+        self.write(((val >> 8) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    /// Write a name, replacing the longest suffix that was already written
+    /// with a pointer to it, the same 0xC0 scheme `read_query_name` follows
+    fn write_qname(&mut self, qname: &DnsName) -> Result<()> {
+        let labels = qname.labels();
+
+        for i in 0..labels.len() {
+            let suffix = &labels[i..];
+            // only point backwards, so entries from an earlier write that was
+            // seeked over are never used
+            if let Some(offset) = self.find_label(suffix) {
+                if offset < self.pos() {
+                    return self.write_u16(0xC000 | offset as u16);
+                }
+            }
+
+            // pointers only have 14 bits for the offset
+            let pos = self.pos();
+            if pos <= 0x3FFF {
+                self.save_label(suffix, pos);
+            }
+            self.write_label(&labels[i])?;
+        }
+
+        self.write_u8(0)?;
+
+        Ok(())
+    }
+
+    /// Write a name without compression, for RDATA where pointers are not allowed
+    fn write_qname_uncompressed(&mut self, qname: &DnsName) -> Result<()> {
+        for label in qname.labels() {
+            self.write_label(label)?;
+        }
+
+        self.write_u8(0)?;
+
+        Ok(())
+    }
+
+    fn write_label(&mut self, label: &[u8]) -> Result<()> {
+        let len = label.len();
+        if len > MAX_LABEL_LEN {
+            return Err(DnsError::LabelTooLong { len });
+        }
+
+        self.write_u8(len as u8)?;
+        self.write_bytes(label)?;
+
+        Ok(())
+    }
+}
+
+/// Fixed size buffer for plain UDP messages, which are capped at 512 bytes
+pub struct BytePacketBuffer {
+    pub buffer: [u8; 512],
+    pub pos: usize,
+    /// Offsets of the name suffixes written so far, used for compression
+    names: HashMap<Vec<Vec<u8>>, usize>,
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BytePacketBuffer {
+    pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer {
+            buffer: [0; 512],
+            pos: 0,
+            names: HashMap::new(),
+        }
+    }
+}
+
+impl PacketBuffer for BytePacketBuffer {
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn step(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+    //read a single byte
+    fn read(&mut self) -> Result<u8> {
+        if self.pos >= 512 {
+            return Err(DnsError::BufferOverrun { offset: self.pos });
+        }
+
+        let res = self.buffer[self.pos];
+        self.pos += 1;
+        Ok(res)
+    }
+
+    fn get(&self, pos: usize) -> Result<u8> {
+        if pos >= 512 {
+            return Err(DnsError::BufferOverrun { offset: pos });
+        }
+
+        Ok(self.buffer[pos])
+    }
+
+    fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
+        if start.saturating_add(len) > 512 {
+            return Err(DnsError::BufferOverrun { offset: start });
+        }
+
+        Ok(&self.buffer[start..start + len])
+    }
+
+    //write a single byte
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= 512 {
+            return Err(DnsError::BufferOverrun { offset: self.pos });
+        }
+        self.buffer[self.pos] = val;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= 512 {
+            return Err(DnsError::BufferOverrun { offset: pos });
+        }
+        self.buffer[pos] = val;
+
+        Ok(())
+    }
+
+    fn find_label(&self, labels: &[Vec<u8>]) -> Option<usize> {
+        self.names.get(labels).copied()
+    }
+
+    fn save_label(&mut self, labels: &[Vec<u8>], pos: usize) {
+        self.names.insert(labels.to_vec(), pos);
+    }
+}
+
+/// Growable buffer for messages that may exceed 512 bytes, such as those
+/// sent over TCP. Writes fail once `limit` bytes have been written.
+pub struct VectorPacketBuffer {
+    pub buffer: Vec<u8>,
+    pub pos: usize,
+    pub limit: usize,
+    names: HashMap<Vec<Vec<u8>>, usize>,
+    compress: bool,
+}
+
+impl Default for VectorPacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VectorPacketBuffer {
+    pub fn new() -> VectorPacketBuffer {
+        VectorPacketBuffer::with_limit(MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_limit(limit: usize) -> VectorPacketBuffer {
+        VectorPacketBuffer {
+            buffer: Vec::new(),
+            pos: 0,
+            limit,
+            names: HashMap::new(),
+            compress: true,
+        }
+    }
+
+    /// A buffer that writes every name in full, as the canonical form used
+    /// for DNSSEC signatures and digests requires
+    pub fn uncompressed() -> VectorPacketBuffer {
+        VectorPacketBuffer {
+            compress: false,
+            ..VectorPacketBuffer::new()
+        }
+    }
+
+    /// Wrap a received message for reading
+    pub fn from_bytes(bytes: &[u8]) -> VectorPacketBuffer {
+        VectorPacketBuffer {
+            buffer: bytes.to_vec(),
+            ..VectorPacketBuffer::new()
+        }
+    }
+}
+
+impl PacketBuffer for VectorPacketBuffer {
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn step(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        let res = self.get(self.pos)?;
+        self.pos += 1;
         Ok(res)
     }
+
+    fn get(&self, pos: usize) -> Result<u8> {
+        match self.buffer.get(pos) {
+            Some(b) => Ok(*b),
+            None => Err(DnsError::BufferOverrun { offset: pos }),
+        }
+    }
+
+    fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
+        match self.buffer.get(start..start.saturating_add(len)) {
+            Some(range) => Ok(range),
+            None => Err(DnsError::BufferOverrun { offset: start }),
+        }
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= self.limit {
+            return Err(DnsError::BufferOverrun { offset: self.pos });
+        }
+        if self.pos >= self.buffer.len() {
+            self.buffer.resize(self.pos + 1, 0);
+        }
+        self.buffer[self.pos] = val;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        match self.buffer.get_mut(pos) {
+            Some(b) => {
+                *b = val;
+                Ok(())
+            }
+            None => Err(DnsError::BufferOverrun { offset: pos }),
+        }
+    }
+
+    fn find_label(&self, labels: &[Vec<u8>]) -> Option<usize> {
+        if !self.compress {
+            return None;
+        }
+        self.names.get(labels).copied()
+    }
+
+    fn save_label(&mut self, labels: &[Vec<u8>], pos: usize) {
+        if self.compress {
+            self.names.insert(labels.to_vec(), pos);
+        }
+    }
 }
 
+/// The Internet class, the only one we hold data for
+pub const CLASS_IN: u16 = 1;
+/// Class of update prerequisites that must not hold, and of single records
+/// to delete
+pub const CLASS_NONE: u16 = 254;
+/// Class of TSIG records, and of update prerequisites and deletions for
+/// whole RRsets or names
+pub const CLASS_ANY: u16 = 255;
+
+/// The ANY query type, which has no `QueryType` of its own
+pub const TYPE_ANY: u16 = 255;
+
+/// Accept the classes defined by RFC 1035 and RFC 2136: IN, CH, HS, NONE
+/// and ANY
+fn check_class(class: u16) -> Result<u16> {
+    match class {
+        CLASS_IN | 3 | 4 | CLASS_NONE | CLASS_ANY => Ok(class),
+        _ => Err(DnsError::UnknownClass(class)),
+    }
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+    UNKNOWN(u16),
+    NOERROR,  // 0
+    FORMERR,  // 1
+    SERVFAIL, // 2
+    NXDOMAIN, // 3
+    NOTIMP,   // 4
+    REFUSED,  // 5
+    YXDOMAIN, // 6
+    YXRRSET,  // 7
+    NXRRSET,  // 8
+    NOTAUTH,  // 9
+    NOTZONE,  // 10
+    // codes from here on need the upper bits carried in the OPT record
+    BADVERS,   // 16
+    BADKEY,    // 17
+    BADTIME,   // 18
+    BADMODE,   // 19
+    BADNAME,   // 20
+    BADALG,    // 21
+    BADTRUNC,  // 22
+    BADCOOKIE, // 23
 }
 
 impl ResultCode {
-    pub fn from_num(num: u8) -> ResultCode {
+    pub fn from_num(num: u16) -> ResultCode {
         match num {
+            0 => ResultCode::NOERROR,
             1 => ResultCode::FORMERR,
             2 => ResultCode::SERVFAIL,
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
+            16 => ResultCode::BADVERS,
+            17 => ResultCode::BADKEY,
+            18 => ResultCode::BADTIME,
+            19 => ResultCode::BADMODE,
+            20 => ResultCode::BADNAME,
+            21 => ResultCode::BADALG,
+            22 => ResultCode::BADTRUNC,
+            23 => ResultCode::BADCOOKIE,
+            _ => ResultCode::UNKNOWN(num),
+        }
+    }
+
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            ResultCode::UNKNOWN(num) => num,
+            ResultCode::NOERROR => 0,
+            ResultCode::FORMERR => 1,
+            ResultCode::SERVFAIL => 2,
+            ResultCode::NXDOMAIN => 3,
+            ResultCode::NOTIMP => 4,
+            ResultCode::REFUSED => 5,
+            ResultCode::YXDOMAIN => 6,
+            ResultCode::YXRRSET => 7,
+            ResultCode::NXRRSET => 8,
+            ResultCode::NOTAUTH => 9,
+            ResultCode::NOTZONE => 10,
+            ResultCode::BADVERS => 16,
+            ResultCode::BADKEY => 17,
+            ResultCode::BADTIME => 18,
+            ResultCode::BADMODE => 19,
+            ResultCode::BADNAME => 20,
+            ResultCode::BADALG => 21,
+            ResultCode::BADTRUNC => 22,
+            ResultCode::BADCOOKIE => 23,
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct DnsHeader {
     pub id: u16,
 
@@ -152,7 +530,7 @@ pub struct DnsHeader {
     pub opcode: u8,                 // 4 bits
     pub response: bool,             // 1 bit
 
-    pub rescode: ResultCode,       // 4 bits
+    pub rescode: ResultCode,       // 4 bits, 12 with EDNS
     pub checking_disabled: bool,   // 1 bit
     pub authed_data: bool,         // 1 bit
     pub z: bool,                   // 1 bit
@@ -164,6 +542,12 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bits
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -184,7 +568,7 @@ impl DnsHeader {
             resource_entries: 0,
         }
     }
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
         self.id = buffer.read_u16()?;
 
         let flags = buffer.read_u16()?;
@@ -196,7 +580,7 @@ impl DnsHeader {
         self.opcode = (a >> 3) & 0x0F;
         self.response = (a & (1 << 7)) > 0;
 
-        self.rescode = ResultCode::from_num(b & 0x0F);
+        self.rescode = ResultCode::from_num((b & 0x0F) as u16);
         self.checking_disabled = (b & (1 << 4)) > 0;
         self.authed_data = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
@@ -209,17 +593,80 @@ impl DnsHeader {
 
         Ok(())
     }
+
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_u16(self.id)?;
+
+        buffer.write_u8(
+            (self.recursion_desired as u8)
+                | ((self.truncated_message as u8) << 1)
+                | ((self.authoritative_answer as u8) << 2)
+                | ((self.opcode & 0x0F) << 3)
+                | ((self.response as u8) << 7),
+        )?;
+
+        buffer.write_u8(
+            ((self.rescode.to_num() & 0x0F) as u8)
+                | ((self.checking_disabled as u8) << 4)
+                | ((self.authed_data as u8) << 5)
+                | ((self.z as u8) << 6)
+                | ((self.recursion_available as u8) << 7),
+        )?;
+
+        buffer.write_u16(self.questions)?;
+        buffer.write_u16(self.answers)?;
+        buffer.write_u16(self.authoritative_entries)?;
+        buffer.write_u16(self.resource_entries)?;
+
+        Ok(())
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum QueryType {
     UNKNOWN(u16),
-    A,
+    A,      // 1
+    NS,     // 2
+    CNAME,  // 5
+    SOA,    // 6
+    PTR,    // 12
+    MX,     // 15
+    TXT,    // 16
+    AAAA,   // 28
+    SRV,    // 33
+    OPT,    // 41
+    DS,     // 43
+    RRSIG,  // 46
+    NSEC,   // 47
+    DNSKEY, // 48
+    NSEC3,  // 50
+    TSIG,   // 250
+    IXFR,   // 251
+    AXFR,   // 252
+    CAA,    // 257
 }
 impl QueryType {
     pub fn from_num(num: u16) -> QueryType {
         match num {
             1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            15 => QueryType::MX,
+            16 => QueryType::TXT,
+            28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            250 => QueryType::TSIG,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -227,10 +674,2341 @@ impl QueryType {
         match *self {
             QueryType::UNKNOWN(num) => num,
             QueryType::A => 1,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
+            QueryType::MX => 15,
+            QueryType::TXT => 16,
+            QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::TSIG => 250,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::CAA => 257,
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            QueryType::A => write!(f, "A"),
+            QueryType::NS => write!(f, "NS"),
+            QueryType::CNAME => write!(f, "CNAME"),
+            QueryType::SOA => write!(f, "SOA"),
+            QueryType::PTR => write!(f, "PTR"),
+            QueryType::MX => write!(f, "MX"),
+            QueryType::TXT => write!(f, "TXT"),
+            QueryType::AAAA => write!(f, "AAAA"),
+            QueryType::SRV => write!(f, "SRV"),
+            QueryType::OPT => write!(f, "OPT"),
+            QueryType::DS => write!(f, "DS"),
+            QueryType::RRSIG => write!(f, "RRSIG"),
+            QueryType::NSEC => write!(f, "NSEC"),
+            QueryType::DNSKEY => write!(f, "DNSKEY"),
+            QueryType::NSEC3 => write!(f, "NSEC3"),
+            QueryType::TSIG => write!(f, "TSIG"),
+            QueryType::IXFR => write!(f, "IXFR"),
+            QueryType::AXFR => write!(f, "AXFR"),
+            QueryType::CAA => write!(f, "CAA"),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = String;
+
+    /// Parse a mnemonic like `MX`, or the `TYPE65280` form used for types
+    /// without one
+    fn from_str(s: &str) -> std::result::Result<QueryType, String> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(num) = upper.strip_prefix("TYPE") {
+            if let Ok(num) = num.parse::<u16>() {
+                return Ok(QueryType::from_num(num));
+            }
+        }
+
+        match upper.as_str() {
+            "A" => Ok(QueryType::A),
+            "NS" => Ok(QueryType::NS),
+            "CNAME" => Ok(QueryType::CNAME),
+            "SOA" => Ok(QueryType::SOA),
+            "PTR" => Ok(QueryType::PTR),
+            "MX" => Ok(QueryType::MX),
+            "TXT" => Ok(QueryType::TXT),
+            "AAAA" => Ok(QueryType::AAAA),
+            "SRV" => Ok(QueryType::SRV),
+            "OPT" => Ok(QueryType::OPT),
+            "DS" => Ok(QueryType::DS),
+            "RRSIG" => Ok(QueryType::RRSIG),
+            "NSEC" => Ok(QueryType::NSEC),
+            "DNSKEY" => Ok(QueryType::DNSKEY),
+            "NSEC3" => Ok(QueryType::NSEC3),
+            "TSIG" => Ok(QueryType::TSIG),
+            "IXFR" => Ok(QueryType::IXFR),
+            "AXFR" => Ok(QueryType::AXFR),
+            "CAA" => Ok(QueryType::CAA),
+            _ => Err(format!("unknown record type: {}", s)),
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct DnsQuestion {
-    pub
-}
\ No newline at end of file
+    pub name: DnsName,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+impl DnsQuestion {
+    /// A question in the IN class
+    pub fn new(name: DnsName, qtype: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name,
+            qtype,
+            class: CLASS_IN,
+        }
+    }
+
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        self.name = buffer.read_query_name()?;
+        self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
+        self.class = check_class(buffer.read_u16()?)?;
+
+        Ok(())
+    }
+
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_qname(&self.name)?;
+
+        buffer.write_u16(self.qtype.to_num())?;
+        buffer.write_u16(self.class)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DnsRecord {
+    UNKNOWN {
+        domain: DnsName,
+        qtype: u16,
+        data: Vec<u8>,
+        ttl: u32,
+    }, // 0
+    A {
+        domain: DnsName,
+        addr: Ipv4Addr,
+        ttl: u32,
+    }, // 1
+    NS {
+        domain: DnsName,
+        host: DnsName,
+        ttl: u32,
+    }, // 2
+    CNAME {
+        domain: DnsName,
+        host: DnsName,
+        ttl: u32,
+    }, // 5
+    SOA {
+        domain: DnsName,
+        mname: DnsName,
+        rname: DnsName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: DnsName,
+        host: DnsName,
+        ttl: u32,
+    }, // 12
+    MX {
+        domain: DnsName,
+        priority: u16,
+        host: DnsName,
+        ttl: u32,
+    }, // 15
+    TXT {
+        domain: DnsName,
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
+    AAAA {
+        domain: DnsName,
+        addr: Ipv6Addr,
+        ttl: u32,
+    }, // 28
+    SRV {
+        domain: DnsName,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: DnsName,
+        ttl: u32,
+    }, // 33
+    DS {
+        domain: DnsName,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: DnsName,
+        type_covered: QueryType,
+        algorithm: u8,
+        /// Labels in the owner name the signature was made for, fewer than
+        /// the owner has when it was expanded from a wildcard
+        labels: u8,
+        original_ttl: u32,
+        /// Validity period, in seconds since the epoch modulo 2^32
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: DnsName,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    NSEC {
+        domain: DnsName,
+        next_domain: DnsName,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 47
+    DNSKEY {
+        domain: DnsName,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    NSEC3 {
+        domain: DnsName,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 50
+    CAA {
+        domain: DnsName,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        ttl: u32,
+    }, // 257
+}
+
+impl DnsRecord {
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<DnsRecord> {
+        let domain = buffer.read_query_name()?;
+
+        let qtype_num = buffer.read_u16()?;
+        let qtype = QueryType::from_num(qtype_num);
+        let class = buffer.read_u16()?;
+        // the class of an OPT record is a payload size
+        if qtype != QueryType::OPT {
+            check_class(class)?;
+        }
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+        let data_start = buffer.pos();
+        let data_end = data_start + data_len as usize;
+
+        let record = match qtype {
+            QueryType::A => {
+                let raw_addr = buffer.read_u32()?;
+                let addr = Ipv4Addr::from(raw_addr);
+
+                DnsRecord::A { domain, addr, ttl }
+            }
+            QueryType::AAAA => {
+                let raw_addr = ((buffer.read_u32()? as u128) << 96)
+                    | ((buffer.read_u32()? as u128) << 64)
+                    | ((buffer.read_u32()? as u128) << 32)
+                    | (buffer.read_u32()? as u128);
+                let addr = Ipv6Addr::from(raw_addr);
+
+                DnsRecord::AAAA { domain, addr, ttl }
+            }
+            QueryType::NS => {
+                let host = buffer.read_query_name()?;
+
+                DnsRecord::NS { domain, host, ttl }
+            }
+            QueryType::CNAME => {
+                let host = buffer.read_query_name()?;
+
+                DnsRecord::CNAME { domain, host, ttl }
+            }
+            QueryType::PTR => {
+                let host = buffer.read_query_name()?;
+
+                DnsRecord::PTR { domain, host, ttl }
+            }
+            QueryType::SOA => {
+                let mname = buffer.read_query_name()?;
+                let rname = buffer.read_query_name()?;
+
+                DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                }
+            }
+            QueryType::MX => {
+                let priority = buffer.read_u16()?;
+                let host = buffer.read_query_name()?;
+
+                DnsRecord::MX {
+                    domain,
+                    priority,
+                    host,
+                    ttl,
+                }
+            }
+            QueryType::TXT => {
+                // one or more <character-string>s filling the whole rdata
+                let mut data = Vec::new();
+                while buffer.pos() < data_end {
+                    let len = buffer.read()? as usize;
+                    data.push(buffer.get_range(buffer.pos(), len)?.to_vec());
+                    buffer.step(len);
+                }
+
+                DnsRecord::TXT { domain, data, ttl }
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let host = buffer.read_query_name()?;
+
+                DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    ttl,
+                }
+            }
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let digest_type = buffer.read()?;
+                let digest = read_rest(buffer, data_end)?;
+
+                DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                }
+            }
+            QueryType::RRSIG => {
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read()?;
+                let labels = buffer.read()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let signer_name = buffer.read_query_name()?;
+                let signature = read_rest(buffer, data_end)?;
+
+                DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature,
+                    ttl,
+                }
+            }
+            QueryType::NSEC => {
+                let next_domain = buffer.read_query_name()?;
+                let types = read_type_bitmap(buffer, qtype, data_end)?;
+
+                DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types,
+                    ttl,
+                }
+            }
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read()?;
+                let algorithm = buffer.read()?;
+                let public_key = read_rest(buffer, data_end)?;
+
+                DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                }
+            }
+            QueryType::NSEC3 => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+                let salt = buffer.get_range(buffer.pos(), salt_len)?.to_vec();
+                buffer.step(salt_len);
+                let hash_len = buffer.read()? as usize;
+                if hash_len == 0 {
+                    return Err(DnsError::BadRdata {
+                        qtype,
+                        reason: "empty next hashed owner name",
+                    });
+                }
+                let next_hashed = buffer.get_range(buffer.pos(), hash_len)?.to_vec();
+                buffer.step(hash_len);
+                let types = read_type_bitmap(buffer, qtype, data_end)?;
+
+                DnsRecord::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                    ttl,
+                }
+            }
+            QueryType::CAA => {
+                let flags = buffer.read()?;
+                let tag_len = buffer.read()? as usize;
+                let tag =
+                    String::from_utf8_lossy(buffer.get_range(buffer.pos(), tag_len)?).to_string();
+                buffer.step(tag_len);
+                let value = buffer
+                    .get_range(buffer.pos(), data_end.saturating_sub(buffer.pos()))?
+                    .to_vec();
+                buffer.seek(data_end);
+
+                DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    ttl,
+                }
+            }
+            // an OPT or TSIG record outside the additional section means
+            // nothing, keep it as opaque data, as well as the query only types
+            QueryType::UNKNOWN(_)
+            | QueryType::OPT
+            | QueryType::TSIG
+            | QueryType::IXFR
+            | QueryType::AXFR => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
+                buffer.step(data_len as usize);
+
+                DnsRecord::UNKNOWN {
+                    domain,
+                    qtype: qtype_num,
+                    data,
+                    ttl,
+                }
+            }
+        };
+
+        if buffer.pos() != data_end {
+            return Err(DnsError::BadRdataLength {
+                qtype,
+                expected: data_len as usize,
+                actual: buffer.pos() - data_start,
+            });
+        }
+
+        Ok(record)
+    }
+
+    /// Write the record, returning the number of bytes written
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
+        let start_pos = buffer.pos();
+
+        buffer.write_qname(self.domain())?;
+        buffer.write_u16(self.query_type().to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(self.ttl())?;
+
+        // the length is only known once the rdata is written, so leave room
+        // for it and fill it in afterwards
+        let len_pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        match *self {
+            DnsRecord::A { ref addr, .. } => {
+                for octet in addr.octets() {
+                    buffer.write_u8(octet)?;
+                }
+            }
+            DnsRecord::AAAA { ref addr, .. } => {
+                for segment in addr.segments() {
+                    buffer.write_u16(segment)?;
+                }
+            }
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. } => {
+                buffer.write_qname(host)?;
+            }
+            DnsRecord::SOA {
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => {
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+            }
+            DnsRecord::MX {
+                priority, ref host, ..
+            } => {
+                buffer.write_u16(priority)?;
+                buffer.write_qname(host)?;
+            }
+            DnsRecord::TXT { ref data, .. } => {
+                for string in data {
+                    if string.len() > 0xFF {
+                        return Err(DnsError::StringTooLong { len: string.len() });
+                    }
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string)?;
+                }
+            }
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                ref host,
+                ..
+            } => {
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                // RFC 2782 forbids compressing the target
+                buffer.write_qname_uncompressed(host)?;
+            }
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            } => {
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                buffer.write_bytes(digest)?;
+            }
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } => {
+                buffer.write_u16(type_covered.to_num())?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                // RFC 4034 forbids compressing the names of DNSSEC records
+                buffer.write_qname_uncompressed(signer_name)?;
+                buffer.write_bytes(signature)?;
+            }
+            DnsRecord::NSEC {
+                ref next_domain,
+                ref types,
+                ..
+            } => {
+                buffer.write_qname_uncompressed(next_domain)?;
+                write_type_bitmap(buffer, types)?;
+            }
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ..
+            } => {
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(public_key)?;
+            }
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ..
+            } => {
+                if salt.len() > 0xFF {
+                    return Err(DnsError::StringTooLong { len: salt.len() });
+                }
+                if next_hashed.len() > 0xFF {
+                    return Err(DnsError::StringTooLong {
+                        len: next_hashed.len(),
+                    });
+                }
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                buffer.write_bytes(salt)?;
+                buffer.write_u8(next_hashed.len() as u8)?;
+                buffer.write_bytes(next_hashed)?;
+                write_type_bitmap(buffer, types)?;
+            }
+            DnsRecord::CAA {
+                flags,
+                ref tag,
+                ref value,
+                ..
+            } => {
+                if tag.len() > 0xFF {
+                    return Err(DnsError::StringTooLong { len: tag.len() });
+                }
+                buffer.write_u8(flags)?;
+                buffer.write_u8(tag.len() as u8)?;
+                buffer.write_bytes(tag.as_bytes())?;
+                buffer.write_bytes(value)?;
+            }
+            DnsRecord::UNKNOWN { ref data, .. } => {
+                buffer.write_bytes(data)?;
+            }
+        }
+
+        let size = buffer.pos() - (len_pos + 2);
+        buffer.set_u16(len_pos, size as u16)?;
+
+        Ok(buffer.pos() - start_pos)
+    }
+
+    /// The record data alone, as it appears on the wire without compression
+    pub fn rdata(&self) -> Result<Vec<u8>> {
+        let mut buffer = VectorPacketBuffer::uncompressed();
+        self.write(&mut buffer)?;
+        // owner name, then type, class, TTL and length
+        let start = self.domain().wire_len() + 10;
+        Ok(buffer.buffer[start..buffer.pos()].to_vec())
+    }
+
+    pub fn domain(&self) -> &DnsName {
+        match *self {
+            DnsRecord::UNKNOWN { ref domain, .. }
+            | DnsRecord::A { ref domain, .. }
+            | DnsRecord::NS { ref domain, .. }
+            | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::SOA { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
+            | DnsRecord::MX { ref domain, .. }
+            | DnsRecord::TXT { ref domain, .. }
+            | DnsRecord::AAAA { ref domain, .. }
+            | DnsRecord::SRV { ref domain, .. }
+            | DnsRecord::DS { ref domain, .. }
+            | DnsRecord::RRSIG { ref domain, .. }
+            | DnsRecord::NSEC { ref domain, .. }
+            | DnsRecord::DNSKEY { ref domain, .. }
+            | DnsRecord::NSEC3 { ref domain, .. }
+            | DnsRecord::CAA { ref domain, .. } => domain,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => ttl,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut ttl, .. }
+            | DnsRecord::A { ref mut ttl, .. }
+            | DnsRecord::NS { ref mut ttl, .. }
+            | DnsRecord::CNAME { ref mut ttl, .. }
+            | DnsRecord::SOA { ref mut ttl, .. }
+            | DnsRecord::PTR { ref mut ttl, .. }
+            | DnsRecord::MX { ref mut ttl, .. }
+            | DnsRecord::TXT { ref mut ttl, .. }
+            | DnsRecord::AAAA { ref mut ttl, .. }
+            | DnsRecord::SRV { ref mut ttl, .. }
+            | DnsRecord::DS { ref mut ttl, .. }
+            | DnsRecord::RRSIG { ref mut ttl, .. }
+            | DnsRecord::NSEC { ref mut ttl, .. }
+            | DnsRecord::DNSKEY { ref mut ttl, .. }
+            | DnsRecord::NSEC3 { ref mut ttl, .. }
+            | DnsRecord::CAA { ref mut ttl, .. } => *ttl = new_ttl,
+        }
+    }
+
+    /// Whether two records are the same but for their TTL
+    pub fn same_data(&self, other: &DnsRecord) -> bool {
+        let mut this = self.clone();
+        this.set_ttl(other.ttl());
+        this == *other
+    }
+
+    pub fn set_domain(&mut self, new_domain: DnsName) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut domain, .. }
+            | DnsRecord::A { ref mut domain, .. }
+            | DnsRecord::NS { ref mut domain, .. }
+            | DnsRecord::CNAME { ref mut domain, .. }
+            | DnsRecord::SOA { ref mut domain, .. }
+            | DnsRecord::PTR { ref mut domain, .. }
+            | DnsRecord::MX { ref mut domain, .. }
+            | DnsRecord::TXT { ref mut domain, .. }
+            | DnsRecord::AAAA { ref mut domain, .. }
+            | DnsRecord::SRV { ref mut domain, .. }
+            | DnsRecord::DS { ref mut domain, .. }
+            | DnsRecord::RRSIG { ref mut domain, .. }
+            | DnsRecord::NSEC { ref mut domain, .. }
+            | DnsRecord::DNSKEY { ref mut domain, .. }
+            | DnsRecord::NSEC3 { ref mut domain, .. }
+            | DnsRecord::CAA { ref mut domain, .. } => *domain = new_domain,
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+        }
+    }
+}
+
+/// The rest of the rdata, for fields like keys and signatures that have no
+/// length of their own
+fn read_rest<T: PacketBuffer>(buffer: &mut T, data_end: usize) -> Result<Vec<u8>> {
+    let rest = buffer
+        .get_range(buffer.pos(), data_end.saturating_sub(buffer.pos()))?
+        .to_vec();
+    buffer.step(rest.len());
+    Ok(rest)
+}
+
+/// Read the type bitmap that fills the rest of an NSEC or NSEC3 record
+/// (RFC 4034 section 4.1.2): for each window of 256 types in use, the window
+/// number, the length of its bitmap and the bitmap itself
+fn read_type_bitmap<T: PacketBuffer>(
+    buffer: &mut T,
+    qtype: QueryType,
+    data_end: usize,
+) -> Result<Vec<QueryType>> {
+    let mut types = Vec::new();
+    let mut last_window = None;
+    while buffer.pos() < data_end {
+        let window = buffer.read()?;
+        let len = buffer.read()? as usize;
+        if len == 0 || len > 32 {
+            return Err(DnsError::BadRdata {
+                qtype,
+                reason: "type bitmap length out of range",
+            });
+        }
+        if last_window.is_some_and(|last| window <= last) {
+            return Err(DnsError::BadRdata {
+                qtype,
+                reason: "type bitmap windows out of order",
+            });
+        }
+        last_window = Some(window);
+
+        let bitmap = buffer.get_range(buffer.pos(), len)?;
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let num = ((window as u16) << 8) | (i * 8 + bit) as u16;
+                    types.push(QueryType::from_num(num));
+                }
+            }
+        }
+        buffer.step(len);
+    }
+
+    Ok(types)
+}
+
+fn write_type_bitmap<T: PacketBuffer>(buffer: &mut T, types: &[QueryType]) -> Result<()> {
+    let mut nums: Vec<u16> = types.iter().map(QueryType::to_num).collect();
+    nums.sort_unstable();
+    nums.dedup();
+
+    for window in nums.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = [0u8; 32];
+        for num in window {
+            let low = (num & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+        let len = (window[window.len() - 1] & 0xFF) as usize / 8 + 1;
+        buffer.write_u8((window[0] >> 8) as u8)?;
+        buffer.write_u8(len as u8)?;
+        buffer.write_bytes(&bitmap[..len])?;
+    }
+
+    Ok(())
+}
+
+/// Render a name fully qualified, the way it appears in zone files
+fn fqdn(name: &DnsName) -> String {
+    format!("{}.", name)
+}
+
+/// Render the types of an NSEC or NSEC3 bitmap, each preceded by a space
+fn type_list(types: &[QueryType]) -> String {
+    types.iter().map(|qtype| format!(" {}", qtype)).collect()
+}
+
+/// Render a <character-string> quoted, escaping anything non-printable
+fn quoted(bytes: &[u8]) -> String {
+    let mut res = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => {
+                res.push('\\');
+                res.push(b as char);
+            }
+            0x20..=0x7e => res.push(b as char),
+            _ => res.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    res.push('"');
+    res
+}
+
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let class = match self.class {
+            CLASS_IN => "IN".to_string(),
+            3 => "CH".to_string(),
+            4 => "HS".to_string(),
+            CLASS_NONE => "NONE".to_string(),
+            CLASS_ANY => "ANY".to_string(),
+            class => format!("CLASS{}", class),
+        };
+        write!(f, ";{}\t{}\t{}", fqdn(&self.name), class, self.qtype)
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\tIN\t{}\t",
+            fqdn(self.domain()),
+            self.ttl(),
+            self.query_type()
+        )?;
+
+        match *self {
+            DnsRecord::A { ref addr, .. } => write!(f, "{}", addr),
+            DnsRecord::AAAA { ref addr, .. } => write!(f, "{}", addr),
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. } => write!(f, "{}", fqdn(host)),
+            DnsRecord::SOA {
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::MX {
+                priority, ref host, ..
+            } => write!(f, "{} {}", priority, fqdn(host)),
+            DnsRecord::TXT { ref data, .. } => {
+                let strings: Vec<String> = data.iter().map(|s| quoted(s)).collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                ref host,
+                ..
+            } => write!(f, "{} {} {} {}", priority, weight, port, fqdn(host)),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                to_hex(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                format_timestamp(expiration),
+                format_timestamp(inception),
+                key_tag,
+                fqdn(signer_name),
+                to_base64(signature)
+            ),
+            DnsRecord::NSEC {
+                ref next_domain,
+                ref types,
+                ..
+            } => write!(f, "{}{}", fqdn(next_domain), type_list(types)),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                to_base64(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {}{}",
+                hash_algorithm,
+                flags,
+                iterations,
+                if salt.is_empty() {
+                    "-".to_string()
+                } else {
+                    to_hex(salt)
+                },
+                to_base32hex(next_hashed),
+                type_list(types)
+            ),
+            DnsRecord::CAA {
+                flags,
+                ref tag,
+                ref value,
+                ..
+            } => write!(f, "{} {} {}", flags, tag, quoted(value)),
+            DnsRecord::UNKNOWN { ref data, .. } => {
+                // RFC 3597 generic rdata
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                for b in data {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A single option carried in the OPT record, such as a cookie or the
+/// client subnet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The EDNS(0) OPT pseudo-record (RFC 6891). It lives in the additional
+/// section, with its class and TTL fields standing in for the values below.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP response the sender is able to receive
+    pub udp_payload_size: u16,
+    /// Upper 8 bits of the 12 bit result code
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// DNSSEC OK: the sender wants DNSSEC records included (RFC 3225)
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Edns {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// The advertised payload size, where anything below 512 counts as 512
+    pub fn max_payload(&self) -> usize {
+        (self.udp_payload_size as usize).max(MAX_UDP_SIZE)
+    }
+
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<Edns> {
+        let owner = buffer.read_query_name()?;
+        if !owner.is_root() {
+            return Err(DnsError::BadOpt("not owned by the root"));
+        }
+        let _ = buffer.read_u16()?; // type
+        let udp_payload_size = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+        let data_start = buffer.pos();
+        let data_end = data_start + data_len as usize;
+
+        let mut options = Vec::new();
+        while buffer.pos() < data_end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+            buffer.step(len);
+            options.push(EdnsOption { code, data });
+        }
+        if buffer.pos() != data_end {
+            return Err(DnsError::BadRdataLength {
+                qtype: QueryType::OPT,
+                expected: data_len as usize,
+                actual: buffer.pos() - data_start,
+            });
+        }
+
+        Ok(Edns {
+            udp_payload_size,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & 0x8000 > 0,
+            options,
+        })
+    }
+
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_u8(0)?; // root
+        buffer.write_u16(QueryType::OPT.to_num())?;
+        buffer.write_u16(self.udp_payload_size)?;
+        buffer.write_u32(
+            ((self.extended_rcode as u32) << 24)
+                | ((self.version as u32) << 16)
+                | ((self.dnssec_ok as u32) << 15),
+        )?;
+
+        let len_pos = buffer.pos();
+        buffer.write_u16(0)?;
+        for option in &self.options {
+            if option.data.len() > 0xFFFF {
+                return Err(DnsError::BadOpt("option exceeds 65535 bytes"));
+            }
+            buffer.write_u16(option.code)?;
+            buffer.write_u16(option.data.len() as u16)?;
+            buffer.write_bytes(&option.data)?;
+        }
+        let size = buffer.pos() - (len_pos + 2);
+        buffer.set_u16(len_pos, size as u16)?;
+
+        Ok(())
+    }
+}
+
+/// The TSIG pseudo-record (RFC 8945), which authenticates the message it
+/// ends with a MAC keyed by a secret both sides share
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tsig {
+    /// Name of the key, which is the owner of the record
+    pub key_name: DnsName,
+    pub algorithm: DnsName,
+    /// Seconds since the epoch, 48 bits on the wire
+    pub time_signed: u64,
+    /// Seconds of clock skew allowed either side of `time_signed`
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    /// The message id before any forwarder changed it
+    pub original_id: u16,
+    /// An extended result code, such as BADSIG, BADKEY or BADTIME
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+impl Tsig {
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<Tsig> {
+        let key_name = buffer.read_query_name()?;
+        let _ = buffer.read_u16()?; // type
+        let class = buffer.read_u16()?;
+        if class != CLASS_ANY {
+            return Err(DnsError::BadTsig("class is not ANY"));
+        }
+        let _ = buffer.read_u32()?; // TTL
+        let data_len = buffer.read_u16()?;
+        let data_start = buffer.pos();
+
+        let algorithm = buffer.read_query_name()?;
+        let time_signed = ((buffer.read_u16()? as u64) << 32) | buffer.read_u32()? as u64;
+        let fudge = buffer.read_u16()?;
+        let mac_len = buffer.read_u16()? as usize;
+        let mac = buffer.get_range(buffer.pos(), mac_len)?.to_vec();
+        buffer.step(mac_len);
+        let original_id = buffer.read_u16()?;
+        let error = buffer.read_u16()?;
+        let other_len = buffer.read_u16()? as usize;
+        let other = buffer.get_range(buffer.pos(), other_len)?.to_vec();
+        buffer.step(other_len);
+
+        if buffer.pos() != data_start + data_len as usize {
+            return Err(DnsError::BadRdataLength {
+                qtype: QueryType::TSIG,
+                expected: data_len as usize,
+                actual: buffer.pos() - data_start,
+            });
+        }
+
+        Ok(Tsig {
+            key_name,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    /// Write the record, with names uncompressed as the MAC covers them
+    /// that way
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_qname_uncompressed(&self.key_name)?;
+        buffer.write_u16(QueryType::TSIG.to_num())?;
+        buffer.write_u16(CLASS_ANY)?;
+        buffer.write_u32(0)?;
+
+        let len_pos = buffer.pos();
+        buffer.write_u16(0)?;
+        buffer.write_qname_uncompressed(&self.algorithm)?;
+        buffer.write_u16((self.time_signed >> 32) as u16)?;
+        buffer.write_u32(self.time_signed as u32)?;
+        buffer.write_u16(self.fudge)?;
+        buffer.write_u16(self.mac.len() as u16)?;
+        buffer.write_bytes(&self.mac)?;
+        buffer.write_u16(self.original_id)?;
+        buffer.write_u16(self.error)?;
+        buffer.write_u16(self.other.len() as u16)?;
+        buffer.write_bytes(&self.other)?;
+        let size = buffer.pos() - (len_pos + 2);
+        buffer.set_u16(len_pos, size as u16)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub resources: Vec<DnsRecord>,
+    /// The OPT record, kept apart from the other additional records
+    pub edns: Option<Edns>,
+    /// The TSIG record, which has to come last
+    pub tsig: Option<Tsig>,
+}
+
+impl Default for DnsPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsPacket {
+    pub fn new() -> DnsPacket {
+        DnsPacket {
+            header: DnsHeader::new(),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            resources: Vec::new(),
+            edns: None,
+            tsig: None,
+        }
+    }
+
+    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<DnsPacket> {
+        let mut result = DnsPacket::new();
+        result.header.read(buffer)?;
+
+        for _ in 0..result.header.questions {
+            let mut question = DnsQuestion::new(DnsName::root(), QueryType::UNKNOWN(0));
+            question.read(buffer)?;
+            result.questions.push(question);
+        }
+
+        for _ in 0..result.header.answers {
+            let rec = DnsRecord::read(buffer)?;
+            result.answers.push(rec);
+        }
+        for _ in 0..result.header.authoritative_entries {
+            let rec = DnsRecord::read(buffer)?;
+            result.authorities.push(rec);
+        }
+        for i in 0..result.header.resource_entries {
+            // peek at the type to pick out the OPT and TSIG records
+            let start = buffer.pos();
+            buffer.read_query_name()?;
+            let qtype = QueryType::from_num(buffer.read_u16()?);
+            buffer.seek(start);
+
+            if qtype == QueryType::TSIG {
+                if i + 1 != result.header.resource_entries {
+                    return Err(DnsError::BadTsig("not the last record"));
+                }
+                result.tsig = Some(Tsig::read(buffer)?);
+            } else if qtype == QueryType::OPT {
+                if result.edns.is_some() {
+                    return Err(DnsError::BadOpt("more than one in the message"));
+                }
+                result.edns = Some(Edns::read(buffer)?);
+            } else {
+                let rec = DnsRecord::read(buffer)?;
+                result.resources.push(rec);
+            }
+        }
+
+        if let Some(ref edns) = result.edns {
+            let rescode = ((edns.extended_rcode as u16) << 4) | result.header.rescode.to_num();
+            result.header.rescode = ResultCode::from_num(rescode);
+        }
+
+        Ok(result)
+    }
+
+    /// Write the packet, updating the header counts to match the sections
+    pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = (self.resources.len()
+            + self.edns.is_some() as usize
+            + self.tsig.is_some() as usize) as u16;
+        if let Some(ref mut edns) = self.edns {
+            edns.extended_rcode = (self.header.rescode.to_num() >> 4) as u8;
+        }
+
+        self.header.write(buffer)?;
+
+        for question in &self.questions {
+            question.write(buffer)?;
+        }
+        for rec in &self.answers {
+            rec.write(buffer)?;
+        }
+        for rec in &self.authorities {
+            rec.write(buffer)?;
+        }
+        for rec in &self.resources {
+            rec.write(buffer)?;
+        }
+        if let Some(ref edns) = self.edns {
+            edns.write(buffer)?;
+        }
+        if let Some(ref tsig) = self.tsig {
+            tsig.write(buffer)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for DnsPacket {
+    /// Pretty print the packet in the same layout `dig` uses
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            header.opcode, header.rescode, header.id
+        )?;
+
+        let flags: Vec<&str> = [
+            (header.response, "qr"),
+            (header.authoritative_answer, "aa"),
+            (header.truncated_message, "tc"),
+            (header.recursion_desired, "rd"),
+            (header.recursion_available, "ra"),
+            (header.authed_data, "ad"),
+            (header.checking_disabled, "cd"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect();
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len() + self.edns.is_some() as usize + self.tsig.is_some() as usize
+        )?;
+
+        if let Some(ref edns) = self.edns {
+            writeln!(f, "\n;; OPT PSEUDOSECTION:")?;
+            writeln!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                edns.version,
+                if edns.dnssec_ok { " do" } else { "" },
+                edns.udp_payload_size
+            )?;
+        }
+
+        if let Some(ref tsig) = self.tsig {
+            writeln!(f, "\n;; TSIG PSEUDOSECTION:")?;
+            writeln!(
+                f,
+                "; key: {}, algorithm: {}, error: {}",
+                tsig.key_name, tsig.algorithm, tsig.error
+            )?;
+        }
+
+        if !self.questions.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, "{}", question)?;
+            }
+        }
+
+        for (title, records) in [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.resources),
+        ] {
+            if records.is_empty() {
+                continue;
+            }
+            writeln!(f, "\n;; {} SECTION:", title)?;
+            for rec in records {
+                writeln!(f, "{}", rec)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+    use proptest::prelude::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buffer[..bytes.len()].copy_from_slice(bytes);
+        buffer
+    }
+
+    // response to `dig google.com A`, captured off the wire
+    const GOOGLE_RESPONSE: [u8; 44] = [
+        0x86, 0x2a, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x06, 0x67, 0x6f,
+        0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0, 0x0c,
+        0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x25, 0x00, 0x04, 0xd8, 0x3a, 0xd3, 0x8e,
+    ];
+
+    #[test]
+    fn test_parse_captured_response() {
+        let mut buffer = buffer_from(&GOOGLE_RESPONSE);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(packet.header.id, 0x862a);
+        assert!(packet.header.response);
+        assert!(packet.header.recursion_desired);
+        assert!(packet.header.recursion_available);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+
+        assert_eq!(
+            packet.questions,
+            vec![DnsQuestion::new(name("google.com"), QueryType::A)]
+        );
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::A {
+                domain: name("google.com"),
+                addr: Ipv4Addr::new(216, 58, 211, 142),
+                ttl: 293,
+            }]
+        );
+        assert!(packet.authorities.is_empty());
+        assert!(packet.resources.is_empty());
+        assert_eq!(buffer.pos(), GOOGLE_RESPONSE.len());
+    }
+
+    #[test]
+    fn test_parse_all_sections() {
+        // example.com A: one answer, one NS in authority, one glue A in additional
+        let bytes = [
+            0x00, 0x01, 0x84, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, // header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // question
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, 93, 184, 216,
+            34, // answer
+            0xc0, 0x0c, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x06, 0x03, b'n',
+            b's', b'1', 0xc0, 0x0c, // authority, NS ns1.example.com
+            0xc0, 0x39, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, 10, 0, 0,
+            1, // additional, glue for ns1.example.com
+        ];
+        let mut buffer = buffer_from(&bytes);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(
+            packet.authorities,
+            vec![DnsRecord::NS {
+                domain: name("example.com"),
+                host: name("ns1.example.com"),
+                ttl: 3600,
+            }]
+        );
+        assert_eq!(
+            packet.resources,
+            vec![DnsRecord::A {
+                domain: name("ns1.example.com"),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 3600,
+            }]
+        );
+        assert_eq!(buffer.pos(), bytes.len());
+    }
+
+    #[test]
+    fn test_write_captured_response() {
+        let mut buffer = buffer_from(&GOOGLE_RESPONSE);
+        let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut out = BytePacketBuffer::new();
+        packet.write(&mut out).unwrap();
+
+        // the answer points back at the question name, just like the capture
+        assert_eq!(&out.buffer[..out.pos()], &GOOGLE_RESPONSE[..]);
+    }
+
+    #[test]
+    fn test_write_qname_compresses_suffixes() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&name("www.example.com")).unwrap();
+        buffer.write_qname(&name("mail.example.com")).unwrap();
+        buffer.write_qname(&name("example.com")).unwrap();
+        buffer.write_qname(&name("www.example.com")).unwrap();
+        buffer.write_qname(&name("example.org")).unwrap();
+
+        let expected: &[u8] = &[
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, // www.example.com at 0
+            4, b'm', b'a', b'i', b'l', 0xc0, 4, // mail + pointer to example.com
+            0xc0, 4, // example.com
+            0xc0, 0, // www.example.com
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'o', b'r', b'g', 0,
+        ];
+        assert_eq!(&buffer.buffer[..buffer.pos()], expected);
+
+        buffer.seek(0);
+        for name in [
+            "www.example.com",
+            "mail.example.com",
+            "example.com",
+            "www.example.com",
+            "example.org",
+        ] {
+            assert_eq!(buffer.read_query_name().unwrap(), name);
+        }
+        assert_eq!(buffer.pos(), expected.len());
+    }
+
+    #[test]
+    fn test_write_qname_uncompressed() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&name("example.com")).unwrap();
+        buffer
+            .write_qname_uncompressed(&name("example.com"))
+            .unwrap();
+
+        assert_eq!(buffer.pos(), 26);
+        assert_eq!(buffer.buffer[..13], buffer.buffer[13..26]);
+    }
+
+    #[test]
+    fn test_compression_keeps_zone_under_512_bytes() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new(
+            name("hosts.internal.corp.example.com"),
+            QueryType::A,
+        ));
+        for i in 0..20 {
+            packet.answers.push(DnsRecord::A {
+                domain: name(&format!("host{}.internal.corp.example.com", i)),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 300,
+            });
+        }
+
+        // uncompressed, the answers alone would take 20 * (33 + 14) bytes
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        assert!(buffer.pos() < 512);
+
+        buffer.seek(0);
+        assert_eq!(DnsPacket::from_buffer(&mut buffer).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_parse_rdata_with_compressed_names() {
+        // example.com MX and SOA, with every name in rdata pointing back
+        let bytes = [
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x0f, 0x00, 0x01, // question
+            0xc0, 0x0c, 0x00, 0x0f, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x09, 0x00, 0x0a,
+            0x04, b'm', b'a', b'i', b'l', 0xc0, 0x0c, // MX 10 mail.example.com
+            0xc0, 0x0c, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x22, 0x03, b'n',
+            b's', b'1', 0xc0, 0x0c, 0x05, b'a', b'd', b'm', b'i', b'n', 0xc0, 0x0c, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x1c, 0x20, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x12, 0x75, 0x00,
+            0x00, 0x00, 0x01, 0x2c, // SOA
+        ];
+        let mut buffer = buffer_from(&bytes);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(packet.questions[0].qtype, QueryType::MX);
+        assert_eq!(
+            packet.answers,
+            vec![
+                DnsRecord::MX {
+                    domain: name("example.com"),
+                    priority: 10,
+                    host: name("mail.example.com"),
+                    ttl: 300,
+                },
+                DnsRecord::SOA {
+                    domain: name("example.com"),
+                    mname: name("ns1.example.com"),
+                    rname: name("admin.example.com"),
+                    serial: 1,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 300,
+                    ttl: 300,
+                }
+            ]
+        );
+        assert_eq!(buffer.pos(), bytes.len());
+    }
+
+    #[test]
+    fn test_parse_txt_and_caa() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::TXT {
+            domain: name("example.com"),
+            data: vec![b"v=spf1 -all".to_vec(), vec![0, 1, 2]],
+            ttl: 60,
+        });
+        packet.answers.push(DnsRecord::CAA {
+            domain: name("example.com"),
+            flags: 0,
+            tag: "issue".to_string(),
+            value: b"letsencrypt.org".to_vec(),
+            ttl: 60,
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+
+        // TXT rdata: 1 + 11 + 1 + 3 bytes, after the 12 byte header and
+        // 13 byte name plus 8 bytes of type, class and ttl
+        assert_eq!(&buffer.buffer[33..35], &[0x00, 16]);
+
+        buffer.seek(0);
+        assert_eq!(DnsPacket::from_buffer(&mut buffer).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_unknown_record_forwarded_unchanged() {
+        let bytes = [
+            0x00, 0x07, 0x81, 0x80, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x03, b'f', b'o', b'o', 0x00, 0x00, 0x63, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00,
+            0x05, 0xde, 0xad, 0xbe, 0xef, 0x00, // type 99 with 5 bytes of rdata
+        ];
+        let mut buffer = buffer_from(&bytes);
+        let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::UNKNOWN {
+                domain: name("foo"),
+                qtype: 99,
+                data: vec![0xde, 0xad, 0xbe, 0xef, 0x00],
+                ttl: 60,
+            }]
+        );
+
+        let mut out = BytePacketBuffer::new();
+        packet.write(&mut out).unwrap();
+        assert_eq!(&out.buffer[..out.pos()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_rdata_length_mismatch() {
+        // an A record claiming 5 bytes of rdata
+        let bytes = [
+            0x00, 0x07, 0x81, 0x80, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x05, 1, 2, 3, 4, 5,
+        ];
+        let mut buffer = buffer_from(&bytes);
+        assert_eq!(
+            DnsPacket::from_buffer(&mut buffer),
+            Err(DnsError::BadRdataLength {
+                qtype: QueryType::A,
+                expected: 5,
+                actual: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_pointer_loop() {
+        // a question name pointing at itself
+        let bytes = [
+            0x00, 0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01,
+        ];
+        let mut buffer = buffer_from(&bytes);
+        assert_eq!(
+            DnsPacket::from_buffer(&mut buffer),
+            Err(DnsError::ForwardPointer { offset: 12 })
+        );
+    }
+
+    #[test]
+    fn test_read_last_byte_of_buffer() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buffer[511] = 0x2a;
+        buffer.seek(511);
+        assert_eq!(buffer.read(), Ok(0x2a));
+        assert_eq!(buffer.read(), Err(DnsError::BufferOverrun { offset: 512 }));
+        assert_eq!(
+            buffer.get(512),
+            Err(DnsError::BufferOverrun { offset: 512 })
+        );
+        assert!(buffer.get_range(511, 1).is_ok());
+        assert!(buffer.get_range(512, 1).is_err());
+    }
+
+    #[test]
+    fn test_names_keep_case_and_bytes() {
+        let mixed = name("wWw.ExAmPlE.cOm");
+        let binary = DnsName::from_labels(vec![vec![0, b'.', 0xff], b"Example".to_vec()]).unwrap();
+
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&name("mail.example.com")).unwrap();
+        buffer.write_qname(&mixed).unwrap();
+        buffer.write_qname(&binary).unwrap();
+
+        // a suffix written earlier in another case must not be reused
+        buffer.seek(0);
+        buffer.read_query_name().unwrap();
+        assert!(buffer.read_query_name().unwrap().eq_case_sensitive(&mixed));
+        assert!(buffer.read_query_name().unwrap().eq_case_sensitive(&binary));
+    }
+
+    /// Messages that broke, or were built to break, the parser. They live
+    /// in `fuzz/regressions` so the fuzz targets can be seeded with them.
+    #[test]
+    fn test_regression_corpus() {
+        let expected = [
+            ("read-at-512.bin", DnsError::BufferOverrun { offset: 512 }),
+            (
+                "forward-pointer.bin",
+                DnsError::ForwardPointer { offset: 12 },
+            ),
+            ("self-pointer.bin", DnsError::ForwardPointer { offset: 12 }),
+            ("pointer-chain.bin", DnsError::PointerLoop { offset: 49 }),
+            ("long-label.bin", DnsError::LabelTooLong { len: 64 }),
+            ("long-name.bin", DnsError::NameTooLong { len: 257 }),
+            (
+                "rdlength-past-end.bin",
+                DnsError::BufferOverrun { offset: 23 },
+            ),
+            (
+                "txt-string-past-rdata.bin",
+                DnsError::BadRdataLength {
+                    qtype: QueryType::TXT,
+                    expected: 2,
+                    actual: 6,
+                },
+            ),
+            (
+                "opt-not-root.bin",
+                DnsError::BadOpt("not owned by the root"),
+            ),
+            (
+                "truncated-header.bin",
+                DnsError::BufferOverrun { offset: 5 },
+            ),
+            ("unknown-class.bin", DnsError::UnknownClass(9)),
+        ];
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions");
+        let mut checked = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let bytes = fs::read(&path).unwrap();
+
+            let mut buffer = VectorPacketBuffer::from_bytes(&bytes);
+            let result = DnsPacket::from_buffer(&mut buffer).map(|_| ());
+            if let Some((_, err)) = expected.iter().find(|(file, _)| *file == name) {
+                assert_eq!(result, Err(err.clone()), "{}", name);
+                checked += 1;
+            }
+
+            // the fixed size buffer pads with zeros, so only a full one is
+            // guaranteed to give the same result
+            if bytes.len() <= 512 {
+                let mut buffer = buffer_from(&bytes);
+                let padded = DnsPacket::from_buffer(&mut buffer).map(|_| ());
+                if bytes.len() == 512 {
+                    assert_eq!(padded, result, "{}", name);
+                }
+            }
+        }
+        assert_eq!(checked, expected.len());
+    }
+
+    #[test]
+    fn test_unknown_class() {
+        let bytes = [
+            0x00, 0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+            0x00, 0x00, 0x01, 0x00, 0x09,
+        ];
+        let mut buffer = buffer_from(&bytes);
+        assert_eq!(
+            DnsPacket::from_buffer(&mut buffer),
+            Err(DnsError::UnknownClass(9))
+        );
+    }
+
+    #[test]
+    fn test_query_type_names() {
+        assert_eq!("mx".parse::<QueryType>().unwrap(), QueryType::MX);
+        assert_eq!("TYPE28".parse::<QueryType>().unwrap(), QueryType::AAAA);
+        assert_eq!(
+            "TYPE65280".parse::<QueryType>().unwrap(),
+            QueryType::UNKNOWN(65280)
+        );
+        assert!("BOGUS".parse::<QueryType>().is_err());
+        assert_eq!(QueryType::UNKNOWN(65280).to_string(), "TYPE65280");
+    }
+
+    #[test]
+    fn test_display_packet() {
+        let mut buffer = buffer_from(&GOOGLE_RESPONSE);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let expected = "\
+;; ->>HEADER<<- opcode: 0, status: NOERROR, id: 34346
+;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 0
+
+;; QUESTION SECTION:
+;google.com.\tIN\tA
+
+;; ANSWER SECTION:
+google.com.\t293\tIN\tA\t216.58.211.142
+";
+        assert_eq!(packet.to_string(), expected);
+
+        let txt = DnsRecord::TXT {
+            domain: name("example.com"),
+            data: vec![b"say \"hi\"".to_vec(), vec![7]],
+            ttl: 60,
+        };
+        assert_eq!(
+            txt.to_string(),
+            "example.com.\t60\tIN\tTXT\t\"say \\\"hi\\\"\" \"\\007\""
+        );
+    }
+
+    #[test]
+    fn test_vector_buffer_grows_past_512_bytes() {
+        let mut packet = DnsPacket::new();
+        for i in 0..100 {
+            packet.answers.push(DnsRecord::A {
+                domain: name(&format!("host{}.example.com", i)),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 300,
+            });
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        assert!(packet.write(&mut buffer).is_err());
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        assert!(buffer.pos() > 512);
+
+        let mut parsed = VectorPacketBuffer::from_bytes(&buffer.buffer);
+        assert_eq!(DnsPacket::from_buffer(&mut parsed).unwrap(), packet);
+
+        let mut limited = VectorPacketBuffer::with_limit(600);
+        assert!(packet.write(&mut limited).is_err());
+    }
+
+    #[test]
+    fn test_vector_buffer_bounds() {
+        let mut buffer = VectorPacketBuffer::from_bytes(&[1, 2, 3]);
+        assert_eq!(buffer.read_u16().unwrap(), 0x0102);
+        assert_eq!(
+            buffer.read_u16(),
+            Err(DnsError::BufferOverrun { offset: 3 })
+        );
+        assert!(buffer.get_range(2, 2).is_err());
+    }
+
+    #[test]
+    fn test_write_label_rejects_long_label() {
+        let mut buffer = BytePacketBuffer::new();
+        assert_eq!(
+            buffer.write_label(&[b'a'; 64]),
+            Err(DnsError::LabelTooLong { len: 64 })
+        );
+    }
+
+    #[test]
+    fn test_write_past_end_of_buffer() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.seek(510);
+        buffer.write_u16(0xbeef).unwrap();
+        assert_eq!(
+            buffer.write_u8(0),
+            Err(DnsError::BufferOverrun { offset: 512 })
+        );
+    }
+
+    #[test]
+    fn test_parse_opt_record() {
+        // query for example.com A with an OPT record: 4096 byte payload,
+        // DO set and a client cookie option
+        let bytes = [
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // question
+            0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x0c, // OPT
+            0x00, 0x0a, 0x00, 0x08, 1, 2, 3, 4, 5, 6, 7, 8, // cookie
+        ];
+        let mut buffer = buffer_from(&bytes);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert!(packet.resources.is_empty());
+        assert_eq!(
+            packet.edns,
+            Some(Edns {
+                udp_payload_size: 4096,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: true,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                }],
+            })
+        );
+        assert_eq!(buffer.pos(), bytes.len());
+
+        // and back to the same bytes
+        let mut packet = packet;
+        let mut written = BytePacketBuffer::new();
+        packet.write(&mut written).unwrap();
+        assert_eq!(&written.buffer[..written.pos()], &bytes[..]);
+    }
+
+    #[test]
+    fn test_extended_rcode() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::BADVERS;
+        packet.edns = Some(Edns::new(1232));
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        // the low bits in the header, the rest in the OPT record
+        assert_eq!(buffer.buffer[3] & 0x0F, 0);
+        assert_eq!(packet.edns.as_ref().unwrap().extended_rcode, 1);
+
+        buffer.seek(0);
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.header.rescode, ResultCode::BADVERS);
+        assert!(parsed
+            .to_string()
+            .contains("; EDNS: version: 0, flags:; udp: 1232"));
+    }
+
+    #[test]
+    fn test_unassigned_rcodes() {
+        // in the header alone, and with the upper bits from the OPT record
+        for (num, edns) in [(12, None), (15, None), (40, Some(Edns::new(1232)))] {
+            let mut packet = DnsPacket::new();
+            packet.header.response = true;
+            packet.header.rescode = ResultCode::from_num(num);
+            packet.edns = edns;
+
+            let mut buffer = BytePacketBuffer::new();
+            packet.write(&mut buffer).unwrap();
+            buffer.seek(0);
+            let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+            assert_eq!(parsed.header.rescode, ResultCode::UNKNOWN(num));
+            assert_ne!(parsed.header.rescode, ResultCode::NOERROR);
+        }
+    }
+
+    #[test]
+    fn test_rejects_second_opt_record() {
+        let mut buffer = BytePacketBuffer::new();
+        let mut packet = DnsPacket::new();
+        packet.header.resource_entries = 2;
+        packet.header.write(&mut buffer).unwrap();
+        Edns::new(512).write(&mut buffer).unwrap();
+        Edns::new(512).write(&mut buffer).unwrap();
+
+        buffer.seek(0);
+        assert_eq!(
+            DnsPacket::from_buffer(&mut buffer),
+            Err(DnsError::BadOpt("more than one in the message"))
+        );
+    }
+
+    #[test]
+    fn test_tsig_must_come_last() {
+        let tsig = Tsig {
+            key_name: name("key.example"),
+            algorithm: name("hmac-sha256"),
+            time_signed: 1_700_000_000,
+            fudge: 300,
+            mac: vec![0xAB; 32],
+            original_id: 7,
+            error: 0,
+            other: Vec::new(),
+        };
+        let mut buffer = BytePacketBuffer::new();
+        let mut packet = DnsPacket::new();
+        packet.header.resource_entries = 2;
+        packet.header.write(&mut buffer).unwrap();
+        tsig.write(&mut buffer).unwrap();
+        Edns::new(512).write(&mut buffer).unwrap();
+
+        buffer.seek(0);
+        assert_eq!(
+            DnsPacket::from_buffer(&mut buffer),
+            Err(DnsError::BadTsig("not the last record"))
+        );
+    }
+
+    fn arb_name() -> impl Strategy<Value = DnsName> {
+        prop::collection::vec(prop::collection::vec(any::<u8>(), 1..10), 0..4)
+            .prop_map(|labels| DnsName::from_labels(labels).unwrap())
+    }
+
+    fn arb_rescode() -> impl Strategy<Value = ResultCode> {
+        (0u16..=10).prop_map(ResultCode::from_num)
+    }
+
+    fn arb_header() -> impl Strategy<Value = DnsHeader> {
+        (any::<u16>(), any::<[bool; 8]>(), 0u8..16, arb_rescode()).prop_map(
+            |(id, flags, opcode, rescode)| DnsHeader {
+                id,
+                recursion_desired: flags[0],
+                truncated_message: flags[1],
+                authoritative_answer: flags[2],
+                opcode,
+                response: flags[3],
+                rescode,
+                checking_disabled: flags[4],
+                authed_data: flags[5],
+                z: flags[6],
+                recursion_available: flags[7],
+                ..DnsHeader::new()
+            },
+        )
+    }
+
+    fn arb_question() -> impl Strategy<Value = DnsQuestion> {
+        (
+            arb_name(),
+            prop_oneof![Just(1u16), 2u16..300],
+            prop::sample::select(vec![1u16, 3, 4, 254, 255]),
+        )
+            .prop_map(|(name, qtype, class)| DnsQuestion {
+                class,
+                ..DnsQuestion::new(name, QueryType::from_num(qtype))
+            })
+    }
+
+    /// Type bitmaps read back in numeric order, so generate them that way
+    fn arb_types() -> impl Strategy<Value = Vec<QueryType>> {
+        prop::collection::btree_set(1u16..1300, 0..6)
+            .prop_map(|nums| nums.into_iter().map(QueryType::from_num).collect())
+    }
+
+    fn arb_record() -> impl Strategy<Value = DnsRecord> {
+        let bytes = || prop::collection::vec(any::<u8>(), 0..16);
+        let rdata = prop_oneof![
+            any::<[u8; 4]>().prop_map(|addr| DnsRecord::A {
+                domain: DnsName::root(),
+                addr: Ipv4Addr::from(addr),
+                ttl: 0,
+            }),
+            any::<[u8; 16]>().prop_map(|addr| DnsRecord::AAAA {
+                domain: DnsName::root(),
+                addr: Ipv6Addr::from(addr),
+                ttl: 0,
+            }),
+            arb_name().prop_map(|host| DnsRecord::NS {
+                domain: DnsName::root(),
+                host,
+                ttl: 0,
+            }),
+            arb_name().prop_map(|host| DnsRecord::CNAME {
+                domain: DnsName::root(),
+                host,
+                ttl: 0,
+            }),
+            arb_name().prop_map(|host| DnsRecord::PTR {
+                domain: DnsName::root(),
+                host,
+                ttl: 0,
+            }),
+            (arb_name(), arb_name(), any::<[u32; 5]>()).prop_map(|(mname, rname, nums)| {
+                DnsRecord::SOA {
+                    domain: DnsName::root(),
+                    mname,
+                    rname,
+                    serial: nums[0],
+                    refresh: nums[1],
+                    retry: nums[2],
+                    expire: nums[3],
+                    minimum: nums[4],
+                    ttl: 0,
+                }
+            }),
+            (any::<u16>(), arb_name()).prop_map(|(priority, host)| DnsRecord::MX {
+                domain: DnsName::root(),
+                priority,
+                host,
+                ttl: 0,
+            }),
+            prop::collection::vec(bytes(), 1..3).prop_map(|data| DnsRecord::TXT {
+                domain: DnsName::root(),
+                data,
+                ttl: 0,
+            }),
+            (any::<[u16; 3]>(), arb_name()).prop_map(|(nums, host)| DnsRecord::SRV {
+                domain: DnsName::root(),
+                priority: nums[0],
+                weight: nums[1],
+                port: nums[2],
+                host,
+                ttl: 0,
+            }),
+            (any::<u8>(), "[a-z]{1,10}", bytes()).prop_map(|(flags, tag, value)| {
+                DnsRecord::CAA {
+                    domain: DnsName::root(),
+                    flags,
+                    tag,
+                    value,
+                    ttl: 0,
+                }
+            }),
+            (any::<(u16, u8, u8)>(), bytes()).prop_map(|(nums, digest)| DnsRecord::DS {
+                domain: DnsName::root(),
+                key_tag: nums.0,
+                algorithm: nums.1,
+                digest_type: nums.2,
+                digest,
+                ttl: 0,
+            }),
+            (any::<(u16, u8, u8, u32, u32, u32)>(), arb_name(), bytes()).prop_map(
+                |(nums, signer_name, signature)| DnsRecord::RRSIG {
+                    domain: DnsName::root(),
+                    type_covered: QueryType::from_num(nums.0),
+                    algorithm: nums.1,
+                    labels: nums.2,
+                    original_ttl: nums.3,
+                    expiration: nums.4,
+                    inception: nums.5,
+                    key_tag: nums.0,
+                    signer_name,
+                    signature,
+                    ttl: 0,
+                }
+            ),
+            (arb_name(), arb_types()).prop_map(|(next_domain, types)| DnsRecord::NSEC {
+                domain: DnsName::root(),
+                next_domain,
+                types,
+                ttl: 0,
+            }),
+            (any::<(u16, u8, u8)>(), bytes()).prop_map(|(nums, public_key)| {
+                DnsRecord::DNSKEY {
+                    domain: DnsName::root(),
+                    flags: nums.0,
+                    protocol: nums.1,
+                    algorithm: nums.2,
+                    public_key,
+                    ttl: 0,
+                }
+            }),
+            (
+                any::<(u8, u8, u16)>(),
+                bytes(),
+                prop::collection::vec(any::<u8>(), 1..21),
+                arb_types()
+            )
+                .prop_map(|(nums, salt, next_hashed, types)| DnsRecord::NSEC3 {
+                    domain: DnsName::root(),
+                    hash_algorithm: nums.0,
+                    flags: nums.1,
+                    iterations: nums.2,
+                    salt,
+                    next_hashed,
+                    types,
+                    ttl: 0,
+                }),
+            (65280u16.., bytes()).prop_map(|(qtype, data)| DnsRecord::UNKNOWN {
+                domain: DnsName::root(),
+                qtype,
+                data,
+                ttl: 0,
+            }),
+        ];
+
+        (arb_name(), any::<u32>(), rdata).prop_map(|(name, ttl, mut record)| {
+            record.set_domain(name);
+            record.set_ttl(ttl);
+            record
+        })
+    }
+
+    fn arb_edns() -> impl Strategy<Value = Edns> {
+        (
+            any::<u16>(),
+            any::<u8>(),
+            any::<bool>(),
+            prop::collection::vec(
+                (any::<u16>(), prop::collection::vec(any::<u8>(), 0..8))
+                    .prop_map(|(code, data)| EdnsOption { code, data }),
+                0..3,
+            ),
+        )
+            .prop_map(|(udp_payload_size, version, dnssec_ok, options)| Edns {
+                udp_payload_size,
+                extended_rcode: 0,
+                version,
+                dnssec_ok,
+                options,
+            })
+    }
+
+    fn arb_tsig() -> impl Strategy<Value = Tsig> {
+        (
+            arb_name(),
+            arb_name(),
+            0u64..1 << 48,
+            any::<u16>(),
+            prop::collection::vec(any::<u8>(), 0..32),
+            any::<u16>(),
+            any::<u16>(),
+            prop::collection::vec(any::<u8>(), 0..6),
+        )
+            .prop_map(
+                |(key_name, algorithm, time_signed, fudge, mac, original_id, error, other)| Tsig {
+                    key_name,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other,
+                },
+            )
+    }
+
+    fn arb_packet() -> impl Strategy<Value = DnsPacket> {
+        (
+            arb_header(),
+            prop::collection::vec(arb_question(), 0..3),
+            prop::collection::vec(arb_record(), 0..3),
+            prop::collection::vec(arb_record(), 0..3),
+            prop::collection::vec(arb_record(), 0..3),
+            prop::option::of(arb_edns()),
+            prop::option::of(arb_tsig()),
+        )
+            .prop_map(
+                |(header, questions, answers, authorities, resources, edns, tsig)| DnsPacket {
+                    header,
+                    questions,
+                    answers,
+                    authorities,
+                    resources,
+                    edns,
+                    tsig,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_packet_round_trip(mut packet in arb_packet()) {
+            let mut buffer = BytePacketBuffer::new();
+            // large random packets may simply not fit in 512 bytes
+            prop_assume!(packet.write(&mut buffer).is_ok());
+            let written = buffer.pos();
+
+            buffer.seek(0);
+            let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+            prop_assert_eq!(parsed, packet);
+            prop_assert_eq!(buffer.pos(), written);
+        }
+
+        #[test]
+        fn prop_header_round_trip(header in arb_header()) {
+            let mut buffer = BytePacketBuffer::new();
+            header.write(&mut buffer).unwrap();
+
+            buffer.seek(0);
+            let mut parsed = DnsHeader::new();
+            parsed.read(&mut buffer).unwrap();
+            prop_assert_eq!(parsed, header);
+        }
+    }
+}
@@ -0,0 +1,580 @@
+//! Query logging. Every query a server answers can be written out, either as
+//! one JSON object per line or as dnstap messages in Frame Streams framing,
+//! the format `dnstap-read`, `fstrm_capture` and friends understand.
+
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::packets::{DnsPacket, PacketBuffer, VectorPacketBuffer};
+use crate::time::civil_from_days;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Content type of Frame Streams carrying dnstap
+const DNSTAP_CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frames and fields
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+/// Largest control frame we accept from a collector
+const MAX_CONTROL_FRAME: usize = 512;
+
+/// Entries waiting to be written, beyond which new ones are dropped
+const LOG_QUEUE_SIZE: usize = 4096;
+
+// dnstap.proto enum values
+const DNSTAP_MESSAGE: u64 = 1;
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+const FAMILY_INET: u64 = 1;
+const FAMILY_INET6: u64 = 2;
+
+/// How a query reached the server
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+        }
+    }
+
+    /// The dnstap `SocketProtocol` value
+    fn dnstap_protocol(&self) -> u64 {
+        match *self {
+            Transport::Udp => 1,
+            Transport::Tcp => 2,
+            Transport::Tls => 3,
+            Transport::Https => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON object per line
+    Json,
+    /// dnstap protobuf messages in Frame Streams
+    Dnstap,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<LogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(LogFormat::Json),
+            "dnstap" => Ok(LogFormat::Dnstap),
+            _ => Err(format!("Invalid query log format: {}", s)),
+        }
+    }
+}
+
+/// A query and how it was answered
+#[derive(Clone, Debug)]
+pub struct QueryEvent<'a> {
+    pub client: SocketAddr,
+    pub transport: Transport,
+    /// When the query arrived
+    pub time: SystemTime,
+    /// How long it took to answer
+    pub latency: Duration,
+    /// The query, if it could be parsed
+    pub query: Option<&'a DnsPacket>,
+    pub response: &'a DnsPacket,
+    pub cache_hit: bool,
+    /// The upstream server that answered, when forwarding
+    pub upstream: Option<SocketAddr>,
+}
+
+/// Where queries get logged to, safe to share between server threads.
+/// Entries are written out on a thread of their own, so a slow disk or
+/// collector never holds up answering; when it falls too far behind,
+/// entries are dropped instead.
+pub struct QueryLog {
+    format: LogFormat,
+    /// Taken on drop, which tells the writer to finish
+    entries: Option<SyncSender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: AtomicU64,
+}
+
+impl QueryLog {
+    /// Log to `out`, which for dnstap starts a Frame Stream
+    pub fn new(format: LogFormat, mut out: Box<dyn Write + Send>) -> Result<QueryLog> {
+        if format == LogFormat::Dnstap {
+            out.write_all(&control_frame(CONTROL_START, true))?;
+            out.flush()?;
+        }
+        let (entries, pending) = mpsc::sync_channel(LOG_QUEUE_SIZE);
+        let writer = thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || write_entries(format, out, pending))?;
+
+        Ok(QueryLog {
+            format,
+            entries: Some(entries),
+            writer: Some(writer),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Log to a file, appending to it, or to a collector listening on a
+    /// Unix socket when `target` is `unix:path`
+    pub fn open(format: LogFormat, target: &str) -> Result<QueryLog> {
+        if let Some(path) = target.strip_prefix("unix:") {
+            return QueryLog::connect(format, path);
+        }
+        let file = OpenOptions::new().create(true).append(true).open(target)?;
+        QueryLog::new(format, Box::new(file))
+    }
+
+    #[cfg(unix)]
+    fn connect(format: LogFormat, path: &str) -> Result<QueryLog> {
+        let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+        if format == LogFormat::Dnstap {
+            // bidirectional Frame Streams: the collector has to accept our
+            // content type before we start
+            stream.write_all(&control_frame(CONTROL_READY, true))?;
+            let accepted = read_control_frame(&mut stream)?;
+            if accepted != CONTROL_ACCEPT {
+                return Err(format!(
+                    "Collector sent control frame {} instead of ACCEPT",
+                    accepted
+                )
+                .into());
+            }
+        }
+        QueryLog::new(format, Box::new(stream))
+    }
+
+    #[cfg(not(unix))]
+    fn connect(_format: LogFormat, _path: &str) -> Result<QueryLog> {
+        Err("Unix sockets are not supported on this platform".into())
+    }
+
+    pub fn log(&self, event: &QueryEvent) -> Result<()> {
+        let entry = match self.format {
+            LogFormat::Json => {
+                let mut line = json_line(event);
+                line.push('\n');
+                line.into_bytes()
+            }
+            LogFormat::Dnstap => {
+                let mut frames = Vec::new();
+                if let Some(query) = event.query {
+                    data_frame(&mut frames, &dnstap_message(event, CLIENT_QUERY, query)?);
+                }
+                data_frame(
+                    &mut frames,
+                    &dnstap_message(event, CLIENT_RESPONSE, event.response)?,
+                );
+                frames
+            }
+        };
+
+        let entries = self.entries.as_ref().ok_or("query log is closed")?;
+        match entries.try_send(entry) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err("query log writer exited".into()),
+        }
+    }
+
+    /// Entries dropped because the writer could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for QueryLog {
+    /// Wait for the entries still queued to be written
+    fn drop(&mut self) {
+        self.entries = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Write out the entries `QueryLog::log` queues, one write each so they
+/// never mix, until it is dropped. Errors are reported once until writing
+/// works again, not for every entry.
+fn write_entries(format: LogFormat, mut out: Box<dyn Write + Send>, pending: Receiver<Vec<u8>>) {
+    let mut failing = false;
+    for entry in pending {
+        match out.write_all(&entry).and_then(|()| out.flush()) {
+            Ok(()) => failing = false,
+            Err(e) if !failing => {
+                eprintln!("failed to write query log: {}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    if format == LogFormat::Dnstap {
+        let _ = out.write_all(&control_frame(CONTROL_STOP, false));
+        let _ = out.flush();
+    }
+}
+
+/// Format `event` as a JSON object
+pub fn json_line(event: &QueryEvent) -> String {
+    let question = event.query.and_then(|query| query.questions.first());
+    let mut line = String::new();
+
+    line.push('{');
+    let _ = write!(line, "\"time\":\"{}\"", format_time(event.time));
+    let _ = write!(line, ",\"client\":\"{}\"", event.client);
+    let _ = write!(line, ",\"protocol\":\"{}\"", event.transport.as_str());
+    let _ = write!(line, ",\"id\":{}", event.response.header.id);
+    match question {
+        Some(question) => {
+            let name = match question.name.is_root() {
+                true => ".".to_string(),
+                false => question.name.to_string(),
+            };
+            let _ = write!(line, ",\"name\":{}", json_string(&name));
+            let _ = write!(line, ",\"type\":\"{}\"", question.qtype);
+        }
+        None => line.push_str(",\"name\":null,\"type\":null"),
+    }
+    let _ = write!(line, ",\"rcode\":\"{:?}\"", event.response.header.rescode);
+    let _ = write!(line, ",\"answers\":{}", event.response.answers.len());
+    let _ = write!(
+        line,
+        ",\"latency_ms\":{:.3}",
+        event.latency.as_secs_f64() * 1000.0
+    );
+    let _ = write!(line, ",\"cache_hit\":{}", event.cache_hit);
+    match event.upstream {
+        Some(upstream) => {
+            let _ = write!(line, ",\"upstream\":\"{}\"", upstream);
+        }
+        None => line.push_str(",\"upstream\":null"),
+    }
+    line.push('}');
+
+    line
+}
+
+/// RFC 3339 UTC timestamp with milliseconds
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Encode a dnstap `Dnstap` message holding a `Message` of `kind`
+fn dnstap_message(event: &QueryEvent, kind: u64, packet: &DnsPacket) -> Result<Vec<u8>> {
+    let mut buffer = VectorPacketBuffer::new();
+    packet.clone().write(&mut buffer)?;
+    let wire = &buffer.buffer[..buffer.pos()];
+
+    let since_epoch = event.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let answered = since_epoch + event.latency;
+
+    let mut message = Vec::new();
+    proto_varint_field(&mut message, 1, kind);
+    let (family, addr) = match event.client.ip() {
+        IpAddr::V4(addr) => (FAMILY_INET, addr.octets().to_vec()),
+        IpAddr::V6(addr) => (FAMILY_INET6, addr.octets().to_vec()),
+    };
+    proto_varint_field(&mut message, 2, family);
+    proto_varint_field(&mut message, 3, event.transport.dnstap_protocol());
+    proto_bytes_field(&mut message, 4, &addr);
+    proto_varint_field(&mut message, 6, event.client.port() as u64);
+    proto_varint_field(&mut message, 8, since_epoch.as_secs());
+    proto_fixed32_field(&mut message, 9, since_epoch.subsec_nanos());
+    if kind == CLIENT_QUERY {
+        proto_bytes_field(&mut message, 10, wire);
+    } else {
+        proto_varint_field(&mut message, 12, answered.as_secs());
+        proto_fixed32_field(&mut message, 13, answered.subsec_nanos());
+        proto_bytes_field(&mut message, 14, wire);
+    }
+
+    let mut dnstap = Vec::new();
+    let version = concat!("my-dns ", env!("CARGO_PKG_VERSION"));
+    proto_bytes_field(&mut dnstap, 2, version.as_bytes());
+    proto_bytes_field(&mut dnstap, 14, &message);
+    proto_varint_field(&mut dnstap, 15, DNSTAP_MESSAGE);
+    Ok(dnstap)
+}
+
+fn proto_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn proto_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    proto_varint(out, field << 3);
+    proto_varint(out, value);
+}
+
+fn proto_fixed32_field(out: &mut Vec<u8>, field: u64, value: u32) {
+    proto_varint(out, (field << 3) | 5);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn proto_bytes_field(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    proto_varint(out, (field << 3) | 2);
+    proto_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn data_frame(out: &mut Vec<u8>, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// A Frame Streams control frame, with our content type if `typed`
+fn control_frame(control: u32, typed: bool) -> Vec<u8> {
+    let mut body = control.to_be_bytes().to_vec();
+    if typed {
+        body.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(DNSTAP_CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(DNSTAP_CONTENT_TYPE);
+    }
+
+    // a zero length marks a control frame
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Read a control frame, returning its type
+fn read_control_frame<R: Read>(stream: &mut R) -> Result<u32> {
+    let mut word = [0; 4];
+    stream.read_exact(&mut word)?;
+    if u32::from_be_bytes(word) != 0 {
+        return Err("Expected a control frame".into());
+    }
+    stream.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if !(4..=MAX_CONTROL_FRAME).contains(&len) {
+        return Err(format!("Invalid control frame length {}", len).into());
+    }
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok(u32::from_be_bytes([body[0], body[1], body[2], body[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{DnsQuestion, DnsRecord, QueryType, ResultCode};
+    use crate::testing::{name, SharedBuffer};
+    use std::net::Ipv4Addr;
+
+    fn packets(qname: &str) -> (DnsPacket, DnsPacket) {
+        let mut query = DnsPacket::new();
+        query.header.id = 0xbeef;
+        query
+            .questions
+            .push(DnsQuestion::new(name(qname), QueryType::AAAA));
+
+        let mut response = query.clone();
+        response.header.response = true;
+        response.answers.push(DnsRecord::AAAA {
+            domain: name(qname),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        });
+        (query, response)
+    }
+
+    fn event<'a>(query: &'a DnsPacket, response: &'a DnsPacket) -> QueryEvent<'a> {
+        QueryEvent {
+            client: SocketAddr::from((Ipv4Addr::new(192, 0, 2, 10), 53000)),
+            transport: Transport::Udp,
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+            latency: Duration::from_micros(1500),
+            query: Some(query),
+            response,
+            cache_hit: false,
+            upstream: Some(SocketAddr::from((Ipv4Addr::new(9, 9, 9, 9), 53))),
+        }
+    }
+
+    #[test]
+    fn test_json_line() {
+        let (query, response) = packets("example.com");
+        assert_eq!(
+            json_line(&event(&query, &response)),
+            "{\"time\":\"2023-11-14T22:13:20.250Z\",\"client\":\"192.0.2.10:53000\",\
+             \"protocol\":\"udp\",\"id\":48879,\"name\":\"example.com\",\"type\":\"AAAA\",\
+             \"rcode\":\"NOERROR\",\"answers\":1,\"latency_ms\":1.500,\"cache_hit\":false,\
+             \"upstream\":\"9.9.9.9:53\"}"
+        );
+
+        // names are arbitrary bytes, and garbage has no question at all
+        let (query, mut response) = packets("we\\\"ird.example");
+        response.header.rescode = ResultCode::FORMERR;
+        let mut garbage = event(&query, &response);
+        let line = json_line(&garbage);
+        assert!(line.contains("\"name\":\"we\\\\\\\"ird.example\""));
+        garbage.query = None;
+        garbage.upstream = None;
+        let line = json_line(&garbage);
+        assert!(line.contains("\"name\":null,\"type\":null,\"rcode\":\"FORMERR\""));
+        assert!(line.ends_with("\"upstream\":null}"));
+    }
+
+    #[test]
+    fn test_dnstap_frames() {
+        let out = SharedBuffer::default();
+        let log = QueryLog::new(LogFormat::Dnstap, Box::new(out.clone())).unwrap();
+        let (query, response) = packets("example.com");
+        log.log(&event(&query, &response)).unwrap();
+        drop(log);
+
+        let bytes = out.contents();
+        let mut stream = &bytes[..];
+        assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_START);
+        assert!(bytes
+            .windows(DNSTAP_CONTENT_TYPE.len())
+            .any(|w| w == DNSTAP_CONTENT_TYPE));
+
+        let mut messages = Vec::new();
+        loop {
+            let len = u32::from_be_bytes(stream[..4].try_into().unwrap()) as usize;
+            if len == 0 {
+                break;
+            }
+            messages.push(&stream[4..4 + len]);
+            stream = &stream[4 + len..];
+        }
+        assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_STOP);
+        assert!(stream.is_empty());
+
+        // a query and a response, each carrying the DNS message in wire format
+        assert_eq!(messages.len(), 2);
+        let mut wire = VectorPacketBuffer::new();
+        response.clone().write(&mut wire).unwrap();
+        let wire = &wire.buffer[..wire.pos()];
+        assert!(messages[1].windows(wire.len()).any(|w| w == wire));
+        assert!(messages[0].ends_with(&[15 << 3, DNSTAP_MESSAGE as u8]));
+        let client_addr = [4 << 3 | 2, 4, 192, 0, 2, 10];
+        assert!(messages[0].windows(6).any(|w| w == client_addr));
+    }
+
+    /// Blocks every write until the sender of its channel is dropped
+    struct Stalled(Receiver<()>);
+
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_drops_entries_when_stalled() {
+        let (release, stalled) = mpsc::channel();
+        let log = QueryLog::new(LogFormat::Json, Box::new(Stalled(stalled))).unwrap();
+        let (query, response) = packets("example.com");
+
+        // the writer holds on to at most one entry, the queue to the rest
+        for _ in 0..LOG_QUEUE_SIZE + 10 {
+            log.log(&event(&query, &response)).unwrap();
+        }
+        assert!((9..=10).contains(&log.dropped()));
+
+        drop(release);
+        drop(log);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dnstap_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("my-dns-dnstap-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let collector = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_READY);
+            stream
+                .write_all(&control_frame(CONTROL_ACCEPT, true))
+                .unwrap();
+            assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_START);
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            rest
+        });
+
+        let target = format!("unix:{}", path.display());
+        let log = QueryLog::open(LogFormat::Dnstap, &target).unwrap();
+        let (query, response) = packets("example.com");
+        log.log(&event(&query, &response)).unwrap();
+        drop(log);
+
+        let rest = collector.join().unwrap();
+        assert!(rest.ends_with(&control_frame(CONTROL_STOP, false)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+//! Response rate limiting (RRL) and per-client query quotas, to keep the
+//! server from being used to amplify floods at spoofed addresses and from
+//! being swamped by a single misbehaving client.
+//!
+//! Clients are grouped by network, so spreading a flood over the addresses
+//! of one subnet does not help. Each network gets a token bucket for every
+//! distinct response it is sent, one for NXDOMAINs in each zone, one for
+//! errors and one for its queries as a whole.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::name::DnsName;
+use crate::packets::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::view;
+
+/// Buckets kept at most. Once there are this many, networks without one
+/// are limited until idle buckets are thrown away.
+const MAX_BUCKETS: usize = 100_000;
+
+/// How often a full set of buckets is searched for idle ones
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The limits, each in responses or queries per second from one client
+/// network. Zero means no limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Identical positive responses
+    pub responses: u32,
+    /// NXDOMAIN responses for names in the same zone
+    pub nxdomains: u32,
+    /// Error responses of any kind
+    pub errors: u32,
+    /// Queries, whatever they get answered with
+    pub queries: u32,
+    /// Send every `slip`th limited response truncated, telling legitimate
+    /// clients to ask again over TCP, and drop the rest. Zero drops them all.
+    pub slip: u32,
+    /// Prefix lengths clients are grouped by
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            responses: 0,
+            nxdomains: 0,
+            errors: 0,
+            queries: 0,
+            slip: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+}
+
+impl FromStr for RateLimits {
+    type Err = String;
+
+    /// Comma separated `key=value` pairs, e.g. `responses=10,slip=2`; the
+    /// keys are the field names, with dashes for underscores
+    fn from_str(s: &str) -> Result<RateLimits, String> {
+        let mut limits = RateLimits::default();
+        for pair in s.split(',') {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid rate limit: {}", pair))?;
+            let invalid = || format!("Invalid value for {}: {}", key, value);
+            let number: u32 = value.parse().map_err(|_| invalid())?;
+            match key {
+                "responses" => limits.responses = number,
+                "nxdomains" => limits.nxdomains = number,
+                "errors" => limits.errors = number,
+                "queries" => limits.queries = number,
+                "slip" => limits.slip = number,
+                "ipv4-prefix" if number <= 32 => limits.ipv4_prefix = number as u8,
+                "ipv6-prefix" if number <= 128 => limits.ipv6_prefix = number as u8,
+                "ipv4-prefix" | "ipv6-prefix" => return Err(invalid()),
+                _ => return Err(format!("Unknown rate limit: {}", key)),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// What to do with a response
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    /// Send it truncated, with only the header and question
+    Slip,
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Category {
+    Response(DnsName, QueryType),
+    /// NXDOMAIN in the zone named, which random subdomain floods share
+    NxDomain(DnsName),
+    Error,
+    Query,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Responses limited so far, for slipping every so many
+    limited: u32,
+}
+
+impl Bucket {
+    /// Refill at `rate` tokens a second, holding at most a second's worth,
+    /// and take one if there is one
+    fn take(&mut self, rate: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, rate: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * rate as f64 >= rate as f64
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<(IpAddr, Category), Bucket>,
+    /// When idle buckets were last thrown away
+    swept: Option<Instant>,
+    /// Responses limited for want of room for a bucket
+    overflowed: u32,
+}
+
+pub struct RateLimiter {
+    pub limits: RateLimits,
+    buckets: Mutex<Buckets>,
+    max_buckets: usize,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(Buckets::default()),
+            max_buckets: MAX_BUCKETS,
+        }
+    }
+
+    /// Whether `client` is still within its query quota
+    pub fn allow_query(&self, client: IpAddr) -> bool {
+        self.allow_query_at(client, Instant::now())
+    }
+
+    /// Decide whether `response` may go out to `client`. Only responses
+    /// over UDP need limiting, other transports prove the client's address.
+    pub fn check_response(&self, client: IpAddr, response: &DnsPacket) -> Verdict {
+        self.check_response_at(client, response, Instant::now())
+    }
+
+    fn allow_query_at(&self, client: IpAddr, now: Instant) -> bool {
+        let rate = self.limits.queries;
+        rate == 0 || self.take(client, Category::Query, rate, now).0
+    }
+
+    fn check_response_at(&self, client: IpAddr, response: &DnsPacket, now: Instant) -> Verdict {
+        let (category, rate) = self.categorize(response);
+        if rate == 0 {
+            return Verdict::Send;
+        }
+
+        match self.take(client, category, rate, now) {
+            (true, _) => Verdict::Send,
+            (false, limited) if self.limits.slip > 0 && limited % self.limits.slip == 0 => {
+                Verdict::Slip
+            }
+            (false, _) => Verdict::Drop,
+        }
+    }
+
+    /// The bucket `response` is counted in, and its rate
+    fn categorize(&self, response: &DnsPacket) -> (Category, u32) {
+        let question = response.questions.first();
+        match response.header.rescode {
+            ResultCode::NOERROR => match question {
+                Some(question) => (
+                    Category::Response(question.name.clone(), question.qtype),
+                    self.limits.responses,
+                ),
+                None => (Category::Error, self.limits.errors),
+            },
+            ResultCode::NXDOMAIN => {
+                let zone = response.authorities.iter().find_map(|rec| match *rec {
+                    DnsRecord::SOA { ref domain, .. } => Some(domain.clone()),
+                    _ => None,
+                });
+                match zone.or_else(|| question.map(|question| question.name.clone())) {
+                    Some(zone) => (Category::NxDomain(zone), self.limits.nxdomains),
+                    None => (Category::Error, self.limits.errors),
+                }
+            }
+            _ => (Category::Error, self.limits.errors),
+        }
+    }
+
+    /// Take a token from the bucket of `client`'s network for `category`.
+    /// Returns whether there was one, and how many times in a row there
+    /// was not.
+    fn take(&self, client: IpAddr, category: Category, rate: u32, now: Instant) -> (bool, u32) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = (self.network(client), category);
+        if buckets.map.len() >= self.max_buckets && !buckets.map.contains_key(&key) {
+            let due = buckets
+                .swept
+                .is_none_or(|swept| now.saturating_duration_since(swept) >= SWEEP_INTERVAL);
+            if due {
+                // buckets that filled up again hold nothing worth remembering
+                buckets.swept = Some(now);
+                buckets
+                    .map
+                    .retain(|(_, category), bucket| !bucket.is_full(self.rate(category), now));
+            }
+            if buckets.map.len() >= self.max_buckets {
+                // a flood from all over: whoever has no bucket yet is over
+                // their limit, rather than having memory grow without end
+                buckets.overflowed = buckets.overflowed.wrapping_add(1);
+                return (false, buckets.overflowed);
+            }
+        }
+
+        let bucket = buckets.map.entry(key).or_insert(Bucket {
+            tokens: rate as f64,
+            updated: now,
+            limited: 0,
+        });
+        if bucket.take(rate, now) {
+            bucket.limited = 0;
+            (true, 0)
+        } else {
+            bucket.limited += 1;
+            (false, bucket.limited)
+        }
+    }
+
+    fn rate(&self, category: &Category) -> u32 {
+        match *category {
+            Category::Response(..) => self.limits.responses,
+            Category::NxDomain(_) => self.limits.nxdomains,
+            Category::Error => self.limits.errors,
+            Category::Query => self.limits.queries,
+        }
+    }
+
+    /// The network `client` is grouped in
+    fn network(&self, client: IpAddr) -> IpAddr {
+        let prefix = match client {
+            IpAddr::V4(_) => self.limits.ipv4_prefix,
+            IpAddr::V6(_) => self.limits.ipv6_prefix,
+        };
+        view::mask(client, prefix)
+    }
+}
+
+/// Cut `response` down to its header and question with TC set, the way a
+/// slipped response goes out
+pub fn truncate(response: &mut DnsPacket) {
+    *response = DnsPacket {
+        header: response.header.clone(),
+        questions: response.questions.clone(),
+        edns: response.edns.clone(),
+        ..DnsPacket::new()
+    };
+    response.header.truncated_message = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::DnsQuestion;
+    use crate::testing::name;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn response(qname: &str, rescode: ResultCode) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.rescode = rescode;
+        response
+            .questions
+            .push(DnsQuestion::new(name(qname), QueryType::A));
+        response
+    }
+
+    fn client(last: u8) -> IpAddr {
+        Ipv4Addr::new(198, 51, 100, last).into()
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let limits: RateLimits = "responses=5,nxdomains=2,slip=3,ipv4-prefix=32"
+            .parse()
+            .unwrap();
+        assert_eq!(limits.responses, 5);
+        assert_eq!(limits.nxdomains, 2);
+        assert_eq!(limits.errors, 0);
+        assert_eq!(limits.slip, 3);
+        assert_eq!(limits.ipv4_prefix, 32);
+        assert!("responses".parse::<RateLimits>().is_err());
+        assert!("ipv4-prefix=33".parse::<RateLimits>().is_err());
+        assert!("bogus=1".parse::<RateLimits>().is_err());
+    }
+
+    #[test]
+    fn test_response_buckets() {
+        let limiter = RateLimiter::new(RateLimits {
+            responses: 2,
+            slip: 2,
+            ..RateLimits::default()
+        });
+        let now = Instant::now();
+        let answer = response("example.com", ResultCode::NOERROR);
+
+        let verdicts: Vec<_> = (0..6)
+            .map(|_| limiter.check_response_at(client(1), &answer, now))
+            .collect();
+        use Verdict::*;
+        assert_eq!(verdicts, [Send, Send, Drop, Slip, Drop, Slip]);
+
+        // the whole /24 shares the bucket, other networks and other
+        // responses have their own
+        assert_eq!(limiter.check_response_at(client(2), &answer, now), Drop);
+        let elsewhere = Ipv4Addr::new(203, 0, 113, 1).into();
+        assert_eq!(limiter.check_response_at(elsewhere, &answer, now), Send);
+        let other = response("www.example.com", ResultCode::NOERROR);
+        assert_eq!(limiter.check_response_at(client(1), &other, now), Send);
+
+        // errors are not limited unless asked for
+        let error = response("example.com", ResultCode::SERVFAIL);
+        for _ in 0..10 {
+            assert_eq!(limiter.check_response_at(client(1), &error, now), Send);
+        }
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check_response_at(client(1), &answer, later), Send);
+        assert_eq!(limiter.check_response_at(client(1), &answer, later), Drop);
+    }
+
+    #[test]
+    fn test_nxdomain_floods() {
+        let limiter = RateLimiter::new(RateLimits {
+            responses: 100,
+            nxdomains: 1,
+            slip: 0,
+            ..RateLimits::default()
+        });
+        let now = Instant::now();
+
+        // random subdomains all count against their zone
+        let mut verdicts = Vec::new();
+        for i in 0..3 {
+            let mut nxdomain = response(&format!("x{}.example.com", i), ResultCode::NXDOMAIN);
+            nxdomain.authorities.push(DnsRecord::SOA {
+                domain: name("example.com"),
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 300,
+            });
+            verdicts.push(limiter.check_response_at(client(1), &nxdomain, now));
+        }
+        assert_eq!(verdicts, [Verdict::Send, Verdict::Drop, Verdict::Drop]);
+    }
+
+    #[test]
+    fn test_query_quota() {
+        let limiter = RateLimiter::new(RateLimits {
+            queries: 3,
+            ipv6_prefix: 64,
+            ..RateLimits::default()
+        });
+        let now = Instant::now();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8::2".parse().unwrap();
+        let stranger: IpAddr = "2001:db8:1::1".parse().unwrap();
+
+        assert!(limiter.allow_query_at(client, now));
+        assert!(limiter.allow_query_at(neighbour, now));
+        assert!(limiter.allow_query_at(client, now));
+        assert!(!limiter.allow_query_at(neighbour, now));
+        assert!(limiter.allow_query_at(stranger, now));
+        assert!(limiter.allow_query_at(client, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_bucket_limit() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            responses: 1,
+            slip: 0,
+            ..RateLimits::default()
+        });
+        limiter.max_buckets = 4;
+        let now = Instant::now();
+        let answer = response("example.com", ResultCode::NOERROR);
+        let network = |i| Ipv4Addr::new(198, 51, i, 1).into();
+
+        for i in 0..4 {
+            assert_eq!(
+                limiter.check_response_at(network(i), &answer, now),
+                Verdict::Send
+            );
+        }
+        // no room for another network while the others are busy
+        assert_eq!(
+            limiter.check_response_at(network(4), &answer, now),
+            Verdict::Drop
+        );
+        assert_eq!(limiter.buckets.lock().unwrap().map.len(), 4);
+
+        // once the buckets refilled, they make way
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check_response_at(network(4), &answer, later),
+            Verdict::Send
+        );
+        assert_eq!(limiter.buckets.lock().unwrap().map.len(), 1);
+    }
+}
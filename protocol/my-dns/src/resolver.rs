@@ -0,0 +1,503 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::client::DnsClient;
+use crate::dnssec::{Security, TrustAnchor, Validator};
+use crate::name::DnsName;
+use crate::packets::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// IPv4 addresses of a.root-servers.net through m.root-servers.net
+pub const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// How deep lookups may nest, through glue-less name servers or CNAMEs,
+/// before we give up
+const MAX_DEPTH: usize = 8;
+
+/// Resolves names by walking down the delegation tree from the root
+/// servers instead of asking an upstream resolver
+pub struct RecursiveResolver {
+    pub root_servers: Vec<SocketAddr>,
+    /// Port used for name servers learned from referrals
+    pub port: u16,
+    /// Upper bound on the referrals followed for a single name
+    pub max_referrals: usize,
+    pub client: DnsClient,
+    /// Validate responses with DNSSEC, trusting these keys. Responses
+    /// that validate are marked with the AD bit, bogus ones are errors.
+    pub trust_anchor: Option<TrustAnchor>,
+}
+
+impl Default for RecursiveResolver {
+    fn default() -> Self {
+        Self::new(
+            ROOT_SERVERS
+                .iter()
+                .map(|ip| SocketAddr::new(IpAddr::V4(*ip), 53))
+                .collect(),
+        )
+    }
+}
+
+impl RecursiveResolver {
+    pub fn new(root_servers: Vec<SocketAddr>) -> RecursiveResolver {
+        let mut client = DnsClient::new();
+        client.recursion_desired = false;
+
+        RecursiveResolver {
+            root_servers,
+            port: 53,
+            max_referrals: 16,
+            client,
+            trust_anchor: None,
+        }
+    }
+
+    /// Resolve `qname`, returning the final response: one with answers, an
+    /// NXDOMAIN, or a NOERROR without data
+    pub fn resolve(&self, qname: &DnsName, qtype: QueryType) -> Result<DnsPacket> {
+        self.resolve_with_cd(qname, qtype, false)
+    }
+
+    /// Resolve `qname`, and validate the response if a trust anchor is
+    /// configured. With `checking_disabled`, as requested by the CD bit,
+    /// responses that fail validation are returned anyway.
+    pub fn resolve_with_cd(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let mut response = self.resolve_at_depth(qname, qtype, 0)?;
+        let anchor = match self.trust_anchor {
+            Some(ref anchor) => anchor,
+            None => return Ok(response),
+        };
+
+        let mut validator = Validator::new(anchor, |name: &DnsName, qtype| {
+            self.resolve_at_depth(name, qtype, 1)
+        });
+        match validator.validate(qname, qtype, &response) {
+            Security::Secure => response.header.authed_data = true,
+            Security::Insecure => response.header.authed_data = false,
+            Security::Bogus(reason) => {
+                if !checking_disabled {
+                    return Err(format!("{} {} is bogus: {}", qname, qtype, reason).into());
+                }
+                response.header.authed_data = false;
+            }
+        }
+
+        Ok(response)
+    }
+
+    fn resolve_at_depth(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        depth: usize,
+    ) -> Result<DnsPacket> {
+        if depth > MAX_DEPTH {
+            return Err(format!("lookup of {} nested too deeply", qname).into());
+        }
+
+        let mut servers = self.root_servers.clone();
+        // the zone the current servers are authoritative for, referrals
+        // have to move strictly closer to qname
+        let mut zone: Option<DnsName> = None;
+
+        for _ in 0..self.max_referrals {
+            let response = self.query_any(qname, qtype, &servers)?;
+
+            if response.header.rescode == ResultCode::NXDOMAIN {
+                return Ok(response);
+            }
+            if response.header.rescode != ResultCode::NOERROR {
+                return Err(format!(
+                    "{} {} failed with {:?}",
+                    qname, qtype, response.header.rescode
+                )
+                .into());
+            }
+
+            if !response.answers.is_empty() {
+                return self.chase_cname(response, qname, qtype, depth);
+            }
+
+            let (next_zone, hosts) = match referral(&response, qname) {
+                Some(referral) => referral,
+                // NOERROR without answers or a referral: the name exists,
+                // but has no data of this type
+                None => return Ok(response),
+            };
+
+            if let Some(ref zone) = zone {
+                if next_zone.label_count() <= zone.label_count() || !next_zone.is_subdomain_of(zone)
+                {
+                    return Err(format!(
+                        "referral loop resolving {}: {} referred to {}",
+                        qname, zone, next_zone
+                    )
+                    .into());
+                }
+            }
+
+            servers = self.glue(&response, &hosts);
+            if servers.is_empty() {
+                servers = self.resolve_hosts(&hosts, qname, depth)?;
+            }
+            zone = Some(next_zone);
+        }
+
+        Err(format!("too many referrals resolving {}", qname).into())
+    }
+
+    /// Send the query to each server in turn until one of them responds
+    fn query_any(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        servers: &[SocketAddr],
+    ) -> Result<DnsPacket> {
+        // signatures and denial proofs only come along with the DO bit
+        let client = DnsClient {
+            dnssec_ok: self.client.dnssec_ok || self.trust_anchor.is_some(),
+            ..self.client
+        };
+
+        let mut last_err: Error = format!("no servers to ask for {}", qname).into();
+        for server in servers {
+            match client.send_query(qname, qtype, *server) {
+                Ok(response) => return Ok(response),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// If the answer is only a CNAME chain, continue with its target and
+    /// append whatever that resolves to
+    fn chase_cname(
+        &self,
+        mut response: DnsPacket,
+        qname: &DnsName,
+        qtype: QueryType,
+        depth: usize,
+    ) -> Result<DnsPacket> {
+        if qtype == QueryType::CNAME || response.answers.iter().any(|rec| rec.query_type() == qtype)
+        {
+            return Ok(response);
+        }
+
+        // a chain can't be longer than the answers holding it, so any step
+        // beyond that goes around in circles
+        let mut target = qname.clone();
+        let mut steps = 0;
+        while let Some(host) = response.answers.iter().find_map(|rec| match rec {
+            DnsRecord::CNAME { domain, host, .. } if *domain == target => Some(host.clone()),
+            _ => None,
+        }) {
+            if steps == response.answers.len() {
+                return Err(format!("CNAME loop at {}", qname).into());
+            }
+            target = host;
+            steps += 1;
+        }
+        if target == *qname {
+            return Ok(response);
+        }
+
+        let chased = self.resolve_at_depth(&target, qtype, depth + 1)?;
+        response.header.rescode = chased.header.rescode;
+        response.answers.extend(chased.answers);
+        // the authority section has to prove whatever is missing at the
+        // end of the chain
+        response.authorities = chased.authorities;
+        Ok(response)
+    }
+
+    /// Addresses for the referred name servers found in the additional section
+    fn glue(&self, response: &DnsPacket, hosts: &[DnsName]) -> Vec<SocketAddr> {
+        response
+            .resources
+            .iter()
+            .filter_map(|rec| match rec {
+                DnsRecord::A { domain, addr, .. } if hosts.contains(domain) => {
+                    Some(SocketAddr::new(IpAddr::V4(*addr), self.port))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Look up the addresses of glue-less name servers, starting over from
+    /// the root
+    fn resolve_hosts(
+        &self,
+        hosts: &[DnsName],
+        qname: &DnsName,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>> {
+        for host in hosts {
+            let response = match self.resolve_at_depth(host, QueryType::A, depth + 1) {
+                Ok(response) => response,
+                Err(_) => continue,
+            };
+            let addrs: Vec<SocketAddr> = response
+                .answers
+                .iter()
+                .filter_map(|rec| match rec {
+                    DnsRecord::A { addr, .. } => {
+                        Some(SocketAddr::new(IpAddr::V4(*addr), self.port))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if !addrs.is_empty() {
+                return Ok(addrs);
+            }
+        }
+
+        Err(format!("could not find an address for any name server of {}", qname).into())
+    }
+}
+
+/// The delegated zone and its name server names, if the response is a
+/// referral towards `qname`
+fn referral(response: &DnsPacket, qname: &DnsName) -> Option<(DnsName, Vec<DnsName>)> {
+    let mut zone = None;
+    let mut hosts = Vec::new();
+
+    for rec in &response.authorities {
+        if let DnsRecord::NS { domain, host, .. } = rec {
+            if !qname.is_subdomain_of(domain) {
+                continue;
+            }
+            if zone.is_none() {
+                zone = Some(domain.clone());
+            }
+            if zone.as_ref() == Some(domain) {
+                hosts.push(host.clone());
+            }
+        }
+    }
+
+    zone.map(|zone| (zone, hosts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{name, spawn_server};
+    use std::time::Duration;
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: name(domain),
+            host: name(host),
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str, addr: Ipv4Addr) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(domain),
+            addr,
+            ttl: 3600,
+        }
+    }
+
+    fn referral_to(zone: &str, host: &str, glue: Option<Ipv4Addr>) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.authorities.push(ns(zone, host));
+        if let Some(addr) = glue {
+            response.resources.push(a(host, addr));
+        }
+        response
+    }
+
+    fn nxdomain() -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.authoritative_answer = true;
+        response.header.rescode = ResultCode::NXDOMAIN;
+        response
+    }
+
+    /// A small hierarchy on 127.0.0.{10..14}, all on the same port:
+    ///
+    /// - the root delegates `com` and `org` with glue
+    /// - `com` delegates `example.com` to `ns.hosting.org`, without glue
+    /// - `org` delegates `hosting.org` with glue
+    /// - `hosting.org` knows its name server, `example.com` serves the data
+    fn hierarchy() -> RecursiveResolver {
+        let root = Ipv4Addr::new(127, 0, 0, 10);
+        let com = Ipv4Addr::new(127, 0, 0, 11);
+        let org = Ipv4Addr::new(127, 0, 0, 12);
+        let hosting = Ipv4Addr::new(127, 0, 0, 13);
+        let example = Ipv4Addr::new(127, 0, 0, 14);
+
+        let port = spawn_server(root, 0, move |request| {
+            let qname = &request.questions[0].name;
+            if qname.is_subdomain_of(&name("com")) {
+                referral_to("com", "a.gtld.test", Some(com))
+            } else if qname.is_subdomain_of(&name("org")) {
+                referral_to("org", "a0.org.test", Some(org))
+            } else if qname.is_subdomain_of(&name("loop")) {
+                // refers back to itself for the zone it was asked about
+                referral_to("", "a.root.test", Some(root))
+            } else {
+                nxdomain()
+            }
+        });
+        spawn_server(com, port, |request| {
+            let qname = &request.questions[0].name;
+            if qname.is_subdomain_of(&name("example.com")) {
+                referral_to("example.com", "ns.hosting.org", None)
+            } else {
+                nxdomain()
+            }
+        });
+        spawn_server(org, port, move |request| {
+            let qname = &request.questions[0].name;
+            if qname.is_subdomain_of(&name("hosting.org")) {
+                referral_to("hosting.org", "ns.hosting.org", Some(hosting))
+            } else {
+                nxdomain()
+            }
+        });
+        spawn_server(hosting, port, move |request| {
+            let mut response = DnsPacket::new();
+            response.header.authoritative_answer = true;
+            if request.questions[0].name == "ns.hosting.org" {
+                response.answers.push(a("ns.hosting.org", example));
+                response
+            } else {
+                nxdomain()
+            }
+        });
+        spawn_server(example, port, |request| {
+            let question = &request.questions[0];
+            let mut response = DnsPacket::new();
+            response.header.authoritative_answer = true;
+            match (question.name.to_string().as_str(), question.qtype) {
+                ("www.example.com", QueryType::A) => {
+                    response
+                        .answers
+                        .push(a("www.example.com", Ipv4Addr::new(192, 0, 2, 1)));
+                }
+                ("alias.example.com", _) => {
+                    response.answers.push(DnsRecord::CNAME {
+                        domain: name("alias.example.com"),
+                        host: name("www.example.com"),
+                        ttl: 3600,
+                    });
+                }
+                ("loop1.example.com", _) | ("loop2.example.com", _) => {
+                    response.answers.push(DnsRecord::CNAME {
+                        domain: name("loop1.example.com"),
+                        host: name("loop2.example.com"),
+                        ttl: 3600,
+                    });
+                    response.answers.push(DnsRecord::CNAME {
+                        domain: name("loop2.example.com"),
+                        host: name("loop1.example.com"),
+                        ttl: 3600,
+                    });
+                }
+                ("www.example.com", _) => {}
+                _ => return nxdomain(),
+            }
+            response
+        });
+
+        let mut resolver = RecursiveResolver::new(vec![SocketAddr::new(IpAddr::V4(root), port)]);
+        resolver.port = port;
+        resolver.client.timeout = Duration::from_millis(500);
+        resolver.client.retries = 0;
+        resolver
+    }
+
+    #[test]
+    fn test_resolve_through_glueless_referral() {
+        let resolver = hierarchy();
+
+        let response = resolver
+            .resolve(&name("www.example.com"), QueryType::A)
+            .unwrap();
+        assert!(response.header.authoritative_answer);
+        assert_eq!(
+            response.answers,
+            vec![a("www.example.com", Ipv4Addr::new(192, 0, 2, 1))]
+        );
+    }
+
+    #[test]
+    fn test_resolve_nxdomain_and_nodata() {
+        let resolver = hierarchy();
+
+        let response = resolver
+            .resolve(&name("nope.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+        let response = resolver.resolve(&name("nope.net"), QueryType::A).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+        let response = resolver
+            .resolve(&name("www.example.com"), QueryType::MX)
+            .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_follows_cname() {
+        let resolver = hierarchy();
+
+        let response = resolver
+            .resolve(&name("alias.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.answers[0].query_type(), QueryType::CNAME);
+        assert_eq!(
+            response.answers[1],
+            a("www.example.com", Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_resolve_detects_cname_loop() {
+        let resolver = hierarchy();
+
+        let err = resolver
+            .resolve(&name("loop1.example.com"), QueryType::A)
+            .unwrap_err();
+        assert!(err.to_string().contains("CNAME loop"));
+    }
+
+    #[test]
+    fn test_resolve_detects_referral_loop() {
+        let resolver = hierarchy();
+
+        let err = resolver
+            .resolve(&name("host.loop"), QueryType::A)
+            .unwrap_err();
+        assert!(err.to_string().contains("referral loop"));
+    }
+}
@@ -0,0 +1,2055 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::blocklist::Blocklist;
+use crate::cache::{CacheKey, DnsCache};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::client::DnsClient;
+use crate::doh::{read_request, write_response, HttpRequest, HttpResponse, DNS_MESSAGE, DOH_PATH};
+use crate::dot::DotClient;
+use crate::encoding::from_base64url;
+use crate::name::DnsName;
+use crate::packets::{
+    DnsPacket, DnsQuestion, DnsRecord, Edns, PacketBuffer, QueryType, ResultCode,
+    VectorPacketBuffer, CLASS_IN, DEFAULT_EDNS_PAYLOAD_SIZE, MAX_MESSAGE_SIZE, MAX_UDP_SIZE,
+};
+use crate::querylog::{QueryEvent, QueryLog, Transport};
+use crate::ratelimit::{self, RateLimiter, Verdict};
+use crate::resolver::RecursiveResolver;
+use crate::tcp::{read_message, write_message};
+use crate::tsig::{self, TsigError, TsigKey};
+use crate::update::{self, UpdateMessage, OPCODE_UPDATE};
+use crate::view::{self, Subnet, View};
+use crate::xfr::{self, Secondary, Transfer, OPCODE_NOTIFY};
+use crate::zone::{serial_newer, Zone};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Where the server gets its answers from
+pub enum Upstream {
+    /// Ask these resolvers, in order, until one responds
+    Forward(Vec<SocketAddr>),
+    /// Same, over DNS over TLS
+    ForwardTls(Vec<DotClient>),
+    /// Resolve names ourselves, starting from the root servers
+    Recursive(RecursiveResolver),
+}
+
+/// Number of responses cached by default
+pub const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+/// Threads answering UDP queries by default
+pub const DEFAULT_UDP_WORKERS: usize = 64;
+
+/// Queries waiting for a UDP worker, beyond which new ones are dropped
+const UDP_QUEUE_SIZE: usize = 1024;
+
+/// How long an idle TCP connection is kept open for further queries
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a secondary zone is refreshed while its SOA is not known
+const DEFAULT_REFRESH: Duration = Duration::from_secs(60);
+
+pub struct DnsServer {
+    pub upstream: Upstream,
+    pub client: DnsClient,
+    pub cache: Mutex<DnsCache>,
+    /// Zones answered authoritatively, without asking upstream
+    pub zones: RwLock<Vec<Zone>>,
+    /// Clients allowed to change the zones with dynamic updates
+    pub update_clients: Vec<IpAddr>,
+    /// Secondaries allowed to transfer the zones
+    pub transfer_clients: Vec<IpAddr>,
+    /// Keys that sign updates and transfers, known by the names TSIG
+    /// records give
+    pub tsig_keys: Vec<TsigKey>,
+    /// Keys whose signature allows an update from anywhere
+    pub update_keys: Vec<DnsName>,
+    /// Keys whose signature allows a transfer from anywhere
+    pub transfer_keys: Vec<DnsName>,
+    /// Secondaries sent a NOTIFY whenever a zone changes
+    pub notify_targets: Vec<SocketAddr>,
+    /// Zones copied from primary servers, served from `zones` once
+    /// transferred
+    pub secondaries: Vec<Secondary>,
+    /// Largest UDP response sent to EDNS clients, whatever they advertise
+    pub udp_payload_size: u16,
+    /// Threads answering UDP queries
+    pub udp_workers: usize,
+    /// Names not to resolve, checked in order after the local zones
+    pub blocklists: Vec<Blocklist>,
+    /// Where every query and its response gets logged, if anywhere
+    pub query_log: Option<QueryLog>,
+    /// Per-client query quotas, and limits on the UDP responses a client
+    /// network is sent
+    pub rate_limiter: Option<RateLimiter>,
+    /// Split-horizon views, the first one matching a client answers it.
+    /// Clients no view matches get the zones, upstream and cache above;
+    /// updates, transfers and NOTIFY only ever deal with those zones.
+    pub views: Vec<View>,
+    /// Clients, forwarding resolvers typically, whose EDNS Client Subnet
+    /// option picks the view instead of their own address
+    pub client_subnet_sources: Vec<Subnet>,
+    /// Set on NOTIFY to wake up `watch_secondaries`
+    refresh_requested: (Mutex<bool>, Condvar),
+}
+
+/// What it took to answer a query, for the query log
+#[derive(Default)]
+struct Outcome {
+    cache_hit: bool,
+    upstream: Option<SocketAddr>,
+}
+
+impl DnsServer {
+    pub fn new(upstream: Upstream) -> DnsServer {
+        DnsServer {
+            upstream,
+            client: DnsClient::new(),
+            cache: Mutex::new(DnsCache::new(DEFAULT_CACHE_SIZE)),
+            zones: RwLock::new(Vec::new()),
+            update_clients: Vec::new(),
+            transfer_clients: Vec::new(),
+            tsig_keys: Vec::new(),
+            update_keys: Vec::new(),
+            transfer_keys: Vec::new(),
+            notify_targets: Vec::new(),
+            secondaries: Vec::new(),
+            udp_payload_size: DEFAULT_EDNS_PAYLOAD_SIZE,
+            udp_workers: DEFAULT_UDP_WORKERS,
+            blocklists: Vec::new(),
+            query_log: None,
+            rate_limiter: None,
+            views: Vec::new(),
+            client_subnet_sources: Vec::new(),
+            refresh_requested: (Mutex::new(false), Condvar::new()),
+        }
+    }
+
+    /// The first blocklist that blocks `name`, which counts it
+    fn blocklist_for(&self, name: &DnsName) -> Option<&Blocklist> {
+        self.blocklists.iter().find(|list| list.check(name))
+    }
+
+    /// Reload the blocklists whose files changed, every `interval`, forever
+    pub fn watch_blocklists(self: Arc<Self>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            for list in &self.blocklists {
+                match list.reload_if_changed() {
+                    Ok(true) => println!(
+                        "reloaded blocklist {}: {} names, {} queries blocked so far",
+                        list.path.display(),
+                        list.len(),
+                        list.blocked()
+                    ),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("failed to reload {}: {}", list.path.display(), e),
+                }
+            }
+        }
+    }
+
+    /// Keep the secondary zones up to date with their primaries forever,
+    /// checking each when its SOA refresh interval is up, or right away on
+    /// a NOTIFY
+    pub fn watch_secondaries(self: Arc<Self>) {
+        let mut next_refresh = vec![Instant::now(); self.secondaries.len()];
+        loop {
+            for (secondary, next) in self.secondaries.iter().zip(&mut next_refresh) {
+                if *next > Instant::now() {
+                    continue;
+                }
+                let result = self.refresh_secondary(secondary);
+                let (refresh, retry) = self.refresh_intervals(&secondary.origin);
+                *next = Instant::now()
+                    + match result {
+                        Ok(_) => refresh,
+                        Err(e) => {
+                            eprintln!("failed to refresh zone {}: {}", secondary.origin, e);
+                            retry
+                        }
+                    };
+            }
+
+            let wakeup = next_refresh.iter().min().copied();
+            let timeout = wakeup.map_or(DEFAULT_REFRESH, |at| {
+                at.saturating_duration_since(Instant::now())
+            });
+            let (ref requested, ref condvar) = self.refresh_requested;
+            let mut requested = requested.lock().unwrap();
+            if !*requested {
+                requested = condvar.wait_timeout(requested, timeout).unwrap().0;
+            }
+            if *requested {
+                // a NOTIFY: check every zone, the serial tells which changed
+                *requested = false;
+                next_refresh.fill(Instant::now());
+            }
+        }
+    }
+
+    /// The SOA refresh and retry intervals of the zone at `origin`
+    fn refresh_intervals(&self, origin: &DnsName) -> (Duration, Duration) {
+        let zones = self.zones.read().unwrap();
+        let soa = zones
+            .iter()
+            .find(|zone| zone.origin == *origin)
+            .and_then(|zone| zone.soa());
+        match soa {
+            Some(&DnsRecord::SOA { refresh, retry, .. }) => (
+                Duration::from_secs(refresh.into()),
+                Duration::from_secs(retry.into()),
+            ),
+            _ => (DEFAULT_REFRESH, DEFAULT_REFRESH),
+        }
+    }
+
+    /// Transfer `secondary` from its primary if the primary has a newer
+    /// version. Returns whether anything changed.
+    pub fn refresh_secondary(&self, secondary: &Secondary) -> Result<bool> {
+        let origin = &secondary.origin;
+        let current = self
+            .zones
+            .read()
+            .unwrap()
+            .iter()
+            .find(|zone| zone.origin == *origin)
+            .cloned();
+
+        if let Some(ref current) = current {
+            let response = self
+                .client
+                .send_query(origin, QueryType::SOA, secondary.primary)?;
+            let serial = response.answers.iter().find_map(|rec| match *rec {
+                DnsRecord::SOA { serial, .. } => Some(serial),
+                _ => None,
+            });
+            match serial {
+                Some(serial) if serial_newer(serial, current.serial()) => {}
+                Some(_) => return Ok(false),
+                None => {
+                    return Err(format!("{} has no SOA for {}", secondary.primary, origin).into())
+                }
+            }
+        }
+
+        let key = match secondary.key {
+            Some(ref name) => Some(
+                self.tsig_keys
+                    .iter()
+                    .find(|key| key.name == *name)
+                    .ok_or_else(|| format!("no TSIG key named {}", name))?,
+            ),
+            None => None,
+        };
+        let current_soa = current.as_ref().and_then(|zone| zone.soa());
+        let transfer =
+            xfr::request_transfer(&self.client, origin, current_soa, key, secondary.primary)?;
+        let mut updated = match transfer {
+            Transfer::UpToDate => return Ok(false),
+            Transfer::Full(mut zone) => {
+                if zone.origin != *origin {
+                    return Err(format!("{} sent zone {}", secondary.primary, zone.origin).into());
+                }
+                if let Some(ref current) = current {
+                    zone.history = current.history.clone();
+                    zone.record_change(current);
+                }
+                zone
+            }
+            Transfer::Incremental(changes) => {
+                let mut zone = current.ok_or("incremental transfer without a zone")?;
+                for change in &changes {
+                    zone.apply_change(change)?;
+                }
+                zone
+            }
+        };
+        updated.path = secondary.path.clone();
+        updated.save()?;
+
+        let mut zones = self.zones.write().unwrap();
+        match zones.iter_mut().find(|zone| zone.origin == *origin) {
+            Some(zone) => *zone = updated,
+            None => zones.push(updated),
+        }
+        drop(zones);
+
+        self.send_notifies(origin);
+        Ok(true)
+    }
+
+    /// Tell the secondaries that `origin` changed, in the background
+    fn send_notifies(&self, origin: &DnsName) {
+        for &target in &self.notify_targets {
+            let client = self.client;
+            let origin = origin.clone();
+            thread::spawn(move || {
+                if let Err(e) = xfr::send_notify(&client, &origin, target) {
+                    eprintln!("failed to notify {} of {}: {}", target, origin, e);
+                }
+            });
+        }
+    }
+
+    /// Answer the query in `req_buffer`. Every request gets a response, even
+    /// if it could not be parsed or resolved.
+    pub fn handle_query<T: PacketBuffer>(&self, req_buffer: &mut T) -> DnsPacket {
+        match DnsPacket::from_buffer(req_buffer) {
+            Ok(request) => self.handle_request(&request),
+            Err(_) => error_response(req_buffer, ResultCode::FORMERR),
+        }
+    }
+
+    /// Answer an already parsed request
+    pub fn handle_request(&self, request: &DnsPacket) -> DnsPacket {
+        self.answer(request, None, &mut Outcome::default())
+    }
+
+    /// Answer the query in `req_buffer` from `client` and log it. Returns
+    /// the request too, unless it could not be parsed.
+    fn handle_query_from<T: PacketBuffer>(
+        &self,
+        req_buffer: &mut T,
+        client: SocketAddr,
+        transport: Transport,
+    ) -> (DnsPacket, Option<DnsPacket>) {
+        // updates carry records a query parser would choke on
+        if opcode(req_buffer) == Some(OPCODE_UPDATE) {
+            let time = SystemTime::now();
+            let started = Instant::now();
+            let (response, update) = self.handle_update(req_buffer, client);
+            let request = update.map(|update| DnsPacket {
+                header: update.header,
+                questions: update.zones,
+                ..DnsPacket::new()
+            });
+            self.log_query(&QueryEvent {
+                client,
+                transport,
+                time,
+                latency: started.elapsed(),
+                query: request.as_ref(),
+                response: &response,
+                cache_hit: false,
+                upstream: None,
+            });
+            return (response, None);
+        }
+
+        match DnsPacket::from_buffer(req_buffer) {
+            Ok(request) => (
+                self.handle_request_from(&request, client, transport),
+                Some(request),
+            ),
+            Err(_) => {
+                let response = error_response(req_buffer, ResultCode::FORMERR);
+                self.log_query(&QueryEvent {
+                    client,
+                    transport,
+                    time: SystemTime::now(),
+                    latency: Duration::ZERO,
+                    query: None,
+                    response: &response,
+                    cache_hit: false,
+                    upstream: None,
+                });
+                (response, None)
+            }
+        }
+    }
+
+    /// Answer a parsed request from `client` and log it
+    fn handle_request_from(
+        &self,
+        request: &DnsPacket,
+        client: SocketAddr,
+        transport: Transport,
+    ) -> DnsPacket {
+        let time = SystemTime::now();
+        let started = Instant::now();
+        let mut outcome = Outcome::default();
+        let response = match request.header.opcode {
+            OPCODE_NOTIFY => self.handle_notify(request, client),
+            _ => {
+                let subnet = self.trusted_client_subnet(request, client);
+                let client_ip = subnet.map_or(client.ip(), |subnet| subnet.addr);
+                let view = self.views.iter().find(|view| view.matches(client_ip));
+                let mut response = self.answer(request, view, &mut outcome);
+                // the answer holds for the whole subnet the client gave
+                if let (Some(subnet), Some(edns)) = (subnet, response.edns.as_mut()) {
+                    edns.options
+                        .push(view::client_subnet_option(&subnet, subnet.prefix));
+                }
+                response
+            }
+        };
+        self.log_query(&QueryEvent {
+            client,
+            transport,
+            time,
+            latency: started.elapsed(),
+            query: Some(request),
+            response: &response,
+            cache_hit: outcome.cache_hit,
+            upstream: outcome.upstream,
+        });
+        response
+    }
+
+    /// The EDNS Client Subnet of `request`, if `client` is trusted to give
+    /// one
+    fn trusted_client_subnet(&self, request: &DnsPacket, client: SocketAddr) -> Option<Subnet> {
+        let trusted = self
+            .client_subnet_sources
+            .iter()
+            .any(|source| source.contains(client.ip()));
+        match trusted {
+            true => view::client_subnet(request),
+            false => None,
+        }
+    }
+
+    /// Acknowledge a NOTIFY from the primary of one of our secondary zones,
+    /// and go check it for changes
+    fn handle_notify(&self, request: &DnsPacket, client: SocketAddr) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.id = request.header.id;
+        response.header.opcode = OPCODE_NOTIFY;
+        response.header.response = true;
+        response.header.authoritative_answer = true;
+        response.questions = request.questions.clone();
+
+        let origin = match request.questions[..] {
+            [ref question] if question.qtype == QueryType::SOA => &question.name,
+            _ => {
+                response.header.rescode = ResultCode::FORMERR;
+                return response;
+            }
+        };
+        response.header.rescode = match self.secondaries.iter().find(|s| s.origin == *origin) {
+            None => ResultCode::NOTAUTH,
+            Some(secondary) if secondary.primary.ip() != client.ip() => ResultCode::REFUSED,
+            Some(_) => {
+                let (ref requested, ref condvar) = self.refresh_requested;
+                *requested.lock().unwrap() = true;
+                condvar.notify_all();
+                ResultCode::NOERROR
+            }
+        };
+        response
+    }
+
+    /// Answer a zone transfer request from `client`, signed with `key` if
+    /// any, with the messages carrying the zone, or a single error response
+    fn handle_transfer(
+        &self,
+        request: &DnsPacket,
+        client: SocketAddr,
+        key: Option<&DnsName>,
+    ) -> Vec<DnsPacket> {
+        let mut response = DnsPacket::new();
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.questions = request.questions.clone();
+
+        let question = &request.questions[0];
+        let zones = self.zones.read().unwrap();
+        let zone = match zones.iter().find(|zone| zone.origin == question.name) {
+            Some(zone) if question.class == CLASS_IN => zone,
+            _ => {
+                response.header.rescode = ResultCode::NOTAUTH;
+                return vec![response];
+            }
+        };
+        let allowed = self.transfer_clients.contains(&client.ip())
+            || key.is_some_and(|key| self.transfer_keys.contains(key));
+        if !allowed {
+            response.header.rescode = ResultCode::REFUSED;
+            return vec![response];
+        }
+
+        let records = match question.qtype {
+            QueryType::AXFR => xfr::axfr_records(zone),
+            // IXFR carries the SOA of the version the client has
+            _ => match request.authorities.first() {
+                Some(&DnsRecord::SOA { serial, .. }) => xfr::ixfr_records(zone, serial),
+                _ => {
+                    response.header.rescode = ResultCode::FORMERR;
+                    return vec![response];
+                }
+            },
+        };
+        xfr::transfer_messages(request, records)
+    }
+
+    /// Apply the dynamic update in `req_buffer` from `client`. Returns the
+    /// response, and the update unless it could not be parsed.
+    fn handle_update<T: PacketBuffer>(
+        &self,
+        req_buffer: &mut T,
+        client: SocketAddr,
+    ) -> (DnsPacket, Option<UpdateMessage>) {
+        let now = tsig::now();
+        let signed = match tsig::verify(req_buffer, &self.tsig_keys, &[], false, now) {
+            Ok(signed) => signed,
+            Err(e) => return (tsig_error_response(req_buffer, &e, now), None),
+        };
+        let update = match UpdateMessage::from_buffer(req_buffer) {
+            Ok(update) => update,
+            Err(_) => return (error_response(req_buffer, ResultCode::FORMERR), None),
+        };
+
+        let mut response = DnsPacket::new();
+        response.header.id = update.header.id;
+        response.header.opcode = OPCODE_UPDATE;
+        response.header.response = true;
+        response.questions = update.zones.clone();
+        let key = signed.as_ref().map(|signed| &signed.key.name);
+        response.header.rescode = match self.apply_update(&update, client, key) {
+            Ok(()) => ResultCode::NOERROR,
+            Err(rescode) => rescode,
+        };
+        if let Some(signed) = signed {
+            if let Err(e) = tsig::sign(&mut response, signed.key, &signed.tsig.mac, false, now) {
+                eprintln!("failed to sign response to {}: {}", client, e);
+            }
+        }
+        (response, Some(update))
+    }
+
+    /// Apply `update` from `client`, signed with `key` if any
+    fn apply_update(
+        &self,
+        update: &UpdateMessage,
+        client: SocketAddr,
+        key: Option<&DnsName>,
+    ) -> std::result::Result<(), ResultCode> {
+        let origin = match update.zones[..] {
+            [ref zone] if zone.qtype == QueryType::SOA => &zone.name,
+            _ => return Err(ResultCode::FORMERR),
+        };
+
+        let mut zones = self.zones.write().unwrap();
+        let zone = zones
+            .iter_mut()
+            .find(|zone| zone.origin == *origin)
+            .ok_or(ResultCode::NOTAUTH)?;
+        // copies of other servers' zones only change by transfer
+        let secondary = self.secondaries.iter().any(|s| s.origin == *origin);
+        let allowed = self.update_clients.contains(&client.ip())
+            || key.is_some_and(|key| self.update_keys.contains(key));
+        if secondary || !allowed {
+            return Err(ResultCode::REFUSED);
+        }
+
+        // work on a copy, so a failed update leaves nothing half applied
+        let mut updated = zone.clone();
+        if !update::apply(&mut updated, update)? {
+            return Ok(());
+        }
+        updated.record_change(zone);
+        if let Err(e) = updated.save() {
+            eprintln!("failed to save zone {}: {}", updated.origin, e);
+            return Err(ResultCode::SERVFAIL);
+        }
+        *zone = updated;
+        drop(zones);
+
+        self.send_notifies(origin);
+        Ok(())
+    }
+
+    fn log_query(&self, event: &QueryEvent) {
+        if let Some(ref log) = self.query_log {
+            if let Err(e) = log.log(event) {
+                eprintln!("failed to log query from {}: {}", event.client, e);
+            }
+        }
+    }
+
+    /// Answer `request` from `view`, or from the server's own zones,
+    /// upstream and cache without one
+    fn answer(&self, request: &DnsPacket, view: Option<&View>, outcome: &mut Outcome) -> DnsPacket {
+        let (zones, upstream, cache) = match view {
+            Some(view) => (&view.zones, &view.upstream, &view.cache),
+            None => (&self.zones, &self.upstream, &self.cache),
+        };
+
+        let mut response = DnsPacket::new();
+        response.header.id = request.header.id;
+        response.header.opcode = request.header.opcode;
+        response.header.response = true;
+        response.header.recursion_desired = request.header.recursion_desired;
+        response.header.recursion_available = true;
+        response.header.checking_disabled = request.header.checking_disabled;
+        response.questions = request.questions.clone();
+
+        if let Some(ref edns) = request.edns {
+            let mut ours = Edns::new(self.udp_payload_size);
+            ours.dnssec_ok = edns.dnssec_ok;
+            response.edns = Some(ours);
+
+            // version 0 is the only one there is
+            if edns.version > 0 {
+                response.header.rescode = ResultCode::BADVERS;
+                return response;
+            }
+        }
+
+        if request.header.opcode != 0 {
+            response.header.rescode = ResultCode::NOTIMP;
+            return response;
+        }
+        // servers in the wild only ever handle a single question
+        if request.questions.len() != 1 {
+            response.header.rescode = ResultCode::FORMERR;
+            return response;
+        }
+
+        let question = &request.questions[0];
+        // zones, upstream answers and the cache only hold IN data, so CH
+        // and HS questions can not be answered for what they ask
+        if question.class != CLASS_IN {
+            response.header.rescode = ResultCode::NOTIMP;
+            return response;
+        }
+        // zone transfers only run over TCP, see handle_connection
+        if matches!(question.qtype, QueryType::AXFR | QueryType::IXFR) {
+            response.header.rescode = ResultCode::REFUSED;
+            return response;
+        }
+        let zone_answer = find_zone(&zones.read().unwrap(), &question.name)
+            .and_then(|zone| zone.answer(&question.name, question.qtype));
+        if let Some(result) = zone_answer {
+            response.header.authoritative_answer = result.header.authoritative_answer;
+            response.header.rescode = result.header.rescode;
+            response.answers = result.answers;
+            response.authorities = result.authorities;
+            response.resources = result.resources;
+            return response;
+        }
+
+        if let Some(list) = self.blocklist_for(&question.name) {
+            list.policy
+                .apply(&mut response, &question.name, question.qtype);
+            return response;
+        }
+
+        let key = CacheKey::new(&question.name, question.qtype, question.class);
+        let cached = cache.lock().unwrap().lookup(&key);
+        outcome.cache_hit = cached.is_some();
+
+        let checking_disabled = request.header.checking_disabled;
+        let result = match cached {
+            Some(result) => Ok(result),
+            None => self
+                .lookup(
+                    upstream,
+                    question,
+                    request.header.recursion_desired,
+                    checking_disabled,
+                    outcome,
+                )
+                .inspect(|result| {
+                    // unvalidated data must not be served to other clients
+                    if !checking_disabled {
+                        cache.lock().unwrap().insert(key, result);
+                    }
+                }),
+        };
+
+        let dnssec_ok = request.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        match result {
+            Ok(result) => {
+                response.header.rescode = result.header.rescode;
+                // AD only goes to clients that show they understand it
+                // (RFC 6840 section 5.7)
+                response.header.authed_data =
+                    result.header.authed_data && (dnssec_ok || request.header.authed_data);
+                response.answers = result.answers;
+                response.authorities = result.authorities;
+                response.resources = result.resources;
+                if !dnssec_ok {
+                    strip_dnssec(&mut response, question.qtype);
+                }
+
+                // trackers hide behind CNAMEs pointing into their own domains
+                let cloaked = response.answers.iter().find_map(|rec| match rec {
+                    DnsRecord::CNAME { host, .. } => self.blocklist_for(host),
+                    _ => None,
+                });
+                if let Some(list) = cloaked {
+                    list.policy
+                        .apply(&mut response, &question.name, question.qtype);
+                }
+            }
+            Err(_) => response.header.rescode = ResultCode::SERVFAIL,
+        }
+
+        response
+    }
+
+    fn lookup(
+        &self,
+        upstream: &Upstream,
+        question: &DnsQuestion,
+        recursion_desired: bool,
+        checking_disabled: bool,
+        outcome: &mut Outcome,
+    ) -> Result<DnsPacket> {
+        match *upstream {
+            Upstream::Forward(ref servers) => {
+                // always ask for signatures, the cached response may be
+                // handed to a client that wants them later
+                let client = DnsClient {
+                    recursion_desired,
+                    dnssec_ok: true,
+                    checking_disabled,
+                    ..self.client
+                };
+
+                let mut last_err: Error = "no upstream servers configured".into();
+                for server in servers {
+                    match client.send_query(&question.name, question.qtype, *server) {
+                        Ok(result) => {
+                            outcome.upstream = Some(*server);
+                            return Ok(result);
+                        }
+                        Err(e) => last_err = e,
+                    }
+                }
+                Err(last_err)
+            }
+            Upstream::ForwardTls(ref servers) => {
+                let client = DnsClient {
+                    recursion_desired,
+                    dnssec_ok: true,
+                    checking_disabled,
+                    ..self.client
+                };
+
+                let mut last_err: Error = "no upstream servers configured".into();
+                for server in servers {
+                    let query = client.build_query(&question.name, question.qtype);
+                    match server.exchange(vec![query]) {
+                        Ok(mut responses) => {
+                            outcome.upstream = Some(server.server);
+                            return Ok(responses.remove(0));
+                        }
+                        Err(e) => last_err = e,
+                    }
+                }
+                Err(last_err)
+            }
+            Upstream::Recursive(ref resolver) => {
+                resolver.resolve_with_cd(&question.name, question.qtype, checking_disabled)
+            }
+        }
+    }
+
+    /// The largest UDP response `request` may be answered with
+    fn udp_response_limit(&self, request: &DnsPacket) -> usize {
+        match request.edns {
+            Some(ref edns) => edns.max_payload().min(self.udp_payload_size as usize),
+            None => MAX_UDP_SIZE,
+        }
+    }
+
+    /// Serve queries arriving on `socket` forever, handing them to a pool of
+    /// `udp_workers` threads so one slow upstream does not hold up everybody
+    /// else. Queries arriving while the pool is swamped are dropped.
+    pub fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+        let (queue, pending) = mpsc::sync_channel(UDP_QUEUE_SIZE);
+        let pending = Arc::new(Mutex::new(pending));
+        for i in 0..self.udp_workers.max(1) {
+            let server = Arc::clone(&self);
+            let socket = Arc::clone(&socket);
+            let pending = Arc::clone(&pending);
+            thread::Builder::new()
+                .name(format!("udp-worker-{}", i))
+                .spawn(move || server.answer_udp(&socket, &pending))?;
+        }
+
+        let mut received = vec![0; MAX_MESSAGE_SIZE];
+
+        loop {
+            let (len, src) = match socket.recv_from(&mut received) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("failed to receive query: {}", e);
+                    continue;
+                }
+            };
+            let req_buffer = VectorPacketBuffer::from_bytes(&received[..len]);
+
+            if !self.allow_query(src) {
+                continue;
+            }
+
+            match queue.try_send((req_buffer, src)) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => return Err("UDP workers exited".into()),
+            }
+        }
+    }
+
+    /// Answer the queries `serve_udp` receives, until it stops
+    fn answer_udp(
+        &self,
+        socket: &UdpSocket,
+        pending: &Mutex<Receiver<(VectorPacketBuffer, SocketAddr)>>,
+    ) {
+        loop {
+            let next = pending.lock().unwrap().recv();
+            let (mut req_buffer, src) = match next {
+                Ok(query) => query,
+                Err(_) => return,
+            };
+
+            let (mut response, request) =
+                self.handle_query_from(&mut req_buffer, src, Transport::Udp);
+            if !self.limit_response(src, &mut response) {
+                continue;
+            }
+            let limit = match request {
+                Some(ref request) => self.udp_response_limit(request),
+                None => MAX_UDP_SIZE,
+            };
+            if let Err(e) = send_response(socket, &mut response, src, limit) {
+                eprintln!("failed to answer {}: {}", src, e);
+            }
+        }
+    }
+
+    /// Whether `client` is within its query quota
+    fn allow_query(&self, client: SocketAddr) -> bool {
+        self.rate_limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.allow_query(client.ip()))
+    }
+
+    /// Apply response rate limiting to a UDP response for `client`,
+    /// truncating it if it slips. Returns whether to send it at all.
+    fn limit_response(&self, client: SocketAddr, response: &mut DnsPacket) -> bool {
+        let verdict = match self.rate_limiter {
+            Some(ref limiter) => limiter.check_response(client.ip(), response),
+            None => Verdict::Send,
+        };
+        match verdict {
+            Verdict::Send => true,
+            Verdict::Slip => {
+                ratelimit::truncate(response);
+                true
+            }
+            Verdict::Drop => false,
+        }
+    }
+
+    /// Serve queries over TCP forever. Each connection gets its own thread
+    /// and may carry any number of queries.
+    pub fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&self);
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    eprintln!("tcp connection failed: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+        let client = stream.peer_addr()?;
+        self.handle_messages(&mut stream, client, Transport::Tcp)
+    }
+
+    /// Answer length-prefixed messages on `stream`, in order, until the
+    /// client is done
+    fn handle_messages<S: Read + Write>(
+        &self,
+        stream: &mut S,
+        client: SocketAddr,
+        transport: Transport,
+    ) -> Result<()> {
+        while let Some(mut req_buffer) = read_message(stream)? {
+            if let Some(request) = transfer_request(&mut req_buffer) {
+                let time = SystemTime::now();
+                let started = Instant::now();
+                let now = tsig::now();
+                let mut messages =
+                    match tsig::verify(&mut req_buffer, &self.tsig_keys, &[], false, now) {
+                        Ok(Some(signed)) => {
+                            let mut messages =
+                                self.handle_transfer(&request, client, Some(&signed.key.name));
+                            tsig::sign_transfer(&mut messages, signed.key, &signed.tsig.mac, now)?;
+                            messages
+                        }
+                        Ok(None) => self.handle_transfer(&request, client, None),
+                        Err(e) => vec![tsig_error_response(&mut req_buffer, &e, now)],
+                    };
+                for message in &mut messages {
+                    write_message(stream, message)?;
+                }
+                stream.flush()?;
+                self.log_query(&QueryEvent {
+                    client,
+                    transport,
+                    time,
+                    latency: started.elapsed(),
+                    query: Some(&request),
+                    response: &messages[0],
+                    cache_hit: false,
+                    upstream: None,
+                });
+                continue;
+            }
+
+            if !self.allow_query(client) {
+                let mut refused = error_response(&mut req_buffer, ResultCode::REFUSED);
+                write_message(stream, &mut refused)?;
+                stream.flush()?;
+                continue;
+            }
+
+            let (mut response, _) = self.handle_query_from(&mut req_buffer, client, transport);
+            if write_message(stream, &mut response).is_err() {
+                let mut servfail = DnsPacket {
+                    header: response.header.clone(),
+                    questions: response.questions.clone(),
+                    ..DnsPacket::new()
+                };
+                servfail.header.rescode = ResultCode::SERVFAIL;
+                write_message(stream, &mut servfail)?;
+            }
+            stream.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Serve DNS over TLS forever, with one thread per connection
+    pub fn serve_tls(self: Arc<Self>, listener: TcpListener, tls: Arc<ServerConfig>) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&self);
+            let tls = Arc::clone(&tls);
+            thread::spawn(move || {
+                if let Err(e) = server.handle_tls_connection(stream, tls) {
+                    eprintln!("tls connection failed: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn handle_tls_connection(&self, stream: TcpStream, tls: Arc<ServerConfig>) -> Result<()> {
+        stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+        let client = stream.peer_addr()?;
+        let conn = ServerConnection::new(tls)?;
+        let mut stream = StreamOwned::new(conn, stream);
+        self.handle_messages(&mut stream, client, Transport::Tls)?;
+
+        stream.conn.send_close_notify();
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Serve DNS over HTTPS forever, with one thread per connection
+    pub fn serve_https(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Arc<ServerConfig>,
+    ) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&self);
+            let tls = Arc::clone(&tls);
+            thread::spawn(move || {
+                if let Err(e) = server.handle_https_connection(stream, tls) {
+                    eprintln!("https connection failed: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn handle_https_connection(&self, stream: TcpStream, tls: Arc<ServerConfig>) -> Result<()> {
+        stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+        let client = stream.peer_addr()?;
+        let conn = ServerConnection::new(tls)?;
+        let mut stream = BufReader::new(StreamOwned::new(conn, stream));
+
+        loop {
+            let request = match read_request(&mut stream) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e) if e.is::<io::Error>() => return Err(e),
+                Err(e) => {
+                    // a broken request leaves the stream out of sync
+                    let response = HttpResponse::error(400, &e.to_string());
+                    write_response(stream.get_mut(), &response)?;
+                    break;
+                }
+            };
+
+            let response = self.handle_http(&request, client);
+            write_response(stream.get_mut(), &response)?;
+            if request.wants_close() {
+                break;
+            }
+        }
+
+        stream.get_mut().conn.send_close_notify();
+        stream.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// Answer a DoH request, a DNS message in a POST body or in the `dns`
+    /// parameter of a GET, sent by `client`
+    pub fn handle_http(&self, request: &HttpRequest, client: SocketAddr) -> HttpResponse {
+        if request.path() != DOH_PATH {
+            return HttpResponse::error(404, "no such endpoint");
+        }
+        if !self.allow_query(client) {
+            return HttpResponse::error(429, "too many queries");
+        }
+
+        let message = match request.method.as_str() {
+            "GET" => match request.query_param("dns").and_then(from_base64url) {
+                Some(message) => message,
+                None => return HttpResponse::error(400, "missing or invalid dns parameter"),
+            },
+            "POST" => {
+                let content_type = request.header("Content-Type").unwrap_or_default();
+                if !content_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                    return HttpResponse::error(415, "expected application/dns-message");
+                }
+                request.body.clone()
+            }
+            _ => return HttpResponse::error(405, "only GET and POST are supported"),
+        };
+
+        let request = match DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&message)) {
+            Ok(request) => request,
+            Err(e) => return HttpResponse::error(400, &format!("malformed DNS message: {}", e)),
+        };
+        let mut response = self.handle_request_from(&request, client, Transport::Https);
+        HttpResponse::dns_message(&mut response)
+            .unwrap_or_else(|e| HttpResponse::error(500, &e.to_string()))
+    }
+}
+
+/// The most specific zone containing `qname`
+fn find_zone<'a>(zones: &'a [Zone], qname: &DnsName) -> Option<&'a Zone> {
+    zones
+        .iter()
+        .filter(|zone| qname.is_subdomain_of(&zone.origin))
+        .max_by_key(|zone| zone.origin.label_count())
+}
+
+/// The opcode of the message in `buffer`, without parsing the rest
+fn opcode<T: PacketBuffer>(buffer: &T) -> Option<u8> {
+    buffer.get(2).ok().map(|flags| (flags >> 3) & 0x0F)
+}
+
+/// The request in `buffer` if it asks for a zone transfer. Anything else is
+/// left for `handle_query_from`, with the buffer rewound.
+fn transfer_request<T: PacketBuffer>(buffer: &mut T) -> Option<DnsPacket> {
+    let request = match opcode(buffer) {
+        Some(0) => DnsPacket::from_buffer(buffer).ok(),
+        _ => None,
+    };
+    buffer.seek(0);
+    request.filter(|request| {
+        matches!(
+            request.questions[..],
+            [ref question] if matches!(question.qtype, QueryType::AXFR | QueryType::IXFR)
+        )
+    })
+}
+
+/// Drop the DNSSEC records a client did not ask for with the DO bit,
+/// keeping those it queried for explicitly (RFC 4035 section 3.2.1)
+fn strip_dnssec(response: &mut DnsPacket, qtype: QueryType) {
+    let is_dnssec = |rtype| matches!(rtype, QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3);
+    response
+        .answers
+        .retain(|rec| rec.query_type() == qtype || !is_dnssec(rec.query_type()));
+    response
+        .authorities
+        .retain(|rec| !is_dnssec(rec.query_type()));
+    response
+        .resources
+        .retain(|rec| !is_dnssec(rec.query_type()));
+}
+
+/// Build a response carrying only an error code, for requests too broken
+/// to parse. As much of the header as can be read is echoed back.
+fn error_response<T: PacketBuffer>(req_buffer: &mut T, rescode: ResultCode) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    req_buffer.seek(0);
+    response.header.id = req_buffer.read_u16().unwrap_or(0);
+    if let Ok(flags) = req_buffer.read() {
+        response.header.recursion_desired = (flags & 1) > 0;
+        response.header.opcode = (flags >> 3) & 0x0F;
+    }
+    response.header.response = true;
+    response.header.recursion_available = true;
+    response.header.rescode = rescode;
+    response
+}
+
+/// The response to a request whose TSIG did not check out
+fn tsig_error_response<T: PacketBuffer>(
+    req_buffer: &mut T,
+    error: &TsigError,
+    now: u64,
+) -> DnsPacket {
+    let mut response = error_response(req_buffer, ResultCode::NOTAUTH);
+    if let Err(e) = tsig::reject(&mut response, error, now) {
+        eprintln!("failed to sign TSIG error response: {}", e);
+    }
+    response
+}
+
+/// Send `response` in a datagram of at most `limit` bytes
+fn send_response(
+    socket: &UdpSocket,
+    response: &mut DnsPacket,
+    dst: SocketAddr,
+    limit: usize,
+) -> Result<()> {
+    let mut res_buffer = VectorPacketBuffer::with_limit(limit);
+    if response.write(&mut res_buffer).is_err() {
+        // too large for a datagram: send only the header and question, with
+        // TC set so the client asks again over TCP
+        *response = DnsPacket {
+            header: response.header.clone(),
+            questions: response.questions.clone(),
+            edns: response.edns.clone(),
+            ..DnsPacket::new()
+        };
+        response.header.truncated_message = true;
+        res_buffer = VectorPacketBuffer::with_limit(limit);
+        response.write(&mut res_buffer)?;
+    }
+
+    socket.send_to(&res_buffer.buffer[0..res_buffer.pos()], dst)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocklist::{BlockPolicy, Entries};
+    use crate::doh::{self, DohClient};
+    use crate::dot;
+    use crate::packets::{BytePacketBuffer, DnsRecord, QueryType};
+    use crate::querylog::LogFormat;
+    use crate::ratelimit::RateLimits;
+    use crate::testing::{ca_signed_cert, name, self_signed_cert, spawn_server, SharedBuffer};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn upstream() -> SocketAddr {
+        let port = spawn_server(Ipv4Addr::LOCALHOST, 0, |request| {
+            let mut response = DnsPacket::new();
+            if request.questions[0].name == "example.com" {
+                response.answers.push(DnsRecord::A {
+                    domain: name("example.com"),
+                    addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: 60,
+                });
+            } else {
+                response.header.rescode = ResultCode::NXDOMAIN;
+            }
+            response
+        });
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn query(id: u16, qname: &str) -> BytePacketBuffer {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(name(qname), QueryType::A));
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0);
+        buffer
+    }
+
+    fn server(upstreams: Vec<SocketAddr>) -> DnsServer {
+        let mut server = DnsServer::new(Upstream::Forward(upstreams));
+        server.client.timeout = Duration::from_millis(200);
+        server.client.retries = 0;
+        server
+    }
+
+    #[test]
+    fn test_forwards_query() {
+        let server = server(vec![upstream()]);
+
+        let response = server.handle_query(&mut query(0x1234, "example.com"));
+        assert_eq!(response.header.id, 0x1234);
+        assert!(response.header.response);
+        assert!(response.header.recursion_available);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.questions[0].name, "example.com");
+        assert_eq!(response.answers.len(), 1);
+
+        let response = server.handle_query(&mut query(7, "nope.example.com"));
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn test_answers_from_cache() {
+        let queries = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&queries);
+        let port = spawn_server(Ipv4Addr::LOCALHOST, 0, move |request| {
+            *counter.lock().unwrap() += 1;
+            let mut response = DnsPacket::new();
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 60,
+            });
+            response
+        });
+        let server = server(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))]);
+
+        let first = server.handle_query(&mut query(1, "example.com"));
+        let second = server.handle_query(&mut query(2, "EXAMPLE.COM"));
+        assert_eq!(second.header.id, 2);
+        assert_eq!(second.answers, first.answers);
+        assert_eq!(*queries.lock().unwrap(), 1);
+
+        let stats = server.cache.lock().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_answers_from_zone() {
+        let mut server = server(vec![]);
+        server.zones.get_mut().unwrap().push(
+            Zone::parse(
+                "$ORIGIN example.com.\n$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\nwww A 192.0.2.7",
+                "",
+            )
+            .unwrap(),
+        );
+
+        let response = server.handle_query(&mut query(3, "www.example.com"));
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+
+        let response = server.handle_query(&mut query(4, "mail.example.com"));
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(response.authorities[0].query_type(), QueryType::SOA);
+
+        // anything outside the zone still goes upstream, where there is none
+        let response = server.handle_query(&mut query(5, "example.org"));
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    }
+
+    #[test]
+    fn test_blocklists() {
+        let port = spawn_server(Ipv4Addr::LOCALHOST, 0, |request| {
+            let mut response = DnsPacket::new();
+            response.answers.push(DnsRecord::CNAME {
+                domain: request.questions[0].name.clone(),
+                host: name("collect.tracker.test"),
+                ttl: 60,
+            });
+            response
+        });
+        let mut server = server(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))]);
+        server.blocklists.push(Blocklist::new(
+            "ads".into(),
+            BlockPolicy::Refused,
+            Entries::parse("ads.example.com"),
+        ));
+        server.blocklists.push(Blocklist::new(
+            "trackers".into(),
+            "sinkhole=192.0.2.99".parse().unwrap(),
+            Entries::parse("*.tracker.test"),
+        ));
+
+        let response = server.handle_query(&mut query(1, "ads.example.com"));
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        assert!(response.answers.is_empty());
+
+        let response = server.handle_query(&mut query(2, "pixel.tracker.test"));
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            response.answers,
+            [DnsRecord::A {
+                domain: name("pixel.tracker.test"),
+                addr: Ipv4Addr::new(192, 0, 2, 99),
+                ttl: 60,
+            }]
+        );
+
+        // a first-party name aliased to the tracker is caught too
+        let response = server.handle_query(&mut query(3, "metrics.example.com"));
+        assert_eq!(response.answers[0].domain(), &name("metrics.example.com"));
+        assert_eq!(response.answers[0].query_type(), QueryType::A);
+
+        assert_eq!(server.blocklists[0].blocked(), 1);
+        assert_eq!(server.blocklists[1].blocked(), 2);
+    }
+
+    #[test]
+    fn test_query_log() {
+        let upstream = upstream();
+        let mut server = server(vec![upstream]);
+        let out = SharedBuffer::default();
+        server.query_log = Some(QueryLog::new(LogFormat::Json, Box::new(out.clone())).unwrap());
+
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        server.handle_query_from(&mut query(1, "example.com"), client, Transport::Udp);
+        server.handle_query_from(&mut query(2, "example.com"), client, Transport::Tcp);
+        let mut garbage = VectorPacketBuffer::from_bytes(&[0, 3, 1]);
+        server.handle_query_from(&mut garbage, client, Transport::Udp);
+        // closing the log waits for its writer
+        server.query_log = None;
+
+        let log = String::from_utf8(out.contents()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("\"client\":\"127.0.0.1:40000\",\"protocol\":\"udp\",\"id\":1"));
+        assert!(lines[0].contains("\"name\":\"example.com\",\"type\":\"A\",\"rcode\":\"NOERROR\""));
+        assert!(lines[0].contains(&format!(
+            "\"cache_hit\":false,\"upstream\":\"{}\"",
+            upstream
+        )));
+        assert!(lines[1].contains("\"protocol\":\"tcp\""));
+        assert!(lines[1].contains("\"cache_hit\":true,\"upstream\":null"));
+        assert!(lines[2].contains("\"id\":3,\"name\":null,\"type\":null,\"rcode\":\"FORMERR\""));
+    }
+
+    #[test]
+    fn test_falls_back_to_next_upstream() {
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = server(vec![dead.local_addr().unwrap(), upstream()]);
+
+        let response = server.handle_query(&mut query(1, "example.com"));
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_servfail_without_upstream() {
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = server(vec![dead.local_addr().unwrap()]);
+
+        let response = server.handle_query(&mut query(0xbeef, "example.com"));
+        assert_eq!(response.header.id, 0xbeef);
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    }
+
+    #[test]
+    fn test_formerr_on_garbage() {
+        let server = server(vec![]);
+
+        // header claims a question that isn't there
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buffer[..13].copy_from_slice(&[
+            0xab, 0xcd, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3f,
+        ]);
+        buffer.buffer[13..].fill(0xc0);
+
+        let response = server.handle_query(&mut buffer);
+        assert_eq!(response.header.id, 0xabcd);
+        assert!(response.header.response);
+        assert!(response.header.recursion_desired);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    }
+
+    #[test]
+    fn test_only_answers_class_in() {
+        let server = server(vec![upstream()]);
+        let mut packet = DnsPacket::new();
+        packet.header.id = 7;
+        packet.questions.push(DnsQuestion {
+            class: 3,
+            ..DnsQuestion::new(name("version.bind"), QueryType::TXT)
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0);
+
+        let response = server.handle_query(&mut buffer);
+        assert_eq!(response.header.rescode, ResultCode::NOTIMP);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.questions[0].class, 3);
+    }
+
+    #[test]
+    fn test_truncates_and_falls_back_to_tcp() {
+        let mut zone = String::from("$ORIGIN example.com.\n$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\n");
+        for i in 0..40 {
+            zone.push_str(&format!("big A 192.0.2.{}\n", i));
+        }
+        let mut server = server(vec![]);
+        server
+            .zones
+            .get_mut()
+            .unwrap()
+            .push(Zone::parse(&zone, "").unwrap());
+        let server = Arc::new(server);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let udp_server = Arc::clone(&server);
+        thread::spawn(move || {
+            let _ = udp_server.serve_udp(socket);
+        });
+        thread::spawn(move || {
+            let _ = server.serve_tcp(listener);
+        });
+
+        let client = DnsClient {
+            timeout: Duration::from_secs(1),
+            edns_payload_size: None,
+            ..DnsClient::new()
+        };
+        let response = client
+            .send_query_udp(&name("big.example.com"), QueryType::A, addr)
+            .unwrap();
+        assert!(response.header.truncated_message);
+        assert!(response.answers.is_empty());
+
+        let response = client
+            .send_query(&name("big.example.com"), QueryType::A, addr)
+            .unwrap();
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers.len(), 40);
+
+        // with EDNS the whole answer fits in a datagram
+        let client = DnsClient {
+            edns_payload_size: Some(4096),
+            ..client
+        };
+        let response = client
+            .send_query_udp(&name("big.example.com"), QueryType::A, addr)
+            .unwrap();
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers.len(), 40);
+        assert_eq!(response.edns.unwrap().udp_payload_size, 1232);
+    }
+
+    #[test]
+    fn test_dnssec_bits() {
+        // a validating upstream, remembering the CD bit of each query
+        let seen_cd = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&seen_cd);
+        let port = spawn_server(Ipv4Addr::LOCALHOST, 0, move |request| {
+            seen.lock().unwrap().push(request.header.checking_disabled);
+            assert!(request.edns.as_ref().unwrap().dnssec_ok);
+
+            let mut response = DnsPacket::new();
+            response.header.authed_data = true;
+            response.answers.push(DnsRecord::A {
+                domain: name("example.com"),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 60,
+            });
+            response.answers.push(DnsRecord::RRSIG {
+                domain: name("example.com"),
+                type_covered: QueryType::A,
+                algorithm: 15,
+                labels: 2,
+                original_ttl: 60,
+                expiration: 2,
+                inception: 1,
+                key_tag: 1,
+                signer_name: name("example.com"),
+                signature: vec![0; 64],
+                ttl: 60,
+            });
+            response
+        });
+        let server = server(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))]);
+
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new(name("example.com"), QueryType::A));
+        let response = server.handle_request(&request);
+        assert!(!response.header.authed_data);
+        assert_eq!(response.answers.len(), 1);
+
+        // signatures and AD only for clients asking with DO
+        let mut edns = Edns::new(4096);
+        edns.dnssec_ok = true;
+        request.edns = Some(edns);
+        let response = server.handle_request(&request);
+        assert!(response.header.authed_data);
+        assert_eq!(response.answers.len(), 2);
+
+        // CD goes upstream, and what comes back is not cached
+        request.header.checking_disabled = true;
+        request.questions[0].name = name("cd.example.com");
+        let response = server.handle_request(&request);
+        assert!(response.header.checking_disabled);
+        request.header.checking_disabled = false;
+        server.handle_request(&request);
+        assert_eq!(*seen_cd.lock().unwrap(), [false, true, false]);
+    }
+
+    #[test]
+    fn test_serve_https() {
+        let (cert, key) = self_signed_cert();
+        let tls = doh::server_config(vec![cert.clone()], key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Arc::new(server(vec![upstream()]));
+        thread::spawn(move || {
+            let _ = server.serve_https(listener, tls);
+        });
+
+        let url = format!("https://localhost:{}/dns-query", port)
+            .parse()
+            .unwrap();
+        let mut client = DohClient::with_tls_config(url, doh::client_config(vec![cert]).unwrap());
+        client.client.timeout = Duration::from_secs(2);
+        for use_get in [false, true] {
+            client.use_get = use_get;
+            let response = client
+                .send_query(&name("example.com"), QueryType::A)
+                .unwrap();
+            assert_eq!(response.header.id, 0);
+            assert_eq!(response.answers.len(), 1);
+        }
+        let response = client
+            .send_query(&name("nope.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn test_serve_tls() {
+        let (ca, cert, key) = ca_signed_cert();
+        let tls = dot::server_config(vec![cert], key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dot_server = Arc::new(server(vec![upstream()]));
+        thread::spawn(move || {
+            let _ = dot_server.serve_tls(listener, tls);
+        });
+
+        let roots = dot::client_config(vec![ca]).unwrap();
+        let client = DotClient::with_tls_config(addr, "localhost", Arc::clone(&roots)).unwrap();
+        let responses = client
+            .send_queries(&[
+                (name("example.com"), QueryType::A),
+                (name("nope.example.com"), QueryType::A),
+            ])
+            .unwrap();
+        assert_eq!(responses[0].answers.len(), 1);
+        assert_eq!(responses[1].header.rescode, ResultCode::NXDOMAIN);
+
+        // forwarding over TLS to that server
+        let mut forwarder = server(vec![]);
+        forwarder.upstream = Upstream::ForwardTls(vec![client]);
+        let response = forwarder.handle_query(&mut query(1, "example.com"));
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+
+        // the certificate has to be for the name we expect
+        let client = DotClient::with_tls_config(addr, "example.com", roots).unwrap();
+        assert!(client
+            .send_query(&name("example.com"), QueryType::A)
+            .is_err());
+    }
+
+    #[test]
+    fn test_http_errors() {
+        let server = server(vec![]);
+        let request = |method: &str, target: &str, content_type: &str, body: &[u8]| HttpRequest {
+            method: method.into(),
+            target: target.into(),
+            headers: vec![("Content-Type".into(), content_type.into())],
+            body: body.to_vec(),
+        };
+
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 50000));
+        let status = |req| server.handle_http(&req, client).status;
+        assert_eq!(status(request("GET", "/", "", b"")), 404);
+        assert_eq!(status(request("PUT", DOH_PATH, DNS_MESSAGE, b"")), 405);
+        assert_eq!(status(request("POST", DOH_PATH, "text/plain", b"")), 415);
+        assert_eq!(status(request("POST", DOH_PATH, DNS_MESSAGE, b"\x01")), 400);
+        assert_eq!(status(request("GET", "/dns-query?dns=AA==", "", b"")), 400);
+        assert_eq!(status(request("GET", "/dns-query", "", b"")), 400);
+
+        // a query for a local zone, the way RFC 8484 section 4.1.1 shows it
+        let mut zone = String::from("$ORIGIN example.com.\n$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\n");
+        zone.push_str("www A 192.0.2.80\n");
+        let mut server = server;
+        server
+            .zones
+            .get_mut()
+            .unwrap()
+            .push(Zone::parse(&zone, "").unwrap());
+        let response = server.handle_http(
+            &request(
+                "GET",
+                "/dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB",
+                "",
+                b"",
+            ),
+            client,
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some(DNS_MESSAGE));
+        assert_eq!(response.header("Cache-Control"), Some("max-age=300"));
+        let packet =
+            DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&response.body)).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_edns_in_response() {
+        let server = server(vec![]);
+
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new(name("example.com"), QueryType::A));
+        let response = server.handle_request(&request);
+        assert!(response.edns.is_none());
+
+        let mut edns = Edns::new(4096);
+        edns.dnssec_ok = true;
+        request.edns = Some(edns);
+        let response = server.handle_request(&request);
+        let edns = response.edns.unwrap();
+        assert_eq!(edns.udp_payload_size, 1232);
+        assert!(edns.dnssec_ok);
+
+        request.edns.as_mut().unwrap().version = 1;
+        let response = server.handle_request(&request);
+        assert_eq!(response.header.rescode, ResultCode::BADVERS);
+        assert_eq!(response.edns.unwrap().version, 0);
+    }
+
+    #[test]
+    fn test_dynamic_update() {
+        let path = std::env::temp_dir().join(format!("my-dns-update-{}.zone", std::process::id()));
+        std::fs::write(
+            &path,
+            "$ORIGIN example.com.\n$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\n@ NS ns1\nns1 A 192.0.2.1\n",
+        )
+        .unwrap();
+        let mut server = server(vec![]);
+        server
+            .zones
+            .get_mut()
+            .unwrap()
+            .push(Zone::load(&path).unwrap());
+        server.update_clients.push(Ipv4Addr::LOCALHOST.into());
+
+        let host = DnsRecord::A {
+            domain: name("ci-1.example.com"),
+            addr: Ipv4Addr::new(192, 0, 2, 99),
+            ttl: 60,
+        };
+        let mut update = UpdateMessage::new(name("example.com"));
+        update.require_absent(name("ci-1.example.com"), None);
+        update.add(host.clone());
+
+        // only the clients given may update
+        let mut req_buffer = VectorPacketBuffer::new();
+        update.write(&mut req_buffer).unwrap();
+        req_buffer.seek(0);
+        let stranger = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 200), 5353));
+        let (response, _) = server.handle_query_from(&mut req_buffer, stranger, Transport::Udp);
+        assert_eq!(response.header.opcode, OPCODE_UPDATE);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(server);
+        let tcp_server = Arc::clone(&server);
+        thread::spawn(move || {
+            let _ = tcp_server.serve_tcp(listener);
+        });
+
+        let client = &server.client;
+        let response = client.send_update(&mut update, None, addr).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        let response = server.handle_query(&mut query(1, "ci-1.example.com"));
+        assert_eq!(response.answers, vec![host.clone()]);
+
+        // the prerequisite no longer holds
+        let response = client.send_update(&mut update, None, addr).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::YXDOMAIN);
+        let mut other = UpdateMessage::new(name("example.org"));
+        let response = client.send_update(&mut other, None, addr).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+
+        // the change survives a restart
+        let saved = Zone::load(&path).unwrap();
+        assert_eq!(saved.serial(), 2);
+        assert_eq!(saved.rrset(&name("ci-1.example.com"), QueryType::A), [host]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tsig() {
+        let key: TsigKey = "xfr-key:c2VjcmV0IHNoYXJlZCBieSBib3RoIHNpZGVz"
+            .parse()
+            .unwrap();
+        let zone = Zone::parse(
+            "$ORIGIN example.com.\n$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\n@ NS ns1\nns1 A 192.0.2.1\n",
+            "",
+        )
+        .unwrap();
+        let mut primary = server(vec![]);
+        primary.zones.get_mut().unwrap().push(zone);
+        primary.tsig_keys.push(key.clone());
+        primary.update_keys.push(key.name.clone());
+        primary.transfer_keys.push(key.name.clone());
+        let (primary, addr) = serve_loopback(primary);
+        let client = &primary.client;
+
+        // the key stands in for an allowed address, and the response is
+        // signed too
+        let mut update = UpdateMessage::new(name("example.com"));
+        update.add(DnsRecord::A {
+            domain: name("ci-1.example.com"),
+            addr: Ipv4Addr::new(192, 0, 2, 99),
+            ttl: 60,
+        });
+        let response = client.send_update(&mut update, Some(&key), addr).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.tsig.is_some());
+        let response = client.send_update(&mut update, None, addr).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+
+        let forged = TsigKey::new(key.name.clone(), b"guessed".to_vec());
+        let response = client
+            .send_update(&mut update, Some(&forged), addr)
+            .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+        assert_eq!(response.tsig.unwrap().error, tsig::BADSIG);
+        let unknown = TsigKey::new(name("other-key"), key.secret.clone());
+        let response = client
+            .send_update(&mut update, Some(&unknown), addr)
+            .unwrap();
+        assert_eq!(response.tsig.unwrap().error, ResultCode::BADKEY.to_num());
+
+        // a stale signature gets a signed BADTIME
+        let mut req_buffer = VectorPacketBuffer::new();
+        update.write(&mut req_buffer).unwrap();
+        let mut message = req_buffer.buffer[..req_buffer.pos()].to_vec();
+        let stale = tsig::sign_message(&message, &key, &[], false, 1_000_000).unwrap();
+        tsig::append(&mut message, &stale).unwrap();
+        let (mut response, _) = primary.handle_query_from(
+            &mut VectorPacketBuffer::from_bytes(&message),
+            addr,
+            Transport::Tcp,
+        );
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+        let mut res_buffer = VectorPacketBuffer::new();
+        response.write(&mut res_buffer).unwrap();
+        res_buffer.seek(0);
+        let keys = [key.clone()];
+        let verified = tsig::verify(&mut res_buffer, &keys, &stale.mac, false, 1_000_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(verified.tsig.error, ResultCode::BADTIME.to_num());
+
+        // transfers take the key as well, with every message signed
+        let transfer = xfr::request_transfer(client, &name("example.com"), None, Some(&key), addr);
+        match transfer.unwrap() {
+            Transfer::Full(zone) => assert_eq!(zone.serial(), 2),
+            other => panic!("expected a full transfer, got {:?}", other),
+        }
+        assert!(xfr::request_transfer(client, &name("example.com"), None, None, addr).is_err());
+    }
+
+    /// Serve `server` on a fresh loopback port, over UDP and TCP alike
+    fn serve_loopback(server: DnsServer) -> (Arc<DnsServer>, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let server = Arc::new(server);
+        let (udp_server, tcp_server) = (Arc::clone(&server), Arc::clone(&server));
+        thread::spawn(move || {
+            let _ = udp_server.serve_udp(socket);
+        });
+        thread::spawn(move || {
+            let _ = tcp_server.serve_tcp(listener);
+        });
+        (server, addr)
+    }
+
+    fn wait_for_answer(server: &DnsServer, qname: &str) -> DnsPacket {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let response = server.handle_query(&mut query(1, qname));
+            if !response.answers.is_empty() || Instant::now() > deadline {
+                return response;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_zone_transfer() {
+        let mut secondary = server(vec![]);
+        let zone = Zone::parse(
+            "$ORIGIN example.com.\n$TTL 300\n@ SOA ns1 admin 1 7200 3600 4 5\n@ NS ns1\nns1 A 192.0.2.1\n",
+            "",
+        )
+        .unwrap();
+        let mut primary = server(vec![]);
+        primary.zones.get_mut().unwrap().push(zone);
+        primary.update_clients.push(Ipv4Addr::LOCALHOST.into());
+        primary.transfer_clients.push(Ipv4Addr::LOCALHOST.into());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        primary.notify_targets.push(socket.local_addr().unwrap());
+        let (primary, primary_addr) = serve_loopback(primary);
+
+        // the secondary starts out empty and picks the zone up in full
+        secondary
+            .secondaries
+            .push(format!("example.com={}", primary_addr).parse().unwrap());
+        let secondary = Arc::new(secondary);
+        let udp_server = Arc::clone(&secondary);
+        thread::spawn(move || {
+            let _ = udp_server.serve_udp(socket);
+        });
+        let watcher = Arc::clone(&secondary);
+        thread::spawn(move || watcher.watch_secondaries());
+        let response = wait_for_answer(&secondary, "ns1.example.com");
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 1);
+
+        // an update on the primary reaches the secondary through NOTIFY
+        let host = DnsRecord::A {
+            domain: name("ci-1.example.com"),
+            addr: Ipv4Addr::new(192, 0, 2, 99),
+            ttl: 60,
+        };
+        let mut update = UpdateMessage::new(name("example.com"));
+        update.add(host.clone());
+        let response = primary
+            .client
+            .send_update(&mut update, None, primary_addr)
+            .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        let response = wait_for_answer(&secondary, "ci-1.example.com");
+        assert_eq!(response.answers, vec![host]);
+        assert_eq!(secondary.zones.read().unwrap()[0].serial(), 2);
+
+        // a secondary at serial 1 only gets the difference
+        let old_soa = DnsRecord::SOA {
+            domain: name("example.com"),
+            mname: name("ns1.example.com"),
+            rname: name("admin.example.com"),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 4,
+            minimum: 5,
+            ttl: 300,
+        };
+        let client = &primary.client;
+        let transfer = xfr::request_transfer(
+            client,
+            &name("example.com"),
+            Some(&old_soa),
+            None,
+            primary_addr,
+        );
+        match transfer.unwrap() {
+            Transfer::Incremental(changes) => assert_eq!(changes.len(), 1),
+            other => panic!("expected an incremental transfer, got {:?}", other),
+        }
+
+        // strangers and UDP don't get the zone; NOTIFY only comes from the primary
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new(name("example.com"), QueryType::AXFR));
+        let stranger = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 200), 5353));
+        let responses = primary.handle_transfer(&request, stranger, None);
+        assert_eq!(responses[0].header.rescode, ResultCode::REFUSED);
+        let response = primary.handle_request(&request);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+
+        request.header.opcode = OPCODE_NOTIFY;
+        request.questions[0].qtype = QueryType::SOA;
+        let response = secondary.handle_notify(&request, stranger);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        let response = secondary.handle_notify(&request, primary_addr);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        let response = primary.handle_notify(&request, primary_addr);
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+    }
+
+    #[test]
+    fn test_rate_limiting() {
+        let mut server = server(vec![upstream()]);
+        server.rate_limiter = Some(RateLimiter::new(RateLimits {
+            responses: 1,
+            queries: 4,
+            slip: 2,
+            ..RateLimits::default()
+        }));
+        let client = SocketAddr::from((Ipv4Addr::new(198, 51, 100, 7), 5353));
+
+        let mut sent = Vec::new();
+        for id in 0..3 {
+            let mut response = server.handle_query(&mut query(id, "example.com"));
+            if server.limit_response(client, &mut response) {
+                sent.push(response);
+            }
+        }
+        // the second response is dropped, the third slips out truncated
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].answers.len(), 1);
+        assert!(sent[1].header.truncated_message);
+        assert!(sent[1].answers.is_empty());
+        assert_eq!(sent[1].header.id, 2);
+
+        // the quota covers every transport
+        for _ in 0..4 {
+            assert!(server.allow_query(client));
+        }
+        let request = HttpRequest {
+            method: "GET".into(),
+            target: format!("{}?dns=AAAB", DOH_PATH),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        assert_eq!(server.handle_http(&request, client).status, 429);
+    }
+
+    #[test]
+    fn test_views() {
+        let zone = Zone::parse(
+            "$ORIGIN corp.example.\n$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\nwiki A 10.0.0.80\n",
+            "",
+        )
+        .unwrap();
+        let mut internal = View::new(
+            "internal",
+            vec!["10.0.0.0/8".parse().unwrap()],
+            Upstream::Forward(vec![upstream()]),
+        );
+        internal.zones.get_mut().unwrap().push(zone);
+        let mut server = server(vec![upstream()]);
+        server.views.push(internal);
+        server
+            .client_subnet_sources
+            .push("192.0.2.53".parse().unwrap());
+
+        let request = |ecs: Option<&str>| {
+            let mut request = DnsPacket::from_buffer(&mut query(1, "wiki.corp.example")).unwrap();
+            let mut edns = Edns::new(1232);
+            edns.options
+                .extend(ecs.map(|s| view::client_subnet_option(&s.parse().unwrap(), 0)));
+            request.edns = Some(edns);
+            request
+        };
+        let inside = SocketAddr::from((Ipv4Addr::new(10, 1, 2, 3), 5353));
+        let outside = SocketAddr::from((Ipv4Addr::new(198, 51, 100, 7), 5353));
+        let forwarder = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 53), 53));
+
+        let response = server.handle_request_from(&request(None), inside, Transport::Udp);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 1);
+        let response = server.handle_request_from(&request(None), outside, Transport::Udp);
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+        // the client subnet only counts coming from a trusted forwarder
+        let ecs = Some("10.9.0.0/16");
+        let response = server.handle_request_from(&request(ecs), outside, Transport::Udp);
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        let response = server.handle_request_from(&request(ecs), forwarder, Transport::Udp);
+        assert_eq!(response.answers.len(), 1);
+        let option = &response.edns.unwrap().options[0];
+        assert_eq!(option.data, [0, 1, 16, 16, 10, 9]);
+        let response = server.handle_request_from(&request(None), forwarder, Transport::Udp);
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+        // each view caches on its own
+        let public = DnsPacket::from_buffer(&mut query(2, "example.com")).unwrap();
+        server.handle_request_from(&public, outside, Transport::Udp);
+        let entries = |cache: &Mutex<DnsCache>| cache.lock().unwrap().stats().entries;
+        assert_eq!(entries(&server.cache), 1);
+        assert_eq!(entries(&server.views[0].cache), 0);
+        server.handle_request_from(&public, inside, Transport::Udp);
+        assert_eq!(entries(&server.views[0].cache), 1);
+    }
+
+    #[test]
+    fn test_serve_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut server = server(vec![upstream()]);
+        server.udp_workers = 2;
+        let server = Arc::new(server);
+        thread::spawn(move || {
+            let _ = server.serve_udp(socket);
+        });
+
+        let client = DnsClient {
+            timeout: Duration::from_secs(1),
+            ..DnsClient::new()
+        };
+        // more queries than workers, which keep going after the first
+        for _ in 0..4 {
+            let response = client
+                .send_query(&name("example.com"), QueryType::A, addr)
+                .unwrap();
+            assert!(response.header.recursion_available);
+            assert_eq!(response.answers.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Length-prefixed framing for DNS over TCP (RFC 1035 section 4.2.2)
+
+use std::io::{Read, Write};
+
+use crate::packets::{DnsPacket, PacketBuffer, VectorPacketBuffer};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Read one message, preceded by its two byte length. Returns `None` if the
+/// peer closed the connection before starting a new message.
+pub fn read_message<R: Read>(stream: &mut R) -> Result<Option<VectorPacketBuffer>> {
+    let mut len_bytes = [0; 2];
+    match stream.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u16::from_be_bytes(len_bytes) as usize;
+    let mut message = vec![0; len];
+    stream.read_exact(&mut message)?;
+
+    Ok(Some(VectorPacketBuffer::from_bytes(&message)))
+}
+
+/// Write `packet` preceded by its two byte length
+pub fn write_message<W: Write>(stream: &mut W, packet: &mut DnsPacket) -> Result<()> {
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer)?;
+    write_raw_message(stream, &buffer.buffer[..buffer.pos()])
+}
+
+/// Write a message that is already in wire format, preceded by its length
+pub fn write_raw_message<W: Write>(stream: &mut W, message: &[u8]) -> Result<()> {
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{DnsQuestion, QueryType};
+    use crate::testing::name;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_framing_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 42;
+        packet
+            .questions
+            .push(DnsQuestion::new(name("example.com"), QueryType::A));
+
+        let mut stream = Vec::new();
+        write_message(&mut stream, &mut packet).unwrap();
+        write_message(&mut stream, &mut packet).unwrap();
+        assert_eq!(&stream[..2], &[0, 29]);
+
+        let mut cursor = Cursor::new(stream);
+        for _ in 0..2 {
+            let mut buffer = read_message(&mut cursor).unwrap().unwrap();
+            assert_eq!(DnsPacket::from_buffer(&mut buffer).unwrap(), packet);
+        }
+        assert!(read_message(&mut cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_short_message() {
+        let mut cursor = Cursor::new(vec![0, 12, 1, 2, 3]);
+        assert!(read_message(&mut cursor).is_err());
+    }
+}
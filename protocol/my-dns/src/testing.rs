@@ -0,0 +1,196 @@
+//! Helpers shared by the tests of the different modules
+
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+use crate::dnssec::{self, ECDSAP256SHA256, ED25519};
+use crate::name::DnsName;
+use crate::packets::{BytePacketBuffer, DnsPacket, DnsRecord, PacketBuffer};
+
+/// Parse a name that is known to be valid
+pub fn name(s: &str) -> DnsName {
+    s.parse().unwrap()
+}
+
+/// A self-signed certificate for `localhost` and its key, for testing the
+/// TLS transports
+pub fn self_signed_cert() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+    (certified.cert.der().clone(), key.into())
+}
+
+/// A certificate authority and a certificate for `localhost` it issued,
+/// with the latter's key: `(ca, cert, key)`
+pub fn ca_signed_cert() -> (
+    CertificateDer<'static>,
+    CertificateDer<'static>,
+    PrivateKeyDer<'static>,
+) {
+    let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = rcgen::CertifiedIssuer::self_signed(ca_params, rcgen::KeyPair::generate().unwrap())
+        .unwrap();
+
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&key, &ca)
+        .unwrap();
+    let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+    (ca.der().clone(), cert.der().clone(), key.into())
+}
+
+/// Bind a fake name server on `ip:port` that answers with `handler`,
+/// returning the port it ended up on
+pub fn spawn_server<F>(ip: Ipv4Addr, port: u16, handler: F) -> u16
+where
+    F: Fn(&DnsPacket) -> DnsPacket + Send + 'static,
+{
+    let socket = UdpSocket::bind((ip, port)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    thread::spawn(move || loop {
+        let mut req_buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut req_buffer.buffer).unwrap();
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+        let mut response = handler(&request);
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.questions = request.questions.clone();
+
+        let mut res_buffer = BytePacketBuffer::new();
+        response.write(&mut res_buffer).unwrap();
+        socket
+            .send_to(&res_buffer.buffer[..res_buffer.pos()], src)
+            .unwrap();
+    });
+
+    port
+}
+
+/// A writer whose output can still be read after handing it away
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum KeyPairKind {
+    Ed25519(Ed25519KeyPair),
+    P256(EcdsaKeyPair),
+}
+
+/// A freshly generated zone key, for signing test zones
+pub struct SigningKey {
+    pub dnskey: DnsRecord,
+    pair: KeyPairKind,
+}
+
+impl SigningKey {
+    pub fn ed25519(zone: &str) -> SigningKey {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = pair.public_key().as_ref().to_vec();
+        SigningKey::new(zone, ED25519, public_key, KeyPairKind::Ed25519(pair))
+    }
+
+    pub fn p256(zone: &str) -> SigningKey {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        // DNSKEY records leave out the uncompressed point prefix
+        let public_key = pair.public_key().as_ref()[1..].to_vec();
+        SigningKey::new(zone, ECDSAP256SHA256, public_key, KeyPairKind::P256(pair))
+    }
+
+    fn new(zone: &str, algorithm: u8, public_key: Vec<u8>, pair: KeyPairKind) -> SigningKey {
+        let dnskey = DnsRecord::DNSKEY {
+            domain: name(zone),
+            flags: dnssec::ZONE_KEY_FLAG | dnssec::SEP_FLAG,
+            protocol: 3,
+            algorithm,
+            public_key,
+            ttl: 3600,
+        };
+        SigningKey { dnskey, pair }
+    }
+
+    /// The DS record the parent zone publishes for this key
+    pub fn ds(&self) -> DnsRecord {
+        DnsRecord::DS {
+            domain: self.dnskey.domain().clone(),
+            key_tag: dnssec::key_tag(&self.dnskey).unwrap(),
+            algorithm: self.algorithm(),
+            digest_type: dnssec::DIGEST_SHA256,
+            digest: dnssec::ds_digest(&self.dnskey, dnssec::DIGEST_SHA256).unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    /// Sign `rrset` with a signature valid for an hour either side of now
+    pub fn sign(&self, rrset: &[DnsRecord]) -> DnsRecord {
+        let now = dnssec::unix_time();
+        self.sign_between(rrset, now - 3600, now + 3600)
+    }
+
+    pub fn sign_between(&self, rrset: &[DnsRecord], inception: u32, expiration: u32) -> DnsRecord {
+        let owner = rrset[0].domain();
+        let labels = owner.label_count() - owner.is_wildcard() as usize;
+        let mut rrsig = DnsRecord::RRSIG {
+            domain: owner.clone(),
+            type_covered: rrset[0].query_type(),
+            algorithm: self.algorithm(),
+            labels: labels as u8,
+            original_ttl: rrset[0].ttl(),
+            expiration,
+            inception,
+            key_tag: dnssec::key_tag(&self.dnskey).unwrap(),
+            signer_name: self.dnskey.domain().clone(),
+            signature: Vec::new(),
+            ttl: rrset[0].ttl(),
+        };
+
+        let data = dnssec::signed_data(&rrsig, rrset).unwrap();
+        let rng = SystemRandom::new();
+        let sig = match self.pair {
+            KeyPairKind::Ed25519(ref pair) => pair.sign(&data).as_ref().to_vec(),
+            KeyPairKind::P256(ref pair) => pair.sign(&rng, &data).unwrap().as_ref().to_vec(),
+        };
+        if let DnsRecord::RRSIG {
+            ref mut signature, ..
+        } = rrsig
+        {
+            *signature = sig;
+        }
+        rrsig
+    }
+
+    fn algorithm(&self) -> u8 {
+        match self.dnskey {
+            DnsRecord::DNSKEY { algorithm, .. } => algorithm,
+            _ => unreachable!(),
+        }
+    }
+}
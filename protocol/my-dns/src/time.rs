@@ -0,0 +1,109 @@
+//! Calendar arithmetic for the UTC timestamps of presentation formats and
+//! logs
+
+/// Render an RRSIG timestamp as `YYYYMMDDHHmmSS` in UTC
+pub fn format_timestamp(secs: u32) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Parse an RRSIG timestamp, either as `YYYYMMDDHHmmSS` or as plain
+/// seconds since the epoch (RFC 4034 section 3.2)
+pub fn parse_timestamp(text: &str) -> Option<u32> {
+    if text.len() != 14 {
+        return text.parse().ok();
+    }
+    if !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| text[range].parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    // the field wraps around in 2106, serial number arithmetic copes
+    Some(secs.rem_euclid(1 << 32) as u32)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Year, month and day of a day count since the epoch, using Howard
+/// Hinnant's algorithm for the proleptic Gregorian calendar
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(format_timestamp(0), "19700101000000");
+        assert_eq!(format_timestamp(1709251199), "20240229235959");
+        assert_eq!(parse_timestamp("20240229235959"), Some(1709251199));
+        assert_eq!(parse_timestamp("1709251199"), Some(1709251199));
+        assert_eq!(parse_timestamp("20241301000000"), None);
+        assert_eq!(parse_timestamp("20240231000000"), None);
+        assert_eq!(parse_timestamp("20230229000000"), None);
+        assert_eq!(parse_timestamp("20000229000000"), Some(951782400));
+        assert_eq!(parse_timestamp("21000229000000"), None);
+        assert_eq!(parse_timestamp("20240431000000"), None);
+        assert_eq!(parse_timestamp("2024022923595x"), None);
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+    }
+}
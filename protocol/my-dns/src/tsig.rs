@@ -0,0 +1,531 @@
+//! Transaction signatures (RFC 8945): a MAC over each message, keyed by a
+//! secret shared with the other side, that authenticates dynamic updates and
+//! zone transfers. Only HMAC-SHA256 is supported.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+use crate::encoding::from_base64;
+use crate::error::DnsError;
+use crate::name::DnsName;
+use crate::packets::{
+    DnsPacket, PacketBuffer, QueryType, ResultCode, Tsig, VectorPacketBuffer, CLASS_ANY,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const HMAC_SHA256: &str = "hmac-sha256";
+
+/// Seconds the clocks of both sides may differ by, as RFC 8945 recommends
+pub const DEFAULT_FUDGE: u16 = 300;
+
+/// TSIG error for a MAC that does not check out. It shares its number with
+/// BADVERS, which only appears in OPT records.
+pub const BADSIG: u16 = 16;
+
+/// A secret shared with another server or client, known by its name
+#[derive(Clone, PartialEq, Eq)]
+pub struct TsigKey {
+    pub name: DnsName,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: DnsName, secret: Vec<u8>) -> TsigKey {
+        TsigKey { name, secret }
+    }
+
+    fn hmac_key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, &self.secret)
+    }
+}
+
+impl fmt::Debug for TsigKey {
+    /// Leave the secret out of logs
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TsigKey({})", self.name)
+    }
+}
+
+impl FromStr for TsigKey {
+    type Err = String;
+
+    /// `[hmac-sha256:]name:secret`, with the secret in base64, the same
+    /// form `nsupdate -y` takes
+    fn from_str(s: &str) -> std::result::Result<TsigKey, String> {
+        let invalid = || format!("Invalid TSIG key: {}", s);
+        let rest = s.strip_prefix("hmac-sha256:").unwrap_or(s);
+        let (name, secret) = rest.split_once(':').ok_or_else(invalid)?;
+        let name = name.parse().map_err(|_| invalid())?;
+        match from_base64(secret) {
+            Some(secret) if !secret.is_empty() => Ok(TsigKey::new(name, secret)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn algorithm() -> DnsName {
+    HMAC_SHA256.parse().unwrap()
+}
+
+/// Seconds since the epoch, as TSIG times go
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Why a signed message was turned down
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TsigError {
+    /// The TSIG record could not be read
+    Malformed(DnsError),
+    /// Signed with a key or algorithm we do not have
+    BadKey(Box<Tsig>),
+    BadSig(Box<Tsig>),
+    /// Signed too long ago, or too far in the future. The key checked out,
+    /// so the error response is signed with it.
+    BadTime(Box<Tsig>, TsigKey),
+}
+
+impl TsigError {
+    /// The error for the TSIG record of the response
+    pub fn code(&self) -> u16 {
+        match *self {
+            TsigError::Malformed(_) => ResultCode::FORMERR.to_num(),
+            TsigError::BadKey(_) => ResultCode::BADKEY.to_num(),
+            TsigError::BadSig(_) => BADSIG,
+            TsigError::BadTime(..) => ResultCode::BADTIME.to_num(),
+        }
+    }
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TsigError::Malformed(ref e) => write!(f, "{}", e),
+            TsigError::BadKey(ref tsig) => write!(f, "unknown TSIG key {}", tsig.key_name),
+            TsigError::BadSig(ref tsig) => {
+                write!(f, "TSIG signature by {} does not verify", tsig.key_name)
+            }
+            TsigError::BadTime(ref tsig, _) => {
+                write!(f, "TSIG time {} is outside the fudge", tsig.time_signed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TsigError {}
+
+/// A message whose TSIG checked out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verified<'a> {
+    pub tsig: Tsig,
+    pub key: &'a TsigKey,
+}
+
+/// What the MAC covers: the MAC it answers, if any, the message as it was
+/// before signing, and the TSIG variables (RFC 8945 section 4.3). Messages
+/// after the first of a transfer only cover the timers of the variables.
+fn signed_data(
+    prior_mac: &[u8],
+    message: &[u8],
+    tsig: &Tsig,
+    timers_only: bool,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if !prior_mac.is_empty() {
+        data.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(prior_mac);
+    }
+    data.extend_from_slice(message);
+
+    let mut variables = VectorPacketBuffer::uncompressed();
+    if !timers_only {
+        variables.write_qname_uncompressed(&tsig.key_name.to_lowercase())?;
+        variables.write_u16(CLASS_ANY)?;
+        variables.write_u32(0)?;
+        variables.write_qname_uncompressed(&tsig.algorithm.to_lowercase())?;
+    }
+    variables.write_u16((tsig.time_signed >> 32) as u16)?;
+    variables.write_u32(tsig.time_signed as u32)?;
+    variables.write_u16(tsig.fudge)?;
+    if !timers_only {
+        variables.write_u16(tsig.error)?;
+        variables.write_u16(tsig.other.len() as u16)?;
+        variables.write_bytes(&tsig.other)?;
+    }
+    data.extend_from_slice(&variables.buffer[..variables.pos()]);
+
+    Ok(data)
+}
+
+/// Sign `message`, in wire format and without a TSIG record, returning the
+/// record to append to it. `prior_mac` is the MAC of the request for a
+/// response, or of the previous message for the rest of a transfer, which
+/// are signed `timers_only`.
+pub fn sign_message(
+    message: &[u8],
+    key: &TsigKey,
+    prior_mac: &[u8],
+    timers_only: bool,
+    time: u64,
+) -> Result<Tsig> {
+    let original_id = match *message {
+        [high, low, ..] => u16::from_be_bytes([high, low]),
+        _ => return Err("message too short to sign".into()),
+    };
+    let mut tsig = Tsig {
+        key_name: key.name.clone(),
+        algorithm: algorithm(),
+        time_signed: time,
+        fudge: DEFAULT_FUDGE,
+        mac: Vec::new(),
+        original_id,
+        error: 0,
+        other: Vec::new(),
+    };
+    let data = signed_data(prior_mac, message, &tsig, timers_only)?;
+    tsig.mac = hmac::sign(&key.hmac_key(), &data).as_ref().to_vec();
+    Ok(tsig)
+}
+
+/// Sign `packet`, replacing any TSIG record it had
+pub fn sign(
+    packet: &mut DnsPacket,
+    key: &TsigKey,
+    prior_mac: &[u8],
+    timers_only: bool,
+    time: u64,
+) -> Result<()> {
+    packet.tsig = None;
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer)?;
+    packet.tsig = Some(sign_message(
+        &buffer.buffer[..buffer.pos()],
+        key,
+        prior_mac,
+        timers_only,
+        time,
+    )?);
+    Ok(())
+}
+
+/// Sign the messages of a zone transfer, each covering the MAC of the one
+/// before, starting from that of the request
+pub fn sign_transfer(
+    messages: &mut [DnsPacket],
+    key: &TsigKey,
+    request_mac: &[u8],
+    time: u64,
+) -> Result<()> {
+    let mut prior_mac = request_mac.to_vec();
+    for (i, message) in messages.iter_mut().enumerate() {
+        sign(message, key, &prior_mac, i > 0, time)?;
+        prior_mac = message
+            .tsig
+            .as_ref()
+            .map_or(Vec::new(), |tsig| tsig.mac.clone());
+    }
+    Ok(())
+}
+
+/// Append `tsig` to a message in wire format
+pub fn append(message: &mut Vec<u8>, tsig: &Tsig) -> Result<()> {
+    if message.len() < 12 {
+        return Err("message too short to sign".into());
+    }
+    let mut buffer = VectorPacketBuffer::uncompressed();
+    tsig.write(&mut buffer)?;
+    message.extend_from_slice(&buffer.buffer[..buffer.pos()]);
+
+    let count = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&count.to_be_bytes());
+    Ok(())
+}
+
+/// Where the last record of the message in `buffer` starts, along with the
+/// record if it is a TSIG one
+fn find_tsig<T: PacketBuffer>(
+    buffer: &mut T,
+) -> std::result::Result<Option<(usize, Tsig)>, DnsError> {
+    buffer.seek(4);
+    let questions = buffer.read_u16()?;
+    let mut records = 0;
+    for _ in 0..3 {
+        records += buffer.read_u16()? as usize;
+    }
+
+    for _ in 0..questions {
+        buffer.read_query_name()?;
+        buffer.step(4);
+    }
+    for _ in 1..records {
+        buffer.read_query_name()?;
+        buffer.step(8);
+        let len = buffer.read_u16()? as usize;
+        buffer.step(len);
+    }
+    if records == 0 {
+        return Ok(None);
+    }
+
+    let start = buffer.pos();
+    buffer.read_query_name()?;
+    if QueryType::from_num(buffer.read_u16()?) != QueryType::TSIG {
+        return Ok(None);
+    }
+    buffer.seek(start);
+    Ok(Some((start, Tsig::read(buffer)?)))
+}
+
+/// Check the TSIG record of the message in `buffer` against `keys`, with
+/// `prior_mac` and `timers_only` as for `sign_message`. Returns `None` if
+/// the message is not signed.
+pub fn verify<'a, T: PacketBuffer>(
+    buffer: &mut T,
+    keys: &'a [TsigKey],
+    prior_mac: &[u8],
+    timers_only: bool,
+    now: u64,
+) -> std::result::Result<Option<Verified<'a>>, TsigError> {
+    let found = find_tsig(buffer);
+    buffer.seek(0);
+    let (start, tsig) = match found.map_err(TsigError::Malformed)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let key = keys
+        .iter()
+        .find(|key| key.name == tsig.key_name)
+        .filter(|_| tsig.algorithm == algorithm());
+    let key = match key {
+        Some(key) => key,
+        None => return Err(TsigError::BadKey(Box::new(tsig))),
+    };
+
+    // the message as it was signed: without the TSIG record, and with the
+    // id it was sent with
+    let mut message = buffer
+        .get_range(0, start)
+        .map_err(TsigError::Malformed)?
+        .to_vec();
+    message[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    let count = u16::from_be_bytes([message[10], message[11]])
+        .checked_sub(1)
+        .ok_or(TsigError::Malformed(DnsError::BadTsig(
+            "not in the additional section",
+        )))?;
+    message[10..12].copy_from_slice(&count.to_be_bytes());
+
+    let data = signed_data(prior_mac, &message, &tsig, timers_only)
+        .map_err(|_| TsigError::BadSig(Box::new(tsig.clone())))?;
+    if hmac::verify(&key.hmac_key(), &data, &tsig.mac).is_err() {
+        return Err(TsigError::BadSig(Box::new(tsig)));
+    }
+    if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(TsigError::BadTime(Box::new(tsig), key.clone()));
+    }
+
+    Ok(Some(Verified { tsig, key }))
+}
+
+/// Turn `response` into the answer to a request whose TSIG did not check
+/// out: NOTAUTH, with a TSIG record carrying the error. Only BADTIME
+/// responses are signed, telling our time (RFC 8945 section 5.2).
+pub fn reject(response: &mut DnsPacket, error: &TsigError, now: u64) -> Result<()> {
+    let request = match *error {
+        TsigError::Malformed(_) => {
+            response.header.rescode = ResultCode::FORMERR;
+            return Ok(());
+        }
+        TsigError::BadKey(ref tsig)
+        | TsigError::BadSig(ref tsig)
+        | TsigError::BadTime(ref tsig, _) => tsig,
+    };
+    response.header.rescode = ResultCode::NOTAUTH;
+    response.tsig = None;
+
+    let mut tsig = Tsig {
+        key_name: request.key_name.clone(),
+        algorithm: request.algorithm.clone(),
+        time_signed: now,
+        fudge: request.fudge,
+        mac: Vec::new(),
+        original_id: response.header.id,
+        error: error.code(),
+        other: Vec::new(),
+    };
+    if let TsigError::BadTime(_, ref key) = *error {
+        tsig.time_signed = request.time_signed;
+        tsig.other = now.to_be_bytes()[2..].to_vec();
+
+        let mut buffer = VectorPacketBuffer::new();
+        response.write(&mut buffer)?;
+        let data = signed_data(&request.mac, &buffer.buffer[..buffer.pos()], &tsig, false)?;
+        tsig.mac = hmac::sign(&key.hmac_key(), &data).as_ref().to_vec();
+    }
+    response.tsig = Some(tsig);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{DnsQuestion, DnsRecord};
+    use crate::testing::name;
+
+    const TIME: u64 = 1_700_000_000;
+
+    fn key() -> TsigKey {
+        "hmac-sha256:update-key.:c2VjcmV0IHNoYXJlZCBieSBib3RoIHNpZGVz"
+            .parse()
+            .unwrap()
+    }
+
+    fn request() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4711;
+        packet
+            .questions
+            .push(DnsQuestion::new(name("example.com"), QueryType::SOA));
+        packet.resources.push(DnsRecord::A {
+            domain: name("ns.example.com"),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 300,
+        });
+        packet
+    }
+
+    fn wire(packet: &mut DnsPacket) -> VectorPacketBuffer {
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        VectorPacketBuffer::from_bytes(&buffer.buffer[..buffer.pos()])
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keys = [key()];
+        let mut request = request();
+        sign(&mut request, &keys[0], &[], false, TIME).unwrap();
+        let mut buffer = wire(&mut request);
+        let verified = verify(&mut buffer, &keys, &[], false, TIME + 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(verified.key, &keys[0]);
+        assert_eq!(verified.tsig.mac.len(), 32);
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed, request);
+
+        // a forwarder may change the id, the original one is signed
+        buffer.buffer[..2].copy_from_slice(&[0, 1]);
+        assert!(verify(&mut buffer, &keys, &[], false, TIME).is_ok());
+
+        // the response covers the request MAC
+        let mut response = request.clone();
+        response.header.response = true;
+        sign(&mut response, &keys[0], &verified.tsig.mac, false, TIME).unwrap();
+        let mut buffer = wire(&mut response);
+        assert!(verify(&mut buffer, &keys, &verified.tsig.mac, false, TIME).is_ok());
+        assert!(matches!(
+            verify(&mut buffer, &keys, &[], false, TIME),
+            Err(TsigError::BadSig(_))
+        ));
+
+        let mut unsigned = wire(&mut self::request());
+        assert_eq!(verify(&mut unsigned, &keys, &[], false, TIME), Ok(None));
+    }
+
+    #[test]
+    fn test_append_to_raw_message() {
+        let mut request = request();
+        let mut message = wire(&mut request).buffer;
+        let tsig = sign_message(&message, &key(), &[], false, TIME).unwrap();
+        append(&mut message, &tsig).unwrap();
+
+        let mut buffer = VectorPacketBuffer::from_bytes(&message);
+        assert!(verify(&mut buffer, &[key()], &[], false, TIME).is_ok());
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.tsig, Some(tsig));
+        assert_eq!(parsed.resources.len(), 1);
+    }
+
+    #[test]
+    fn test_rejections() {
+        let keys = [key()];
+        let mut request = request();
+        sign(&mut request, &keys[0], &[], false, TIME).unwrap();
+
+        // tampered with on the way
+        let mut tampered = request.clone();
+        tampered.header.recursion_desired = true;
+        let error = verify(&mut wire(&mut tampered), &keys, &[], false, TIME).unwrap_err();
+        assert_eq!(error.code(), BADSIG);
+
+        let other = TsigKey::new(name("other-key"), b"secret".to_vec());
+        let error = verify(&mut wire(&mut request), &[other], &[], false, TIME).unwrap_err();
+        assert_eq!(error.code(), ResultCode::BADKEY.to_num());
+
+        let late = TIME + DEFAULT_FUDGE as u64 + 1;
+        let error = verify(&mut wire(&mut request), &keys, &[], false, late).unwrap_err();
+        assert_eq!(error.code(), ResultCode::BADTIME.to_num());
+
+        // BADTIME is signed, so the client can tell it is real and learn
+        // our time from it
+        let mut response = request.clone();
+        response.header.response = true;
+        reject(&mut response, &error, late).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+        let request_mac = &request.tsig.as_ref().unwrap().mac;
+        let mut buffer = wire(&mut response);
+        let verified = verify(&mut buffer, &keys, request_mac, false, TIME)
+            .unwrap()
+            .unwrap();
+        assert_eq!(verified.tsig.error, ResultCode::BADTIME.to_num());
+        assert_eq!(verified.tsig.other, late.to_be_bytes()[2..]);
+
+        // the others are not
+        let mut response = request.clone();
+        reject(
+            &mut response,
+            &TsigError::BadSig(Box::new(request.tsig.clone().unwrap())),
+            TIME,
+        )
+        .unwrap();
+        assert!(response.tsig.as_ref().unwrap().mac.is_empty());
+        assert_eq!(response.tsig.as_ref().unwrap().error, BADSIG);
+    }
+
+    #[test]
+    fn test_transfer_chain() {
+        let keys = [key()];
+        let mut messages = [request(), request(), request()];
+        sign_transfer(&mut messages, &keys[0], b"request mac", TIME).unwrap();
+
+        let mut prior_mac = b"request mac".to_vec();
+        for (i, message) in messages.iter_mut().enumerate() {
+            let verified = verify(&mut wire(message), &keys, &prior_mac, i > 0, TIME)
+                .unwrap()
+                .unwrap();
+            prior_mac = verified.tsig.mac;
+        }
+        // out of order they do not verify
+        let first_mac = messages[0].tsig.as_ref().unwrap().mac.clone();
+        assert!(verify(&mut wire(&mut messages[2]), &keys, &first_mac, true, TIME).is_err());
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let key = key();
+        assert_eq!(key.name, name("update-key"));
+        assert_eq!(key.secret, b"secret shared by both sides");
+        assert_eq!(format!("{:?}", key), "TsigKey(update-key)");
+        assert!("name-only".parse::<TsigKey>().is_err());
+        assert!("key:not base64!".parse::<TsigKey>().is_err());
+    }
+}
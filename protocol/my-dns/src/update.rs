@@ -0,0 +1,575 @@
+//! Dynamic updates (RFC 2136). An update message reuses the sections of a
+//! query for the zone, the prerequisites that must hold and the changes to
+//! make, with classes and empty record data that carry special meanings.
+
+use crate::client::random_id;
+use crate::error::Result;
+use crate::name::DnsName;
+use crate::packets::{
+    DnsHeader, DnsQuestion, DnsRecord, PacketBuffer, QueryType, ResultCode, CLASS_ANY, CLASS_IN,
+    CLASS_NONE, TYPE_ANY,
+};
+use crate::zone::{serial_newer, Zone};
+
+pub const OPCODE_UPDATE: u8 = 5;
+
+/// IXFR, AXFR, MAILB, MAILA and ANY, which only make sense in queries
+const META_TYPES: [u16; 5] = [251, 252, 253, 254, 255];
+
+/// A record of an update message. Its data is missing for the
+/// prerequisites and deletions that apply to a whole RRset or name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdateRecord {
+    pub name: DnsName,
+    pub rtype: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    pub record: Option<DnsRecord>,
+}
+
+impl UpdateRecord {
+    /// A record without data, standing for an RRset, or a whole name when
+    /// `rtype` is ANY
+    pub fn empty(name: DnsName, rtype: QueryType, class: u16) -> UpdateRecord {
+        UpdateRecord {
+            name,
+            rtype,
+            class,
+            ttl: 0,
+            record: None,
+        }
+    }
+
+    pub fn with_record(mut record: DnsRecord, class: u16, ttl: u32) -> UpdateRecord {
+        record.set_ttl(ttl);
+        UpdateRecord {
+            name: record.domain().clone(),
+            rtype: record.query_type(),
+            class,
+            ttl,
+            record: Some(record),
+        }
+    }
+
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<UpdateRecord> {
+        let start = buffer.pos();
+        let name = buffer.read_query_name()?;
+        let rtype = QueryType::from_num(buffer.read_u16()?);
+        let class = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+
+        let record = if data_len == 0 {
+            None
+        } else {
+            buffer.seek(start);
+            Some(DnsRecord::read(buffer)?)
+        };
+
+        Ok(UpdateRecord {
+            name,
+            rtype,
+            class,
+            ttl,
+            record,
+        })
+    }
+
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_qname(&self.name)?;
+        buffer.write_u16(self.rtype.to_num())?;
+        buffer.write_u16(self.class)?;
+        buffer.write_u32(self.ttl)?;
+        match self.record {
+            Some(ref rec) => {
+                let data = rec.rdata()?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(&data)?;
+            }
+            None => buffer.write_u16(0)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// An update message, built up with the methods below or read off the wire
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdateMessage {
+    pub header: DnsHeader,
+    /// The zone section, which must hold exactly the SOA of the zone
+    pub zones: Vec<DnsQuestion>,
+    pub prerequisites: Vec<UpdateRecord>,
+    pub updates: Vec<UpdateRecord>,
+    pub additional: Vec<UpdateRecord>,
+}
+
+impl UpdateMessage {
+    pub fn new(zone: DnsName) -> UpdateMessage {
+        let mut header = DnsHeader::new();
+        header.id = random_id();
+        header.opcode = OPCODE_UPDATE;
+
+        UpdateMessage {
+            header,
+            zones: vec![DnsQuestion::new(zone, QueryType::SOA)],
+            prerequisites: Vec::new(),
+            updates: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    /// Require `name` to own records, of type `rtype` unless it is `None`
+    pub fn require_exists(&mut self, name: DnsName, rtype: Option<QueryType>) {
+        let rtype = rtype.unwrap_or(QueryType::UNKNOWN(TYPE_ANY));
+        self.prerequisites
+            .push(UpdateRecord::empty(name, rtype, CLASS_ANY));
+    }
+
+    /// Require `name` to own no records, of type `rtype` unless it is `None`
+    pub fn require_absent(&mut self, name: DnsName, rtype: Option<QueryType>) {
+        let rtype = rtype.unwrap_or(QueryType::UNKNOWN(TYPE_ANY));
+        self.prerequisites
+            .push(UpdateRecord::empty(name, rtype, CLASS_NONE));
+    }
+
+    /// Require the RRset of `records` to hold exactly these records. Give
+    /// all records of the set in one go.
+    pub fn require_rrset(&mut self, records: Vec<DnsRecord>) {
+        for rec in records {
+            self.prerequisites
+                .push(UpdateRecord::with_record(rec, CLASS_IN, 0));
+        }
+    }
+
+    pub fn add(&mut self, record: DnsRecord) {
+        let ttl = record.ttl();
+        self.updates
+            .push(UpdateRecord::with_record(record, CLASS_IN, ttl));
+    }
+
+    /// Delete the records of type `rtype` owned by `name`, or all of them
+    /// when `rtype` is `None`
+    pub fn delete_rrset(&mut self, name: DnsName, rtype: Option<QueryType>) {
+        let rtype = rtype.unwrap_or(QueryType::UNKNOWN(TYPE_ANY));
+        self.updates
+            .push(UpdateRecord::empty(name, rtype, CLASS_ANY));
+    }
+
+    /// Delete the record with the data of `record`
+    pub fn delete(&mut self, record: DnsRecord) {
+        self.updates
+            .push(UpdateRecord::with_record(record, CLASS_NONE, 0));
+    }
+
+    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<UpdateMessage> {
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
+
+        let mut zones = Vec::new();
+        for _ in 0..header.questions {
+            let mut zone = DnsQuestion::new(DnsName::root(), QueryType::UNKNOWN(0));
+            zone.read(buffer)?;
+            zones.push(zone);
+        }
+        let mut read_section = |count| {
+            (0..count)
+                .map(|_| UpdateRecord::read(buffer))
+                .collect::<Result<Vec<_>>>()
+        };
+        let prerequisites = read_section(header.answers)?;
+        let updates = read_section(header.authoritative_entries)?;
+        let additional = read_section(header.resource_entries)?;
+
+        Ok(UpdateMessage {
+            header,
+            zones,
+            prerequisites,
+            updates,
+            additional,
+        })
+    }
+
+    /// Write the message, updating the header counts to match the sections
+    pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        self.header.questions = self.zones.len() as u16;
+        self.header.answers = self.prerequisites.len() as u16;
+        self.header.authoritative_entries = self.updates.len() as u16;
+        self.header.resource_entries = self.additional.len() as u16;
+
+        self.header.write(buffer)?;
+        for zone in &self.zones {
+            zone.write(buffer)?;
+        }
+        for rec in self
+            .prerequisites
+            .iter()
+            .chain(&self.updates)
+            .chain(&self.additional)
+        {
+            rec.write(buffer)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the prerequisites of `update` against `zone` and apply its changes,
+/// all or nothing (RFC 2136 section 3). The SOA serial goes up with every
+/// change. Returns whether the zone changed, or the response code to fail
+/// the update with.
+pub fn apply(zone: &mut Zone, update: &UpdateMessage) -> std::result::Result<bool, ResultCode> {
+    check_prerequisites(zone, &update.prerequisites)?;
+    prescan(zone, &update.updates)?;
+
+    let serial = zone.serial();
+    let mut changed = false;
+    for up in &update.updates {
+        changed |= apply_one(zone, up);
+    }
+    // an update may set a newer serial itself
+    if changed && zone.serial() == serial {
+        zone.set_serial(serial.wrapping_add(1));
+    }
+
+    Ok(changed)
+}
+
+/// RFC 2136 section 3.2
+fn check_prerequisites(
+    zone: &Zone,
+    prerequisites: &[UpdateRecord],
+) -> std::result::Result<(), ResultCode> {
+    let mut expected: Vec<&DnsRecord> = Vec::new();
+    for pre in prerequisites {
+        if pre.ttl != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+        if !pre.name.is_subdomain_of(&zone.origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        let any_type = pre.rtype.to_num() == TYPE_ANY;
+        let in_use = zone.records.contains_key(&pre.name);
+        match (pre.class, &pre.record) {
+            (CLASS_ANY, None) if any_type && !in_use => return Err(ResultCode::NXDOMAIN),
+            (CLASS_ANY, None) if !any_type && zone.rrset(&pre.name, pre.rtype).is_empty() => {
+                return Err(ResultCode::NXRRSET)
+            }
+            (CLASS_NONE, None) if any_type && in_use => return Err(ResultCode::YXDOMAIN),
+            (CLASS_NONE, None) if !any_type && !zone.rrset(&pre.name, pre.rtype).is_empty() => {
+                return Err(ResultCode::YXRRSET)
+            }
+            (CLASS_ANY, None) | (CLASS_NONE, None) => {}
+            (CLASS_IN, Some(rec)) => expected.push(rec),
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    // value dependent prerequisites name whole RRsets, compared without TTLs
+    for rec in &expected {
+        let mut have = zone.rrset(rec.domain(), rec.query_type());
+        let mut want: Vec<DnsRecord> = expected
+            .iter()
+            .filter(|other| {
+                other.domain() == rec.domain() && other.query_type() == rec.query_type()
+            })
+            .map(|&other| other.clone())
+            .collect();
+        for rec in have.iter_mut().chain(want.iter_mut()) {
+            rec.set_ttl(0);
+        }
+        have.sort();
+        have.dedup();
+        want.sort();
+        want.dedup();
+        if have != want {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+
+    Ok(())
+}
+
+/// RFC 2136 section 3.4.1
+fn prescan(zone: &Zone, updates: &[UpdateRecord]) -> std::result::Result<(), ResultCode> {
+    for up in updates {
+        if !up.name.is_subdomain_of(&zone.origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        let meta = META_TYPES.contains(&up.rtype.to_num());
+        let valid = match up.class {
+            CLASS_IN => !meta && up.record.is_some(),
+            CLASS_ANY => {
+                up.ttl == 0 && up.record.is_none() && (!meta || up.rtype.to_num() == TYPE_ANY)
+            }
+            CLASS_NONE => up.ttl == 0 && !meta && up.record.is_some(),
+            _ => false,
+        };
+        if !valid {
+            return Err(ResultCode::FORMERR);
+        }
+    }
+
+    Ok(())
+}
+
+/// RFC 2136 section 3.4.2, returning whether the zone changed
+fn apply_one(zone: &mut Zone, up: &UpdateRecord) -> bool {
+    let at_apex = up.name == zone.origin;
+    let apex_type = at_apex && matches!(up.rtype, QueryType::SOA | QueryType::NS);
+
+    match up.class {
+        CLASS_IN => {
+            let rec = match up.record {
+                Some(ref rec) => rec.clone(),
+                None => return false,
+            };
+            let types: Vec<QueryType> = zone
+                .records
+                .get(&up.name)
+                .map(|records| records.iter().map(DnsRecord::query_type).collect())
+                .unwrap_or_default();
+            match up.rtype {
+                QueryType::SOA => {
+                    if !at_apex {
+                        return false;
+                    }
+                    let serial = match rec {
+                        DnsRecord::SOA { serial, .. } => serial,
+                        _ => return false,
+                    };
+                    if !serial_newer(serial, zone.serial()) {
+                        return false;
+                    }
+                    zone.remove_rrset(&up.name, Some(QueryType::SOA));
+                    zone.insert(rec)
+                }
+                // a CNAME can not share its name with other data
+                QueryType::CNAME if types.iter().any(|t| *t != QueryType::CNAME) => false,
+                _ if up.rtype != QueryType::CNAME && types.contains(&QueryType::CNAME) => false,
+                QueryType::CNAME => {
+                    zone.remove_rrset(&up.name, Some(QueryType::CNAME));
+                    zone.insert(rec)
+                }
+                _ => zone.insert(rec),
+            }
+        }
+        CLASS_ANY if up.rtype.to_num() == TYPE_ANY => {
+            if !at_apex {
+                return zone.remove_rrset(&up.name, None);
+            }
+            // the apex keeps its SOA and NS records
+            let types: Vec<QueryType> = zone.records[&up.name]
+                .iter()
+                .map(DnsRecord::query_type)
+                .filter(|t| !matches!(t, QueryType::SOA | QueryType::NS))
+                .collect();
+            let mut changed = false;
+            for qtype in types {
+                changed |= zone.remove_rrset(&up.name, Some(qtype));
+            }
+            changed
+        }
+        CLASS_ANY if apex_type => false,
+        CLASS_ANY => zone.remove_rrset(&up.name, Some(up.rtype)),
+        CLASS_NONE => {
+            let rec = match up.record {
+                Some(ref rec) => rec,
+                None => return false,
+            };
+            if up.rtype == QueryType::SOA {
+                return false;
+            }
+            // never remove the last name server of the zone
+            if apex_type && zone.rrset(&up.name, QueryType::NS).len() <= 1 {
+                return false;
+            }
+            zone.remove(rec)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::VectorPacketBuffer;
+    use crate::testing::name;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 300
+@     SOA   ns1 hostmaster 10 7200 3600 1209600 300
+@     NS    ns1
+ns1   A     192.0.2.1
+www   A     192.0.2.80
+www   A     192.0.2.81
+alias CNAME www
+";
+
+    fn zone() -> Zone {
+        Zone::parse(ZONE, "").unwrap()
+    }
+
+    fn a(domain: &str, last: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(domain),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 60,
+        }
+    }
+
+    fn update() -> UpdateMessage {
+        UpdateMessage::new(name("example.com"))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut message = update();
+        message.require_exists(name("www.example.com"), Some(QueryType::A));
+        message.require_absent(name("new.example.com"), None);
+        message.add(a("new.example.com", 9));
+        message.delete_rrset(name("old.example.com"), None);
+        message.delete(a("www.example.com", 80));
+
+        let mut buffer = VectorPacketBuffer::new();
+        message.write(&mut buffer).unwrap();
+        assert_eq!((buffer.buffer[2] >> 3) & 0x0F, OPCODE_UPDATE);
+        buffer.seek(0);
+        let parsed = UpdateMessage::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed, message);
+        assert_eq!(parsed.updates[1].record, None);
+        assert_eq!(parsed.updates[1].class, CLASS_ANY);
+    }
+
+    #[test]
+    fn test_prerequisites() {
+        let check = |build: &dyn Fn(&mut UpdateMessage)| {
+            let mut message = update();
+            build(&mut message);
+            apply(&mut zone(), &message)
+        };
+
+        let www = || name("www.example.com");
+        let new = || name("new.example.com");
+        assert_eq!(check(&|m| m.require_exists(www(), None)), Ok(false));
+        assert_eq!(
+            check(&|m| m.require_exists(new(), None)),
+            Err(ResultCode::NXDOMAIN)
+        );
+        assert_eq!(
+            check(&|m| m.require_exists(www(), Some(QueryType::MX))),
+            Err(ResultCode::NXRRSET)
+        );
+        assert_eq!(
+            check(&|m| m.require_absent(www(), None)),
+            Err(ResultCode::YXDOMAIN)
+        );
+        assert_eq!(
+            check(&|m| m.require_absent(www(), Some(QueryType::A))),
+            Err(ResultCode::YXRRSET)
+        );
+        assert_eq!(check(&|m| m.require_absent(new(), None)), Ok(false));
+        assert_eq!(
+            check(&|m| m.require_exists(name("example.org"), None)),
+            Err(ResultCode::NOTZONE)
+        );
+
+        // the whole RRset has to match, in any order
+        let both = || vec![a("www.example.com", 81), a("www.example.com", 80)];
+        assert_eq!(check(&|m| m.require_rrset(both())), Ok(false));
+        assert_eq!(
+            check(&|m| m.require_rrset(vec![a("www.example.com", 80)])),
+            Err(ResultCode::NXRRSET)
+        );
+    }
+
+    #[test]
+    fn test_add_and_delete() {
+        let mut zone = zone();
+        let mut message = update();
+        message.add(a("ci-1234.example.com", 10));
+        message.delete(a("www.example.com", 80));
+        assert_eq!(apply(&mut zone, &message), Ok(true));
+        assert_eq!(
+            zone.rrset(&name("ci-1234.example.com"), QueryType::A),
+            [a("ci-1234.example.com", 10)]
+        );
+        assert_eq!(zone.rrset(&name("www.example.com"), QueryType::A).len(), 1);
+        assert_eq!(zone.serial(), 11);
+
+        // adding what is there already changes nothing, not even the serial
+        assert_eq!(apply(&mut zone, &message), Ok(false));
+        assert_eq!(zone.serial(), 11);
+
+        let mut message = update();
+        message.delete_rrset(name("ci-1234.example.com"), None);
+        assert_eq!(apply(&mut zone, &message), Ok(true));
+        assert!(!zone.records.contains_key(&name("ci-1234.example.com")));
+        assert_eq!(zone.serial(), 12);
+    }
+
+    #[test]
+    fn test_protected_records() {
+        let mut zone = zone();
+        let mut message = update();
+        message.delete_rrset(name("example.com"), None);
+        message.delete_rrset(name("example.com"), Some(QueryType::NS));
+        message.delete(DnsRecord::NS {
+            domain: name("example.com"),
+            host: name("ns1.example.com"),
+            ttl: 300,
+        });
+        // a CNAME next to other data, and data next to a CNAME
+        message.add(DnsRecord::CNAME {
+            domain: name("www.example.com"),
+            host: name("example.net"),
+            ttl: 60,
+        });
+        message.add(a("alias.example.com", 1));
+        assert_eq!(apply(&mut zone, &message), Ok(false));
+        assert!(zone.soa().is_some());
+        assert_eq!(zone.rrset(&name("example.com"), QueryType::NS).len(), 1);
+        assert_eq!(zone.rrset(&name("alias.example.com"), QueryType::A), []);
+
+        // a newer SOA replaces the old one and keeps its serial
+        let mut message = update();
+        let mut soa = zone.soa().unwrap().clone();
+        if let DnsRecord::SOA { ref mut serial, .. } = soa {
+            *serial = 2024010100;
+        }
+        message.add(soa);
+        assert_eq!(apply(&mut zone, &message), Ok(true));
+        assert_eq!(zone.serial(), 2024010100);
+    }
+
+    #[test]
+    fn test_invalid_updates() {
+        let mut message = update();
+        message.add(a("www.example.org", 1));
+        assert_eq!(apply(&mut zone(), &message), Err(ResultCode::NOTZONE));
+
+        let mut message = update();
+        message.updates.push(UpdateRecord::empty(
+            name("www.example.com"),
+            QueryType::A,
+            CLASS_IN,
+        ));
+        assert_eq!(apply(&mut zone(), &message), Err(ResultCode::FORMERR));
+
+        // nothing is applied when a later update is broken
+        let mut zone = zone();
+        let mut message = update();
+        message.add(a("ok.example.com", 1));
+        message.updates.push(UpdateRecord::empty(
+            name("example.com"),
+            QueryType::UNKNOWN(252),
+            CLASS_ANY,
+        ));
+        assert_eq!(apply(&mut zone, &message), Err(ResultCode::FORMERR));
+        assert!(!zone.records.contains_key(&name("ok.example.com")));
+    }
+}
@@ -0,0 +1,195 @@
+//! Split-horizon views: separate zones, upstreams and caches for different
+//! groups of clients, picked by their address, so that names meant for one
+//! network never reach clients on another.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use crate::cache::DnsCache;
+use crate::packets::{DnsPacket, EdnsOption};
+use crate::server::{Upstream, DEFAULT_CACHE_SIZE};
+use crate::zone::Zone;
+
+/// EDNS option code of Client Subnet (RFC 7871)
+pub const CLIENT_SUBNET: u16 = 8;
+
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// An address block in CIDR notation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    pub fn new(addr: IpAddr, prefix: u8) -> Subnet {
+        Subnet {
+            addr: mask(addr, prefix),
+            prefix,
+        }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv4() == self.addr.is_ipv4() && mask(addr, self.prefix) == self.addr
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    /// `addr/prefix`, or a bare address for a single host
+    fn from_str(s: &str) -> Result<Subnet, String> {
+        let invalid = || format!("Invalid subnet: {}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Subnet::new(addr, prefix))
+    }
+}
+
+/// Clear all but the first `prefix` bits of `addr`
+pub fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32) as u32);
+            IpAddr::V4((u32::from(addr) & mask.unwrap_or(0)).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128) as u32);
+            IpAddr::V6((u128::from(addr) & mask.unwrap_or(0)).into())
+        }
+    }
+}
+
+/// The subnet in the EDNS Client Subnet option of `request`, if it has a
+/// well formed one
+pub fn client_subnet(request: &DnsPacket) -> Option<Subnet> {
+    let edns = request.edns.as_ref()?;
+    let option = edns.options.iter().find(|opt| opt.code == CLIENT_SUBNET)?;
+    let data = &option.data;
+    if data.len() < 4 {
+        return None;
+    }
+
+    let family = u16::from_be_bytes([data[0], data[1]]);
+    let prefix = data[2];
+    let addr = &data[4..];
+    // only as many bytes as the prefix needs are sent
+    if addr.len() != prefix.div_ceil(8) as usize {
+        return None;
+    }
+    let addr = match family {
+        FAMILY_IPV4 if prefix <= 32 => {
+            let mut octets = [0; 4];
+            octets[..addr.len()].copy_from_slice(addr);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 if prefix <= 128 => {
+            let mut octets = [0; 16];
+            octets[..addr.len()].copy_from_slice(addr);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(Subnet::new(addr, prefix))
+}
+
+/// An EDNS Client Subnet option for `subnet`, telling that the answer holds
+/// for the first `scope` bits of it
+pub fn client_subnet_option(subnet: &Subnet, scope: u8) -> EdnsOption {
+    let (family, octets) = match subnet.addr {
+        IpAddr::V4(addr) => (FAMILY_IPV4, addr.octets().to_vec()),
+        IpAddr::V6(addr) => (FAMILY_IPV6, addr.octets().to_vec()),
+    };
+    let mut data = family.to_be_bytes().to_vec();
+    data.push(subnet.prefix);
+    data.push(scope);
+    data.extend_from_slice(&octets[..subnet.prefix.div_ceil(8) as usize]);
+    EdnsOption {
+        code: CLIENT_SUBNET,
+        data,
+    }
+}
+
+/// What a group of clients gets to see
+pub struct View {
+    pub name: String,
+    /// The clients the view is for
+    pub subnets: Vec<Subnet>,
+    pub upstream: Upstream,
+    /// Zones answered authoritatively to these clients only
+    pub zones: RwLock<Vec<Zone>>,
+    pub cache: Mutex<DnsCache>,
+}
+
+impl View {
+    pub fn new(name: &str, subnets: Vec<Subnet>, upstream: Upstream) -> View {
+        View {
+            name: name.to_string(),
+            subnets,
+            upstream,
+            zones: RwLock::new(Vec::new()),
+            cache: Mutex::new(DnsCache::new(DEFAULT_CACHE_SIZE)),
+        }
+    }
+
+    pub fn matches(&self, client: IpAddr) -> bool {
+        self.subnets.iter().any(|subnet| subnet.contains(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::Edns;
+
+    #[test]
+    fn test_subnets() {
+        let subnet: Subnet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(subnet.addr, "10.0.0.0".parse::<IpAddr>().unwrap());
+        assert!(subnet.contains("10.200.0.1".parse().unwrap()));
+        assert!(!subnet.contains("11.0.0.1".parse().unwrap()));
+        assert!(!subnet.contains("::a00:1".parse().unwrap()));
+
+        let host: Subnet = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.prefix, 128);
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        let everything: Subnet = "::/0".parse().unwrap();
+        assert!(everything.contains("2001:db8::2".parse().unwrap()));
+
+        for s in ["10.0.0.0/33", "10.0.0/8", "10.0.0.0/x", "::/129"] {
+            assert!(s.parse::<Subnet>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_client_subnet_option() {
+        let subnet: Subnet = "198.51.100.77/20".parse().unwrap();
+        let option = client_subnet_option(&subnet, 0);
+        assert_eq!(option.data, [0, 1, 20, 0, 198, 51, 96]);
+
+        let mut request = DnsPacket::new();
+        assert_eq!(client_subnet(&request), None);
+        request.edns = Some(Edns::new(1232));
+        request.edns.as_mut().unwrap().options.push(option);
+        assert_eq!(client_subnet(&request), Some(subnet));
+
+        // the address has to be as long as the prefix says
+        request.edns.as_mut().unwrap().options[0].data.push(0);
+        assert_eq!(client_subnet(&request), None);
+    }
+}
@@ -0,0 +1,444 @@
+//! Zone transfers, full (AXFR, RFC 5936) and incremental (IXFR, RFC 1995),
+//! and the NOTIFY messages (RFC 1996) that tell secondaries to fetch them.
+
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::client::{random_id, DnsClient};
+use crate::name::DnsName;
+use crate::packets::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, PacketBuffer, QueryType, ResultCode,
+    VectorPacketBuffer,
+};
+use crate::tcp::{read_message, write_message};
+use crate::tsig::{self, TsigKey};
+use crate::zone::{serial_newer, Zone, ZoneChange};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const OPCODE_NOTIFY: u8 = 4;
+
+/// Rough size of each transfer message. Records are measured without name
+/// compression, so the real messages come out smaller.
+const MESSAGE_BUDGET: usize = 16 * 1024;
+
+/// A zone copied from a primary server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Secondary {
+    pub origin: DnsName,
+    pub primary: SocketAddr,
+    /// Where the copy is kept between restarts
+    pub path: Option<PathBuf>,
+    /// Name of the TSIG key transfers are signed with, if any
+    pub key: Option<DnsName>,
+}
+
+impl FromStr for Secondary {
+    type Err = String;
+
+    /// `zone=addr[:port][,file][,key=name]`
+    fn from_str(s: &str) -> std::result::Result<Secondary, String> {
+        let invalid = || format!("Invalid secondary zone: {}", s);
+        let (origin, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let mut fields = rest.split(',');
+        let primary = fields.next().ok_or_else(invalid)?;
+        let mut path = None;
+        let mut key = None;
+        for field in fields {
+            match field.strip_prefix("key=") {
+                Some(name) => key = Some(name.parse().map_err(|_| invalid())?),
+                None if path.is_none() => path = Some(PathBuf::from(field)),
+                None => return Err(invalid()),
+            }
+        }
+        let primary = primary
+            .parse()
+            .or_else(|_| primary.parse().map(|ip| SocketAddr::new(ip, 53)))
+            .map_err(|_| invalid())?;
+
+        Ok(Secondary {
+            origin: origin.parse().map_err(|_| invalid())?,
+            primary,
+            path,
+            key,
+        })
+    }
+}
+
+/// The records of a full transfer: the SOA, everything else, the SOA again
+pub fn axfr_records(zone: &Zone) -> Vec<DnsRecord> {
+    let mut records = zone.all_records();
+    records.extend(zone.soa().cloned());
+    records
+}
+
+/// The records of an incremental transfer to a secondary at `serial`. That
+/// is the current SOA alone when it is up to date, and a full transfer when
+/// the history does not reach back far enough.
+pub fn ixfr_records(zone: &Zone, serial: u32) -> Vec<DnsRecord> {
+    let soa = match zone.soa() {
+        Some(soa) => soa.clone(),
+        None => return Vec::new(),
+    };
+    if !serial_newer(zone.serial(), serial) {
+        return vec![soa];
+    }
+    let changes = match zone.changes_since(serial) {
+        Some(changes) => changes,
+        None => return axfr_records(zone),
+    };
+
+    let mut records = vec![soa.clone()];
+    for change in changes {
+        records.extend(change.removed.iter().cloned());
+        records.extend(change.added.iter().cloned());
+    }
+    records.push(soa);
+    records
+}
+
+/// Spread the records of a transfer over as many responses to `request` as
+/// it takes
+pub fn transfer_messages(request: &DnsPacket, records: Vec<DnsRecord>) -> Vec<DnsPacket> {
+    let new_message = || {
+        let mut message = DnsPacket::new();
+        message.header.id = request.header.id;
+        message.header.response = true;
+        message.header.authoritative_answer = true;
+        message.questions = request.questions.clone();
+        message
+    };
+
+    let mut messages = vec![new_message()];
+    let mut size = 0;
+    for rec in records {
+        let mut buffer = VectorPacketBuffer::uncompressed();
+        let len = rec.write(&mut buffer).unwrap_or(0);
+        if size + len > MESSAGE_BUDGET && size > 0 {
+            messages.push(new_message());
+            size = 0;
+        }
+        size += len;
+        messages.last_mut().unwrap().answers.push(rec);
+    }
+    messages
+}
+
+/// What a transfer brought
+#[derive(Clone, Debug)]
+pub enum Transfer {
+    /// The secondary already has the latest version
+    UpToDate,
+    Full(Zone),
+    Incremental(Vec<ZoneChange>),
+}
+
+/// Transfer `origin` from `server` over TCP: incrementally from `current`,
+/// the SOA of the copy we have, or in full without one. With a `key` the
+/// request is signed, and every response has to be.
+pub fn request_transfer(
+    client: &DnsClient,
+    origin: &DnsName,
+    current: Option<&DnsRecord>,
+    key: Option<&TsigKey>,
+    server: SocketAddr,
+) -> Result<Transfer> {
+    let qtype = match current {
+        Some(_) => QueryType::IXFR,
+        None => QueryType::AXFR,
+    };
+    let mut request = DnsPacket::new();
+    request.header.id = random_id();
+    request
+        .questions
+        .push(DnsQuestion::new(origin.clone(), qtype));
+    request.authorities.extend(current.cloned());
+    if let Some(key) = key {
+        tsig::sign(&mut request, key, &[], false, tsig::now())?;
+    }
+
+    let mut stream = TcpStream::connect_timeout(&server, client.timeout)?;
+    stream.set_read_timeout(Some(client.timeout))?;
+    stream.set_write_timeout(Some(client.timeout))?;
+    write_message(&mut stream, &mut request)?;
+
+    let mut records = Vec::new();
+    let mut prior_mac = request.tsig.map(|tsig| tsig.mac).unwrap_or_default();
+    loop {
+        let mut res_buffer = read_message(&mut stream)?
+            .ok_or_else(|| format!("{} closed the connection mid transfer", server))?;
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if response.header.id != request.header.id {
+            return Err(format!("response from {} does not match the transfer id", server).into());
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            let reason = match response.tsig {
+                Some(ref tsig) if tsig.error != 0 => format!("TSIG error {}", tsig.error),
+                _ => format!("{:?}", response.header.rescode),
+            };
+            return Err(format!("{} refused to transfer {}: {}", server, origin, reason).into());
+        }
+
+        let first = records.is_empty();
+        if let Some(key) = key {
+            // the messages after the first only sign their timers
+            let keys = std::slice::from_ref(key);
+            match tsig::verify(&mut res_buffer, keys, &prior_mac, !first, tsig::now())? {
+                Some(verified) => prior_mac = verified.tsig.mac,
+                None => return Err(format!("{} sent an unsigned transfer", server).into()),
+            }
+        }
+        records.extend(response.answers);
+        // a lone SOA in the first message says we are up to date
+        if first && records.len() == 1 && qtype == QueryType::IXFR {
+            return Ok(Transfer::UpToDate);
+        }
+        if transfer_complete(&records) {
+            return parse_transfer(origin, records);
+        }
+    }
+}
+
+fn serial_of(rec: &DnsRecord) -> Option<u32> {
+    match *rec {
+        DnsRecord::SOA { serial, .. } => Some(serial),
+        _ => None,
+    }
+}
+
+/// Whether the records received so far end the transfer, which closes with
+/// the SOA it opened with
+fn transfer_complete(records: &[DnsRecord]) -> bool {
+    let serial = match records.first().and_then(serial_of) {
+        Some(serial) => serial,
+        None => return !records.is_empty(),
+    };
+    let closing = records[1..]
+        .iter()
+        .filter(|rec| serial_of(rec) == Some(serial))
+        .count();
+    let ends_with_soa = records.last().and_then(serial_of) == Some(serial);
+
+    // in incremental transfers the new SOA also ends the last change
+    let incremental = records
+        .get(1)
+        .and_then(serial_of)
+        .is_some_and(|second| second != serial);
+    ends_with_soa && closing >= if incremental { 2 } else { 1 }
+}
+
+fn parse_transfer(origin: &DnsName, records: Vec<DnsRecord>) -> Result<Transfer> {
+    let serial = match records.first().and_then(serial_of) {
+        Some(serial) if records[0].domain() == origin => serial,
+        _ => return Err("transfer does not start with the SOA of the zone".into()),
+    };
+
+    let incremental = records
+        .get(1)
+        .and_then(serial_of)
+        .is_some_and(|second| second != serial);
+    if !incremental {
+        let mut records = records;
+        records.pop();
+        let zone = Zone::from_records(records)?;
+        return Ok(Transfer::Full(zone));
+    }
+
+    // SOA, then per change: old SOA, removals, new SOA, additions; then SOA
+    let body = &records[1..records.len() - 1];
+    let mut changes = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        let mut change = ZoneChange {
+            removed: vec![body[i].clone()],
+            added: Vec::new(),
+        };
+        i += 1;
+        while i < body.len() && serial_of(&body[i]).is_none() {
+            change.removed.push(body[i].clone());
+            i += 1;
+        }
+        if i == body.len() {
+            return Err("incremental transfer ends in the middle of a change".into());
+        }
+        change.added.push(body[i].clone());
+        i += 1;
+        while i < body.len() && serial_of(&body[i]).is_none() {
+            change.added.push(body[i].clone());
+            i += 1;
+        }
+        changes.push(change);
+    }
+
+    Ok(Transfer::Incremental(changes))
+}
+
+/// Tell `server` that `origin` changed, waiting for it to acknowledge
+pub fn send_notify(client: &DnsClient, origin: &DnsName, server: SocketAddr) -> Result<DnsPacket> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = random_id();
+    packet.header.opcode = OPCODE_NOTIFY;
+    packet.header.authoritative_answer = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(origin.clone(), QueryType::SOA));
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    for _ in 0..=client.retries {
+        socket.send_to(&req_buffer.buffer[..req_buffer.pos()], server)?;
+        if let Some(response) = client.recv_response(&socket, server, packet.header.id)? {
+            return Ok(response);
+        }
+    }
+
+    Err(format!("no response from {} to NOTIFY for {}", server, origin).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 300
+@     SOA   ns1 hostmaster 1 7200 3600 1209600 300
+@     NS    ns1
+ns1   A     192.0.2.1
+www   A     192.0.2.80
+";
+
+    fn zone() -> Zone {
+        Zone::parse(ZONE, "").unwrap()
+    }
+
+    /// The zone after `n` changes, each adding a host and bumping the serial
+    fn changed(n: u8) -> Zone {
+        let mut zone = zone();
+        for i in 0..n {
+            let old = zone.clone();
+            zone.insert(DnsRecord::A {
+                domain: name(&format!("host{}.example.com", i)),
+                addr: [192, 0, 2, 100 + i].into(),
+                ttl: 60,
+            });
+            zone.set_serial(zone.serial() + 1);
+            zone.record_change(&old);
+        }
+        zone
+    }
+
+    fn sorted(zone: &Zone) -> Vec<String> {
+        let mut records: Vec<_> = zone
+            .all_records()
+            .iter()
+            .map(|rec| rec.to_string())
+            .collect();
+        records.sort();
+        records
+    }
+
+    fn request(qtype: QueryType) -> DnsPacket {
+        let mut request = DnsPacket::new();
+        request.header.id = 7;
+        request
+            .questions
+            .push(DnsQuestion::new(name("example.com"), qtype));
+        request
+    }
+
+    #[test]
+    fn test_axfr_round_trip() {
+        let zone = zone();
+        let records = axfr_records(&zone);
+        assert_eq!(records.len(), 5);
+        assert!(transfer_complete(&records));
+        assert!(!transfer_complete(&records[..4]));
+
+        match parse_transfer(&zone.origin, records).unwrap() {
+            Transfer::Full(copy) => assert_eq!(copy.records, zone.records),
+            other => panic!("expected a full transfer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ixfr_round_trip() {
+        let primary = changed(3);
+        assert_eq!(primary.serial(), 4);
+        assert_eq!(ixfr_records(&primary, 4).len(), 1);
+        // too old for the history: a full transfer instead
+        assert_eq!(ixfr_records(&primary, 0), axfr_records(&primary));
+
+        let mut secondary = changed(1);
+        let records = ixfr_records(&primary, 2);
+        assert!(transfer_complete(&records));
+        assert!(!transfer_complete(&records[..records.len() - 1]));
+
+        let changes = match parse_transfer(&primary.origin, records).unwrap() {
+            Transfer::Incremental(changes) => changes,
+            other => panic!("expected an incremental transfer, got {:?}", other),
+        };
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].from_serial(), changes[1].to_serial()), (2, 4));
+        for change in &changes {
+            secondary.apply_change(change).unwrap();
+        }
+        assert_eq!(sorted(&secondary), sorted(&primary));
+
+        // changes have to line up with the serial we hold
+        assert!(secondary.apply_change(&changes[0]).is_err());
+    }
+
+    #[test]
+    fn test_transfer_messages() {
+        let mut zone = zone();
+        for i in 0..200u8 {
+            zone.insert(DnsRecord::TXT {
+                domain: name(&format!("t{}.example.com", i)),
+                data: vec![vec![b'x'; 200]],
+                ttl: 60,
+            });
+        }
+        let records = axfr_records(&zone);
+        let messages = transfer_messages(&request(QueryType::AXFR), records.clone());
+        assert!(messages.len() > 1);
+        let mut sent = Vec::new();
+        for mut message in messages {
+            assert_eq!(message.header.id, 7);
+            assert!(message.header.authoritative_answer);
+            let mut buffer = VectorPacketBuffer::new();
+            message.write(&mut buffer).unwrap();
+            sent.extend(message.answers);
+        }
+        assert_eq!(sent, records);
+    }
+
+    #[test]
+    fn test_parse_secondary() {
+        assert_eq!(
+            "example.com=192.0.2.1".parse(),
+            Ok(Secondary {
+                origin: name("example.com"),
+                primary: "192.0.2.1:53".parse().unwrap(),
+                path: None,
+                key: None,
+            })
+        );
+        let secondary: Secondary = "example.com=[::1]:5353,/tmp/example.zone".parse().unwrap();
+        assert_eq!(secondary.primary, "[::1]:5353".parse().unwrap());
+        assert_eq!(secondary.path, Some(PathBuf::from("/tmp/example.zone")));
+        let secondary: Secondary = "example.com=192.0.2.1,key=xfr-key".parse().unwrap();
+        assert_eq!(secondary.path, None);
+        assert_eq!(secondary.key, Some(name("xfr-key")));
+        assert!("example.com".parse::<Secondary>().is_err());
+    }
+}
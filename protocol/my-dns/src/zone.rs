@@ -0,0 +1,1257 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use crate::encoding::{from_base32hex, from_base64, from_hex};
+use crate::name::DnsName;
+use crate::packets::{
+    DnsPacket, DnsRecord, PacketBuffer, QueryType, ResultCode, VectorPacketBuffer,
+};
+use crate::time::parse_timestamp;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// How many CNAMEs are followed inside the zone before giving up
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How many changes a zone remembers for incremental transfers
+const MAX_HISTORY: usize = 64;
+
+/// A zone loaded from an RFC 1035 master file, answered authoritatively
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub origin: DnsName,
+    /// Records by owner name, in canonical order
+    pub records: BTreeMap<DnsName, Vec<DnsRecord>>,
+    /// The master file the zone was loaded from, and is saved back to
+    pub path: Option<PathBuf>,
+    /// Recent changes, oldest first, for incremental transfers
+    pub history: Vec<ZoneChange>,
+}
+
+/// The difference between two versions of a zone, laid out the way IXFR
+/// sends it (RFC 1995): each list starts with the SOA of its version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZoneChange {
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+impl ZoneChange {
+    pub fn from_serial(&self) -> u32 {
+        soa_serial(self.removed.first())
+    }
+
+    pub fn to_serial(&self) -> u32 {
+        soa_serial(self.added.first())
+    }
+}
+
+impl Zone {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Zone> {
+        let text = fs::read_to_string(path.as_ref())?;
+        let mut zone = Zone::parse(&text, "")?;
+        zone.path = Some(path.as_ref().to_path_buf());
+        Ok(zone)
+    }
+
+    /// Write the zone back to the file it was loaded from, if any. The new
+    /// file replaces the old one in a single rename, so a crash halfway
+    /// never leaves a truncated zone behind.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.to_string())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Parse a master file. `origin` is used for relative names until the
+    /// file sets its own with `$ORIGIN`; the zone itself is rooted at the
+    /// owner of its SOA record.
+    pub fn parse(text: &str, origin: &str) -> Result<Zone> {
+        Zone::from_records(parse_records(text, origin)?)
+    }
+
+    /// Build a zone out of its records, which have to include one SOA
+    pub fn from_records(records: Vec<DnsRecord>) -> Result<Zone> {
+        let mut soas = records
+            .iter()
+            .filter(|rec| rec.query_type() == QueryType::SOA);
+        let origin = match (soas.next(), soas.next()) {
+            (Some(soa), None) => soa.domain().clone(),
+            _ => return Err("zone must have exactly one SOA record".into()),
+        };
+
+        let mut zone = Zone {
+            origin,
+            records: BTreeMap::new(),
+            path: None,
+            history: Vec::new(),
+        };
+        for rec in records {
+            if !rec.domain().is_subdomain_of(&zone.origin) {
+                return Err(format!("{} is outside of zone {}", rec.domain(), zone.origin).into());
+            }
+            zone.records
+                .entry(rec.domain().clone())
+                .or_default()
+                .push(rec);
+        }
+
+        Ok(zone)
+    }
+
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.records
+            .get(&self.origin)?
+            .iter()
+            .find(|rec| rec.query_type() == QueryType::SOA)
+    }
+
+    pub fn serial(&self) -> u32 {
+        soa_serial(self.soa())
+    }
+
+    /// All records of the zone, SOA first
+    pub fn all_records(&self) -> Vec<DnsRecord> {
+        let soa = self.soa().into_iter();
+        let rest = self
+            .records
+            .values()
+            .flatten()
+            .filter(|rec| rec.query_type() != QueryType::SOA);
+        soa.chain(rest).cloned().collect()
+    }
+
+    /// Remember how `old` turned into this zone, for incremental transfers
+    pub fn record_change(&mut self, old: &Zone) {
+        let (old_records, new_records) = (old.all_records(), self.all_records());
+        if old_records.is_empty() || new_records.is_empty() || old.serial() == self.serial() {
+            return;
+        }
+
+        let old_set: HashSet<&DnsRecord> = old_records[1..].iter().collect();
+        let new_set: HashSet<&DnsRecord> = new_records[1..].iter().collect();
+        let mut removed = vec![old_records[0].clone()];
+        removed.extend(
+            old_records[1..]
+                .iter()
+                .filter(|rec| !new_set.contains(rec))
+                .cloned(),
+        );
+        let mut added = vec![new_records[0].clone()];
+        added.extend(
+            new_records[1..]
+                .iter()
+                .filter(|rec| !old_set.contains(rec))
+                .cloned(),
+        );
+
+        self.history.push(ZoneChange { removed, added });
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+    }
+
+    /// The changes that lead from `serial` to the current version, if the
+    /// history reaches back that far
+    pub fn changes_since(&self, serial: u32) -> Option<&[ZoneChange]> {
+        let start = self
+            .history
+            .iter()
+            .position(|change| change.from_serial() == serial)?;
+        Some(&self.history[start..])
+    }
+
+    /// Apply a change received from a primary server
+    pub fn apply_change(&mut self, change: &ZoneChange) -> Result<()> {
+        let starts_with_soa = |records: &[DnsRecord]| {
+            records
+                .first()
+                .is_some_and(|rec| rec.query_type() == QueryType::SOA)
+        };
+        if !starts_with_soa(&change.removed) || !starts_with_soa(&change.added) {
+            return Err("change does not start with SOA records".into());
+        }
+        if change.from_serial() != self.serial() {
+            return Err(format!(
+                "change from serial {} does not apply to serial {}",
+                change.from_serial(),
+                self.serial()
+            )
+            .into());
+        }
+
+        let old = self.clone();
+        self.remove_rrset(&self.origin.clone(), Some(QueryType::SOA));
+        for rec in &change.removed[1..] {
+            self.remove(rec);
+        }
+        for rec in &change.added {
+            if !rec.domain().is_subdomain_of(&self.origin) {
+                return Err(format!("{} is outside of zone {}", rec.domain(), self.origin).into());
+            }
+            self.insert(rec.clone());
+        }
+        if self.soa().is_none() {
+            return Err("change leaves the zone without a SOA record".into());
+        }
+        self.record_change(&old);
+        Ok(())
+    }
+
+    pub fn set_serial(&mut self, new_serial: u32) {
+        let records = self.records.get_mut(&self.origin).into_iter().flatten();
+        for rec in records {
+            if let DnsRecord::SOA { ref mut serial, .. } = *rec {
+                *serial = new_serial;
+            }
+        }
+    }
+
+    /// The records of one type owned by `name`
+    pub fn rrset(&self, name: &DnsName, qtype: QueryType) -> Vec<DnsRecord> {
+        self.records
+            .get(name)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|rec| rec.query_type() == qtype)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Add a record, or replace the TTL of one with the same data. Returns
+    /// whether the zone changed.
+    pub fn insert(&mut self, rec: DnsRecord) -> bool {
+        let records = self.records.entry(rec.domain().clone()).or_default();
+        match records.iter_mut().find(|old| old.same_data(&rec)) {
+            Some(old) if *old == rec => false,
+            Some(old) => {
+                *old = rec;
+                true
+            }
+            None => {
+                records.push(rec);
+                true
+            }
+        }
+    }
+
+    /// Remove the record with the same data as `rec`, whatever its TTL.
+    /// Returns whether there was one.
+    pub fn remove(&mut self, rec: &DnsRecord) -> bool {
+        self.remove_matching(rec.domain(), |old| old.same_data(rec))
+    }
+
+    /// Remove all records of one type owned by `name`, or all records of
+    /// the name when `qtype` is `None`. Returns whether there were any.
+    pub fn remove_rrset(&mut self, name: &DnsName, qtype: Option<QueryType>) -> bool {
+        self.remove_matching(name, |old| {
+            qtype.is_none_or(|qtype| old.query_type() == qtype)
+        })
+    }
+
+    fn remove_matching<F: Fn(&DnsRecord) -> bool>(&mut self, name: &DnsName, matches: F) -> bool {
+        let Some(records) = self.records.get_mut(name) else {
+            return false;
+        };
+        let before = records.len();
+        records.retain(|rec| !matches(rec));
+        let removed = records.len() != before;
+        // names without records must not look like they exist
+        if records.is_empty() {
+            self.records.remove(name);
+        }
+        removed
+    }
+
+    /// Answer a query for a name inside the zone. Returns `None` when the
+    /// name is not ours to answer.
+    pub fn answer(&self, qname: &DnsName, qtype: QueryType) -> Option<DnsPacket> {
+        if !qname.is_subdomain_of(&self.origin) {
+            return None;
+        }
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        let mut name = qname.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(cut) = self.delegation(&name) {
+                // only the part of a CNAME chain inside our data is
+                // authoritative, the rest is a referral
+                packet.header.authoritative_answer = !packet.answers.is_empty();
+                packet.authorities = self.rrset(&cut, QueryType::NS);
+                packet.resources = self.glue(&packet.authorities);
+                return Some(packet);
+            }
+
+            let records = match self.records_at(&name) {
+                Some(records) => records,
+                None => {
+                    if !self.exists(&name) {
+                        packet.header.rescode = ResultCode::NXDOMAIN;
+                    }
+                    packet.authorities.extend(self.negative_soa());
+                    break;
+                }
+            };
+
+            let matching: Vec<DnsRecord> = records
+                .iter()
+                .filter(|rec| rec.query_type() == qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                packet.answers.extend(matching);
+                break;
+            }
+
+            let cname = records.iter().find_map(|rec| match rec {
+                DnsRecord::CNAME { host, .. } => Some((rec.clone(), host.clone())),
+                _ => None,
+            });
+            match cname {
+                Some((rec, target)) => {
+                    packet.answers.push(rec);
+                    if !target.is_subdomain_of(&self.origin) {
+                        break;
+                    }
+                    name = target;
+                }
+                None => {
+                    packet.authorities.extend(self.negative_soa());
+                    break;
+                }
+            }
+        }
+
+        packet.resources = self.glue(&packet.answers);
+        Some(packet)
+    }
+
+    /// The topmost zone cut between the origin and `name`, if the name has
+    /// been delegated away
+    fn delegation(&self, name: &DnsName) -> Option<DnsName> {
+        (self.origin.label_count() + 1..=name.label_count())
+            .map(|n| name.suffix(n))
+            .find(|cut| !self.rrset(cut, QueryType::NS).is_empty())
+    }
+
+    /// Records owned by `name`, or synthesized from a matching wildcard
+    fn records_at(&self, name: &DnsName) -> Option<Vec<DnsRecord>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+        if self.exists(name) {
+            return None;
+        }
+
+        // the wildcard has to sit directly below the closest encloser
+        let mut encloser = name.clone();
+        while let Some(parent) = encloser.parent() {
+            encloser = parent;
+            if self.exists(&encloser) {
+                let wildcard = encloser.child(b"*").ok()?;
+                return self
+                    .records
+                    .get(&wildcard)
+                    .map(|records| records.iter().map(|rec| with_domain(rec, name)).collect());
+            }
+        }
+
+        None
+    }
+
+    /// Whether `name` owns records, or is an empty non-terminal above some.
+    /// In canonical order a name is directly followed by its descendants.
+    fn exists(&self, name: &DnsName) -> bool {
+        self.records
+            .range(name..)
+            .next()
+            .is_some_and(|(owner, _)| owner.is_subdomain_of(name))
+    }
+
+    /// The SOA for the authority section of NXDOMAIN and NODATA responses,
+    /// with its TTL capped to the minimum as RFC 2308 asks
+    fn negative_soa(&self) -> Option<DnsRecord> {
+        let mut soa = self.soa()?.clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = soa {
+            soa.set_ttl(minimum.min(ttl));
+        }
+        Some(soa)
+    }
+
+    /// Addresses we hold for the hosts named by NS, MX and SRV records
+    fn glue(&self, records: &[DnsRecord]) -> Vec<DnsRecord> {
+        let mut glue = Vec::new();
+        for rec in records {
+            let host = match rec {
+                DnsRecord::NS { host, .. }
+                | DnsRecord::MX { host, .. }
+                | DnsRecord::SRV { host, .. } => host,
+                _ => continue,
+            };
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                for addr in self.rrset(host, qtype) {
+                    if !glue.contains(&addr) {
+                        glue.push(addr);
+                    }
+                }
+            }
+        }
+        glue
+    }
+}
+
+impl fmt::Display for Zone {
+    /// The zone as a master file, SOA first
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for rec in self.all_records() {
+            writeln!(f, "{}", rec)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `a` is newer than `b` in the serial number arithmetic of
+/// RFC 1982, which lets SOA serials wrap around
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+fn soa_serial(soa: Option<&DnsRecord>) -> u32 {
+    match soa {
+        Some(DnsRecord::SOA { serial, .. }) => *serial,
+        _ => 0,
+    }
+}
+
+/// Parse the records of a master file without requiring them to form a
+/// zone, for files like trust anchors that only list a few records
+pub fn parse_records(text: &str, origin: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser {
+        origin: if origin.is_empty() {
+            None
+        } else {
+            Some(origin.parse()?)
+        },
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+
+    let mut records = Vec::new();
+    for (line_no, line) in logical_lines(text)?.into_iter().enumerate() {
+        parser
+            .parse_line(line, &mut records)
+            .map_err(|e| format!("line {}: {}", line_no + 1, e))?;
+    }
+
+    Ok(records)
+}
+
+/// Copy of `rec` with another owner name, for wildcard synthesis
+fn with_domain(rec: &DnsRecord, name: &DnsName) -> DnsRecord {
+    let mut rec = rec.clone();
+    rec.set_domain(name.clone());
+    rec
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(Vec<u8>),
+}
+
+impl Token {
+    fn as_bytes(&self) -> Vec<u8> {
+        match self {
+            Token::Word(word) => word.as_bytes().to_vec(),
+            Token::Quoted(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// An entry of the master file: its tokens, and whether it started with
+/// whitespace, meaning it belongs to the previous owner
+struct Line {
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Split the file into entries, dropping comments and joining lines
+/// inside parentheses
+fn logical_lines(text: &str) -> Result<Vec<Line>> {
+    let mut lines = Vec::new();
+    let mut tokens = Vec::new();
+    let mut blank_owner = false;
+    let mut at_line_start = true;
+    let mut depth = 0;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if at_line_start {
+            blank_owner = c == ' ' || c == '\t';
+            at_line_start = false;
+        }
+
+        match c {
+            '\n' => {
+                if depth == 0 {
+                    if !tokens.is_empty() {
+                        lines.push(Line {
+                            blank_owner,
+                            tokens: std::mem::take(&mut tokens),
+                        });
+                    }
+                    at_line_start = true;
+                }
+            }
+            ';' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err("unbalanced parentheses".into());
+                }
+                depth -= 1;
+            }
+            '"' => {
+                let mut bytes = Vec::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => bytes.extend(unescape(&mut chars)?),
+                        Some(c) => {
+                            let mut buf = [0; 4];
+                            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                        None => return Err("unterminated quoted string".into()),
+                    }
+                }
+                tokens.push(Token::Quoted(bytes));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = String::from(c);
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "();\"".contains(next) {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err("unbalanced parentheses".into());
+    }
+    if !tokens.is_empty() {
+        lines.push(Line {
+            blank_owner,
+            tokens,
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Decode what follows a backslash in a quoted string: `\DDD` or an
+/// escaped character
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Vec<u8>> {
+    let first = chars.next().ok_or("dangling escape")?;
+    if !first.is_ascii_digit() {
+        let mut buf = [0; 4];
+        return Ok(first.encode_utf8(&mut buf).as_bytes().to_vec());
+    }
+
+    let mut digits = String::from(first);
+    for _ in 0..2 {
+        match chars.next() {
+            Some(c) if c.is_ascii_digit() => digits.push(c),
+            _ => return Err("escape needs three digits".into()),
+        }
+    }
+    let value: u8 = digits.parse().map_err(|_| "escape out of range")?;
+    Ok(vec![value])
+}
+
+/// Parse a TTL, either in seconds or with BIND style units like `1h30m`
+fn parse_ttl(word: &str) -> Option<u32> {
+    if let Ok(ttl) = word.parse() {
+        return Some(ttl);
+    }
+
+    let mut total: u32 = 0;
+    let mut num = String::new();
+    for c in word.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        let value: u32 = num.parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        num.clear();
+    }
+
+    if num.is_empty() {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+struct Parser {
+    origin: Option<DnsName>,
+    default_ttl: Option<u32>,
+    last_owner: Option<DnsName>,
+    last_ttl: Option<u32>,
+}
+
+impl Parser {
+    fn parse_line(&mut self, line: Line, records: &mut Vec<DnsRecord>) -> Result<()> {
+        let mut tokens = line.tokens.into_iter().peekable();
+
+        if let Some(Token::Word(word)) = tokens.peek() {
+            match word.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    tokens.next();
+                    let origin = self.word(tokens.next())?;
+                    self.origin = Some(self.absolute(&origin)?);
+                    return Ok(());
+                }
+                "$TTL" => {
+                    tokens.next();
+                    let ttl = self.word(tokens.next())?;
+                    self.default_ttl = Some(parse_ttl(&ttl).ok_or("invalid $TTL")?);
+                    return Ok(());
+                }
+                "$INCLUDE" | "$GENERATE" => {
+                    return Err(format!("{} is not supported", word).into());
+                }
+                _ => {}
+            }
+        }
+
+        let owner = if line.blank_owner {
+            self.last_owner.clone().ok_or("no previous owner name")?
+        } else {
+            let owner = self.word(tokens.next())?;
+            self.absolute(&owner)?
+        };
+        self.last_owner = Some(owner.clone());
+
+        // TTL and class may come in either order, both are optional
+        let mut ttl = None;
+        let qtype = loop {
+            let word = self.word(tokens.next())?;
+            if ttl.is_none() {
+                if let Some(value) = parse_ttl(&word) {
+                    ttl = Some(value);
+                    continue;
+                }
+            }
+            match word.to_ascii_uppercase().as_str() {
+                "IN" => continue,
+                "CH" | "HS" | "CS" => return Err("only class IN is supported".into()),
+                _ => break word.parse::<QueryType>()?,
+            }
+        };
+
+        let ttl = match ttl {
+            Some(ttl) => {
+                self.last_ttl = Some(ttl);
+                ttl
+            }
+            None => self
+                .default_ttl
+                .or(self.last_ttl)
+                .ok_or("no TTL given and no $TTL set")?,
+        };
+
+        let rdata: Vec<Token> = tokens.collect();
+        records.push(self.record(owner, qtype, ttl, rdata)?);
+        Ok(())
+    }
+
+    fn record(
+        &self,
+        domain: DnsName,
+        qtype: QueryType,
+        ttl: u32,
+        rdata: Vec<Token>,
+    ) -> Result<DnsRecord> {
+        let mut fields = rdata.into_iter();
+
+        // RFC 3597 generic form works for every type
+        if let Some(Token::Word(word)) = fields.clone().next() {
+            if word == "\\#" {
+                fields.next();
+                let len: usize = self.word(fields.next())?.parse()?;
+                let hex: String = fields
+                    .map(|t| String::from_utf8_lossy(&t.as_bytes()).into_owned())
+                    .collect();
+                let data = from_hex(&hex).ok_or("invalid hex in generic rdata")?;
+                if data.len() != len {
+                    return Err("generic rdata length does not match its data".into());
+                }
+                return generic_record(domain, qtype, ttl, data);
+            }
+        }
+
+        let record = match qtype {
+            QueryType::A => DnsRecord::A {
+                domain,
+                addr: self.word(fields.next())?.parse::<Ipv4Addr>()?,
+                ttl,
+            },
+            QueryType::AAAA => DnsRecord::AAAA {
+                domain,
+                addr: self.word(fields.next())?.parse::<Ipv6Addr>()?,
+                ttl,
+            },
+            QueryType::NS => DnsRecord::NS {
+                domain,
+                host: self.name(fields.next())?,
+                ttl,
+            },
+            QueryType::CNAME => DnsRecord::CNAME {
+                domain,
+                host: self.name(fields.next())?,
+                ttl,
+            },
+            QueryType::PTR => DnsRecord::PTR {
+                domain,
+                host: self.name(fields.next())?,
+                ttl,
+            },
+            QueryType::SOA => DnsRecord::SOA {
+                domain,
+                mname: self.name(fields.next())?,
+                rname: self.name(fields.next())?,
+                serial: self.number(fields.next())?,
+                refresh: self.ttl(fields.next())?,
+                retry: self.ttl(fields.next())?,
+                expire: self.ttl(fields.next())?,
+                minimum: self.ttl(fields.next())?,
+                ttl,
+            },
+            QueryType::MX => DnsRecord::MX {
+                domain,
+                priority: self.number(fields.next())?,
+                host: self.name(fields.next())?,
+                ttl,
+            },
+            QueryType::TXT => {
+                let data: Vec<Vec<u8>> = fields.by_ref().map(|t| t.as_bytes()).collect();
+                if data.is_empty() {
+                    return Err("TXT record needs at least one string".into());
+                }
+                DnsRecord::TXT { domain, data, ttl }
+            }
+            QueryType::SRV => DnsRecord::SRV {
+                domain,
+                priority: self.number(fields.next())?,
+                weight: self.number(fields.next())?,
+                port: self.number(fields.next())?,
+                host: self.name(fields.next())?,
+                ttl,
+            },
+            QueryType::CAA => DnsRecord::CAA {
+                domain,
+                flags: self.number(fields.next())?,
+                tag: self.word(fields.next())?,
+                value: fields.next().ok_or("missing CAA value")?.as_bytes(),
+                ttl,
+            },
+            QueryType::DS => DnsRecord::DS {
+                domain,
+                key_tag: self.number(fields.next())?,
+                algorithm: self.number(fields.next())?,
+                digest_type: self.number(fields.next())?,
+                digest: from_hex(&self.joined(&mut fields)?).ok_or("invalid hex in DS digest")?,
+                ttl,
+            },
+            QueryType::DNSKEY => DnsRecord::DNSKEY {
+                domain,
+                flags: self.number(fields.next())?,
+                protocol: self.number(fields.next())?,
+                algorithm: self.number(fields.next())?,
+                public_key: self.base64(&mut fields)?,
+                ttl,
+            },
+            QueryType::RRSIG => DnsRecord::RRSIG {
+                domain,
+                type_covered: self.word(fields.next())?.parse()?,
+                algorithm: self.number(fields.next())?,
+                labels: self.number(fields.next())?,
+                original_ttl: self.ttl(fields.next())?,
+                expiration: self.timestamp(fields.next())?,
+                inception: self.timestamp(fields.next())?,
+                key_tag: self.number(fields.next())?,
+                signer_name: self.name(fields.next())?,
+                signature: self.base64(&mut fields)?,
+                ttl,
+            },
+            QueryType::NSEC => DnsRecord::NSEC {
+                domain,
+                next_domain: self.name(fields.next())?,
+                types: self.types(&mut fields)?,
+                ttl,
+            },
+            QueryType::NSEC3 => DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm: self.number(fields.next())?,
+                flags: self.number(fields.next())?,
+                iterations: self.number(fields.next())?,
+                salt: match self.word(fields.next())?.as_str() {
+                    "-" => Vec::new(),
+                    salt => from_hex(salt).ok_or("invalid hex in NSEC3 salt")?,
+                },
+                next_hashed: {
+                    let word = self.word(fields.next())?;
+                    from_base32hex(&word).ok_or("invalid base32hex in NSEC3 record")?
+                },
+                types: self.types(&mut fields)?,
+                ttl,
+            },
+            QueryType::UNKNOWN(_) => {
+                return Err(format!("{} records need the \\# generic form", qtype).into())
+            }
+            QueryType::OPT | QueryType::TSIG => {
+                return Err(format!("{} records only exist on the wire", qtype).into())
+            }
+            QueryType::IXFR | QueryType::AXFR => {
+                return Err(format!("{} is a query type, not a record type", qtype).into())
+            }
+        };
+
+        if fields.next().is_some() {
+            return Err(format!("trailing data after {} record", qtype).into());
+        }
+        Ok(record)
+    }
+
+    fn word(&self, token: Option<Token>) -> Result<String> {
+        match token {
+            Some(Token::Word(word)) => Ok(word),
+            Some(Token::Quoted(_)) => Err("unexpected quoted string".into()),
+            None => Err("unexpected end of record".into()),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&self, token: Option<Token>) -> Result<T> {
+        let word = self.word(token)?;
+        word.parse()
+            .map_err(|_| format!("invalid number: {}", word).into())
+    }
+
+    fn ttl(&self, token: Option<Token>) -> Result<u32> {
+        let word = self.word(token)?;
+        parse_ttl(&word).ok_or_else(|| format!("invalid time value: {}", word).into())
+    }
+
+    fn timestamp(&self, token: Option<Token>) -> Result<u32> {
+        let word = self.word(token)?;
+        parse_timestamp(&word).ok_or_else(|| format!("invalid timestamp: {}", word).into())
+    }
+
+    /// The remaining words of the record run together, as long hex and
+    /// base64 values are often split across lines
+    fn joined(&self, fields: &mut impl Iterator<Item = Token>) -> Result<String> {
+        let mut joined = String::new();
+        for token in fields {
+            joined.push_str(&self.word(Some(token))?);
+        }
+        if joined.is_empty() {
+            return Err("unexpected end of record".into());
+        }
+        Ok(joined)
+    }
+
+    fn base64(&self, fields: &mut impl Iterator<Item = Token>) -> Result<Vec<u8>> {
+        let text = self.joined(fields)?;
+        from_base64(&text).ok_or_else(|| "invalid base64".into())
+    }
+
+    /// The type list of NSEC and NSEC3 records, in the order of the bitmap
+    fn types(&self, fields: &mut impl Iterator<Item = Token>) -> Result<Vec<QueryType>> {
+        let mut types = Vec::new();
+        for token in fields {
+            types.push(self.word(Some(token))?.parse::<QueryType>()?);
+        }
+        types.sort_by_key(QueryType::to_num);
+        types.dedup();
+        Ok(types)
+    }
+
+    fn name(&self, token: Option<Token>) -> Result<DnsName> {
+        let word = self.word(token)?;
+        self.absolute(&word)
+    }
+
+    /// Resolve `@` and relative names against the current origin
+    fn absolute(&self, name: &str) -> Result<DnsName> {
+        let origin = self.origin.as_ref();
+        if name == "@" {
+            return origin
+                .cloned()
+                .ok_or_else(|| "@ used without $ORIGIN".into());
+        }
+
+        let parsed: DnsName = name.parse()?;
+        if name.ends_with('.') && !name.ends_with("\\.") {
+            return Ok(parsed);
+        }
+        match origin {
+            Some(origin) => Ok(parsed.concat(origin)?),
+            None => Err(format!("relative name {} without $ORIGIN", name).into()),
+        }
+    }
+}
+
+/// Turn generic rdata into a typed record by running it through the wire
+/// format parser
+fn generic_record(domain: DnsName, qtype: QueryType, ttl: u32, data: Vec<u8>) -> Result<DnsRecord> {
+    let record = DnsRecord::UNKNOWN {
+        domain,
+        qtype: qtype.to_num(),
+        data,
+        ttl,
+    };
+    if let QueryType::UNKNOWN(_) = qtype {
+        return Ok(record);
+    }
+
+    let mut buffer = VectorPacketBuffer::new();
+    record.write(&mut buffer)?;
+    buffer.seek(0);
+    Ok(DnsRecord::read(&mut buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                2h         ; refresh
+                1h         ; retry
+                2w         ; expire
+                300 )      ; minimum
+        IN  NS  ns1
+        IN  NS  ns2.example.net.
+        IN  MX  10 mail
+ns1     IN  A   192.0.2.1
+mail    600 IN A 192.0.2.2
+        IN  AAAA 2001:db8::2
+www     CNAME web
+web     A   192.0.2.3
+txt     TXT "v=spf1 -all" "semi;colon \"quoted\""
+*.apps  A   192.0.2.4
+a.b.deep A  192.0.2.5
+outside CNAME www.example.org.
+sub     NS  ns.sub
+ns.sub  A   192.0.2.53
+$ORIGIN sub2.example.com.
+host    A   192.0.2.6
+"#;
+
+    fn zone() -> Zone {
+        Zone::parse(ZONE, "").unwrap()
+    }
+
+    fn a(domain: &str, last: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(domain),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn test_parse_master_file() {
+        let zone = zone();
+        assert_eq!(zone.origin, "example.com");
+        assert_eq!(
+            zone.soa(),
+            Some(&DnsRecord::SOA {
+                domain: name("example.com"),
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 3600,
+            })
+        );
+        assert_eq!(zone.records[&name("example.com")].len(), 4);
+        assert_eq!(
+            zone.records[&name("mail.example.com")][0],
+            a("mail.example.com", 2, 600)
+        );
+        // the blank owner line inherits the owner and, without its own
+        // TTL, falls back to $TTL
+        assert_eq!(
+            zone.records[&name("mail.example.com")][1].query_type(),
+            QueryType::AAAA
+        );
+        assert_eq!(zone.records[&name("mail.example.com")][1].ttl(), 3600);
+        assert_eq!(
+            zone.records[&name("txt.example.com")][0],
+            DnsRecord::TXT {
+                domain: name("txt.example.com"),
+                data: vec![b"v=spf1 -all".to_vec(), b"semi;colon \"quoted\"".to_vec()],
+                ttl: 3600,
+            }
+        );
+        assert!(zone.records.contains_key(&name("host.sub2.example.com")));
+    }
+
+    #[test]
+    fn test_master_file_round_trip() {
+        let zone = zone();
+        let text = zone.to_string();
+        assert!(text.starts_with("example.com.\t"));
+        assert_eq!(Zone::parse(&text, "").unwrap().records, zone.records);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Zone::parse("@ 300 IN A 192.0.2.1", "").is_err());
+        assert!(Zone::parse("$ORIGIN example.com.\n@ 300 IN A 192.0.2.1", "").is_err());
+        assert!(Zone::parse("$ORIGIN example.com.\n@ 300 IN SOA a b ( 1 2 3 4 5", "").is_err());
+        let err = Zone::parse(
+            "$ORIGIN example.com.\n@ 300 IN SOA a b 1 2 3 4 5\nwww 300 IN A nope",
+            "",
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("line 3"));
+    }
+
+    #[test]
+    fn test_generic_rdata() {
+        let zone = Zone::parse(
+            "$ORIGIN example.com.\n$TTL 60\n@ SOA a b 1 2 3 4 5\n\
+             x TYPE1 \\# 4 c0000201\ny TYPE65280 \\# 2 beef",
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            zone.records[&name("x.example.com")][0],
+            a("x.example.com", 1, 60)
+        );
+        assert_eq!(
+            zone.records[&name("y.example.com")][0],
+            DnsRecord::UNKNOWN {
+                domain: name("y.example.com"),
+                qtype: 65280,
+                data: vec![0xbe, 0xef],
+                ttl: 60,
+            }
+        );
+
+        for bad in ["aéb", "+f", "abc"] {
+            let text = format!("$ORIGIN example.com.\n$TTL 60\nx TYPE65280 \\# 2 {}", bad);
+            assert!(parse_records(&text, "").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_dnssec_records() {
+        let text = "$ORIGIN example.com.\n$TTL 300\n\
+            @ DNSKEY 257 3 15 ( l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQw\n\
+                                AAAAAAAAAAA= )\n\
+            @ RRSIG DNSKEY 15 2 300 20240101000000 20231201000000 12345 example.com. (\n\
+                AAECAwQF BgcICQ== )\n\
+            sub DS 12345 13 2 ( 2BB183AF5F22588179A53B0A98631FAD\n\
+                                1A292118 )\n\
+            @ NSEC www AAAA A RRSIG NSEC TYPE1234\n\
+            0p9mhaveqvm6t7vbl5lop2u3t2rp3tom NSEC3 1 1 12 aabbccdd \
+                2t7b4g4vsa5smi47k61mv5bv1a22bojr A RRSIG\n\
+            x NSEC3 1 0 0 - 2T7B4G4VSA5SMI47K61MV5BV1A22BOJR\n";
+        let records = parse_records(text, "").unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(
+            records[2],
+            DnsRecord::DS {
+                domain: name("sub.example.com"),
+                key_tag: 12345,
+                algorithm: 13,
+                digest_type: 2,
+                digest: from_hex("2BB183AF5F22588179A53B0A98631FAD1A292118").unwrap(),
+                ttl: 300,
+            }
+        );
+        match records[1] {
+            DnsRecord::RRSIG {
+                type_covered,
+                expiration,
+                inception,
+                ref signature,
+                ..
+            } => {
+                assert_eq!(type_covered, QueryType::DNSKEY);
+                assert_eq!(expiration, 1704067200);
+                assert_eq!(inception, 1701388800);
+                assert_eq!(signature, &(0..10).collect::<Vec<u8>>());
+            }
+            ref other => panic!("expected RRSIG, got {:?}", other),
+        }
+        // types come out in bitmap order
+        match records[3] {
+            DnsRecord::NSEC { ref types, .. } => assert_eq!(
+                types,
+                &[
+                    QueryType::A,
+                    QueryType::AAAA,
+                    QueryType::RRSIG,
+                    QueryType::NSEC,
+                    QueryType::UNKNOWN(1234)
+                ]
+            ),
+            ref other => panic!("expected NSEC, got {:?}", other),
+        }
+
+        // the presentation format reads back the same
+        for rec in records {
+            let reparsed = parse_records(&rec.to_string(), "").unwrap();
+            assert_eq!(reparsed, vec![rec]);
+        }
+
+        assert!(parse_records("$ORIGIN example.com.\n@ 300 DNSKEY 257 3 15 !!", "").is_err());
+    }
+
+    #[test]
+    fn test_answer_with_glue() {
+        let packet = zone().answer(&name("example.com"), QueryType::MX).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.resources.len(), 2);
+        assert_eq!(packet.resources[0], a("mail.example.com", 2, 600));
+    }
+
+    #[test]
+    fn test_nxdomain_and_nodata() {
+        let zone = zone();
+
+        let packet = zone
+            .answer(&name("nope.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.authorities.len(), 1);
+        assert_eq!(packet.authorities[0].ttl(), 300);
+
+        let packet = zone
+            .answer(&name("web.example.com"), QueryType::MX)
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].query_type(), QueryType::SOA);
+
+        // empty non-terminal
+        let packet = zone
+            .answer(&name("b.deep.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+
+        assert!(zone.answer(&name("example.org"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn test_cname_chasing() {
+        let zone = zone();
+
+        let packet = zone.answer(&name("WWW.example.com"), QueryType::A).unwrap();
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[0].query_type(), QueryType::CNAME);
+        assert_eq!(packet.answers[1], a("web.example.com", 3, 3600));
+
+        let packet = zone
+            .answer(&name("www.example.com"), QueryType::CNAME)
+            .unwrap();
+        assert_eq!(packet.answers.len(), 1);
+
+        // the target is somebody else's, so the chain just ends
+        let packet = zone
+            .answer(&name("outside.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let zone = zone();
+
+        let packet = zone
+            .answer(&name("foo.apps.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(packet.answers, vec![a("foo.apps.example.com", 4, 3600)]);
+
+        let packet = zone
+            .answer(&name("a.b.apps.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(packet.answers, vec![a("a.b.apps.example.com", 4, 3600)]);
+
+        // the wildcard does not cover names that exist
+        let packet = zone
+            .answer(&name("c.deep.example.com"), QueryType::A)
+            .unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn test_delegation() {
+        let packet = zone()
+            .answer(&name("www.sub.example.com"), QueryType::A)
+            .unwrap();
+        assert!(!packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert_eq!(
+            packet.authorities,
+            vec![DnsRecord::NS {
+                domain: name("sub.example.com"),
+                host: name("ns.sub.example.com"),
+                ttl: 3600,
+            }]
+        );
+        assert_eq!(packet.resources, vec![a("ns.sub.example.com", 53, 3600)]);
+    }
+}